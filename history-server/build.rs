@@ -0,0 +1,26 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 在编译时记录git提交和构建时间，供 /api/version 使用
+fn main() {
+    // 没有.git目录时（如Docker构建）可通过环境变量传入提交哈希
+    let git_commit = std::env::var("GIT_COMMIT_HASH").ok().or_else(|| {
+        Command::new("git")
+            .args(["rev-parse", "--short", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+
+    let build_timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+
+    println!("cargo:rustc-env=GIT_COMMIT_HASH={}", git_commit.unwrap_or_else(|| "unknown".to_string()));
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", build_timestamp);
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT_HASH");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs");
+}
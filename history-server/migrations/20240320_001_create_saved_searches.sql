@@ -0,0 +1,10 @@
+-- 创建保存的搜索表
+CREATE TABLE IF NOT EXISTS saved_searches (
+    id SERIAL PRIMARY KEY,
+    owner VARCHAR(100) NOT NULL,
+    name VARCHAR(200) NOT NULL,
+    filters JSONB NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    UNIQUE (owner, name)
+);
@@ -0,0 +1,12 @@
+-- 创建规则变更审计表
+CREATE TABLE IF NOT EXISTS audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    rule_id INTEGER NOT NULL,
+    action VARCHAR(20) NOT NULL,
+    before_value JSONB,
+    after_value JSONB,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_audit_log_rule_id
+ON audit_log(rule_id, created_at);
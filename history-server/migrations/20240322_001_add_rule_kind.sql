@@ -0,0 +1,3 @@
+-- 规则类型：regex 为正则替换，strip_path_params 为内置的路径参数清理
+ALTER TABLE normalization_rules
+ADD COLUMN IF NOT EXISTS kind VARCHAR(50) NOT NULL DEFAULT 'regex';
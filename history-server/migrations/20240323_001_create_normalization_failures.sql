@@ -0,0 +1,11 @@
+-- 归一化失败记录，便于排查和重新处理
+CREATE TABLE IF NOT EXISTS normalization_failures (
+    id BIGSERIAL PRIMARY KEY,
+    url TEXT NOT NULL,
+    rule_id INTEGER,
+    error TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_normalization_failures_created_at
+ON normalization_failures(created_at);
@@ -0,0 +1,6 @@
+-- 规则分类（如 tracking、experimental），用于按分类批量启用/禁用
+ALTER TABLE normalization_rules
+ADD COLUMN IF NOT EXISTS category VARCHAR(100);
+
+CREATE INDEX IF NOT EXISTS idx_normalization_rules_category
+ON normalization_rules(category);
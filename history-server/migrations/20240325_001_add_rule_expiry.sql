@@ -0,0 +1,3 @@
+-- 临时规则的过期时间，过期后加载规则时视为禁用，后台任务会把它们改为 enabled = false
+ALTER TABLE normalization_rules
+ADD COLUMN IF NOT EXISTS expires_at TIMESTAMP WITH TIME ZONE;
@@ -1,4 +1,7 @@
+use actix_web::{http::StatusCode, HttpMessage, HttpRequest, HttpResponse};
+use serde_json::{json, Map, Value};
 use thiserror::Error;
+use tracing_actix_web::RequestId;
 
 #[derive(Error, Debug)]
 pub enum AppError {
@@ -12,4 +15,141 @@ pub enum AppError {
     InternalError(String),
 }
 
-impl actix_web::error::ResponseError for AppError {}
+impl actix_web::error::ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            AppError::DatabaseError(_) | AppError::InternalError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // ResponseError 拿不到请求，request_id 为 null
+    fn error_response(&self) -> HttpResponse {
+        ApiError::new(self.status_code(), self.to_string()).build(None)
+    }
+}
+
+/// 统一的错误响应：`{ "error": { "code", "message", "request_id" } }`
+///
+/// 批量写入等需要告诉客户端哪些记录未处理的场景，额外字段放在响应顶层。
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+    fields: Map<String, Value>,
+    retry_after_seconds: Option<u64>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            fields: Map::new(),
+            retry_after_seconds: None,
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, message)
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::CONFLICT, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, message)
+    }
+
+    /// 在响应顶层附加字段
+    pub fn with_field(mut self, name: &str, value: Value) -> Self {
+        self.fields.insert(name.to_string(), value);
+        self
+    }
+
+    /// 设置 Retry-After 响应头
+    pub fn with_retry_after(mut self, seconds: u64) -> Self {
+        self.retry_after_seconds = Some(seconds);
+        self
+    }
+
+    pub fn response(self, request_id: &RequestId) -> HttpResponse {
+        self.build(Some(request_id))
+    }
+
+    /// 在没有 RequestId 提取器的地方（如请求体解析失败的回调）从请求中读取请求ID
+    pub fn response_for(self, request: &HttpRequest) -> HttpResponse {
+        let request_id = request.extensions().get::<RequestId>().copied();
+        self.build(request_id.as_ref())
+    }
+
+    fn build(self, request_id: Option<&RequestId>) -> HttpResponse {
+        let mut builder = HttpResponse::build(self.status);
+        if let Some(seconds) = self.retry_after_seconds {
+            builder.insert_header(("Retry-After", seconds.to_string()));
+        }
+        builder.json(self.body(request_id.map(|id| id.to_string())))
+    }
+
+    fn body(self, request_id: Option<String>) -> Value {
+        let mut body = self.fields;
+        body.insert("error".to_string(), json!({
+            "code": error_code(self.status),
+            "message": self.message,
+            "request_id": request_id
+        }));
+        Value::Object(body)
+    }
+}
+
+/// 由状态码得到错误码
+fn error_code(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::BAD_REQUEST => "bad_request",
+        StatusCode::UNAUTHORIZED => "unauthorized",
+        StatusCode::NOT_FOUND => "not_found",
+        StatusCode::CONFLICT => "conflict",
+        StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+        StatusCode::TOO_MANY_REQUESTS => "too_many_requests",
+        StatusCode::SERVICE_UNAVAILABLE => "service_unavailable",
+        status if status.is_server_error() => "internal_error",
+        _ => "error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_envelope() {
+        let body = ApiError::not_found("Rule not found").body(Some("req-1".to_string()));
+        assert_eq!(body, json!({
+            "error": {
+                "code": "not_found",
+                "message": "Rule not found",
+                "request_id": "req-1"
+            }
+        }));
+    }
+
+    #[test]
+    fn test_error_envelope_with_fields() {
+        let body = ApiError::new(StatusCode::TOO_MANY_REQUESTS, "Retry later")
+            .with_field("not_processed", json!([1, 2]))
+            .body(None);
+        assert_eq!(body, json!({
+            "error": {
+                "code": "too_many_requests",
+                "message": "Retry later",
+                "request_id": null
+            },
+            "not_processed": [1, 2]
+        }));
+    }
+}
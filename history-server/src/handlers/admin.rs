@@ -0,0 +1,141 @@
+use actix_web::{get, http::StatusCode, web, HttpRequest, HttpResponse, Responder};
+use futures_util::TryStreamExt;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tracing_actix_web::RequestId;
+
+use crate::AppState;
+use crate::error::ApiError;
+use crate::services::dump::{self, DumpCompression};
+use crate::services::es;
+
+const API_KEY_HEADER: &str = "X-API-Key";
+
+/// 校验管理接口的API密钥，未配置密钥时拒绝所有请求
+fn check_api_key(request: &HttpRequest, expected: Option<&str>) -> Result<(), ApiError> {
+    let Some(expected) = expected else {
+        return Err(ApiError::not_found("Admin API is disabled"));
+    };
+
+    let provided = request.headers().get(API_KEY_HEADER).and_then(|value| value.to_str().ok());
+    if provided.is_some_and(|provided| keys_match(provided, expected)) {
+        Ok(())
+    } else {
+        Err(ApiError::new(StatusCode::UNAUTHORIZED, "Missing or invalid API key"))
+    }
+}
+
+/// 比较两个密钥的 SHA-256 摘要并逐字节累积差异，耗时与密钥在哪一位不同无关
+fn keys_match(provided: &str, expected: &str) -> bool {
+    let provided = Sha256::digest(provided.as_bytes());
+    let expected = Sha256::digest(expected.as_bytes());
+    provided.iter().zip(expected.iter()).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// 返回服务实际生效的配置（合并默认配置、RUN_MODE 配置和环境变量之后），凭据已隐藏
+#[utoipa::path(
+    get,
+    path = "/api/admin/config",
+    tag = "admin",
+    params(
+        ("X-API-Key" = String, Header, description = "Admin API key")
+    ),
+    responses(
+        (status = 200, description = "Effective configuration with secrets redacted"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 404, description = "Admin API is disabled")
+    )
+)]
+#[get("/api/admin/config")]
+pub async fn get_effective_config(
+    request_id: RequestId,
+    request: HttpRequest,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    if let Err(e) = check_api_key(&request, app_state.config.admin.api_key.as_deref()) {
+        return e.response(&request_id);
+    }
+
+    HttpResponse::Ok().json(json!({
+        "status": "success",
+        "data": app_state.config.redacted()
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DumpQuery {
+    pub compress: Option<DumpCompression>,
+}
+
+/// 以NDJSON流导出整个索引（每行一个文档的 `_id` 和 `_source`），`compress=gzip` 时流式gzip压缩
+#[utoipa::path(
+    get,
+    path = "/api/admin/dump",
+    tag = "admin",
+    params(
+        ("X-API-Key" = String, Header, description = "Admin API key"),
+        ("compress" = Option<String>, Query, description = "Compression: gzip (sent with Content-Encoding: gzip)")
+    ),
+    responses(
+        (status = 200, description = "NDJSON stream of every document in the index"),
+        (status = 400, description = "Unsupported compression"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 404, description = "Admin API is disabled")
+    )
+)]
+#[get("/api/admin/dump")]
+pub async fn dump_history(
+    request_id: RequestId,
+    request: HttpRequest,
+    query: web::Query<DumpQuery>,
+    es_client: web::Data<es::ReadClient>,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    if let Err(e) = check_api_key(&request, app_state.config.admin.api_key.as_deref()) {
+        return e.response(&request_id);
+    }
+    tracing::info!("GET /api/admin/dump: {:?}", query);
+
+    let index = app_state.config.elasticsearch.index.clone();
+    // 滚动上下文由流持有，压缩层不影响释放：流结束、出错或客户端断开时都会清理
+    let body = dump::ndjson_stream(es_client.0.clone(), index, query.compress)
+        .map_ok(web::Bytes::from)
+        .inspect_err(|e| tracing::error!(error = %e, "History dump failed"));
+
+    let filename = format!("browser-history-{}.ndjson", chrono::Utc::now().format("%Y%m%d-%H%M%S"));
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type("application/x-ndjson")
+        .insert_header(("Content-Disposition", format!("attachment; filename=\"{}\"", filename)));
+    if query.compress == Some(DumpCompression::Gzip) {
+        response.insert_header(("Content-Encoding", "gzip"));
+    }
+    response.streaming(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_check_api_key() {
+        let request = TestRequest::default().insert_header((API_KEY_HEADER, "secret")).to_http_request();
+        assert!(check_api_key(&request, Some("secret")).is_ok());
+        assert!(check_api_key(&request, Some("other")).is_err());
+        assert!(check_api_key(&request, None).is_err());
+
+        let request = TestRequest::default().to_http_request();
+        assert!(check_api_key(&request, Some("secret")).is_err());
+    }
+
+    #[test]
+    fn test_keys_match() {
+        assert!(keys_match("secret", "secret"));
+        assert!(!keys_match("secret", "secreT"));
+        assert!(!keys_match("secret", "secret-longer"));
+        assert!(!keys_match("", "secret"));
+    }
+}
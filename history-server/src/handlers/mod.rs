@@ -1 +1,3 @@
-pub mod normalization;
\ No newline at end of file
+pub mod normalization;
+pub mod saved_searches;
+pub mod admin;
@@ -1,26 +1,138 @@
 use actix_web::{web, HttpResponse, Responder, get, post, put, delete};
+use elasticsearch::Elasticsearch;
 use serde_json::json;
+use chrono::{DateTime, Utc};
 use std::sync::Arc;
+use std::time::Duration;
+use tracing_actix_web::RequestId;
 use utoipa::ToSchema;
 use serde::{Deserialize, Serialize};
 
 use crate::AppState;
-use crate::services::database::{CreateRuleRequest, UpdateRuleRequest, TestRuleRequest, TestRuleResponse};
+use crate::config::DuplicateOrderAction;
+use crate::error::ApiError;
+use crate::services::database::{RULE_KINDS, RULE_KIND_STRIP_PATH_PARAMS, FieldErrors, RuleExport, CreateRuleRequest, UpdateRuleRequest, TestRuleRequest, TestRecentRequest, SimulateReorderRequest, ToggleCategoryRequest, ValidatePatternRequest, ValidatePatternResult, DetectConflictsRequest};
+use crate::services::es;
+use crate::services::url_normalizer::{compile_rule, NormalizationError, UrlNormalizer};
 
-/// 获取所有归一化规则
+// 规则变更后缓存刷新失败时，后台重试的次数和首次重试前的等待时间（之后每次加倍）
+const CACHE_REFRESH_RETRIES: u32 = 5;
+const CACHE_REFRESH_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// 规则已提交到数据库后刷新归一化缓存并通知其他实例，返回写入响应的 `cache_refresh` 状态
+/// 刷新失败时不影响已提交的变更，返回 "failed" 提示客户端，并在后台重试
+async fn refresh_after_change(app_state: &AppState) -> &'static str {
+    let status = match app_state.url_normalizer.refresh_rules_cache().await {
+        Ok(_) => "ok",
+        Err(e) => {
+            tracing::error!("Failed to refresh normalizer cache, retrying in background: {}", e);
+            spawn_refresh_retry(app_state.url_normalizer.clone());
+            "failed"
+        }
+    };
+    if let Some(invalidator) = &app_state.rule_invalidator {
+        invalidator.publish().await;
+    }
+    status
+}
+
+fn spawn_refresh_retry(url_normalizer: Arc<UrlNormalizer>) {
+    tokio::spawn(async move {
+        let mut delay = CACHE_REFRESH_RETRY_DELAY;
+        for attempt in 1..=CACHE_REFRESH_RETRIES {
+            tokio::time::sleep(delay).await;
+            match url_normalizer.refresh_rules_cache().await {
+                Ok(_) => {
+                    tracing::info!("Normalizer cache refreshed on retry {}", attempt);
+                    return;
+                }
+                Err(e) => tracing::warn!("Normalizer cache refresh retry {} failed: {}", attempt, e),
+            }
+            delay *= 2;
+        }
+        tracing::error!("Giving up refreshing normalizer cache after {} retries", CACHE_REFRESH_RETRIES);
+    });
+}
+
+/// 校验规则类型，未提供时使用默认的 regex
+fn validate_kind(kind: Option<&str>) -> Result<(), String> {
+    match kind {
+        Some(kind) if !RULE_KINDS.contains(&kind) => {
+            Err(format!("Unknown rule kind '{}', expected one of: {}", kind, RULE_KINDS.join(", ")))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// 字段校验失败时返回 400，`field_errors` 列出每个字段的错误
+fn field_errors_response(errors: FieldErrors, request_id: &RequestId) -> Option<HttpResponse> {
+    if errors.is_empty() {
+        return None;
+    }
+    let summary = errors
+        .iter()
+        .map(|(field, message)| format!("{} {}", field, message))
+        .collect::<Vec<_>>()
+        .join("; ");
+    Some(
+        ApiError::bad_request(format!("Invalid rule data: {}", summary))
+            .with_field("field_errors", json!(errors))
+            .response(request_id),
+    )
+}
+
+/// 规则列表查询参数
+#[derive(Debug, Deserialize)]
+pub struct RulesQuery {
+    #[serde(rename = "modifiedSince")]
+    modified_since: Option<String>,
+}
+
+/// 获取所有归一化规则；提供 modifiedSince 时只返回之后变更的规则和已删除的规则ID
 #[utoipa::path(
     get,
     path = "/api/normalization-rules",
     tag = "normalization",
+    params(
+        ("modifiedSince" = Option<String>, Query, description = "RFC 3339 time; only rules created or updated after it are returned, plus `deleted_ids` and `as_of` for the next sync")
+    ),
     responses(
         (status = 200, description = "List of normalization rules"),
+        (status = 400, description = "Invalid modifiedSince"),
         (status = 500, description = "Internal server error")
     )
 )]
 #[get("/api/normalization-rules")]
-pub async fn get_rules(app_state: web::Data<Arc<AppState>>) -> impl Responder {
-    tracing::info!("GET /api/normalization-rules");
-    
+pub async fn get_rules(
+    request_id: RequestId,
+    query: web::Query<RulesQuery>,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    tracing::info!("GET /api/normalization-rules: {:?}", query);
+
+    if let Some(modified_since) = &query.modified_since {
+        let since = match DateTime::parse_from_rfc3339(modified_since) {
+            Ok(since) => since.with_timezone(&Utc),
+            Err(_) => {
+                return ApiError::bad_request(format!("Invalid modifiedSince '{}', expected an RFC 3339 time", modified_since))
+                    .response(&request_id);
+            }
+        };
+        return match app_state.database.get_rules_modified_since(since).await {
+            Ok(changes) => HttpResponse::Ok().json(json!({
+                "status": "success",
+                "total": changes.rules.len(),
+                "data": changes.rules,
+                "deleted_ids": changes.deleted_ids,
+                "as_of": changes.as_of
+            })),
+            Err(e) => {
+                tracing::error!("Failed to get normalization rules modified since {}: {}", since, e);
+                ApiError::internal("Failed to retrieve rules").response(&request_id)
+            }
+        };
+    }
+
     match app_state.database.get_all_normalization_rules().await {
         Ok(rules) => {
             HttpResponse::Ok().json(json!({
@@ -31,10 +143,7 @@ pub async fn get_rules(app_state: web::Data<Arc<AppState>>) -> impl Responder {
         }
         Err(e) => {
             tracing::error!("Failed to get normalization rules: {}", e);
-            HttpResponse::InternalServerError().json(json!({
-                "status": "error",
-                "message": "Failed to retrieve rules"
-            }))
+            ApiError::internal("Failed to retrieve rules").response(&request_id)
         }
     }
 }
@@ -48,43 +157,50 @@ pub async fn get_rules(app_state: web::Data<Arc<AppState>>) -> impl Responder {
     responses(
         (status = 201, description = "Rule created successfully"),
         (status = 400, description = "Invalid rule data"),
+        (status = 409, description = "order_index is already used and duplicate_order_action is reject"),
         (status = 500, description = "Internal server error")
     )
 )]
 #[post("/api/normalization-rules")]
 pub async fn create_rule(
+    request_id: RequestId,
     app_state: web::Data<Arc<AppState>>,
     rule_data: web::Json<CreateRuleRequest>,
 ) -> impl Responder {
     tracing::info!("POST /api/normalization-rules: {:?}", rule_data);
+
+    if let Some(response) = field_errors_response(rule_data.validate(), &request_id) {
+        return response;
+    }
+    if let Err(message) = validate_kind(rule_data.kind.as_deref()) {
+        return ApiError::bad_request(message).response(&request_id);
+    }
     
     // 验证正则表达式
     if let Err(e) = regex::Regex::new(&rule_data.pattern) {
-        return HttpResponse::BadRequest().json(json!({
-            "status": "error",
-            "message": format!("Invalid regex pattern: {}", e)
-        }));
+        return ApiError::bad_request(format!("Invalid regex pattern: {}", e)).response(&request_id);
     }
     
-    match app_state.database.create_rule(&rule_data).await {
-        Ok(new_rule) => {
-            // 刷新URL归一化器的缓存
-            if let Err(e) = app_state.url_normalizer.refresh_rules_cache().await {
-                tracing::error!("Failed to refresh normalizer cache: {}", e);
-            }
-            
+    let shift_on_duplicate = app_state.config.normalization.duplicate_order_action == DuplicateOrderAction::Shift;
+    match app_state.database.create_rule(&rule_data, shift_on_duplicate).await {
+        Ok(Some(new_rule)) => {
+            let cache_refresh = refresh_after_change(&app_state).await;
+
             HttpResponse::Created().json(json!({
                 "status": "success",
                 "message": "Rule created successfully",
-                "data": new_rule
+                "data": new_rule,
+                "cache_refresh": cache_refresh
             }))
         }
+        Ok(None) => ApiError::conflict(format!(
+            "order_index {} is already used by another enabled rule",
+            rule_data.order_index.unwrap_or_default()
+        ))
+        .response(&request_id),
         Err(e) => {
             tracing::error!("Failed to create rule: {}", e);
-            HttpResponse::InternalServerError().json(json!({
-                "status": "error",
-                "message": "Failed to create rule"
-            }))
+            ApiError::internal("Failed to create rule").response(&request_id)
         }
     }
 }
@@ -99,7 +215,7 @@ pub async fn create_rule(
     ),
     request_body = UpdateRuleRequest,
     responses(
-        (status = 200, description = "Rule updated successfully"),
+        (status = 200, description = "Rule updated successfully; `cache_refresh` is \"failed\" when the normalizer cache could not be reloaded (retried in background)"),
         (status = 404, description = "Rule not found"),
         (status = 400, description = "Invalid rule data"),
         (status = 500, description = "Internal server error")
@@ -107,49 +223,210 @@ pub async fn create_rule(
 )]
 #[put("/api/normalization-rules/{id}")]
 pub async fn update_rule(
+    request_id: RequestId,
     app_state: web::Data<Arc<AppState>>,
     path: web::Path<i32>,
     rule_data: web::Json<UpdateRuleRequest>,
 ) -> impl Responder {
     let rule_id = path.into_inner();
     tracing::info!("PUT /api/normalization-rules/{}: {:?}", rule_id, rule_data);
-    
+
+    if let Some(response) = field_errors_response(rule_data.validate(), &request_id) {
+        return response;
+    }
+    if let Err(message) = validate_kind(rule_data.kind.as_deref()) {
+        return ApiError::bad_request(message).response(&request_id);
+    }
+
     // 验证正则表达式（如果提供了）
     if let Some(pattern) = &rule_data.pattern {
         if let Err(e) = regex::Regex::new(pattern) {
-            return HttpResponse::BadRequest().json(json!({
-                "status": "error",
-                "message": format!("Invalid regex pattern: {}", e)
-            }));
+            return ApiError::bad_request(format!("Invalid regex pattern: {}", e)).response(&request_id);
         }
     }
     
     match app_state.database.update_rule(rule_id, &rule_data).await {
         Ok(Some(updated_rule)) => {
-            // 刷新URL归一化器的缓存
-            if let Err(e) = app_state.url_normalizer.refresh_rules_cache().await {
-                tracing::error!("Failed to refresh normalizer cache: {}", e);
-            }
-            
+            let cache_refresh = refresh_after_change(&app_state).await;
+
             HttpResponse::Ok().json(json!({
                 "status": "success",
                 "message": "Rule updated successfully",
-                "data": updated_rule
+                "data": updated_rule,
+                "cache_refresh": cache_refresh
             }))
         }
         Ok(None) => {
-            HttpResponse::NotFound().json(json!({
-                "status": "error",
-                "message": "Rule not found"
-            }))
+            ApiError::not_found("Rule not found").response(&request_id)
         }
         Err(e) => {
             tracing::error!("Failed to update rule: {}", e);
-            HttpResponse::InternalServerError().json(json!({
-                "status": "error",
-                "message": "Failed to update rule"
+            ApiError::internal("Failed to update rule").response(&request_id)
+        }
+    }
+}
+
+/// 导出所有规则，返回的数组可直接作为 POST /api/normalization-rules/import 的请求体
+#[utoipa::path(
+    get,
+    path = "/api/normalization-rules/export",
+    tag = "normalization",
+    responses(
+        (status = 200, description = "All rules (including disabled) as an array of { kind, category, pattern, replacement, enabled, order_index }"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+#[get("/api/normalization-rules/export")]
+pub async fn export_rules(
+    request_id: RequestId,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    tracing::info!("GET /api/normalization-rules/export");
+
+    match app_state.database.export_rules().await {
+        Ok(rules) => HttpResponse::Ok().json(rules),
+        Err(e) => {
+            tracing::error!("Failed to export rules: {}", e);
+            ApiError::internal("Failed to export rules").response(&request_id)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportRulesQuery {
+    /// 为 true 时先删除所有现有规则
+    #[serde(default)]
+    pub replace: bool,
+}
+
+/// 校验导入的单条规则，返回错误信息（字段名 -> 错误）
+fn validate_import_entry(rule: &RuleExport) -> FieldErrors {
+    let mut errors = rule.validate();
+    if let Err(message) = validate_kind(Some(&rule.kind)) {
+        errors.insert("kind", message);
+    }
+    if !errors.contains_key("pattern") {
+        // 内置规则不使用替换串，只校验 pattern
+        let compiled = if rule.kind == RULE_KIND_STRIP_PATH_PARAMS {
+            regex::Regex::new(&rule.pattern).map(|_| ()).map_err(|e| e.to_string())
+        } else {
+            compile_rule(&rule.pattern, &rule.replacement).map(|_| ()).map_err(|e| e.to_string())
+        };
+        if let Err(message) = compiled {
+            errors.insert("pattern", message);
+        }
+    }
+    errors
+}
+
+/// 导入规则（格式与导出相同），所有规则校验通过后在一个事务中写入
+#[utoipa::path(
+    post,
+    path = "/api/normalization-rules/import",
+    tag = "normalization",
+    params(
+        ("replace" = Option<bool>, Query, description = "Delete all existing rules before importing (default false)")
+    ),
+    request_body = Vec<RuleExport>,
+    responses(
+        (status = 200, description = "Rules imported"),
+        (status = 400, description = "Some rules are invalid; `invalid` lists them by position and nothing is imported"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+#[post("/api/normalization-rules/import")]
+pub async fn import_rules(
+    request_id: RequestId,
+    app_state: web::Data<Arc<AppState>>,
+    query: web::Query<ImportRulesQuery>,
+    rules: web::Json<Vec<RuleExport>>,
+) -> impl Responder {
+    tracing::info!("POST /api/normalization-rules/import: {} rules, replace={}", rules.len(), query.replace);
+
+    let invalid: Vec<serde_json::Value> = rules
+        .iter()
+        .enumerate()
+        .filter_map(|(index, rule)| {
+            let errors = validate_import_entry(rule);
+            (!errors.is_empty()).then(|| json!({ "index": index, "field_errors": errors }))
+        })
+        .collect();
+    if !invalid.is_empty() {
+        return ApiError::bad_request(format!("{} of {} rules are invalid, nothing was imported", invalid.len(), rules.len()))
+            .with_field("invalid", json!(invalid))
+            .response(&request_id);
+    }
+
+    match app_state.database.import_rules(&rules, query.replace).await {
+        Ok(imported) => {
+            let cache_refresh = refresh_after_change(&app_state).await;
+
+            HttpResponse::Ok().json(json!({
+                "status": "success",
+                "data": imported,
+                "total": imported.len(),
+                "cache_refresh": cache_refresh
             }))
         }
+        Err(e) => {
+            tracing::error!("Failed to import rules: {}", e);
+            ApiError::internal("Failed to import rules").response(&request_id)
+        }
+    }
+}
+
+/// 启用或禁用某个分类下的所有规则（如故障期间关闭 experimental 分类），只刷新一次缓存
+#[utoipa::path(
+    post,
+    path = "/api/normalization-rules/category/{category}/toggle",
+    tag = "normalization",
+    params(
+        ("category" = String, Path, description = "Rule category")
+    ),
+    request_body = ToggleCategoryRequest,
+    responses(
+        (status = 200, description = "Number of rules whose enabled state changed"),
+        (status = 404, description = "No rules in this category"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+#[post("/api/normalization-rules/category/{category}/toggle")]
+pub async fn toggle_category(
+    request_id: RequestId,
+    app_state: web::Data<Arc<AppState>>,
+    path: web::Path<String>,
+    request: web::Json<ToggleCategoryRequest>,
+) -> impl Responder {
+    let category = path.into_inner();
+    tracing::info!("POST /api/normalization-rules/category/{}/toggle: {:?}", category, request);
+
+    match app_state.database.set_category_enabled(&category, request.enabled).await {
+        Ok(Some(changed)) => {
+            // 没有规则变化时缓存无需刷新
+            let cache_refresh = if changed.is_empty() {
+                "ok"
+            } else {
+                refresh_after_change(&app_state).await
+            };
+
+            HttpResponse::Ok().json(json!({
+                "status": "success",
+                "data": {
+                    "category": category,
+                    "enabled": request.enabled,
+                    "changed": changed.len(),
+                    "rule_ids": changed.iter().map(|rule| rule.id).collect::<Vec<_>>()
+                },
+                "cache_refresh": cache_refresh
+            }))
+        }
+        Ok(None) => {
+            ApiError::not_found(format!("No rules in category '{}'", category)).response(&request_id)
+        }
+        Err(e) => {
+            tracing::error!("Failed to toggle rule category {}: {}", category, e);
+            ApiError::internal("Failed to toggle rule category").response(&request_id)
+        }
     }
 }
 
@@ -169,6 +446,7 @@ pub async fn update_rule(
 )]
 #[delete("/api/normalization-rules/{id}")]
 pub async fn delete_rule(
+    request_id: RequestId,
     app_state: web::Data<Arc<AppState>>,
     path: web::Path<i32>,
 ) -> impl Responder {
@@ -177,28 +455,20 @@ pub async fn delete_rule(
     
     match app_state.database.delete_rule(rule_id).await {
         Ok(true) => {
-            // 刷新URL归一化器的缓存
-            if let Err(e) = app_state.url_normalizer.refresh_rules_cache().await {
-                tracing::error!("Failed to refresh normalizer cache: {}", e);
-            }
-            
+            let cache_refresh = refresh_after_change(&app_state).await;
+
             HttpResponse::Ok().json(json!({
                 "status": "success",
-                "message": format!("Rule {} deleted successfully", rule_id)
+                "message": format!("Rule {} deleted successfully", rule_id),
+                "cache_refresh": cache_refresh
             }))
         }
         Ok(false) => {
-            HttpResponse::NotFound().json(json!({
-                "status": "error",
-                "message": "Rule not found"
-            }))
+            ApiError::not_found("Rule not found").response(&request_id)
         }
         Err(e) => {
             tracing::error!("Failed to delete rule: {}", e);
-            HttpResponse::InternalServerError().json(json!({
-                "status": "error",
-                "message": "Failed to delete rule"
-            }))
+            ApiError::internal("Failed to delete rule").response(&request_id)
         }
     }
 }
@@ -210,38 +480,251 @@ pub async fn delete_rule(
     tag = "normalization",
     request_body = TestRuleRequest,
     responses(
-        (status = 200, description = "Test result"),
+        (status = 200, description = "Test result with the capture groups of the first match and the expanded replacement"),
         (status = 400, description = "Invalid test data"),
         (status = 500, description = "Internal server error")
     )
 )]
 #[post("/api/normalization-rules/test")]
 pub async fn test_rule(
+    request_id: RequestId,
     app_state: web::Data<Arc<AppState>>,
     test_data: web::Json<TestRuleRequest>,
 ) -> impl Responder {
     tracing::info!("POST /api/normalization-rules/test: {:?}", test_data);
     
     match app_state.url_normalizer.test_rule(&test_data.pattern, &test_data.replacement, &test_data.test_url).await {
-        Ok(result) => {
-            let response = TestRuleResponse {
-                original_url: result.original_url,
-                normalized_url: result.normalized_url,
-                matched: result.matched,
-            };
-            
+        Ok(response) => {
             HttpResponse::Ok().json(json!({
                 "status": "success",
                 "data": response
             }))
         }
         Err(e) => {
-            HttpResponse::BadRequest().json(json!({
-                "status": "error",
-                "message": format!("Test failed: {}", e)
+            ApiError::bad_request(format!("Test failed: {}", e)).response(&request_id)
+        }
+    }
+}
+
+/// 用最近写入的真实URL测试候选规则，返回每个URL的前后对比和匹配数量
+#[utoipa::path(
+    post,
+    path = "/api/normalization-rules/test-recent",
+    tag = "normalization",
+    request_body = TestRecentRequest,
+    responses(
+        (status = 200, description = "Before/after pairs for the most recent distinct stored URLs"),
+        (status = 400, description = "Invalid pattern or replacement"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+#[post("/api/normalization-rules/test-recent")]
+pub async fn test_rule_on_recent(
+    request_id: RequestId,
+    es_client: web::Data<Arc<Elasticsearch>>,
+    app_state: web::Data<Arc<AppState>>,
+    request: web::Json<TestRecentRequest>,
+) -> impl Responder {
+    let limit = request.limit.unwrap_or(200).clamp(1, MAX_CONFLICT_SCAN);
+    tracing::info!("POST /api/normalization-rules/test-recent: {:?}", request);
+
+    // 先校验规则，无效时不必查询ES
+    if let Err(e) = compile_rule(&request.pattern, &request.replacement) {
+        return ApiError::bad_request(format!("Test failed: {}", e)).response(&request_id);
+    }
+
+    let urls = match es::sample_original_urls(&es_client, &app_state.config.elasticsearch.index, limit).await {
+        Ok(urls) => urls,
+        Err(e) => {
+            tracing::error!("Failed to sample stored URLs: {}", e);
+            return ApiError::internal("Failed to sample stored URLs").response(&request_id);
+        }
+    };
+
+    match UrlNormalizer::test_rule_on_urls(&request.pattern, &request.replacement, &urls) {
+        Ok(results) => {
+            let matched = results.iter().filter(|result| result.matched).count();
+
+            HttpResponse::Ok().json(json!({
+                "status": "success",
+                "data": {
+                    "sampled": results.len(),
+                    "matched": matched,
+                    "results": results
+                }
+            }))
+        }
+        Err(e) => ApiError::bad_request(format!("Test failed: {}", e)).response(&request_id),
+    }
+}
+
+/// 批量校验规则（正则编译和替换串的分组引用），不创建规则
+#[utoipa::path(
+    post,
+    path = "/api/normalization-rules/validate",
+    tag = "normalization",
+    request_body = Vec<ValidatePatternRequest>,
+    responses(
+        (status = 200, description = "Per-entry validation results, in request order")
+    )
+)]
+#[post("/api/normalization-rules/validate")]
+pub async fn validate_rules(
+    entries: web::Json<Vec<ValidatePatternRequest>>,
+) -> impl Responder {
+    tracing::info!("POST /api/normalization-rules/validate: {} entries", entries.len());
+
+    let results: Vec<ValidatePatternResult> = entries
+        .iter()
+        .map(|entry| match compile_rule(&entry.pattern, &entry.replacement) {
+            Ok(_) => ValidatePatternResult { valid: true, error: None },
+            Err(e) => ValidatePatternResult { valid: false, error: Some(e.to_string()) },
+        })
+        .collect();
+    let invalid = results.iter().filter(|result| !result.valid).count();
+
+    HttpResponse::Ok().json(json!({
+        "status": "success",
+        "data": results,
+        "total": results.len(),
+        "invalid": invalid
+    }))
+}
+
+/// 预览规则重排序，只使用内存中的规则，不写数据库
+#[utoipa::path(
+    post,
+    path = "/api/normalization-rules/simulate-reorder",
+    tag = "normalization",
+    request_body = SimulateReorderRequest,
+    responses(
+        (status = 200, description = "URLs that would normalize differently under the proposed order"),
+        (status = 400, description = "Proposed order does not list every rule exactly once"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+#[post("/api/normalization-rules/simulate-reorder")]
+pub async fn simulate_reorder(
+    request_id: RequestId,
+    app_state: web::Data<Arc<AppState>>,
+    request: web::Json<SimulateReorderRequest>,
+) -> impl Responder {
+    tracing::info!("POST /api/normalization-rules/simulate-reorder: {:?}", request);
+
+    match app_state.url_normalizer.simulate_reorder(&request.rule_ids, &request.urls).await {
+        Ok(diffs) => {
+            HttpResponse::Ok().json(json!({
+                "status": "success",
+                "data": {
+                    "sampled": request.urls.len(),
+                    "changed": diffs.len(),
+                    "diffs": diffs
+                }
             }))
         }
+        Err(e @ NormalizationError::InvalidRuleOrder(_)) => {
+            ApiError::bad_request(e.to_string()).response(&request_id)
+        }
+        Err(e) => {
+            tracing::error!("Failed to simulate rule reorder: {}", e);
+            ApiError::internal("Failed to simulate rule reorder").response(&request_id)
+        }
+    }
+}
+
+// 冲突检测单次最多分析的URL数量
+const MAX_CONFLICT_SCAN: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+pub struct ConflictSampleQuery {
+    /// 抽取最近访问的URL数量
+    #[serde(rename = "sampleSize")]
+    pub sample_size: Option<usize>,
+}
+
+/// 分析URL样本中的规则冲突并生成响应
+async fn conflicts_response(request_id: &RequestId, app_state: &AppState, urls: &[String]) -> HttpResponse {
+    match app_state.url_normalizer.find_conflicts(urls).await {
+        Ok(conflicts) => {
+            let differing = conflicts.iter().filter(|conflict| conflict.differing).count();
+            HttpResponse::Ok().json(json!({
+                "status": "success",
+                "data": {
+                    "sampled": urls.len(),
+                    "conflicting": conflicts.len(),
+                    "differing": differing,
+                    "conflicts": conflicts
+                }
+            }))
+        }
+        Err(e) => {
+            tracing::error!("Failed to detect rule conflicts: {}", e);
+            ApiError::internal("Failed to detect rule conflicts").response(request_id)
+        }
+    }
+}
+
+/// 在最近访问的URL样本中查找被多条启用规则同时匹配的URL
+#[utoipa::path(
+    get,
+    path = "/api/normalization-rules/conflicts",
+    tag = "normalization",
+    params(
+        ("sampleSize" = Option<usize>, Query, description = "Number of recently visited URLs to analyze (default 200, max 1000)")
+    ),
+    responses(
+        (status = 200, description = "URLs matched by more than one enabled rule, with each rule's output"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+#[get("/api/normalization-rules/conflicts")]
+pub async fn detect_conflicts(
+    request_id: RequestId,
+    query: web::Query<ConflictSampleQuery>,
+    es_client: web::Data<Arc<Elasticsearch>>,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    let sample_size = query.sample_size.unwrap_or(200).clamp(1, MAX_CONFLICT_SCAN);
+    tracing::info!("GET /api/normalization-rules/conflicts: sample_size={}", sample_size);
+
+    let urls = match es::sample_original_urls(&es_client, &app_state.config.elasticsearch.index, sample_size).await {
+        Ok(urls) => urls,
+        Err(e) => {
+            tracing::error!("Failed to sample stored URLs: {}", e);
+            return ApiError::internal("Failed to sample stored URLs").response(&request_id);
+        }
+    };
+
+    conflicts_response(&request_id, &app_state, &urls).await
+}
+
+/// 在给定的URL列表中查找被多条启用规则同时匹配的URL
+#[utoipa::path(
+    post,
+    path = "/api/normalization-rules/conflicts",
+    tag = "normalization",
+    request_body = DetectConflictsRequest,
+    responses(
+        (status = 200, description = "URLs matched by more than one enabled rule, with each rule's output"),
+        (status = 400, description = "Too many URLs"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+#[post("/api/normalization-rules/conflicts")]
+pub async fn detect_conflicts_in_urls(
+    request_id: RequestId,
+    request: web::Json<DetectConflictsRequest>,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    tracing::info!("POST /api/normalization-rules/conflicts: {} urls", request.urls.len());
+
+    if request.urls.len() > MAX_CONFLICT_SCAN {
+        return ApiError::bad_request(format!(
+            "Too many URLs: {} (maximum {})", request.urls.len(), MAX_CONFLICT_SCAN
+        )).response(&request_id);
     }
+
+    conflicts_response(&request_id, &app_state, &request.urls).await
 }
 
 /// 刷新规则缓存
@@ -250,33 +733,145 @@ pub async fn test_rule(
     path = "/api/normalization-rules/refresh-cache",
     tag = "normalization",
     responses(
-        (status = 200, description = "Cache refreshed successfully"),
+        (status = 200, description = "Cache refreshed successfully, with any rules that failed to compile"),
         (status = 500, description = "Internal server error")
     )
 )]
 #[post("/api/normalization-rules/refresh-cache")]
-pub async fn refresh_cache(app_state: web::Data<Arc<AppState>>) -> impl Responder {
+pub async fn refresh_cache(
+    request_id: RequestId,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
     tracing::info!("POST /api/normalization-rules/refresh-cache");
     
     match app_state.url_normalizer.refresh_rules_cache().await {
-        Ok(_) => {
-            let (regex_cache_size, rules_cached) = app_state.url_normalizer.get_cache_stats().await;
+        Ok(broken_rules) => {
+            // 规则可能是直接在数据库中修改的，其他实例也需要刷新
+            if let Some(invalidator) = &app_state.rule_invalidator {
+                invalidator.publish().await;
+            }
+            let cache_stats = app_state.url_normalizer.get_cache_stats().await;
             
             HttpResponse::Ok().json(json!({
                 "status": "success",
                 "message": "Rules cache refreshed successfully",
-                "cache_stats": {
-                    "regex_cache_size": regex_cache_size,
-                    "rules_cached": rules_cached
-                }
+                "cache_stats": cache_stats,
+                "broken_rules": broken_rules
             }))
         }
         Err(e) => {
             tracing::error!("Failed to refresh cache: {}", e);
-            HttpResponse::InternalServerError().json(json!({
-                "status": "error",
-                "message": "Failed to refresh cache"
+            ApiError::internal("Failed to refresh cache").response(&request_id)
+        }
+    }
+}
+
+/// 归一化缓存的大小和命中率
+#[utoipa::path(
+    get,
+    path = "/api/normalization-rules/cache-stats",
+    tag = "normalization",
+    responses(
+        (status = 200, description = "Regex and rules cache sizes with hit/miss counters since startup")
+    )
+)]
+#[get("/api/normalization-rules/cache-stats")]
+pub async fn get_cache_stats(app_state: web::Data<Arc<AppState>>) -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "status": "success",
+        "data": app_state.url_normalizer.get_cache_stats().await
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditLogQuery {
+    #[serde(rename = "ruleId")]
+    pub rule_id: Option<i32>,
+    pub limit: Option<i64>,
+}
+
+/// 获取规则变更审计记录
+#[utoipa::path(
+    get,
+    path = "/api/normalization-rules/audit",
+    tag = "normalization",
+    params(
+        ("ruleId" = Option<i32>, Query, description = "Only return entries for this rule"),
+        ("limit" = Option<i64>, Query, description = "Maximum number of entries (default 100, max 1000)")
+    ),
+    responses(
+        (status = 200, description = "Rule change audit log, newest first"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+#[get("/api/normalization-rules/audit")]
+pub async fn get_audit_log(
+    request_id: RequestId,
+    app_state: web::Data<Arc<AppState>>,
+    query: web::Query<AuditLogQuery>,
+) -> impl Responder {
+    tracing::info!("GET /api/normalization-rules/audit: {:?}", query);
+
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+
+    match app_state.database.get_audit_log(query.rule_id, limit).await {
+        Ok(entries) => {
+            HttpResponse::Ok().json(json!({
+                "status": "success",
+                "data": entries,
+                "total": entries.len()
             }))
         }
+        Err(e) => {
+            tracing::error!("Failed to get audit log: {}", e);
+            ApiError::internal("Failed to retrieve audit log").response(&request_id)
+        }
     }
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FailureLogQuery {
+    #[serde(rename = "ruleId")]
+    pub rule_id: Option<i32>,
+    pub limit: Option<i64>,
+}
+
+/// 获取持久化的归一化失败记录（需开启 normalization.record_failures）
+#[utoipa::path(
+    get,
+    path = "/api/normalization-rules/failures",
+    tag = "normalization",
+    params(
+        ("ruleId" = Option<i32>, Query, description = "Only return failures of this rule"),
+        ("limit" = Option<i64>, Query, description = "Maximum number of entries (default 100, max 1000)")
+    ),
+    responses(
+        (status = 200, description = "Normalization failures, newest first"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+#[get("/api/normalization-rules/failures")]
+pub async fn get_failures(
+    request_id: RequestId,
+    app_state: web::Data<Arc<AppState>>,
+    query: web::Query<FailureLogQuery>,
+) -> impl Responder {
+    tracing::info!("GET /api/normalization-rules/failures: {:?}", query);
+
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+
+    match app_state.database.get_normalization_failures(query.rule_id, limit).await {
+        Ok(failures) => {
+            HttpResponse::Ok().json(json!({
+                "status": "success",
+                "data": failures,
+                "total": failures.len(),
+                "recording": app_state.config.normalization.record_failures
+            }))
+        }
+        Err(e) => {
+            tracing::error!("Failed to get normalization failures: {}", e);
+            ApiError::internal("Failed to retrieve normalization failures").response(&request_id)
+        }
+    }
+}
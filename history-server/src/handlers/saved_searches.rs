@@ -0,0 +1,313 @@
+use actix_web::{web, HttpResponse, Responder, get, post, put, delete};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use tracing_actix_web::RequestId;
+
+use crate::{acquire_es_permit, AppState};
+use crate::error::ApiError;
+use crate::services::es;
+use crate::services::database::{
+    is_unique_violation, CreateSavedSearchRequest, SavedSearchFilters, UpdateSavedSearchRequest,
+    MAX_SAVED_SEARCH_NAME_LEN, MAX_SAVED_SEARCH_OWNER_LEN,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct SavedSearchListQuery {
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RunSavedSearchQuery {
+    pub page: Option<i32>,
+}
+
+/// 校验名称和所有者，超出列宽的值在写库前返回400；更新请求中未提供的字段为 None，不校验
+fn validate_name_and_owner(name: Option<&str>, owner: Option<&str>) -> Result<(), String> {
+    if let Some(name) = name {
+        if name.trim().is_empty() {
+            return Err("Saved search name must not be empty".to_string());
+        }
+        if name.chars().count() > MAX_SAVED_SEARCH_NAME_LEN {
+            return Err(format!("Saved search name must be at most {} characters", MAX_SAVED_SEARCH_NAME_LEN));
+        }
+    }
+    if let Some(owner) = owner {
+        if owner.chars().count() > MAX_SAVED_SEARCH_OWNER_LEN {
+            return Err(format!("Saved search owner must be at most {} characters", MAX_SAVED_SEARCH_OWNER_LEN));
+        }
+    }
+    Ok(())
+}
+
+/// 校验保存的过滤条件，相对日期在执行时才解析，因此保存时只校验语法
+fn validate_filters(filters: &SavedSearchFilters) -> Result<(), String> {
+    for date in [&filters.start_date, &filters.end_date].into_iter().flatten() {
+        es::validate_date_filter(date)?;
+    }
+    Ok(())
+}
+
+/// 获取保存的搜索
+#[utoipa::path(
+    get,
+    path = "/api/saved-searches",
+    tag = "saved-searches",
+    params(
+        ("owner" = Option<String>, Query, description = "Filter by owner")
+    ),
+    responses(
+        (status = 200, description = "List of saved searches"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+#[get("/api/saved-searches")]
+pub async fn get_saved_searches(
+    request_id: RequestId,
+    app_state: web::Data<Arc<AppState>>,
+    query: web::Query<SavedSearchListQuery>,
+) -> impl Responder {
+    tracing::info!("GET /api/saved-searches: {:?}", query);
+
+    match app_state.database.get_saved_searches(query.owner.as_deref()).await {
+        Ok(searches) => {
+            HttpResponse::Ok().json(json!({
+                "status": "success",
+                "data": searches,
+                "total": searches.len()
+            }))
+        }
+        Err(e) => {
+            tracing::error!("Failed to get saved searches: {}", e);
+            ApiError::internal("Failed to retrieve saved searches").response(&request_id)
+        }
+    }
+}
+
+/// 创建保存的搜索
+#[utoipa::path(
+    post,
+    path = "/api/saved-searches",
+    tag = "saved-searches",
+    request_body = CreateSavedSearchRequest,
+    responses(
+        (status = 201, description = "Saved search created successfully"),
+        (status = 400, description = "Invalid saved search data"),
+        (status = 409, description = "A saved search with this name already exists"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+#[post("/api/saved-searches")]
+pub async fn create_saved_search(
+    request_id: RequestId,
+    app_state: web::Data<Arc<AppState>>,
+    search_data: web::Json<CreateSavedSearchRequest>,
+) -> impl Responder {
+    tracing::info!("POST /api/saved-searches: {:?}", search_data);
+
+    if let Err(message) = validate_name_and_owner(Some(&search_data.name), search_data.owner.as_deref()) {
+        return ApiError::bad_request(message).response(&request_id);
+    }
+
+    if let Err(message) = validate_filters(&search_data.filters) {
+        return ApiError::bad_request(message).response(&request_id);
+    }
+
+    match app_state.database.create_saved_search(&search_data).await {
+        Ok(search) => {
+            HttpResponse::Created().json(json!({
+                "status": "success",
+                "message": "Saved search created successfully",
+                "data": search
+            }))
+        }
+        Err(e) if is_unique_violation(&e) => {
+            ApiError::conflict(format!("Saved search '{}' already exists", search_data.name)).response(&request_id)
+        }
+        Err(e) => {
+            tracing::error!("Failed to create saved search: {}", e);
+            ApiError::internal("Failed to create saved search").response(&request_id)
+        }
+    }
+}
+
+/// 更新保存的搜索
+#[utoipa::path(
+    put,
+    path = "/api/saved-searches/{id}",
+    tag = "saved-searches",
+    params(
+        ("id" = i32, Path, description = "Saved search ID")
+    ),
+    request_body = UpdateSavedSearchRequest,
+    responses(
+        (status = 200, description = "Saved search updated successfully"),
+        (status = 400, description = "Invalid saved search data"),
+        (status = 404, description = "Saved search not found"),
+        (status = 409, description = "A saved search with this name already exists"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+#[put("/api/saved-searches/{id}")]
+pub async fn update_saved_search(
+    request_id: RequestId,
+    app_state: web::Data<Arc<AppState>>,
+    path: web::Path<i32>,
+    search_data: web::Json<UpdateSavedSearchRequest>,
+) -> impl Responder {
+    let search_id = path.into_inner();
+    tracing::info!("PUT /api/saved-searches/{}: {:?}", search_id, search_data);
+
+    if let Err(message) = validate_name_and_owner(search_data.name.as_deref(), None) {
+        return ApiError::bad_request(message).response(&request_id);
+    }
+    if let Some(filters) = &search_data.filters {
+        if let Err(message) = validate_filters(filters) {
+            return ApiError::bad_request(message).response(&request_id);
+        }
+    }
+
+    match app_state.database.update_saved_search(search_id, &search_data).await {
+        Ok(Some(search)) => {
+            HttpResponse::Ok().json(json!({
+                "status": "success",
+                "message": "Saved search updated successfully",
+                "data": search
+            }))
+        }
+        Ok(None) => {
+            ApiError::not_found("Saved search not found").response(&request_id)
+        }
+        Err(e) if is_unique_violation(&e) => {
+            ApiError::conflict("A saved search with this name already exists").response(&request_id)
+        }
+        Err(e) => {
+            tracing::error!("Failed to update saved search: {}", e);
+            ApiError::internal("Failed to update saved search").response(&request_id)
+        }
+    }
+}
+
+/// 删除保存的搜索
+#[utoipa::path(
+    delete,
+    path = "/api/saved-searches/{id}",
+    tag = "saved-searches",
+    params(
+        ("id" = i32, Path, description = "Saved search ID")
+    ),
+    responses(
+        (status = 200, description = "Saved search deleted successfully"),
+        (status = 404, description = "Saved search not found"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+#[delete("/api/saved-searches/{id}")]
+pub async fn delete_saved_search(
+    request_id: RequestId,
+    app_state: web::Data<Arc<AppState>>,
+    path: web::Path<i32>,
+) -> impl Responder {
+    let search_id = path.into_inner();
+    tracing::info!("DELETE /api/saved-searches/{}", search_id);
+
+    match app_state.database.delete_saved_search(search_id).await {
+        Ok(true) => {
+            HttpResponse::Ok().json(json!({
+                "status": "success",
+                "message": format!("Saved search {} deleted successfully", search_id)
+            }))
+        }
+        Ok(false) => {
+            ApiError::not_found("Saved search not found").response(&request_id)
+        }
+        Err(e) => {
+            tracing::error!("Failed to delete saved search: {}", e);
+            ApiError::internal("Failed to delete saved search").response(&request_id)
+        }
+    }
+}
+
+/// 执行保存的搜索
+#[utoipa::path(
+    get,
+    path = "/api/saved-searches/{id}/run",
+    tag = "saved-searches",
+    params(
+        ("id" = i32, Path, description = "Saved search ID"),
+        ("page" = Option<i32>, Query, description = "Page number")
+    ),
+    responses(
+        (status = 200, description = "Search results for the stored filters"),
+        (status = 404, description = "Saved search not found"),
+        (status = 500, description = "Internal server error"),
+        (status = 503, description = "Too many concurrent searches, retry after Retry-After")
+    )
+)]
+#[get("/api/saved-searches/{id}/run")]
+pub async fn run_saved_search(
+    request_id: RequestId,
+    app_state: web::Data<Arc<AppState>>,
+    es_client: web::Data<es::ReadClient>,
+    path: web::Path<i32>,
+    query: web::Query<RunSavedSearchQuery>,
+) -> impl Responder {
+    let search_id = path.into_inner();
+    tracing::info!("GET /api/saved-searches/{}/run: {:?}", search_id, query);
+
+    let search = match app_state.database.get_saved_search(search_id).await {
+        Ok(Some(search)) => search,
+        Ok(None) => {
+            return ApiError::not_found("Saved search not found").response(&request_id);
+        }
+        Err(e) => {
+            tracing::error!("Failed to get saved search: {}", e);
+            return ApiError::internal("Failed to retrieve saved search").response(&request_id);
+        }
+    };
+
+    let filters = search.filters.0;
+
+    let _permit = match acquire_es_permit(&app_state) {
+        Ok(permit) => permit,
+        Err(e) => return e.response(&request_id),
+    };
+
+    match es::search_history(
+        &es_client,
+        &app_state.config.elasticsearch.index,
+        filters.keyword,
+        filters.domain,
+        filters.start_date,
+        filters.end_date,
+        Some(query.page.unwrap_or(1)),
+        Some(filters.page_size.unwrap_or(30)),
+        &es::SearchOptions {
+            track_total_hits: app_state.config.elasticsearch.track_total_hits,
+            legacy_url_field: app_state.config.elasticsearch.write_legacy_url_field,
+            ..Default::default()
+        },
+    ).await {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to run saved search {}", search_id);
+            ApiError::internal("Failed to run saved search").response(&request_id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_name_and_owner() {
+        assert!(validate_name_and_owner(Some("Work"), Some("alice")).is_ok());
+        assert!(validate_name_and_owner(None, None).is_ok());
+        assert!(validate_name_and_owner(Some("   "), None).is_err());
+        assert!(validate_name_and_owner(Some(""), None).is_err());
+        assert!(validate_name_and_owner(Some(&"n".repeat(MAX_SAVED_SEARCH_NAME_LEN)), None).is_ok());
+        assert!(validate_name_and_owner(Some(&"n".repeat(MAX_SAVED_SEARCH_NAME_LEN + 1)), None).is_err());
+        assert!(validate_name_and_owner(None, Some(&"o".repeat(MAX_SAVED_SEARCH_OWNER_LEN + 1))).is_err());
+    }
+}
@@ -1,27 +1,41 @@
 use actix_cors::Cors;
-use actix_web::{get, web, App, HttpResponse, HttpServer, Responder, post};
+use actix_web::{get, web, App, HttpRequest, HttpResponse, HttpServer, Responder, post, delete};
+use actix_web::http::StatusCode;
 use elasticsearch::Elasticsearch;
 use tracing::{info, error};
+use tracing_actix_web::RequestId;
 use serde::{Deserialize, Serialize};
 use utoipa::{IntoParams, OpenApi, ToSchema};
 use utoipa_swagger_ui::SwaggerUi;
+use std::borrow::Cow;
 use std::sync::Arc;
-use std::time::Duration;
+use tokio::sync::OwnedSemaphorePermit;
 use elasticsearch::http::transport::Transport;
 use serde_json::json;
 
 mod config;
+mod error;
 mod services;
 mod handlers;
 mod tracing_config;
 
-use crate::config::{AppConfig, ElasticsearchConfig};
+use crate::config::{redact_url, AppConfig, CorsConfig, DatabaseConfig, DocumentIdMode, FeaturesConfig, MalformedUrlAction, NormalizationMode, RejectAction};
 use crate::services::es;
 use crate::services::cache::Cache;
 use crate::services::redis_cache::RedisCache;
 use crate::services::database::DatabaseService;
 use crate::services::url_normalizer::UrlNormalizer;
-use crate::handlers::normalization;
+use crate::services::blocking_pool::BlockingPool;
+use crate::services::coalesce::RequestCoalescer;
+use crate::services::bulkhead::EsBulkhead;
+use crate::services::site::SiteResolver;
+use crate::services::rule_sync::RuleInvalidator;
+use crate::services::compaction::CompactionJobs;
+use crate::services::{ingest_queue, optimizer, retention, rule_expiry, xlsx_export};
+use crate::services::ingest_queue::{HandleError, MessageHandler};
+use crate::services::query_builder::NormalizationStatus;
+use crate::handlers::{admin, normalization, saved_searches};
+use crate::error::ApiError;
 
 // 应用状态结构体 - 存储全局配置和服务实例
 #[derive(Clone)]
@@ -30,36 +44,165 @@ pub struct AppState {
     pub cache: Option<Box<dyn Cache>>, // 如果Redis可用则有缓存，否则为None
     pub database: Arc<DatabaseService>,
     pub url_normalizer: Arc<UrlNormalizer>,
+    pub compaction_jobs: CompactionJobs,
+    pub search_coalescer: RequestCoalescer, // 合并相同的并发搜索
+    pub es_bulkhead: EsBulkhead, // 限制并发的ES查询
+    pub site_resolver: Option<Arc<SiteResolver>>, // 配置了公共后缀列表时计算站点（eTLD+1）
+    pub rule_invalidator: Option<RuleInvalidator>, // 多实例部署时通知其他实例刷新规则缓存
+}
+
+impl AppState {
+    /// 主机名对应的站点（eTLD+1），未配置公共后缀列表时为 None
+    fn registrable_domain(&self, host: &str) -> Option<String> {
+        self.site_resolver.as_ref()?.registrable_domain(host)
+    }
+}
+
+// ES并发查询已满时建议客户端的重试间隔（秒）
+const ES_BUSY_RETRY_AFTER_SECONDS: u64 = 1;
+// ES并发查询已满时的错误信息，合并的查询也以此识别名额不足
+const ES_BUSY_MESSAGE: &str = "Too many concurrent searches, retry later";
+
+fn es_busy_error() -> ApiError {
+    ApiError::new(StatusCode::SERVICE_UNAVAILABLE, ES_BUSY_MESSAGE).with_retry_after(ES_BUSY_RETRY_AFTER_SECONDS)
+}
+
+// 获取ES查询名额，已满时返回503
+fn acquire_es_permit(app_state: &AppState) -> Result<OwnedSemaphorePermit, ApiError> {
+    app_state.es_bulkhead.try_acquire().ok_or_else(|| {
+        tracing::warn!(in_use = app_state.es_bulkhead.in_use(), "Too many concurrent Elasticsearch requests");
+        es_busy_error()
+    })
 }
 
 // 获取 ES 客户端的函数
-async fn create_es_client(config: &ElasticsearchConfig) -> Elasticsearch {
-    let transport = Transport::single_node(&config.url)
+async fn create_es_client(url: &str) -> Elasticsearch {
+    let transport = Transport::single_node(url)
         .expect("Failed to create transport");
     Elasticsearch::new(transport)
 }
 
+// 根据配置构建CORS中间件
+fn build_cors(config: &CorsConfig) -> Cors {
+    let cors = Cors::default()
+        .allow_any_origin()
+        .allow_any_method()
+        .max_age(config.max_age);
+
+    let cors = if config.allowed_headers.is_empty() {
+        cors.allow_any_header()
+    } else {
+        cors.allowed_headers(config.allowed_headers.iter().map(String::as_str))
+    };
+
+    if config.expose_headers.is_empty() {
+        cors
+    } else {
+        cors.expose_headers(config.expose_headers.iter().map(String::as_str))
+    }
+}
+
+// 注册可按配置关闭的接口，关闭的功能不挂载路由
+fn configure_optional_services(cfg: &mut web::ServiceConfig, features: &FeaturesConfig) {
+    if features.timeline {
+        cfg.service(history_timeline);
+    }
+    if features.suggest {
+        cfg.service(suggest_history);
+    }
+    if features.import {
+        cfg.service(prepare_import);
+    }
+    if features.compaction {
+        cfg.service(compact_history).service(compaction_progress);
+    }
+    if features.purge {
+        cfg.service(purge_expired_history).service(purge_domain_history);
+    }
+    if features.saved_searches {
+        cfg.service(saved_searches::get_saved_searches)
+            .service(saved_searches::create_saved_search)
+            .service(saved_searches::update_saved_search)
+            .service(saved_searches::delete_saved_search)
+            .service(saved_searches::run_saved_search);
+    }
+    if features.admin {
+        cfg.service(admin::get_effective_config)
+            .service(admin::dump_history);
+    }
+}
+
+// 没有匹配的路由时也返回统一的JSON错误格式，而不是空的404
+async fn route_not_found(request: HttpRequest) -> HttpResponse {
+    ApiError::not_found(format!("No route for {} {}", request.method(), request.path())).response_for(&request)
+}
+
+// 请求体、查询参数和路径参数解析失败时也返回统一的错误格式
+fn extractor_error<E: actix_web::ResponseError + 'static>(err: E, request: &HttpRequest) -> actix_web::Error {
+    let response = ApiError::new(err.status_code(), err.to_string()).response_for(request);
+    actix_web::error::InternalError::from_response(err, response).into()
+}
+
 // 定义API文档
 #[derive(OpenApi)]
 #[openapi(
     paths(
         health,
+        ready,
+        version,
+        get_capabilities,
         search_history,
+        history_timeline,
+        suggest_history,
+        recent_domains,
+        history_sources,
+        browsing_gaps,
+        compare_periods,
+        export_history,
+        bulk_update_tags,
         report_history,
         query_history_by_urls,
+        history_exists,
+        query_all_visits_by_urls,
+        report_history_bulk,
+        prepare_import,
+        compact_history,
+        compaction_progress,
+        purge_expired_history,
+        purge_domain_history,
         normalization::get_rules,
         normalization::create_rule,
         normalization::update_rule,
         normalization::delete_rule,
+        normalization::toggle_category,
+        normalization::export_rules,
+        normalization::import_rules,
         normalization::test_rule,
+        normalization::test_rule_on_recent,
+        normalization::simulate_reorder,
+        normalization::validate_rules,
+        normalization::detect_conflicts,
+        normalization::detect_conflicts_in_urls,
         normalization::refresh_cache,
+        normalization::get_cache_stats,
+        normalization::get_audit_log,
+        normalization::get_failures,
+        saved_searches::get_saved_searches,
+        saved_searches::create_saved_search,
+        saved_searches::update_saved_search,
+        saved_searches::delete_saved_search,
+        saved_searches::run_saved_search,
+        admin::get_effective_config,
+        admin::dump_history,
     ),
     components(
-        schemas(HistoryRecord, HistoryRequest, UrlQueryRequest)
+        schemas(HistoryRecord, HistoryRequest, BulkTagRequest, HistoryFilter, UrlQueryRequest, UrlExistsRequest, UrlVisitsRequest)
     ),
     tags(
         (name = "history", description = "Browser History API"),
-        (name = "normalization", description = "URL Normalization Rules API")
+        (name = "normalization", description = "URL Normalization Rules API"),
+        (name = "saved-searches", description = "Saved Searches API"),
+        (name = "admin", description = "Administration API")
     )
 )]
 struct ApiDoc;
@@ -92,8 +235,246 @@ struct SearchQuery {
     #[param(default = "30")]
     #[serde(rename = "pageSize")]
     page_size: Option<i32>,
+    #[param(example = false)]
+    #[serde(rename = "includeFacets", default)]
+    include_facets: bool,
+    #[param(value_type = Option<String>, example = "recent")]
+    #[serde(rename = "sortBy", default)]
+    sort_by: es::SortBy,
+    /// 按归一化状态过滤：failed、missing 或 ok
+    #[param(value_type = Option<String>, example = "missing")]
+    #[serde(rename = "normalizationStatus")]
+    normalization_status: Option<NormalizationStatus>,
+    /// 最低相关度得分，只在 sortBy=relevance 时生效，未提供时使用配置的默认值
+    #[param(example = 0.5)]
+    #[serde(rename = "minScore")]
+    min_score: Option<f64>,
+    /// 原始URL中包含的片段（任意位置，不区分大小写），用于只记得部分路径的情况；比 keyword 搜索慢
+    #[param(example = "my-article-slug")]
+    #[serde(rename = "urlContains")]
+    url_contains: Option<String>,
+    /// 总数统计方式：exact（默认）、estimate（统计到上限，返回 ">=N"）或 none（total 为 null）
+    #[param(value_type = Option<String>, example = "estimate")]
+    #[serde(rename = "countMode", default)]
+    count_mode: es::CountMode,
+    /// 访问时的星期，如 sat,sun 或 mon-fri；只匹配写入时记录了 day_of_week 的记录
+    #[param(example = "sat,sun")]
+    weekday: Option<String>,
+    /// 资源类型，如 document 或 document,download；document 也匹配没有记录资源类型的旧记录
+    #[param(example = "document")]
+    #[serde(rename = "resourceType")]
+    resource_type: Option<String>,
+    /// 路径前缀（如 /api/v2/），必须与 domain 一起使用；只匹配写入时记录了 url_path 的记录
+    #[param(example = "/api/v2/")]
+    #[serde(rename = "pathPrefix")]
+    path_prefix: Option<String>,
+    /// 域名分面的分组方式：host（默认，按主机名）或 site（按 eTLD+1 站点）
+    #[param(value_type = Option<String>, example = "site")]
+    #[serde(rename = "groupBy", default)]
+    group_by: es::GroupBy,
+    /// 第一页结果很少时在 didYouMean 中返回拼写纠正后的关键词
+    #[param(example = false)]
+    #[serde(default)]
+    suggest: bool,
+    /// 域名分面是否排除配置的噪音域名（elasticsearch.facet_excluded_domains），默认排除
+    #[param(example = true)]
+    #[serde(rename = "excludeNoiseDomains", default = "crate::config::default_true")]
+    exclude_noise_domains: bool,
+}
+
+impl SearchQuery {
+    /// 除基础过滤和分页外，影响结果的参数，用于生成缓存键（只包含非默认值，保持原有缓存键不变）
+    fn cache_key_params(&self) -> Vec<(&'static str, String)> {
+        let mut params = Vec::new();
+        if self.include_facets {
+            params.push(("includeFacets", "true".to_string()));
+        }
+        match self.sort_by {
+            es::SortBy::Recent => {}
+            es::SortBy::Frequency => params.push(("sortBy", "frequency".to_string())),
+            es::SortBy::Relevance => params.push(("sortBy", "relevance".to_string())),
+        }
+        if let (es::SortBy::Relevance, Some(min_score)) = (self.sort_by, self.min_score) {
+            params.push(("minScore", min_score.to_string()));
+        }
+        if let Some(status) = self.normalization_status {
+            params.push(("normalizationStatus", status.as_str().to_string()));
+        }
+        if let Some(fragment) = self.url_contains.as_deref().filter(|fragment| !fragment.is_empty()) {
+            params.push(("urlContains", fragment.to_string()));
+        }
+        if let Some(weekday) = &self.weekday {
+            params.push(("weekday", weekday.to_lowercase()));
+        }
+        if let Some(resource_type) = &self.resource_type {
+            params.push(("resourceType", resource_type.to_lowercase()));
+        }
+        if let Some(path_prefix) = self.path_prefix.as_deref().filter(|path_prefix| !path_prefix.is_empty()) {
+            params.push(("pathPrefix", path_prefix.to_string()));
+        }
+        if self.include_facets && self.group_by != es::GroupBy::Host {
+            params.push(("groupBy", self.group_by.as_str().to_string()));
+        }
+        if self.count_mode != es::CountMode::Exact {
+            params.push(("countMode", self.count_mode.as_str().to_string()));
+        }
+        if self.suggest {
+            params.push(("suggest", "true".to_string()));
+        }
+        if self.include_facets && !self.exclude_noise_domains {
+            params.push(("excludeNoiseDomains", "false".to_string()));
+        }
+        params
+    }
+}
+
+// 时间线查询参数
+#[derive(Debug, Deserialize)]
+struct TimelineQuery {
+    domain: Option<String>,
+    #[serde(rename = "startDate")]
+    start_date: Option<String>,
+    #[serde(rename = "endDate")]
+    end_date: Option<String>,
+    #[serde(rename = "topDomainPerBucket", default)]
+    top_domain_per_bucket: bool,
+}
+
+// 自动补全查询参数
+#[derive(Debug, Deserialize)]
+struct SuggestQuery {
+    prefix: String,
+    #[serde(default)]
+    source: es::SuggestSource,
+    size: Option<usize>,
+}
+
+// 最近访问域名查询参数
+#[derive(Debug, Deserialize)]
+struct RecentDomainsQuery {
+    limit: Option<usize>,
+}
+
+// 上报来源统计查询参数
+#[derive(Debug, Deserialize)]
+struct SourcesQuery {
+    limit: Option<usize>,
+}
+
+// 空档查询参数
+#[derive(Debug, Deserialize)]
+struct GapsQuery {
+    #[serde(rename = "startDate")]
+    start_date: Option<String>,
+    #[serde(rename = "endDate")]
+    end_date: Option<String>,
+    #[serde(default)]
+    interval: es::GapInterval,
+    limit: Option<usize>,
+}
+
+// 空档查询默认时间范围、默认和最多返回的空档数
+const DEFAULT_GAPS_START: &str = "now-7d";
+const DEFAULT_GAPS_END: &str = "now";
+const DEFAULT_GAPS_LIMIT: usize = 10;
+const MAX_GAPS_LIMIT: usize = 100;
+
+// 时间段对比查询参数，时间段格式为 start..end
+#[derive(Debug, Deserialize)]
+struct CompareQuery {
+    #[serde(rename = "periodA")]
+    period_a: String,
+    #[serde(rename = "periodB")]
+    period_b: String,
+    limit: Option<usize>,
+}
+
+// 时间段对比默认和最多返回的域名数
+const DEFAULT_COMPARE_LIMIT: usize = 20;
+const MAX_COMPARE_LIMIT: usize = 200;
+
+// 导出文件格式
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ExportFormat {
+    Xlsx,
+}
+
+// 导出查询参数，过滤条件与搜索相同
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    format: ExportFormat,
+    keyword: Option<String>,
+    domain: Option<String>,
+    #[serde(rename = "startDate")]
+    start_date: Option<String>,
+    #[serde(rename = "endDate")]
+    end_date: Option<String>,
+    #[serde(rename = "normalizationStatus")]
+    normalization_status: Option<NormalizationStatus>,
+    #[serde(rename = "urlContains")]
+    url_contains: Option<String>,
+    weekday: Option<String>,
+    #[serde(rename = "resourceType")]
+    resource_type: Option<String>,
+    #[serde(rename = "pathPrefix")]
+    path_prefix: Option<String>,
+}
+
+// 单次导出的最大记录数，工作簿需要整个在内存中生成
+const MAX_EXPORT_ROWS: usize = 100_000;
+
+// 批量打标签时单次请求最多影响的记录数，超过时要求缩小过滤条件
+const MAX_BULK_TAG_RECORDS: u64 = 10_000;
+
+// 批量打标签的过滤条件，与搜索参数相同
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default)]
+struct HistoryFilter {
+    keyword: Option<String>,
+    domain: Option<String>,
+    #[serde(rename = "startDate")]
+    start_date: Option<String>,
+    #[serde(rename = "endDate")]
+    end_date: Option<String>,
+    #[serde(rename = "normalizationStatus")]
+    #[schema(value_type = Option<String>)]
+    normalization_status: Option<NormalizationStatus>,
+    #[serde(rename = "urlContains")]
+    url_contains: Option<String>,
+    weekday: Option<String>,
+    #[serde(rename = "resourceType")]
+    resource_type: Option<String>,
+    #[serde(rename = "pathPrefix")]
+    path_prefix: Option<String>,
+}
+
+// 批量打标签请求：add 和 remove 至少有一个非空
+#[derive(Debug, Deserialize, ToSchema)]
+struct BulkTagRequest {
+    #[serde(default)]
+    filter: HistoryFilter,
+    #[serde(default)]
+    add: Vec<String>,
+    #[serde(default)]
+    remove: Vec<String>,
 }
 
+// 最近访问域名默认和最多返回的数量
+const DEFAULT_RECENT_DOMAINS: usize = 10;
+const MAX_RECENT_DOMAINS: usize = 100;
+
+// 上报来源统计默认和最多返回的来源数量
+const DEFAULT_SOURCES: usize = 50;
+const MAX_SOURCES: usize = 500;
+
+// 上报来源名称的最大长度（字符）
+const MAX_SOURCE_LEN: usize = 64;
+
+// 自动补全默认和最多返回的候选数
+const DEFAULT_SUGGEST_SIZE: usize = 10;
+const MAX_SUGGEST_SIZE: usize = 50;
+
 fn default_page() -> Option<i32> {
     Some(1)
 }
@@ -105,14 +486,195 @@ fn default_page_size() -> Option<i32> {
 // Add new request model
 #[derive(Deserialize, ToSchema)]
 struct HistoryRequest {
-    // 支持新旧两种字段名：url（旧）和 original_url（新）
-    #[serde(alias = "original_url")]
+    // 支持新旧两种字段名：url（旧）和 original_url（新），同时提供时必须一致
     #[schema(example = "https://example.com")]
-    url: String,
+    url: Option<String>,
+    #[schema(example = "https://example.com")]
+    original_url: Option<String>,
     #[schema(example = "2024-03-19T10:30:00Z")]
     timestamp: String,
     #[schema(example = "example.com")]
     domain: String,
+    // 客户端提供的规范URL（如 rel=canonical），有效时直接作为归一化URL，跳过正则规则
+    #[schema(example = "https://example.com/")]
+    canonical_url: Option<String>,
+    // 站点图标URL，只存储并随搜索结果返回
+    #[schema(example = "https://example.com/favicon.ico")]
+    favicon_url: Option<String>,
+    // 资源类型（document、image、download 等），未提供或无效时为 document
+    #[schema(example = "document")]
+    resource_type: Option<String>,
+    // 上报来源（设备或浏览器配置的名称），用于按设备统计
+    #[schema(example = "work-laptop")]
+    source: Option<String>,
+}
+
+impl HistoryRequest {
+    /// 返回有效的规范URL：必须是带主机名的 http/https 绝对URL，无效时记录警告并忽略
+    fn valid_canonical_url(&self) -> Option<&str> {
+        let canonical_url = self.canonical_url.as_deref()?;
+
+        match url::Url::parse(canonical_url) {
+            Ok(parsed) if matches!(parsed.scheme(), "http" | "https") && parsed.has_host() => Some(canonical_url),
+            _ => {
+                tracing::warn!("Ignoring invalid canonical_url: {}", canonical_url);
+                None
+            }
+        }
+    }
+
+    /// 返回要存储的资源类型（小写），未提供时为 document，无效时记录警告并使用 document
+    fn resource_type(&self) -> String {
+        let Some(resource_type) = self.resource_type.as_deref().map(str::trim).filter(|value| !value.is_empty()) else {
+            return es::DEFAULT_RESOURCE_TYPE.to_string();
+        };
+        let resource_type = resource_type.to_lowercase();
+        if es::is_valid_resource_type(&resource_type) {
+            resource_type
+        } else {
+            tracing::warn!("Ignoring invalid resource_type: {}", resource_type);
+            es::DEFAULT_RESOURCE_TYPE.to_string()
+        }
+    }
+
+    /// 返回要存储的上报来源（去掉首尾空白），过长或含控制字符时记录警告并忽略
+    fn source(&self) -> Option<&str> {
+        let source = self.source.as_deref().map(str::trim).filter(|source| !source.is_empty())?;
+        if source.chars().count() > MAX_SOURCE_LEN || source.chars().any(char::is_control) {
+            tracing::warn!("Ignoring invalid source: {:?}", source);
+            return None;
+        }
+        Some(source)
+    }
+
+    /// 构建写入ES的文档（见 `es::history_document`），并附带上报来源和归一化失败标记
+    fn document(
+        &self,
+        original_url: &str,
+        normalized_url: Option<&str>,
+        normalization_failed: bool,
+        app_state: &AppState,
+    ) -> serde_json::Value {
+        let mut doc = es::history_document(
+            original_url,
+            normalized_url,
+            &self.timestamp,
+            &self.domain,
+            self.valid_favicon_url(),
+            &self.resource_type(),
+            app_state.registrable_domain(&self.domain).as_deref(),
+        );
+        if let Some(source) = self.source() {
+            doc["source"] = json!(source);
+        }
+        if app_state.config.elasticsearch.write_legacy_url_field {
+            doc["url"] = json!(original_url);
+        }
+        if normalization_failed {
+            doc["normalization_failed"] = json!(true);
+        }
+        doc
+    }
+
+    /// 返回有效的站点图标URL：http/https 绝对URL或 data:image/ URL，无效时记录警告并忽略
+    fn valid_favicon_url(&self) -> Option<&str> {
+        let favicon_url = self.favicon_url.as_deref().filter(|url| !url.is_empty())?;
+
+        if favicon_url.starts_with("data:image/") {
+            return Some(favicon_url);
+        }
+        match url::Url::parse(favicon_url) {
+            Ok(parsed) if matches!(parsed.scheme(), "http" | "https") && parsed.has_host() => Some(favicon_url),
+            _ => {
+                tracing::warn!("Ignoring invalid favicon_url: {}", favicon_url);
+                None
+            }
+        }
+    }
+
+    /// 计算上报记录的归一化URL：优先使用有效的规范URL，否则按规则归一化；归一化出错时返回 None
+    async fn normalized_url(&self, original_url: &str, url_normalizer: &UrlNormalizer) -> Option<String> {
+        match self.valid_canonical_url() {
+            Some(canonical_url) => {
+                tracing::info!("URL canonicalized by client: {} -> {}", original_url, canonical_url);
+                Some(canonical_url.to_string())
+            }
+            None => url_normalizer.try_normalize_url(original_url).await,
+        }
+    }
+
+    /// 按归一化模式计算要存储的归一化URL，仅查询时归一化模式下为 None
+    /// 归一化出错时存储原始URL，并返回 true 表示文档需要带 `normalization_failed` 标记（normalizationStatus=failed）
+    async fn stored_normalized_url(&self, original_url: &str, app_state: &AppState) -> (Option<String>, bool) {
+        match app_state.config.normalization.mode {
+            NormalizationMode::Store => match self.normalized_url(original_url, &app_state.url_normalizer).await {
+                Some(normalized_url) => {
+                    tracing::info!("URL normalization: {} -> {}", original_url, normalized_url);
+                    (Some(normalized_url), false)
+                }
+                None => (Some(original_url.to_string()), true),
+            },
+            NormalizationMode::QueryOnly => (None, false),
+        }
+    }
+
+    /// 解析上报的URL：两个字段任选其一，同时提供且不一致时拒绝，避免静默取其中一个
+    fn resolve_url(&self) -> Result<&str, String> {
+        match (self.url.as_deref(), self.original_url.as_deref()) {
+            (Some(url), Some(original_url)) if url != original_url => Err(format!(
+                "Conflicting values for 'url' ({}) and 'original_url' ({})",
+                url, original_url
+            )),
+            (Some(url), _) | (None, Some(url)) => Ok(url),
+            (None, None) => Err("Missing field 'original_url' (or legacy 'url')".to_string()),
+        }
+    }
+}
+
+// 隐私模式请求头：值为true时接受上报但不存储
+const NO_STORE_HEADER: &str = "X-No-Store";
+
+/// 请求是否要求不存储（如隐身模式）
+fn is_no_store(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(NO_STORE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+/// 检查URL中的控制字符（换行、空字符等），它们会破坏keyword字段和读取时的序列化
+/// sanitize 模式下去掉控制字符，之后URL仍需能被正常解析
+fn check_url_characters(url: &str, action: MalformedUrlAction) -> Result<Cow<'_, str>, String> {
+    let url = if url.chars().any(char::is_control) {
+        match action {
+            MalformedUrlAction::Reject => {
+                return Err(format!("URL '{}' contains control characters", url.escape_debug()));
+            }
+            MalformedUrlAction::Sanitize => Cow::Owned(url.chars().filter(|c| !c.is_control()).collect()),
+        }
+    } else {
+        Cow::Borrowed(url)
+    };
+
+    match url::Url::parse(&url) {
+        Ok(_) => Ok(url),
+        Err(e) => Err(format!("URL '{}' is not a valid URL: {}", url, e)),
+    }
+}
+
+/// 检查URL协议是否在允许列表中（不区分大小写）
+fn check_url_scheme(url: &str, allowed_schemes: &[String]) -> Result<(), String> {
+    let scheme = match url::Url::parse(url) {
+        Ok(parsed) => parsed.scheme().to_string(),
+        Err(_) => return Err(format!("URL '{}' has no valid scheme", url)),
+    };
+
+    if allowed_schemes.iter().any(|allowed| allowed.eq_ignore_ascii_case(&scheme)) {
+        Ok(())
+    } else {
+        Err(format!("URL scheme '{}' is not allowed", scheme))
+    }
 }
 
 // URL查询请求模型
@@ -124,6 +686,58 @@ struct UrlQueryRequest {
     // 支持批量URL查询
     #[serde(alias = "original_urls")]
     urls: Option<Vec<String>>,
+    // 只返回指定的文档字段（如 timestamp、domain），不提供时返回完整文档
+    fields: Option<Vec<String>>,
+    // 为true时在响应的 normalized 中返回每个输入URL实际查询的归一化URL，便于排查查不到记录的原因
+    #[serde(rename = "includeNormalized", default)]
+    include_normalized: bool,
+}
+
+// 导入预处理结果：归一化并去重后的记录
+#[derive(Debug, Serialize, ToSchema)]
+struct PreparedImportRecord {
+    original_url: String,
+    normalized_url: String,
+    timestamp: String,
+    domain: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    favicon_url: Option<String>,
+    // 合并到该记录的输入条数（含自身）
+    duplicates: usize,
+    #[serde(skip)]
+    visited_at: chrono::DateTime<chrono::Utc>,
+}
+
+// 导入预处理中被拒绝的记录
+#[derive(Debug, Serialize, ToSchema)]
+struct RejectedImportRecord {
+    index: usize,
+    reason: String,
+}
+
+/// 按归一化URL分组，每组保留时间最新的记录，输出顺序为各组首次出现的顺序
+fn dedup_import_records(records: Vec<PreparedImportRecord>) -> Vec<PreparedImportRecord> {
+    let mut positions: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
+    let mut deduped: Vec<PreparedImportRecord> = Vec::new();
+
+    for record in records {
+        match positions.get(&record.normalized_url) {
+            Some(&position) => {
+                let existing = &mut deduped[position];
+                let duplicates = existing.duplicates + record.duplicates;
+                if record.visited_at > existing.visited_at {
+                    *existing = record;
+                }
+                existing.duplicates = duplicates;
+            }
+            None => {
+                positions.insert(record.normalized_url.clone(), deduped.len());
+                deduped.push(record);
+            }
+        }
+    }
+
+    deduped
 }
 
 /// Check service health
@@ -141,11 +755,141 @@ async fn health(app_state: web::Data<Arc<AppState>>) -> impl Responder {
     let status = json!({
         "status": "OK",
         "cache_available": app_state.cache.is_some(),
-        "cache_ttl": app_state.config.cache.ttl_seconds
+        "cache_ttl": app_state.config.cache.ttl_seconds,
+        "normalizer_cache": app_state.url_normalizer.get_cache_stats().await
     });
     HttpResponse::Ok().json(status)
 }
 
+/// 构建信息：版本号、git提交和构建时间（由build.rs在编译时写入）
+fn version_info() -> serde_json::Value {
+    let build_timestamp = env!("BUILD_TIMESTAMP")
+        .parse::<i64>()
+        .ok()
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+        .map(|time| time.to_rfc3339());
+
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_commit": env!("GIT_COMMIT_HASH"),
+        "build_timestamp": build_timestamp
+    })
+}
+
+/// 客户端可据此判断服务端启用的可选功能和各项限制，而不是假设服务端版本
+fn capabilities(config: &AppConfig) -> serde_json::Value {
+    let features = &config.features;
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "features": {
+            "timeline": features.timeline,
+            "suggest": features.suggest,
+            "import": features.import,
+            "compaction": features.compaction,
+            "purge": features.purge,
+            "saved_searches": features.saved_searches,
+            // 未配置API密钥时管理接口不可用
+            "admin": features.admin && config.admin.api_key.is_some(),
+            "bulk_ingest": true,
+            "tags": true,
+            "export_formats": ["xlsx"],
+            "ingest_queue": config.ingest_queue.is_some()
+        },
+        "ingest": {
+            "document_ids": config.ingest.document_ids,
+            "normalization_mode": config.normalization.mode,
+            "allowed_schemes": config.ingest.allowed_schemes
+        },
+        "limits": {
+            "max_batch_size": config.query.max_batch_size,
+            "max_page_size": es::MAX_PAGE_SIZE,
+            "max_export_rows": MAX_EXPORT_ROWS,
+            "max_bulk_tag_records": MAX_BULK_TAG_RECORDS,
+            "max_tags_per_request": es::MAX_TAGS_PER_REQUEST
+        },
+        "auth": {
+            // 历史记录接口不需要认证，只有管理接口要求 X-API-Key
+            "required": false,
+            "admin_api_key_header": "X-API-Key"
+        }
+    })
+}
+
+/// Get enabled optional features and limits
+#[utoipa::path(
+    get,
+    path = "/api/capabilities",
+    tag = "history",
+    responses(
+        (status = 200, description = "Enabled optional features, ingest settings, limits and authentication requirements")
+    )
+)]
+#[get("/api/capabilities")]
+async fn get_capabilities(app_state: web::Data<Arc<AppState>>) -> impl Responder {
+    HttpResponse::Ok().json(capabilities(&app_state.config))
+}
+
+/// Get build and version information
+#[utoipa::path(
+    get,
+    path = "/api/version",
+    tag = "history",
+    responses(
+        (status = 200, description = "Crate version, git commit and build timestamp")
+    )
+)]
+#[get("/api/version")]
+async fn version() -> impl Responder {
+    HttpResponse::Ok().json(version_info())
+}
+
+/// Check service readiness
+#[utoipa::path(
+    get,
+    path = "/api/ready",
+    tag = "history",
+    responses(
+        (status = 200, description = "Service is ready"),
+        (status = 503, description = "Service is not ready")
+    )
+)]
+#[get("/api/ready")]
+async fn ready(
+    es_client: web::Data<Arc<Elasticsearch>>,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    let index = &app_state.config.elasticsearch.index;
+
+    let mapping_check = match es::check_index_mapping(&es_client, index).await {
+        Ok(problems) => json!({
+            "ok": problems.is_empty(),
+            "problems": problems
+        }),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to check index mapping");
+            json!({
+                "ok": false,
+                "problems": [format!("Elasticsearch unavailable: {}", e)]
+            })
+        }
+    };
+
+    let is_ready = mapping_check["ok"] == true;
+    let body = json!({
+        "status": if is_ready { "ready" } else { "not_ready" },
+        "version": version_info(),
+        "checks": {
+            "elasticsearch_mapping": mapping_check
+        }
+    });
+
+    if is_ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
 /// Search browser history
 #[utoipa::path(
     get,
@@ -154,44 +898,81 @@ async fn health(app_state: web::Data<Arc<AppState>>) -> impl Responder {
     params(
         ("keyword" = Option<String>, Query, description = "Search keyword"),
         ("domain" = Option<String>, Query, description = "Domain filter"),
-        ("startDate" = Option<String>, Query, description = "Start date (ISO 8601)"),
-        ("endDate" = Option<String>, Query, description = "End date (ISO 8601)"),
+        ("startDate" = Option<String>, Query, description = "Start date (ISO 8601, or relative such as now-7d)"),
+        ("endDate" = Option<String>, Query, description = "End date (ISO 8601, or relative such as now-1d/d)"),
         ("page" = Option<i32>, Query, description = "Page number"),
-        ("pageSize" = Option<i32>, Query, description = "Items per page")
+        ("pageSize" = Option<i32>, Query, description = "Items per page"),
+        ("includeFacets" = Option<bool>, Query, description = "Include top domains and the unique domain count under `facets`"),
+        ("groupBy" = Option<String>, Query, description = "Group facets by `host` (default) or `site` (registrable domain, eTLD+1)"),
+        ("excludeNoiseDomains" = Option<bool>, Query, description = "Leave the configured noise domains and their subdomains out of the facets (default true)"),
+        ("sortBy" = Option<String>, Query, description = "`recent` (default) or `frequency`: one item per normalized URL, most visited first, with a `visits` count"),
+        ("suggest" = Option<bool>, Query, description = "When the first page has fewer than 5 results, return a spelling-corrected keyword under `didYouMean`")
     ),
     responses(
         (status = 200, description = "List of history records", body = Vec<HistoryRecord>),
         (status = 400, description = "Bad request"),
-        (status = 500, description = "Internal server error")
+        (status = 500, description = "Internal server error"),
+        (status = 503, description = "Too many concurrent searches, retry after Retry-After")
     )
 )]
 #[get("/api/history")]
 async fn search_history(
+    request_id: RequestId,
     query: web::Query<SearchQuery>,
-    es_client: web::Data<Arc<Elasticsearch>>,
+    es_client: web::Data<es::ReadClient>,
     app_state: web::Data<Arc<AppState>>,
 ) -> impl Responder {
     use crate::services::cache::CacheKeyGenerator;
     
-    let page_size = query.page_size.unwrap_or(30).min(1000);
+    let page_size = query.page_size.unwrap_or(30).min(es::MAX_PAGE_SIZE);
     let page = query.page.unwrap_or(1);
     tracing::info!(REQUEST = "search_history", keyword = ?query.keyword, domain = ?query.domain, page = page);
+
+    if let Err(message) = es::validate_page_depth(query.sort_by, page, page_size) {
+        return ApiError::bad_request(message).response(&request_id);
+    }
+
+    // 校验日期过滤（支持 now-7d 等相对日期）
+    for date in [&query.start_date, &query.end_date].into_iter().flatten() {
+        if let Err(message) = es::validate_date_filter(date) {
+            return ApiError::bad_request(message).response(&request_id);
+        }
+    }
+    let weekdays = match query.weekday.as_deref().map(es::parse_weekday_filter).transpose() {
+        Ok(weekdays) => weekdays,
+        Err(message) => return ApiError::bad_request(message).response(&request_id),
+    };
+    let resource_types = match query.resource_type.as_deref().map(es::parse_resource_type_filter).transpose() {
+        Ok(resource_types) => resource_types,
+        Err(message) => return ApiError::bad_request(message).response(&request_id),
+    };
+    if let Some(path_prefix) = query.path_prefix.as_deref().filter(|path_prefix| !path_prefix.is_empty()) {
+        if let Err(message) = es::validate_path_prefix(path_prefix, query.domain.as_deref()) {
+            return ApiError::bad_request(message).response(&request_id);
+        }
+    }
     
+    let cache_key = CacheKeyGenerator::history_search_key(
+        &query.keyword,
+        &query.domain,
+        &query.start_date,
+        &query.end_date,
+        page,
+        page_size,
+        &query.cache_key_params(),
+    );
+
     // 尝试从缓存获取数据（如果缓存可用）
     if let Some(cache_impl) = &app_state.cache {
-        let cache_key = CacheKeyGenerator::history_search_key(
-            &query.keyword,
-            &query.domain,
-            &query.start_date,
-            &query.end_date,
-            page,
-            page_size,
-        );
-        
         // 尝试从缓存获取数据，任何错误都不影响正常查询
         match cache_impl.get(&cache_key).await {
             Ok(Some(cached_data)) => {
-                tracing::info!("Cache hit for key: {}", cache_key);
+                let is_empty = cached_data["items"].as_array().is_some_and(|items| items.is_empty());
+                if is_empty {
+                    tracing::info!("Negative cache hit for key: {}", cache_key);
+                } else {
+                    tracing::info!("Cache hit for key: {}", cache_key);
+                }
                 return HttpResponse::Ok().json(cached_data);
             }
             Ok(None) => {
@@ -203,42 +984,89 @@ async fn search_history(
         }
     }
     
-    // 从Elasticsearch查询数据
-    match es::search_history(
-        &es_client,
-        &app_state.config.elasticsearch.index,
+    // 从Elasticsearch查询数据，相同的并发查询合并为一次
+    let es_client = es_client.0.clone();
+    let index = app_state.config.elasticsearch.index.clone();
+    let options = es::SearchOptions {
+        track_total_hits: app_state.config.elasticsearch.track_total_hits,
+        include_facets: query.include_facets,
+        sort_by: query.sort_by,
+        normalization_status: query.normalization_status,
+        recency_boost_decay: app_state.config.elasticsearch.recency_boost_decay,
+        min_score: query.min_score.or(app_state.config.elasticsearch.min_score),
+        url_contains: query.url_contains.clone(),
+        count_mode: query.count_mode,
+        weekdays,
+        resource_types,
+        path_prefix: query.path_prefix.clone(),
+        group_by: query.group_by,
+        did_you_mean: query.suggest,
+        facet_excluded_domains: if query.exclude_noise_domains {
+            app_state.config.elasticsearch.facet_excluded_domains.clone()
+        } else {
+            Vec::new()
+        },
+        legacy_url_field: app_state.config.elasticsearch.write_legacy_url_field,
+    };
+    let (keyword, domain, start_date, end_date) = (
         query.keyword.clone(),
         query.domain.clone(),
         query.start_date.clone(),
         query.end_date.clone(),
-        Some(page),
-        Some(page_size),
-    ).await {
+    );
+    // 只有发起查询的请求占用ES名额，加入已有查询的请求直接等待结果
+    let (result, joined) = app_state.search_coalescer.run(&cache_key, || {
+        let permit = acquire_es_permit(&app_state).map_err(|_| ES_BUSY_MESSAGE.to_string());
+        async move {
+            let _permit = permit?;
+            es::search_history(
+                &es_client,
+                &index,
+                keyword,
+                domain,
+                start_date,
+                end_date,
+                Some(page),
+                Some(page_size),
+                &options,
+            ).await.map_err(|e| e.to_string())
+        }
+    }).await;
+
+    if joined {
+        tracing::info!("Coalesced search for key: {}", cache_key);
+    }
+
+    match result {
         Ok(response) => {
-            // 如果有缓存且查询成功有数据，异步写入缓存
-            if let Some(cache_impl) = &app_state.cache {
-                // 检查是否有数据（items数组不为空）
+            // 如果有缓存且查询成功，异步写入缓存（合并的请求只由发起者写入）
+            // 空结果只在启用负缓存时以较短的时间缓存，缓存的是完整响应，命中时同样返回 total: 0
+            if let (Some(cache_impl), false) = (&app_state.cache, joined) {
                 if let Some(items) = response.get("items").and_then(|v| v.as_array()) {
-                    if !items.is_empty() {
-                        let cache_key = CacheKeyGenerator::history_search_key(
-                            &query.keyword,
-                            &query.domain,
-                            &query.start_date,
-                            &query.end_date,
-                            page,
-                            page_size,
-                        );
-                        
-                        let ttl = Duration::from_secs(app_state.config.cache.ttl_seconds);
+                    if let Some(ttl) = app_state.config.cache.ttl_for_result("search_history", items.is_empty()) {
+                        let is_empty = items.is_empty();
                         
                         // 异步写入缓存，不阻塞响应，缓存失败不影响结果返回
                         let cache_clone = cache_impl.clone();
                         let response_clone = response.clone();
                         let cache_key_clone = cache_key.clone();
                         
+                        let max_cache_value_bytes = app_state.config.cache.max_cache_value_bytes;
+
                         tokio::spawn(async move {
+                            // 过大的响应不写入缓存，避免单个查询挤掉大量小条目
+                            let size = serde_json::to_vec(&response_clone).map_or(0, |bytes| bytes.len());
+                            if size > max_cache_value_bytes {
+                                tracing::warn!(
+                                    "Not caching response for key {}: {} bytes exceeds max_cache_value_bytes ({})",
+                                    cache_key_clone, size, max_cache_value_bytes
+                                );
+                                return;
+                            }
                             if let Err(e) = cache_clone.set(&cache_key_clone, &response_clone, ttl).await {
                                 tracing::error!("Failed to set cache for key {}: {}", cache_key_clone, e);
+                            } else if is_empty {
+                                tracing::info!("Cached empty result for key: {} ({}s)", cache_key_clone, ttl.as_secs());
                             } else {
                                 tracing::info!("Cached data for key: {}", cache_key_clone);
                             }
@@ -249,136 +1077,1541 @@ async fn search_history(
             
             HttpResponse::Ok().json(response)
         }
+        Err(e) if e == ES_BUSY_MESSAGE => es_busy_error().response(&request_id),
         Err(e) => {
             tracing::error!(error = %e, "Failed to search history");
-            HttpResponse::InternalServerError().json(json!({
-                "error": "Failed to search history",
-                "items": [],
-                "total": 0,
-                "page": page,
-                "pageSize": page_size
-            }))
+            ApiError::internal("Failed to search history").response(&request_id)
         }
     }
 }
 
-/// Report browser history
+/// 按天统计历史记录数量（日历热力图）
 #[utoipa::path(
-    post,
-    path = "/api/history",
+    get,
+    path = "/api/history/timeline",
     tag = "history",
-    request_body = HistoryRequest,
+    params(
+        ("domain" = Option<String>, Query, description = "Domain filter"),
+        ("startDate" = Option<String>, Query, description = "Start date (ISO 8601, or relative such as now-30d)"),
+        ("endDate" = Option<String>, Query, description = "End date (ISO 8601, or relative such as now)"),
+        ("topDomainPerBucket" = Option<bool>, Query, description = "Include the busiest domain of each day as `top_domain`")
+    ),
     responses(
-        (status = 200, description = "History recorded successfully"),
-        (status = 400, description = "Invalid request data"),
-        (status = 500, description = "Internal server error")
+        (status = 200, description = "Per-day document counts: { date, count, top_domain? }"),
+        (status = 400, description = "Bad request"),
+        (status = 500, description = "Internal server error"),
+        (status = 503, description = "Too many concurrent searches, retry after Retry-After")
     )
 )]
-#[post("/api/history")]
-async fn report_history(
-    request: web::Json<HistoryRequest>,
-    es_client: web::Data<Arc<Elasticsearch>>,
+#[get("/api/history/timeline")]
+async fn history_timeline(
+    request_id: RequestId,
+    query: web::Query<TimelineQuery>,
+    es_client: web::Data<es::ReadClient>,
     app_state: web::Data<Arc<AppState>>,
 ) -> impl Responder {
-    tracing::info!(REQUEST = "report_history", url = %request.url, domain = %request.domain);
-    
-    // 获取原始URL和归一化URL
-    let original_url = &request.url;
-    let normalized_url = app_state.url_normalizer.normalize_url(original_url).await;
-    
-    tracing::info!("URL normalization: {} -> {}", original_url, normalized_url);
-    
-    match es::insert_history(&es_client, &app_state.config.elasticsearch.index, original_url, &normalized_url, &request.timestamp, &request.domain).await {
-        Ok(_) => {
-            HttpResponse::Ok().json(json!({
-                "status": "success",
-                "message": "Record added successfully",
+    tracing::info!("GET /api/history/timeline: {:?}", query);
+
+    for date in [&query.start_date, &query.end_date].into_iter().flatten() {
+        if let Err(message) = es::validate_date_filter(date) {
+            return ApiError::bad_request(message).response(&request_id);
+        }
+    }
+
+    let _permit = match acquire_es_permit(&app_state) {
+        Ok(permit) => permit,
+        Err(e) => return e.response(&request_id),
+    };
+
+    match es::history_timeline(
+        &es_client,
+        &app_state.config.elasticsearch.index,
+        query.domain.clone(),
+        query.start_date.clone(),
+        query.end_date.clone(),
+        query.top_domain_per_bucket,
+    ).await {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to build history timeline");
+            ApiError::internal("Failed to build history timeline").response(&request_id)
+        }
+    }
+}
+
+/// 自动补全：按前缀返回域名或URL候选，经常且最近访问的排在前面
+#[utoipa::path(
+    get,
+    path = "/api/history/suggest",
+    tag = "history",
+    params(
+        ("prefix" = String, Query, description = "Prefix typed by the user"),
+        ("source" = Option<String>, Query, description = "Field to complete: domain (default) or url"),
+        ("size" = Option<usize>, Query, description = "Maximum number of suggestions (default 10, max 50)")
+    ),
+    responses(
+        (status = 200, description = "Suggestions ranked by visit count and how recently they were visited: { value, count, last_visit }"),
+        (status = 400, description = "Bad request"),
+        (status = 500, description = "Internal server error"),
+        (status = 503, description = "Too many concurrent searches, retry after Retry-After")
+    )
+)]
+#[get("/api/history/suggest")]
+async fn suggest_history(
+    request_id: RequestId,
+    query: web::Query<SuggestQuery>,
+    es_client: web::Data<es::ReadClient>,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    tracing::info!("GET /api/history/suggest: {:?}", query);
+
+    let prefix = query.prefix.trim();
+    if prefix.is_empty() {
+        return ApiError::bad_request("prefix must not be empty").response(&request_id);
+    }
+    let size = query.size.unwrap_or(DEFAULT_SUGGEST_SIZE);
+    if size == 0 || size > MAX_SUGGEST_SIZE {
+        return ApiError::bad_request(format!("size must be between 1 and {}", MAX_SUGGEST_SIZE)).response(&request_id);
+    }
+
+    let _permit = match acquire_es_permit(&app_state) {
+        Ok(permit) => permit,
+        Err(e) => return e.response(&request_id),
+    };
+
+    match es::suggest(&es_client, &app_state.config.elasticsearch.index, query.source, prefix, size).await {
+        Ok(suggestions) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "data": suggestions
+        })),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to build suggestions");
+            ApiError::internal("Failed to build suggestions").response(&request_id)
+        }
+    }
+}
+
+/// 最近访问的不重复域名（按最后一次访问时间排序），用于快速切换站点
+#[utoipa::path(
+    get,
+    path = "/api/history/recent-domains",
+    tag = "history",
+    params(
+        ("limit" = Option<usize>, Query, description = "Maximum number of domains (default 10, max 100)")
+    ),
+    responses(
+        (status = 200, description = "Domains ordered by most recent visit: { domain, last_visit, count }"),
+        (status = 400, description = "Bad request"),
+        (status = 500, description = "Internal server error"),
+        (status = 503, description = "Too many concurrent searches, retry after Retry-After")
+    )
+)]
+#[get("/api/history/recent-domains")]
+async fn recent_domains(
+    request_id: RequestId,
+    query: web::Query<RecentDomainsQuery>,
+    es_client: web::Data<es::ReadClient>,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    tracing::info!("GET /api/history/recent-domains: {:?}", query);
+
+    let limit = query.limit.unwrap_or(DEFAULT_RECENT_DOMAINS);
+    if limit == 0 || limit > MAX_RECENT_DOMAINS {
+        return ApiError::bad_request(format!("limit must be between 1 and {}", MAX_RECENT_DOMAINS)).response(&request_id);
+    }
+
+    let _permit = match acquire_es_permit(&app_state) {
+        Ok(permit) => permit,
+        Err(e) => return e.response(&request_id),
+    };
+
+    match es::recent_domains(&es_client, &app_state.config.elasticsearch.index, limit).await {
+        Ok(domains) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "data": domains
+        })),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to list recent domains");
+            ApiError::internal("Failed to list recent domains").response(&request_id)
+        }
+    }
+}
+
+/// 各上报来源（设备）的记录数和最后上报时间，用于发现停止同步的设备
+#[utoipa::path(
+    get,
+    path = "/api/history/sources",
+    tag = "history",
+    params(
+        ("limit" = Option<usize>, Query, description = "Maximum number of sources (default 50, max 500)")
+    ),
+    responses(
+        (status = 200, description = "Sources ordered by record count: { sources: [{ source, count, last_seen }], unattributed }"),
+        (status = 400, description = "Bad request"),
+        (status = 500, description = "Internal server error"),
+        (status = 503, description = "Too many concurrent searches, retry after Retry-After")
+    )
+)]
+#[get("/api/history/sources")]
+async fn history_sources(
+    request_id: RequestId,
+    query: web::Query<SourcesQuery>,
+    es_client: web::Data<es::ReadClient>,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    tracing::info!("GET /api/history/sources: {:?}", query);
+
+    let limit = query.limit.unwrap_or(DEFAULT_SOURCES);
+    if limit == 0 || limit > MAX_SOURCES {
+        return ApiError::bad_request(format!("limit must be between 1 and {}", MAX_SOURCES)).response(&request_id);
+    }
+
+    let _permit = match acquire_es_permit(&app_state) {
+        Ok(permit) => permit,
+        Err(e) => return e.response(&request_id),
+    };
+
+    match es::source_stats(&es_client, &app_state.config.elasticsearch.index, limit).await {
+        Ok(stats) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "data": stats
+        })),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to aggregate history sources");
+            ApiError::internal("Failed to aggregate history sources").response(&request_id)
+        }
+    }
+}
+
+/// 时间范围内没有浏览记录的时段（按持续时间从长到短）
+#[utoipa::path(
+    get,
+    path = "/api/history/gaps",
+    tag = "history",
+    params(
+        ("startDate" = Option<String>, Query, description = "Start date (ISO 8601, or relative; default now-7d)"),
+        ("endDate" = Option<String>, Query, description = "End date (ISO 8601, or relative; default now)"),
+        ("interval" = Option<String>, Query, description = "Bucket size: hour (default) or day"),
+        ("limit" = Option<usize>, Query, description = "Maximum number of gaps (default 10, max 100)")
+    ),
+    responses(
+        (status = 200, description = "Longest gaps first: { interval, gaps: [{ start, end, duration_seconds }] }"),
+        (status = 400, description = "Bad request"),
+        (status = 500, description = "Internal server error"),
+        (status = 503, description = "Too many concurrent searches, retry after Retry-After")
+    )
+)]
+#[get("/api/history/gaps")]
+async fn browsing_gaps(
+    request_id: RequestId,
+    query: web::Query<GapsQuery>,
+    es_client: web::Data<es::ReadClient>,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    tracing::info!("GET /api/history/gaps: {:?}", query);
+
+    let start_date = query.start_date.as_deref().unwrap_or(DEFAULT_GAPS_START);
+    let end_date = query.end_date.as_deref().unwrap_or(DEFAULT_GAPS_END);
+    for date in [start_date, end_date] {
+        if let Err(message) = es::validate_date_filter(date) {
+            return ApiError::bad_request(message).response(&request_id);
+        }
+    }
+    if let Err(message) = es::validate_gap_range(start_date, end_date, query.interval, chrono::Utc::now()) {
+        return ApiError::bad_request(message).response(&request_id);
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_GAPS_LIMIT);
+    if limit == 0 || limit > MAX_GAPS_LIMIT {
+        return ApiError::bad_request(format!("limit must be between 1 and {}", MAX_GAPS_LIMIT)).response(&request_id);
+    }
+
+    let _permit = match acquire_es_permit(&app_state) {
+        Ok(permit) => permit,
+        Err(e) => return e.response(&request_id),
+    };
+
+    match es::browsing_gaps(&es_client, &app_state.config.elasticsearch.index, start_date, end_date, query.interval, limit).await {
+        Ok(gaps) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "data": gaps
+        })),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to find browsing gaps");
+            ApiError::internal("Failed to find browsing gaps").response(&request_id)
+        }
+    }
+}
+
+/// 对比两个时间段内各域名的访问次数（如本月与上月），返回变化最大的域名
+#[utoipa::path(
+    get,
+    path = "/api/history/compare",
+    tag = "history",
+    params(
+        ("periodA" = String, Query, description = "First period as start..end, such as now-60d/d..now-30d/d"),
+        ("periodB" = String, Query, description = "Second period as start..end, such as now-30d/d..now"),
+        ("limit" = Option<usize>, Query, description = "Maximum number of domains (default 20, max 200)")
+    ),
+    responses(
+        (status = 200, description = "Per-period totals and the most changed domains: { domain, count_a, count_b, change }"),
+        (status = 400, description = "Bad request"),
+        (status = 500, description = "Internal server error"),
+        (status = 503, description = "Too many concurrent searches, retry after Retry-After")
+    )
+)]
+#[get("/api/history/compare")]
+async fn compare_periods(
+    request_id: RequestId,
+    query: web::Query<CompareQuery>,
+    es_client: web::Data<es::ReadClient>,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    use crate::services::cache::CacheKeyGenerator;
+
+    tracing::info!("GET /api/history/compare: {:?}", query);
+
+    let (period_a, period_b) = match (es::parse_period(&query.period_a), es::parse_period(&query.period_b)) {
+        (Ok(period_a), Ok(period_b)) => (period_a, period_b),
+        (Err(message), _) | (_, Err(message)) => return ApiError::bad_request(message).response(&request_id),
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_COMPARE_LIMIT);
+    if limit == 0 || limit > MAX_COMPARE_LIMIT {
+        return ApiError::bad_request(format!("limit must be between 1 and {}", MAX_COMPARE_LIMIT)).response(&request_id);
+    }
+
+    // 只缓存两端都是绝对日期的对比：结果稳定；含 now 的时间段每次请求都在变化，不缓存
+    let cache_key = CacheKeyGenerator::history_compare_key(&query.period_a, &query.period_b, limit);
+    let cacheable = ![&period_a.0, &period_a.1, &period_b.0, &period_b.1]
+        .into_iter()
+        .any(|date| es::is_relative_date(date));
+    let cache = app_state.cache.as_ref().filter(|_| cacheable);
+    if let Some(cache_impl) = cache {
+        match cache_impl.get(&cache_key).await {
+            Ok(Some(cached_data)) => {
+                tracing::info!("Cache hit for key: {}", cache_key);
+                return HttpResponse::Ok().json(cached_data);
+            }
+            Ok(None) => tracing::info!("Cache miss for key: {}", cache_key),
+            Err(e) => tracing::error!("Cache get error (will fallback to DB): {}", e),
+        }
+    }
+
+    let _permit = match acquire_es_permit(&app_state) {
+        Ok(permit) => permit,
+        Err(e) => return e.response(&request_id),
+    };
+
+    let result = es::compare_periods(
+        &es_client,
+        &app_state.config.elasticsearch.index,
+        (&period_a.0, &period_a.1),
+        (&period_b.0, &period_b.1),
+        limit,
+    ).await;
+
+    match result {
+        Ok(comparison) => {
+            let response = json!({
+                "status": "success",
+                "data": comparison
+            });
+            if let Some(cache_impl) = cache {
+                let cache_clone = cache_impl.clone();
+                let response_clone = response.clone();
+                let ttl = app_state.config.cache.ttl_for("compare_history");
+                tokio::spawn(async move {
+                    if let Err(e) = cache_clone.set(&cache_key, &response_clone, ttl).await {
+                        tracing::error!("Failed to set cache for key {}: {}", cache_key, e);
+                    }
+                });
+            }
+            HttpResponse::Ok().json(response)
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to compare periods");
+            ApiError::internal("Failed to compare periods").response(&request_id)
+        }
+    }
+}
+
+/// 按搜索条件导出历史记录（XLSX：日期类型的访问时间列和可点击的URL）
+#[utoipa::path(
+    get,
+    path = "/api/history/export",
+    tag = "history",
+    params(
+        ("format" = String, Query, description = "Export format: xlsx"),
+        ("keyword" = Option<String>, Query, description = "Search keyword"),
+        ("domain" = Option<String>, Query, description = "Domain filter"),
+        ("startDate" = Option<String>, Query, description = "Start date (ISO 8601, or relative such as now-7d)"),
+        ("endDate" = Option<String>, Query, description = "End date (ISO 8601, or relative such as now-1d/d)"),
+        ("normalizationStatus" = Option<String>, Query, description = "failed, missing or ok"),
+        ("urlContains" = Option<String>, Query, description = "Fragment of the original URL"),
+        ("weekday" = Option<String>, Query, description = "Days of the week, such as sat,sun or mon-fri"),
+        ("resourceType" = Option<String>, Query, description = "Resource types, such as document or document,download"),
+        ("pathPrefix" = Option<String>, Query, description = "URL path prefix within `domain`, such as /api/v2/")
+    ),
+    responses(
+        (status = 200, description = "Spreadsheet with the newest records first (at most 100000, X-Export-Truncated: true when more matched)"),
+        (status = 400, description = "Bad request"),
+        (status = 500, description = "Internal server error"),
+        (status = 503, description = "Too many concurrent searches, retry after Retry-After")
+    )
+)]
+#[get("/api/history/export")]
+async fn export_history(
+    request_id: RequestId,
+    query: web::Query<ExportQuery>,
+    es_client: web::Data<es::ReadClient>,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    tracing::info!("GET /api/history/export: {:?}", query);
+
+    for date in [&query.start_date, &query.end_date].into_iter().flatten() {
+        if let Err(message) = es::validate_date_filter(date) {
+            return ApiError::bad_request(message).response(&request_id);
+        }
+    }
+    let weekdays = match query.weekday.as_deref().map(es::parse_weekday_filter).transpose() {
+        Ok(weekdays) => weekdays,
+        Err(message) => return ApiError::bad_request(message).response(&request_id),
+    };
+    let resource_types = match query.resource_type.as_deref().map(es::parse_resource_type_filter).transpose() {
+        Ok(resource_types) => resource_types,
+        Err(message) => return ApiError::bad_request(message).response(&request_id),
+    };
+    if let Some(path_prefix) = query.path_prefix.as_deref().filter(|path_prefix| !path_prefix.is_empty()) {
+        if let Err(message) = es::validate_path_prefix(path_prefix, query.domain.as_deref()) {
+            return ApiError::bad_request(message).response(&request_id);
+        }
+    }
+
+    let _permit = match acquire_es_permit(&app_state) {
+        Ok(permit) => permit,
+        Err(e) => return e.response(&request_id),
+    };
+
+    let options = es::SearchOptions {
+        normalization_status: query.normalization_status,
+        url_contains: query.url_contains.clone(),
+        weekdays,
+        resource_types,
+        path_prefix: query.path_prefix.clone(),
+        legacy_url_field: app_state.config.elasticsearch.write_legacy_url_field,
+        ..es::SearchOptions::default()
+    };
+    let exported = match es::export_history(
+        &es_client,
+        &app_state.config.elasticsearch.index,
+        query.keyword.clone(),
+        query.domain.clone(),
+        query.start_date.clone(),
+        query.end_date.clone(),
+        &options,
+        MAX_EXPORT_ROWS,
+    ).await {
+        Ok(exported) => exported,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to export history");
+            return ApiError::internal("Failed to export history").response(&request_id);
+        }
+    };
+    if exported.truncated {
+        tracing::warn!("History export truncated to {} records", MAX_EXPORT_ROWS);
+    }
+
+    // 生成工作簿是CPU密集的操作，不占用请求处理线程
+    let (content_type, extension) = match query.format {
+        ExportFormat::Xlsx => (xlsx_export::XLSX_CONTENT_TYPE, "xlsx"),
+    };
+    let records = exported.records;
+    let buffer = match web::block(move || xlsx_export::history_workbook(&records)).await {
+        Ok(Ok(buffer)) => buffer,
+        Ok(Err(e)) => {
+            tracing::error!(error = %e, "Failed to build export workbook");
+            return ApiError::internal("Failed to export history").response(&request_id);
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Export workbook task failed");
+            return ApiError::internal("Failed to export history").response(&request_id);
+        }
+    };
+
+    let filename = format!("browser-history-{}.{}", chrono::Utc::now().format("%Y%m%d-%H%M%S"), extension);
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(("Content-Disposition", format!("attachment; filename=\"{}\"", filename)))
+        .insert_header(("X-Export-Truncated", exported.truncated.to_string()))
+        .body(buffer)
+}
+
+/// 按搜索条件批量添加和移除标签，返回实际更新的记录数
+#[utoipa::path(
+    post,
+    path = "/api/history/tags/bulk",
+    tag = "history",
+    request_body = BulkTagRequest,
+    responses(
+        (status = 200, description = "Number of records updated (records that already had the tags are counted in noops)"),
+        (status = 400, description = "Invalid tags or filter, or the filter matches too many records"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+#[post("/api/history/tags/bulk")]
+async fn bulk_update_tags(
+    request_id: RequestId,
+    request: web::Json<BulkTagRequest>,
+    es_client: web::Data<Arc<Elasticsearch>>,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    let filter = &request.filter;
+
+    let (add, remove) = match es::normalize_tag_changes(&request.add, &request.remove) {
+        Ok(changes) => changes,
+        Err(message) => return ApiError::bad_request(message).response(&request_id),
+    };
+    for date in [&filter.start_date, &filter.end_date].into_iter().flatten() {
+        if let Err(message) = es::validate_date_filter(date) {
+            return ApiError::bad_request(message).response(&request_id);
+        }
+    }
+    let weekdays = match filter.weekday.as_deref().map(es::parse_weekday_filter).transpose() {
+        Ok(weekdays) => weekdays,
+        Err(message) => return ApiError::bad_request(message).response(&request_id),
+    };
+    let resource_types = match filter.resource_type.as_deref().map(es::parse_resource_type_filter).transpose() {
+        Ok(resource_types) => resource_types,
+        Err(message) => return ApiError::bad_request(message).response(&request_id),
+    };
+    if let Some(path_prefix) = filter.path_prefix.as_deref().filter(|path_prefix| !path_prefix.is_empty()) {
+        if let Err(message) = es::validate_path_prefix(path_prefix, filter.domain.as_deref()) {
+            return ApiError::bad_request(message).response(&request_id);
+        }
+    }
+
+    let options = es::SearchOptions {
+        normalization_status: filter.normalization_status,
+        url_contains: filter.url_contains.clone(),
+        weekdays,
+        resource_types,
+        path_prefix: filter.path_prefix.clone(),
+        legacy_url_field: app_state.config.elasticsearch.write_legacy_url_field,
+        ..es::SearchOptions::default()
+    };
+    let query = es::filtered_history_query(
+        filter.keyword.clone(),
+        filter.domain.clone(),
+        filter.start_date.clone(),
+        filter.end_date.clone(),
+        &options,
+    );
+    // 不允许不带条件地给全部历史打标签
+    if query.is_empty() {
+        return ApiError::bad_request("At least one filter is required").response(&request_id);
+    }
+    let query = query.build();
+
+    let index = &app_state.config.elasticsearch.index;
+    let matched = match es::count_matching(&es_client, index, &query).await {
+        Ok(matched) => matched,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to count records for bulk tagging");
+            return ApiError::internal("Failed to update tags").response(&request_id);
+        }
+    };
+    if matched > MAX_BULK_TAG_RECORDS {
+        return ApiError::bad_request(format!(
+            "Filter matches {} records, more than the limit of {}; narrow the filter",
+            matched, MAX_BULK_TAG_RECORDS
+        ))
+        .response(&request_id);
+    }
+
+    match es::update_tags_by_query(&es_client, index, query, &add, &remove, MAX_BULK_TAG_RECORDS).await {
+        Ok(outcome) => {
+            tracing::info!(
+                request_id = %request_id,
+                matched,
+                updated = outcome.updated,
+                failed = outcome.failed,
+                add = ?add,
+                remove = ?remove,
+                "Bulk updated tags"
+            );
+            HttpResponse::Ok().json(json!({
+                "status": "success",
+                "data": outcome
+            }))
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Bulk tag update failed");
+            ApiError::internal("Failed to update tags").response(&request_id)
+        }
+    }
+}
+
+/// Report browser history
+#[utoipa::path(
+    post,
+    path = "/api/history",
+    tag = "history",
+    request_body = HistoryRequest,
+    params(
+        ("X-No-Store" = Option<bool>, Header, description = "When true the record is accepted but not stored (private browsing)")
+    ),
+    responses(
+        (status = 200, description = "History recorded successfully"),
+        (status = 400, description = "Invalid request data"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+#[post("/api/history")]
+async fn report_history(
+    request_id: RequestId,
+    http_request: HttpRequest,
+    request: web::Json<HistoryRequest>,
+    es_client: web::Data<Arc<Elasticsearch>>,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    // 隐私模式：在记录任何日志之前返回，不存储也不输出可识别信息
+    if is_no_store(&http_request) {
+        tracing::info!(REQUEST = "report_history", "Skipped storing record (no-store requested)");
+        return HttpResponse::Ok().json(json!({
+            "status": "success",
+            "message": "Record accepted but not stored",
+            "stored": false
+        }));
+    }
+
+    match ingest_history_record(&request, &es_client, &app_state).await {
+        Ok(IngestOutcome::Stored { original_url, normalized_url }) => {
+            HttpResponse::Ok().json(json!({
+                "status": "success",
+                "message": "Record added successfully",
                 "original_url": original_url,
                 "normalized_url": normalized_url
             }))
         }
+        Ok(IngestOutcome::Dropped(reason)) => HttpResponse::Ok().json(json!({
+            "status": "dropped",
+            "message": format!("{}, record was not stored", reason)
+        })),
+        Err(IngestError::Invalid(message)) => ApiError::bad_request(message).response(&request_id),
+        Err(IngestError::Storage(e)) => {
+            tracing::error!(error = %e, "Failed to insert history record");
+            ApiError::internal("Failed to store record").response(&request_id)
+        }
+    }
+}
+
+// 单条上报记录的处理结果
+enum IngestOutcome {
+    Stored {
+        original_url: String,
+        normalized_url: Option<String>,
+    },
+    // 协议不允许且配置为丢弃
+    Dropped(String),
+}
+
+enum IngestError {
+    Invalid(String),
+    Storage(elasticsearch::Error),
+}
+
+/// 校验、归一化并写入单条上报记录，HTTP上报和队列消费共用
+async fn ingest_history_record(
+    request: &HistoryRequest,
+    es_client: &Elasticsearch,
+    app_state: &AppState,
+) -> Result<IngestOutcome, IngestError> {
+    // 获取原始URL和归一化URL
+    let original_url = request.resolve_url()
+        .and_then(|url| check_url_characters(url, app_state.config.ingest.malformed_url_action))
+        .map_err(IngestError::Invalid)?;
+    let original_url = original_url.as_ref();
+    tracing::info!(REQUEST = "report_history", url = %original_url, domain = %request.domain);
+
+    if let Err(reason) = check_url_scheme(original_url, &app_state.config.ingest.allowed_schemes) {
+        return match app_state.config.ingest.disallowed_scheme_action {
+            RejectAction::Reject => Err(IngestError::Invalid(reason)),
+            RejectAction::Drop => Ok(IngestOutcome::Dropped(reason)),
+        };
+    }
+    
+    // 仅查询时归一化模式下只存储原始URL
+    let (normalized_url, normalization_failed) = request.stored_normalized_url(original_url, app_state).await;
+
+    let dedup_by_id = app_state.config.ingest.document_ids == DocumentIdMode::Dedup;
+    let doc = request.document(original_url, normalized_url.as_deref(), normalization_failed, app_state);
+    es::insert_history(es_client, &app_state.config.elasticsearch.index, doc, dedup_by_id)
+        .await
+        .map_err(IngestError::Storage)?;
+
+    Ok(IngestOutcome::Stored {
+        original_url: original_url.to_string(),
+        normalized_url,
+    })
+}
+
+/// 把队列消息交给与HTTP上报相同的处理流程：无效记录丢弃，写入失败留待重试
+fn queue_message_handler(es_client: Arc<Elasticsearch>, app_state: Arc<AppState>) -> MessageHandler {
+    Arc::new(move |payload| {
+        let es_client = es_client.clone();
+        let app_state = app_state.clone();
+        Box::pin(async move {
+            let request: HistoryRequest = serde_json::from_value(payload)
+                .map_err(|e| HandleError::Rejected(format!("invalid history record: {}", e)))?;
+
+            match ingest_history_record(&request, &es_client, &app_state).await {
+                Ok(_) => Ok(()),
+                Err(IngestError::Invalid(reason)) => Err(HandleError::Rejected(reason)),
+                // ES因文档本身拒绝写入（如映射错误）时重试也不会成功；429 和 5xx 稍后重试
+                Err(IngestError::Storage(e)) if e.status_code().map(|status| status.as_u16()).is_some_and(|status| {
+                    (400..500).contains(&status) && status != 429
+                }) => Err(HandleError::Rejected(e.to_string())),
+                Err(IngestError::Storage(e)) => Err(HandleError::Retry(e.to_string())),
+            }
+        })
+    })
+}
+
+/// Query history by URLs with normalization
+#[utoipa::path(
+    post,
+    path = "/api/history/query",
+    tag = "history",
+    request_body = UrlQueryRequest,
+    responses(
+        (status = 200, description = "Query results, plus the normalized URL searched for each input URL when includeNormalized is true; `truncated` lists URLs that could not be resolved in query-only normalization mode"),
+        (status = 400, description = "Invalid request data"),
+        (status = 500, description = "Internal server error"),
+        (status = 503, description = "Too many concurrent searches, retry after Retry-After")
+    )
+)]
+#[post("/api/history/query")]
+async fn query_history_by_urls(
+    request_id: RequestId,
+    request: web::Json<UrlQueryRequest>,
+    es_client: web::Data<Arc<Elasticsearch>>,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    tracing::info!(REQUEST = "query_history_by_urls", request = ?request);
+    
+    // 收集所有需要查询的URL
+    let mut original_urls = Vec::new();
+    
+    if let Some(url) = &request.url {
+        original_urls.push(url.clone());
+    }
+    
+    if let Some(urls) = &request.urls {
+        original_urls.extend(urls.clone());
+    }
+    
+    if original_urls.is_empty() {
+        return ApiError::bad_request("No URLs provided for query").response(&request_id);
+    }
+
+    if let Err(message) = check_batch_size(original_urls.len(), app_state.config.query.max_batch_size) {
+        return ApiError::bad_request(message).response(&request_id);
+    }
+    
+    // 归一化所有URL，多个原始URL可能归一化为同一个URL，查询前去重
+    let mut url_mapping: std::collections::HashMap<String, Vec<String>> = std::collections::HashMap::new();
+    let mut normalized_urls = Vec::new();
+    
+    let normalized_batch = app_state.url_normalizer.normalize_urls(original_urls.clone()).await;
+    for (original_url, normalized) in original_urls.iter().zip(normalized_batch) {
+        let originals = url_mapping.entry(normalized.clone()).or_default();
+        if originals.is_empty() {
+            normalized_urls.push(normalized);
+        }
+        originals.push(original_url.clone());
+    }
+    
+    let _permit = match acquire_es_permit(&app_state) {
+        Ok(permit) => permit,
+        Err(e) => return e.response(&request_id),
+    };
+
+    // 分批查询ES并合并结果，避免单个terms查询过大；去重后每个归一化URL只出现在一个批次中
+    let chunk_size = app_state.config.query.terms_chunk_size.max(1);
+    let mut merged_results = std::collections::HashMap::new();
+    let mut truncated = Vec::new();
+    let mut query_error = None;
+
+    for chunk in normalized_urls.chunks(chunk_size) {
+        let chunk_results = match app_state.config.normalization.mode {
+            NormalizationMode::Store => {
+                // 没有 normalized_url 的旧文档按原始URL回退匹配
+                let legacy_urls: std::collections::HashMap<String, String> = chunk
+                    .iter()
+                    .flat_map(|normalized| {
+                        url_mapping[normalized].iter().map(move |original| (original.clone(), normalized.clone()))
+                    })
+                    .collect();
+                es::search_history_by_normalized_urls(&es_client, &app_state.config.elasticsearch.index, chunk.to_vec(), &legacy_urls, request.fields.as_deref()).await
+                    .map(|results| (results, Vec::new()))
+                    .map_err(es::MsearchError::from)
+            }
+            NormalizationMode::QueryOnly => {
+                let lookups = chunk.iter().map(|normalized| (normalized.clone(), url_mapping[normalized].clone())).collect();
+                search_history_normalizing_candidates(&es_client, &app_state, lookups).await
+                    .map(|matches| (matches.found, matches.truncated))
+            }
+        };
+
+        match chunk_results {
+            Ok((chunk_results, chunk_truncated)) => {
+                merged_results.extend(chunk_results);
+                truncated.extend(chunk_truncated);
+            }
+            Err(e) => {
+                query_error = Some(e);
+                break;
+            }
+        }
+    }
+
+    let results = match query_error {
+        Some(e) => Err(e),
+        None => Ok(merged_results),
+    };
+
+    match results {
+        Ok(results) => {
+            // 将结果映射回原始URL
+            let mut response_data = std::collections::HashMap::new();
+            
+            for (normalized_url, record) in results {
+                if let Some(original_urls) = url_mapping.get(&normalized_url) {
+                    for original_url in original_urls {
+                        response_data.insert(original_url.clone(), record.clone());
+                    }
+                }
+            }
+            
+            let mut body = json!({
+                "status": "success",
+                "data": response_data,
+                "total": response_data.len()
+            });
+            if request.include_normalized {
+                body["normalized"] = json!(normalized_by_original(&url_mapping));
+            }
+            // 候选记录过多、无法确定是否有访问记录的原始URL，不能当作未访问
+            if !truncated.is_empty() {
+                let truncated: Vec<&String> = truncated.iter().flat_map(|normalized| &url_mapping[normalized]).collect();
+                body["truncated"] = json!(truncated);
+            }
+            HttpResponse::Ok().json(body)
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to query history by URLs");
+            ApiError::internal("Failed to query history").response(&request_id)
+        }
+    }
+}
+
+/// 把 归一化URL -> 原始URL列表 的映射反转为 原始URL -> 归一化URL
+fn normalized_by_original(url_mapping: &std::collections::HashMap<String, Vec<String>>) -> std::collections::HashMap<&str, &str> {
+    url_mapping
+        .iter()
+        .flat_map(|(normalized, originals)| originals.iter().map(move |original| (original.as_str(), normalized.as_str())))
+        .collect()
+}
+
+/// 校验按URL查询的批量大小
+fn check_batch_size(count: usize, max_batch_size: usize) -> Result<(), String> {
+    if count > max_batch_size {
+        Err(format!("Too many URLs in one request: {} (maximum is {})", count, max_batch_size))
+    } else {
+        Ok(())
+    }
+}
+
+// 分页查询每个URL全部访问记录的请求
+#[derive(Debug, Deserialize, ToSchema)]
+struct UrlVisitsRequest {
+    urls: Vec<String>,
+    #[serde(default = "default_page")]
+    page: Option<i32>,
+    #[serde(rename = "pageSize", default = "default_page_size")]
+    page_size: Option<i32>,
+}
+
+// 单次请求最多查询的URL数量，每个URL对应 _msearch 中的一个查询
+const MAX_VISIT_LOOKUP_URLS: usize = 100;
+// 每个URL每页最多返回的访问记录数
+const MAX_VISITS_PAGE_SIZE: i32 = 100;
+
+/// List every visit of each URL, paginated per URL
+#[utoipa::path(
+    post,
+    path = "/api/history/query/all",
+    tag = "history",
+    request_body = UrlVisitsRequest,
+    responses(
+        (status = 200, description = "Per original URL: normalized URL, total visits and one page of visits, newest first"),
+        (status = 400, description = "Invalid request data"),
+        (status = 500, description = "Internal server error"),
+        (status = 503, description = "Too many concurrent searches, retry after Retry-After")
+    )
+)]
+#[post("/api/history/query/all")]
+async fn query_all_visits_by_urls(
+    request_id: RequestId,
+    request: web::Json<UrlVisitsRequest>,
+    es_client: web::Data<Arc<Elasticsearch>>,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    tracing::info!(REQUEST = "query_all_visits_by_urls", count = request.urls.len());
+
+    if request.urls.is_empty() {
+        return ApiError::bad_request("No URLs provided for query").response(&request_id);
+    }
+    if let Err(message) = check_batch_size(request.urls.len(), MAX_VISIT_LOOKUP_URLS) {
+        return ApiError::bad_request(message).response(&request_id);
+    }
+    // 仅查询时归一化模式下索引中没有 normalized_url，无法按归一化URL精确分页
+    if app_state.config.normalization.mode == NormalizationMode::QueryOnly {
+        return ApiError::bad_request("Listing all visits requires normalization mode 'store'").response(&request_id);
+    }
+
+    let page = request.page.unwrap_or(1).max(1);
+    let page_size = request.page_size.unwrap_or(30).clamp(1, MAX_VISITS_PAGE_SIZE);
+    if let Err(message) = es::validate_result_window(page, page_size) {
+        return ApiError::bad_request(message).response(&request_id);
+    }
+    let from = ((page - 1) * page_size) as usize;
+
+    // 多个原始URL可能归一化为同一个URL，只查询一次
+    let normalized_batch = app_state.url_normalizer.normalize_urls(request.urls.clone()).await;
+    let mut lookups: Vec<(String, Vec<String>)> = Vec::new();
+    for (original_url, normalized) in request.urls.iter().zip(&normalized_batch) {
+        match lookups.iter_mut().find(|(existing, _)| existing == normalized) {
+            Some((_, originals)) => originals.push(original_url.clone()),
+            None => lookups.push((normalized.clone(), vec![original_url.clone()])),
+        }
+    }
+
+    let _permit = match acquire_es_permit(&app_state) {
+        Ok(permit) => permit,
+        Err(e) => return e.response(&request_id),
+    };
+
+    let pages = match es::search_visits_by_normalized_urls(&es_client, &app_state.config.elasticsearch.index, &lookups, from, page_size as usize).await {
+        Ok(pages) => pages,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to query visits by URLs");
+            return ApiError::internal("Failed to query history").response(&request_id);
+        }
+    };
+
+    let mut data = serde_json::Map::new();
+    for ((normalized_url, original_urls), visits) in lookups.iter().zip(pages) {
+        for original_url in original_urls {
+            data.insert(original_url.clone(), json!({
+                "normalized_url": normalized_url,
+                "total": visits.total,
+                "items": visits.items
+            }));
+        }
+    }
+
+    HttpResponse::Ok().json(json!({
+        "status": "success",
+        "data": data,
+        "page": page,
+        "pageSize": page_size
+    }))
+}
+
+// URL存在性检查请求
+#[derive(Debug, Deserialize, ToSchema)]
+struct UrlExistsRequest {
+    urls: Vec<String>,
+}
+
+/// Check which URLs have been visited, without returning the records
+#[utoipa::path(
+    post,
+    path = "/api/history/exists",
+    tag = "history",
+    request_body = UrlExistsRequest,
+    responses(
+        (status = 200, description = "Map of each requested URL to whether it has history; URLs that could not be resolved in query-only normalization mode are listed in `truncated` instead"),
+        (status = 400, description = "Invalid request data"),
+        (status = 500, description = "Internal server error"),
+        (status = 503, description = "Too many concurrent searches, retry after Retry-After")
+    )
+)]
+#[post("/api/history/exists")]
+async fn history_exists(
+    request_id: RequestId,
+    request: web::Json<UrlExistsRequest>,
+    es_client: web::Data<Arc<Elasticsearch>>,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    tracing::info!(REQUEST = "history_exists", count = request.urls.len());
+
+    if let Err(message) = check_batch_size(request.urls.len(), app_state.config.query.max_batch_size) {
+        return ApiError::bad_request(message).response(&request_id);
+    }
+
+    // 归一化并去重后分批查询
+    let mut normalized_by_url = Vec::with_capacity(request.urls.len());
+    let mut normalized_urls = Vec::new();
+    let normalized_batch = app_state.url_normalizer.normalize_urls(request.urls.clone()).await;
+    for (url, normalized) in request.urls.iter().zip(normalized_batch) {
+        if !normalized_urls.contains(&normalized) {
+            normalized_urls.push(normalized.clone());
+        }
+        normalized_by_url.push((url, normalized));
+    }
+
+    let _permit = match acquire_es_permit(&app_state) {
+        Ok(permit) => permit,
+        Err(e) => return e.response(&request_id),
+    };
+
+    let chunk_size = app_state.config.query.terms_chunk_size.max(1);
+    let mut present = std::collections::HashSet::new();
+    let mut truncated = std::collections::HashSet::new();
+
+    for chunk in normalized_urls.chunks(chunk_size) {
+        let chunk_present = match app_state.config.normalization.mode {
+            NormalizationMode::Store => {
+                // 没有 normalized_url 的旧文档按原始URL回退匹配，避免被当作不存在而重复写入
+                let legacy_urls: std::collections::HashMap<String, String> = normalized_by_url
+                    .iter()
+                    .filter(|(_, normalized)| chunk.contains(normalized))
+                    .map(|(url, normalized)| ((*url).clone(), normalized.clone()))
+                    .collect();
+                es::existing_normalized_urls(&es_client, &app_state.config.elasticsearch.index, chunk, &legacy_urls).await
+                    .map(|existing| (existing, Vec::new()))
+                    .map_err(es::MsearchError::from)
+            }
+            NormalizationMode::QueryOnly => {
+                let lookups = chunk
+                    .iter()
+                    .map(|normalized| {
+                        let originals = normalized_by_url
+                            .iter()
+                            .filter(|(_, other)| other == normalized)
+                            .map(|(url, _)| (*url).clone())
+                            .collect();
+                        (normalized.clone(), originals)
+                    })
+                    .collect();
+                search_history_normalizing_candidates(&es_client, &app_state, lookups).await
+                    .map(|matches| (matches.found.into_keys().collect(), matches.truncated))
+            }
+        };
+
+        match chunk_present {
+            Ok((chunk_present, chunk_truncated)) => {
+                present.extend(chunk_present);
+                truncated.extend(chunk_truncated);
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to check history existence");
+                return ApiError::internal("Failed to check history").response(&request_id);
+            }
+        }
+    }
+
+    // 候选记录过多、无法确定是否存在的URL不放入 data，单独列在 truncated 中
+    let (truncated_urls, normalized_by_url): (Vec<_>, Vec<_>) = normalized_by_url
+        .into_iter()
+        .partition(|(_, normalized)| truncated.contains(normalized));
+    let data: std::collections::HashMap<&String, bool> = normalized_by_url
+        .into_iter()
+        .map(|(url, normalized)| (url, present.contains(&normalized)))
+        .collect();
+
+    let mut body = json!({
+        "status": "success",
+        "data": data
+    });
+    if !truncated_urls.is_empty() {
+        body["truncated"] = json!(truncated_urls.into_iter().map(|(url, _)| url).collect::<Vec<_>>());
+    }
+    HttpResponse::Ok().json(body)
+}
+
+// 仅查询时归一化模式下每个URL的候选记录上限
+const QUERY_ONLY_CANDIDATES_PER_URL: usize = 200;
+
+/// 仅查询时归一化模式的查询结果
+#[derive(Default)]
+struct CandidateMatches {
+    /// 归一化URL -> 最新记录
+    found: std::collections::HashMap<String, serde_json::Value>,
+    /// 候选记录达到上限仍未匹配的归一化URL：无法确定是否存在，不能当作未找到
+    truncated: Vec<String>,
+}
+
+/// 仅查询时归一化模式：按URL前缀和域名取候选记录（每个URL单独限量），对其原始URL即时归一化，保留每个归一化URL的最新记录
+/// `lookups` 为（归一化URL, 归一化为它的查询URL），候选域名取自两者的主机名
+async fn search_history_normalizing_candidates(
+    es_client: &Elasticsearch,
+    app_state: &AppState,
+    lookups: Vec<(String, Vec<String>)>,
+) -> Result<CandidateMatches, es::MsearchError> {
+    let normalized_urls: Vec<String> = lookups.iter().map(|(normalized, _)| normalized.clone()).collect();
+    let candidate_lookups: Vec<(String, Vec<String>)> = lookups
+        .iter()
+        .map(|(normalized, originals)| {
+            let urls = std::iter::once(normalized.as_str()).chain(originals.iter().map(String::as_str));
+            (normalized.clone(), es::url_lookup_domains(urls))
+        })
+        .collect();
+    let candidates = es::search_history_candidates(
+        es_client,
+        &app_state.config.elasticsearch.index,
+        &candidate_lookups,
+        QUERY_ONLY_CANDIDATES_PER_URL,
+    ).await?;
+
+    let mut matches = CandidateMatches::default();
+    let mut full_lookups = Vec::new();
+
+    for (normalized_url, candidates) in normalized_urls.iter().zip(candidates) {
+        if candidates.len() >= QUERY_ONLY_CANDIDATES_PER_URL {
+            full_lookups.push(normalized_url);
+        }
+
+        // 候选记录已按时间倒序排列，第一个匹配的即为最新记录；同域名的候选也可能属于其他URL，只与本URL比较
+        for candidate in candidates {
+            let Some(candidate_url) = candidate["original_url"].as_str() else {
+                continue;
+            };
+
+            if app_state.url_normalizer.normalize_url(candidate_url).await == *normalized_url {
+                matches.found.insert(normalized_url.clone(), candidate);
+                break;
+            }
+        }
+    }
+
+    matches.truncated = full_lookups
+        .into_iter()
+        .filter(|normalized_url| !matches.found.contains_key(*normalized_url))
+        .cloned()
+        .collect();
+    if !matches.truncated.is_empty() {
+        tracing::warn!("Candidate limit reached without a match for {} URLs", matches.truncated.len());
+    }
+
+    Ok(matches)
+}
+
+// ES繁忙时建议客户端等待的秒数
+const BULK_RETRY_AFTER_SECONDS: u64 = 5;
+
+/// Report browser history in bulk
+#[utoipa::path(
+    post,
+    path = "/api/history/bulk",
+    tag = "history",
+    request_body = Vec<HistoryRequest>,
+    params(
+        ("X-No-Store" = Option<bool>, Header, description = "When true the records are accepted but not stored (private browsing)")
+    ),
+    responses(
+        (status = 200, description = "Records processed, with per-item failures; records with a disallowed scheme are listed in `rejected`, or in `dropped` when disallowed_scheme_action is drop"),
+        (status = 413, description = "Batch too large for Elasticsearch, split it and retry"),
+        (status = 429, description = "Elasticsearch is overloaded, retry the not_processed items after Retry-After"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+#[post("/api/history/bulk")]
+async fn report_history_bulk(
+    request_id: RequestId,
+    http_request: HttpRequest,
+    request: web::Json<Vec<HistoryRequest>>,
+    es_client: web::Data<Arc<Elasticsearch>>,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    if is_no_store(&http_request) {
+        tracing::info!(REQUEST = "report_history_bulk", "Skipped storing records (no-store requested)");
+        return HttpResponse::Ok().json(json!({
+            "status": "success",
+            "message": "Records accepted but not stored",
+            "stored": false
+        }));
+    }
+
+    tracing::info!(REQUEST = "report_history_bulk", count = request.len());
+
+    // docs 与 positions 一一对应，positions 记录文档在请求中的下标
+    let mut docs = Vec::with_capacity(request.len());
+    let mut positions = Vec::with_capacity(request.len());
+    let mut rejected = Vec::new();
+    // 协议不允许且配置为丢弃的记录，与单条上报一致：不存储，也不算作拒绝
+    let mut dropped = Vec::new();
+
+    for (index, record) in request.iter().enumerate() {
+        let original_url = match record.resolve_url()
+            .and_then(|url| check_url_characters(url, app_state.config.ingest.malformed_url_action))
+        {
+            Ok(url) => url,
+            Err(reason) => {
+                rejected.push(RejectedImportRecord { index, reason });
+                continue;
+            }
+        };
+        let original_url = original_url.as_ref();
+
+        if let Err(reason) = check_url_scheme(original_url, &app_state.config.ingest.allowed_schemes) {
+            match app_state.config.ingest.disallowed_scheme_action {
+                RejectAction::Reject => rejected.push(RejectedImportRecord { index, reason }),
+                RejectAction::Drop => dropped.push(RejectedImportRecord { index, reason }),
+            }
+            continue;
+        }
+
+        let (normalized_url, normalization_failed) = record.stored_normalized_url(original_url, &app_state).await;
+        docs.push(record.document(original_url, normalized_url.as_deref(), normalization_failed, &app_state));
+        positions.push(index);
+    }
+
+    let dedup_by_id = app_state.config.ingest.document_ids == DocumentIdMode::Dedup;
+    match es::bulk_insert_history(&es_client, &app_state.config.elasticsearch.index, docs, dedup_by_id).await {
+        Ok(es::BulkInsertOutcome::Rejected { status: 413 }) => {
+            ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "Batch too large for Elasticsearch, split it into smaller batches and retry")
+                .with_field("processed", json!(0))
+                .with_field("not_processed", json!(positions))
+                .with_field("rejected", json!(rejected))
+                .with_field("dropped", json!(dropped))
+                .response(&request_id)
+        }
+        Ok(es::BulkInsertOutcome::Rejected { .. }) => {
+            ApiError::new(StatusCode::TOO_MANY_REQUESTS, "Elasticsearch is overloaded, retry the not_processed items later")
+                .with_retry_after(BULK_RETRY_AFTER_SECONDS)
+                .with_field("retry_after_seconds", json!(BULK_RETRY_AFTER_SECONDS))
+                .with_field("processed", json!(0))
+                .with_field("not_processed", json!(positions))
+                .with_field("rejected", json!(rejected))
+                .with_field("dropped", json!(dropped))
+                .response(&request_id)
+        }
+        Ok(es::BulkInsertOutcome::Completed { backpressure, failed }) => {
+            let not_processed: Vec<usize> = backpressure.iter().map(|&i| positions[i]).collect();
+            let failed: Vec<es::BulkItemFailure> = failed
+                .into_iter()
+                .map(|failure| es::BulkItemFailure { index: positions[failure.index], ..failure })
+                .collect();
+            let processed = positions.len() - not_processed.len() - failed.len();
+
+            if not_processed.is_empty() {
+                HttpResponse::Ok().json(json!({
+                    "status": "success",
+                    "processed": processed,
+                    "failed": failed,
+                    "rejected": rejected,
+                    "dropped": dropped
+                }))
+            } else {
+                ApiError::new(StatusCode::TOO_MANY_REQUESTS, "Some records were not processed because Elasticsearch is overloaded, retry them later")
+                    .with_retry_after(BULK_RETRY_AFTER_SECONDS)
+                    .with_field("retry_after_seconds", json!(BULK_RETRY_AFTER_SECONDS))
+                    .with_field("processed", json!(processed))
+                    .with_field("not_processed", json!(not_processed))
+                    .with_field("failed", json!(failed))
+                    .with_field("rejected", json!(rejected))
+                    .with_field("dropped", json!(dropped))
+                    .response(&request_id)
+            }
+        }
         Err(e) => {
-            tracing::error!(error = %e, "Failed to insert history record");
-            HttpResponse::InternalServerError().json(json!({
-                "status": "error",
-                "message": "Failed to store record"
-            }))
+            tracing::error!(error = %e, "Failed to bulk insert history records");
+            ApiError::internal("Failed to store records").response(&request_id)
         }
     }
 }
 
-/// Query history by URLs with normalization
+#[derive(Debug, Deserialize, IntoParams)]
+struct CompactQuery {
+    // 合并时是否把被删除文档的访问次数累加到保留的文档上
+    #[serde(rename = "sumVisits", default)]
+    sum_visits: bool,
+}
+
+/// Start compacting documents that share a normalized URL
 #[utoipa::path(
     post,
-    path = "/api/history/query",
+    path = "/api/history/compact",
     tag = "history",
-    request_body = UrlQueryRequest,
+    params(
+        ("sumVisits" = Option<bool>, Query, description = "Sum visit counts into the kept document")
+    ),
     responses(
-        (status = 200, description = "Query results"),
-        (status = 400, description = "Invalid request data"),
+        (status = 202, description = "Compaction job started"),
+        (status = 409, description = "A compaction job is already running")
+    )
+)]
+#[post("/api/history/compact")]
+async fn compact_history(
+    request_id: RequestId,
+    query: web::Query<CompactQuery>,
+    es_client: web::Data<Arc<Elasticsearch>>,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    tracing::info!(REQUEST = "compact_history", sum_visits = query.sum_visits);
+
+    let started = app_state.compaction_jobs.start(
+        es_client.get_ref().clone(),
+        app_state.config.elasticsearch.index.clone(),
+        query.sum_visits,
+    ).await;
+
+    match started {
+        Some(progress) => HttpResponse::Accepted().json(json!({
+            "status": "success",
+            "message": "Compaction started",
+            "data": progress
+        })),
+        None => ApiError::conflict("A compaction job is already running")
+            .with_field("data", json!(app_state.compaction_jobs.progress().await))
+            .response(&request_id),
+    }
+}
+
+/// Get the progress of the current or last compaction job
+#[utoipa::path(
+    get,
+    path = "/api/history/compact",
+    tag = "history",
+    responses(
+        (status = 200, description = "Compaction progress"),
+        (status = 404, description = "No compaction job has been started")
+    )
+)]
+#[get("/api/history/compact")]
+async fn compaction_progress(
+    request_id: RequestId,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    match app_state.compaction_jobs.progress().await {
+        Some(progress) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "data": progress
+        })),
+        None => ApiError::not_found("No compaction job has been started").response(&request_id),
+    }
+}
+
+/// Delete history records older than the configured retention period
+#[utoipa::path(
+    post,
+    path = "/api/history/retention/purge",
+    tag = "history",
+    responses(
+        (status = 200, description = "Expired records deleted"),
+        (status = 400, description = "No retention period is configured"),
         (status = 500, description = "Internal server error")
     )
 )]
-#[post("/api/history/query")]
-async fn query_history_by_urls(
-    request: web::Json<UrlQueryRequest>,
+#[post("/api/history/retention/purge")]
+async fn purge_expired_history(
+    request_id: RequestId,
     es_client: web::Data<Arc<Elasticsearch>>,
     app_state: web::Data<Arc<AppState>>,
 ) -> impl Responder {
-    tracing::info!(REQUEST = "query_history_by_urls", request = ?request);
-    
-    // 收集所有需要查询的URL
-    let mut original_urls = Vec::new();
-    
-    if let Some(url) = &request.url {
-        original_urls.push(url.clone());
+    let retention = &app_state.config.retention;
+    let max_age_days = match retention.max_age_days {
+        Some(days) => days,
+        None => {
+            return ApiError::bad_request("No retention period is configured (retention.max_age_days)").response(&request_id);
+        }
+    };
+
+    tracing::info!(REQUEST = "purge_expired_history", max_age_days, strategy = ?retention.strategy);
+
+    match retention::purge_expired(&es_client, &app_state.config.elasticsearch.index, max_age_days, retention).await {
+        Ok(outcome) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "data": outcome
+        })),
+        Err(e) => {
+            tracing::error!(error = %e, "Retention purge failed");
+            ApiError::internal("Retention purge failed").response(&request_id)
+        }
     }
-    
-    if let Some(urls) = &request.urls {
-        original_urls.extend(urls.clone());
+}
+
+// 按域名删除的查询参数
+#[derive(Debug, Deserialize)]
+struct DomainPurgeQuery {
+    #[serde(default)]
+    confirm: bool,
+    #[serde(rename = "includeSubdomains", default)]
+    include_subdomains: bool,
+}
+
+/// 校验要删除的域名：不能为空，也不能包含通配符（包含子域名时会拼入 wildcard 查询）
+fn check_purge_domain(domain: &str) -> Result<(), String> {
+    if domain.trim().is_empty() {
+        return Err("Domain must not be empty".to_string());
     }
-    
-    if original_urls.is_empty() {
-        return HttpResponse::BadRequest().json(json!({
-            "status": "error",
-            "message": "No URLs provided for query"
-        }));
+    if domain.contains(['*', '?']) {
+        return Err(format!("Domain must not contain wildcards: {}", domain));
     }
-    
-    // 归一化所有URL
-    let mut url_mapping = std::collections::HashMap::new();
-    let mut normalized_urls = Vec::new();
-    
-    for original_url in &original_urls {
-        let normalized = app_state.url_normalizer.normalize_url(original_url).await;
-        url_mapping.insert(normalized.clone(), original_url.clone());
-        normalized_urls.push(normalized);
+    Ok(())
+}
+
+/// Delete all history records for one domain
+#[utoipa::path(
+    delete,
+    path = "/api/history/domain/{domain}",
+    tag = "history",
+    params(
+        ("domain" = String, Path, description = "Exact domain to delete"),
+        ("confirm" = bool, Query, description = "Must be true, guards against accidental deletion"),
+        ("includeSubdomains" = Option<bool>, Query, description = "Also delete records of all subdomains")
+    ),
+    responses(
+        (status = 200, description = "Records deleted, returns the deleted count"),
+        (status = 400, description = "Missing confirmation or invalid domain"),
+        (status = 500, description = "Internal server error")
+    )
+)]
+#[delete("/api/history/domain/{domain}")]
+async fn purge_domain_history(
+    request_id: RequestId,
+    path: web::Path<String>,
+    query: web::Query<DomainPurgeQuery>,
+    es_client: web::Data<Arc<Elasticsearch>>,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    let domain = path.into_inner();
+
+    if let Err(message) = check_purge_domain(&domain) {
+        return ApiError::bad_request(message).response(&request_id);
     }
-    
-    // 查询ES
-    match es::search_history_by_normalized_urls(&es_client, &app_state.config.elasticsearch.index, normalized_urls).await {
-        Ok(results) => {
-            // 将结果映射回原始URL
-            let mut response_data = std::collections::HashMap::new();
-            
-            for (normalized_url, record) in results {
-                if let Some(original_url) = url_mapping.get(&normalized_url) {
-                    response_data.insert(original_url.clone(), record);
-                }
-            }
-            
+    if !query.confirm {
+        return ApiError::bad_request("Deleting all records of a domain requires confirm=true").response(&request_id);
+    }
+
+    match retention::purge_domain(&es_client, &app_state.config.elasticsearch.index, &domain, query.include_subdomains, &app_state.config.retention).await {
+        Ok(outcome) => {
+            // 审计日志：记录谁的请求删除了哪个域名的多少条记录
+            tracing::warn!(
+                AUDIT = "purge_domain",
+                request_id = %request_id,
+                domain = %domain,
+                include_subdomains = query.include_subdomains,
+                deleted = outcome.deleted,
+                failed = outcome.failed,
+                "Deleted history records for domain"
+            );
             HttpResponse::Ok().json(json!({
                 "status": "success",
-                "data": response_data,
-                "total": response_data.len()
+                "data": outcome
             }))
         }
         Err(e) => {
-            tracing::error!(error = %e, "Failed to query history by URLs");
-            HttpResponse::InternalServerError().json(json!({
-                "status": "error",
-                "message": "Failed to query history"
-            }))
+            tracing::error!(error = %e, domain = %domain, "Domain purge failed");
+            ApiError::internal("Domain purge failed").response(&request_id)
+        }
+    }
+}
+
+/// Normalize and deduplicate records before a bulk import
+#[utoipa::path(
+    post,
+    path = "/api/history/prepare-import",
+    tag = "history",
+    request_body = Vec<HistoryRequest>,
+    responses(
+        (status = 200, description = "Normalized and deduplicated records, nothing is stored"),
+        (status = 400, description = "Invalid request data")
+    )
+)]
+#[post("/api/history/prepare-import")]
+async fn prepare_import(
+    request: web::Json<Vec<HistoryRequest>>,
+    app_state: web::Data<Arc<AppState>>,
+) -> impl Responder {
+    tracing::info!(REQUEST = "prepare_import", count = request.len());
+
+    let mut prepared = Vec::with_capacity(request.len());
+    let mut rejected = Vec::new();
+
+    for (index, record) in request.iter().enumerate() {
+        let original_url = match record.resolve_url() {
+            Ok(url) => url,
+            Err(reason) => {
+                rejected.push(RejectedImportRecord { index, reason });
+                continue;
+            }
+        };
+
+        let visited_at = match chrono::DateTime::parse_from_rfc3339(&record.timestamp) {
+            Ok(visited_at) => visited_at.with_timezone(&chrono::Utc),
+            Err(e) => {
+                rejected.push(RejectedImportRecord {
+                    index,
+                    reason: format!("Invalid timestamp '{}': {}", record.timestamp, e),
+                });
+                continue;
+            }
+        };
+
+        let normalized_url = record.normalized_url(original_url, &app_state.url_normalizer)
+            .await
+            .unwrap_or_else(|| original_url.to_string());
+        prepared.push(PreparedImportRecord {
+            original_url: original_url.to_string(),
+            normalized_url,
+            timestamp: record.timestamp.clone(),
+            domain: record.domain.clone(),
+            favicon_url: record.valid_favicon_url().map(str::to_string),
+            duplicates: 1,
+            visited_at,
+        });
+    }
+
+    let records = dedup_import_records(prepared);
+
+    HttpResponse::Ok().json(json!({
+        "status": "success",
+        "data": records,
+        "total": records.len(),
+        "input_total": request.len(),
+        "rejected": rejected
+    }))
+}
+
+// 连接数据库并初始化表，失败时按 connect_retry_delay 退避重试，最多 connect_attempts 次
+async fn connect_database(config: &DatabaseConfig) -> Result<DatabaseService, sqlx::Error> {
+    let attempts = config.connect_attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        let result = async {
+            let db = DatabaseService::new(&config.url).await?;
+            tracing::info!("✓ Database connected: {}", redact_url(&config.url));
+            db.init_tables().await?;
+            tracing::info!("✓ Database tables initialized");
+            Ok::<_, sqlx::Error>(db)
+        }
+        .await;
+
+        match result {
+            Ok(db) => return Ok(db),
+            Err(e) if attempt < attempts => {
+                let delay = config.connect_retry_delay(attempt);
+                tracing::warn!(
+                    "✗ Database not ready (attempt {}/{}): {}, retrying in {:?}",
+                    attempt, attempts, e, delay
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
         }
     }
 }
@@ -393,36 +2626,82 @@ async fn main() -> std::io::Result<()> {
     let config = Arc::new(AppConfig::new().expect("Failed to load config"));
     
     // 创建 ES 客户端
-    let es_client = Arc::new(create_es_client(&config.elasticsearch).await);
-    
-    // 创建数据库服务
-    let database = match DatabaseService::new(&config.database.url).await {
-        Ok(db) => {
-            tracing::info!("✓ Database connected: {}", config.database.url);
-            
-            // 初始化数据库表
-            if let Err(e) = db.init_tables().await {
-                tracing::error!("✗ Failed to initialize database tables: {}", e);
-                panic!("Failed to initialize database tables: {}", e);
+    let es_client = Arc::new(create_es_client(&config.elasticsearch.url).await);
+    // 未配置只读副本时搜索也使用写入客户端
+    let es_read_client = match &config.elasticsearch.read_url {
+        Some(read_url) => {
+            tracing::info!("✓ Elasticsearch read replica: {}", redact_url(read_url));
+            es::ReadClient(Arc::new(create_es_client(read_url).await))
+        }
+        None => es::ReadClient(es_client.clone()),
+    };
+
+    // 校验索引映射，防止动态映射把timestamp变成text导致范围查询静默失效
+    match es::check_index_mapping(&es_client, &config.elasticsearch.index).await {
+        Ok(problems) if problems.is_empty() => {
+            tracing::info!("✓ Elasticsearch mapping verified: {}", config.elasticsearch.index);
+        }
+        Ok(problems) => {
+            for problem in &problems {
+                tracing::warn!("✗ Elasticsearch mapping problem: {}", problem);
             }
-            tracing::info!("✓ Database tables initialized");
-            
-            Arc::new(db)
+            tracing::warn!("✗ Index {} has an unexpected mapping, searches may return wrong results", config.elasticsearch.index);
+        }
+        Err(e) => {
+            tracing::warn!("✗ Failed to verify Elasticsearch mapping: {}", e);
         }
+    }
+    
+    // resource_type、url_path 需要是 keyword 才能按类型和路径前缀过滤，字段已存在时忽略错误
+    if let Err(e) = es::ensure_keyword_mappings(&es_client, &config.elasticsearch.index).await {
+        tracing::warn!("✗ Failed to add resource_type/url_path mappings (they may already exist): {}", e);
+    }
+
+    // 低峰期回收已删除文档占用的空间
+    if config.optimizer.enabled {
+        optimizer::spawn_scheduler(es_client.clone(), config.elasticsearch.index.clone(), &config.optimizer);
+        tracing::info!("✓ Index optimizer scheduled daily at {:02}:00 UTC", config.optimizer.hour_utc.min(23));
+    }
+
+    // 创建数据库服务，数据库尚未就绪时按配置退避重试
+    let database = match connect_database(&config.database).await {
+        Ok(db) => Arc::new(db),
         Err(e) => {
-            tracing::error!("✗ Database connection failed: {}", e);
-            panic!("Failed to connect to database: {}", e);
+            tracing::error!("✗ Database initialization failed after {} attempts: {}", config.database.connect_attempts.max(1), e);
+            panic!("Failed to initialize database: {}", e);
         }
     };
 
     // 创建URL归一化服务
-    let url_normalizer = Arc::new(UrlNormalizer::new(database.clone()));
-    tracing::info!("✓ URL normalizer initialized");
+    let mut url_normalizer = UrlNormalizer::new(database.clone(), config.normalization.rule_match)
+        .with_failure_log(config.normalization.record_failures)
+        .with_max_output_length(config.normalization.max_output_length)
+        .with_output_url_validation(config.normalization.validate_output_url);
+    if let Some(threads) = config.server.normalization_threads {
+        let pool = Arc::new(BlockingPool::new("normalize", threads));
+        url_normalizer = url_normalizer.with_blocking_pool(pool, config.normalization.parallel_threshold);
+        tracing::info!("✓ Normalization thread pool started with {} threads", threads);
+    }
+    let url_normalizer = Arc::new(url_normalizer);
+
+    // 启动前加载规则并预编译正则，避免最初的请求承担加载开销；规则无法加载时直接退出
+    match url_normalizer.refresh_rules_cache().await {
+        Ok(broken_rules) => {
+            for broken in &broken_rules {
+                tracing::warn!("✗ Normalization rule {} failed to compile and will be skipped: {}", broken.rule_id, broken.error);
+            }
+            tracing::info!("✓ URL normalizer initialized ({} broken rules)", broken_rules.len());
+        }
+        Err(e) => {
+            tracing::error!("✗ Failed to load normalization rules: {}", e);
+            panic!("Failed to load normalization rules: {}", e);
+        }
+    }
 
     // 尝试创建缓存客户端 - 默认启用，如果Redis不可用则自动跳过
     let cache_client: Option<Box<dyn Cache>> = match RedisCache::new(&config.cache.redis_url).await {
         Ok(redis_cache) => {
-            tracing::info!("✓ Redis cache enabled: {}", config.cache.redis_url);
+            tracing::info!("✓ Redis cache enabled: {}", redact_url(&config.cache.redis_url));
             Some(Box::new(redis_cache))
         }
         Err(e) => {
@@ -431,55 +2710,494 @@ async fn main() -> std::io::Result<()> {
         }
     };
 
+    // 规则变更时通知其他实例刷新规则缓存，Redis 不可用时只依赖缓存过期
+    let rule_invalidator = if config.normalization.sync_rules_across_instances && cache_client.is_some() {
+        match RuleInvalidator::new(&config.cache.redis_url, &config.normalization.rule_invalidation_channel) {
+            Ok(invalidator) => {
+                invalidator.spawn_subscriber(url_normalizer.clone());
+                Some(invalidator)
+            }
+            Err(e) => {
+                tracing::error!("✗ Cross-instance rule invalidation disabled: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // 定期禁用已过期的规则
+    rule_expiry::spawn_sweeper(
+        database.clone(),
+        url_normalizer.clone(),
+        rule_invalidator.clone(),
+        std::time::Duration::from_secs(config.normalization.expiry_sweep_interval_seconds.max(1)),
+    );
+
+    // 可选：加载公共后缀列表，用于记录站点（eTLD+1）
+    let site_resolver = config.ingest.public_suffix_list.as_deref().map(|path| {
+        match SiteResolver::load(path) {
+            Ok(resolver) => {
+                tracing::info!("✓ Public suffix list loaded: {}", path);
+                Arc::new(resolver)
+            }
+            Err(e) => {
+                tracing::error!("✗ Failed to load public suffix list {}: {}", path, e);
+                panic!("Failed to load public suffix list {}: {}", path, e);
+            }
+        }
+    });
+
     // 创建应用状态
     let app_state = Arc::new(AppState {
         config: config.clone(),
         cache: cache_client,
         database,
         url_normalizer,
+        compaction_jobs: CompactionJobs::default(),
+        search_coalescer: RequestCoalescer::default(),
+        es_bulkhead: EsBulkhead::new(config.server.max_concurrent_es_requests),
+        site_resolver,
+        rule_invalidator,
     });
     
     tracing::info!("✓ AppState created successfully");
     tracing::info!("✓ AppState has cache: {}", app_state.cache.is_some());
+
+    // 可选：从消息队列消费上报记录
+    if let Some(queue_config) = &config.ingest_queue {
+        ingest_queue::spawn_consumer(queue_config.clone(), queue_message_handler(es_client.clone(), app_state.clone()));
+        tracing::info!(
+            "✓ Ingest queue consumer started: stream {} on {}",
+            queue_config.stream, redact_url(&queue_config.url)
+        );
+    }
     
     // 生成API文档
     let openapi = ApiDoc::openapi();
 
     tracing::info!("Starting server on {}:{}", config.server.host, config.server.port);
-    tracing::info!("Elasticsearch URL: {}", config.elasticsearch.url);
+    tracing::info!("Elasticsearch URL: {}", redact_url(&config.elasticsearch.url));
+    tracing::info!(
+        "Elasticsearch index: {} (prefix: {})",
+        config.elasticsearch.index,
+        config.elasticsearch.index_prefix.as_deref().unwrap_or("none")
+    );
     if app_state.cache.is_some() {
         tracing::info!("Cache TTL: {} seconds", config.cache.ttl_seconds);
     }
+    let disabled_features = config.features.disabled();
+    if !disabled_features.is_empty() {
+        tracing::info!("Disabled features: {}", disabled_features.join(", "));
+    }
 
-    HttpServer::new(move || {
-        let cors = Cors::default()
-            .allow_any_origin()
-            .allow_any_method()
-            .allow_any_header()
-            .max_age(3600);
+    let server = HttpServer::new(move || {
+        let cors = build_cors(&app_state.config.cors);
 
         App::new()
             .wrap(cors)
             .wrap(tracing_actix_web::TracingLogger::default())  // tracing中间件
             .app_data(web::Data::new(es_client.clone()))
+            .app_data(web::Data::new(es_read_client.clone()))
             .app_data(web::Data::new(app_state.clone()))
+            .app_data(web::JsonConfig::default().error_handler(extractor_error))
+            .app_data(web::QueryConfig::default().error_handler(extractor_error))
+            .app_data(web::PathConfig::default().error_handler(extractor_error))
             .service(
                 SwaggerUi::new("/swagger-ui/{_:.*}")
                     .url("/api-docs/openapi.json", openapi.clone()),
             )
             .service(health)
+            .service(ready)
+            .service(version)
+            .service(get_capabilities)
             .service(search_history)
+            .service(recent_domains)
+            .service(history_sources)
+            .service(browsing_gaps)
+            .service(compare_periods)
+            .service(export_history)
+            .service(bulk_update_tags)
             .service(report_history)
             .service(query_history_by_urls)
+            .service(history_exists)
+            .service(query_all_visits_by_urls)
+            .service(report_history_bulk)
             // 规则管理API
             .service(normalization::get_rules)
             .service(normalization::create_rule)
             .service(normalization::update_rule)
             .service(normalization::delete_rule)
+            .service(normalization::toggle_category)
+            .service(normalization::export_rules)
+            .service(normalization::import_rules)
             .service(normalization::test_rule)
+            .service(normalization::test_rule_on_recent)
+            .service(normalization::simulate_reorder)
+            .service(normalization::validate_rules)
+            .service(normalization::detect_conflicts)
+            .service(normalization::detect_conflicts_in_urls)
             .service(normalization::refresh_cache)
-    })
-    .bind((config.server.host.as_str(), config.server.port))?
-    .run()
-    .await
+            .service(normalization::get_cache_stats)
+            .service(normalization::get_audit_log)
+            .service(normalization::get_failures)
+            .configure(|cfg| configure_optional_services(cfg, &app_state.config.features))
+            .default_service(web::route().to(route_not_found))
+    });
+
+    // 容器中看到的CPU数可能远多于实际配额，允许显式指定工作线程数
+    let server = match config.server.workers.filter(|&workers| workers > 0) {
+        Some(workers) => {
+            tracing::info!("HTTP workers: {}", workers);
+            server.workers(workers)
+        }
+        None => server,
+    };
+
+    server
+        .bind((config.server.host.as_str(), config.server.port))?
+        .run()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prepared_record(original_url: &str, normalized_url: &str, timestamp: &str) -> PreparedImportRecord {
+        PreparedImportRecord {
+            original_url: original_url.to_string(),
+            normalized_url: normalized_url.to_string(),
+            timestamp: timestamp.to_string(),
+            domain: "example.com".to_string(),
+            favicon_url: None,
+            duplicates: 1,
+            visited_at: chrono::DateTime::parse_from_rfc3339(timestamp).unwrap().with_timezone(&chrono::Utc),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_unknown_route_returns_json_404() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .service(version)
+                .default_service(web::route().to(route_not_found)),
+        ).await;
+
+        let request = actix_web::test::TestRequest::get().uri("/api/does-not-exist").to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let body: serde_json::Value = actix_web::test::read_body_json(response).await;
+        assert_eq!(body["error"]["code"], "not_found");
+        assert_eq!(body["error"]["message"], "No route for GET /api/does-not-exist");
+    }
+
+    #[test]
+    fn test_capabilities_reflect_config() {
+        let app_state = test_app_state();
+        let capabilities = capabilities(&app_state.config);
+
+        assert_eq!(capabilities["features"]["timeline"], true);
+        // 默认配置没有API密钥，管理接口不可用
+        assert_eq!(capabilities["features"]["admin"], false);
+        assert_eq!(capabilities["ingest"]["document_ids"], json!(app_state.config.ingest.document_ids));
+        assert_eq!(capabilities["limits"]["max_batch_size"], app_state.config.query.max_batch_size);
+        assert_eq!(capabilities["limits"]["max_page_size"], es::MAX_PAGE_SIZE);
+        assert_eq!(capabilities["auth"]["required"], false);
+    }
+
+    fn test_app_state() -> Arc<AppState> {
+        let config: AppConfig = ::config::Config::builder()
+            .add_source(::config::File::with_name("config/default"))
+            .build()
+            .and_then(|config| config.try_deserialize())
+            .expect("Failed to load default config");
+        let database = Arc::new(DatabaseService::lazy(&config.database.url));
+        let es_bulkhead = EsBulkhead::new(config.server.max_concurrent_es_requests);
+
+        Arc::new(AppState {
+            config: Arc::new(config),
+            cache: None,
+            url_normalizer: Arc::new(UrlNormalizer::new(database.clone(), crate::config::RuleMatchMode::FirstMatch)),
+            database,
+            compaction_jobs: CompactionJobs::default(),
+            search_coalescer: RequestCoalescer::default(),
+            es_bulkhead,
+            site_resolver: None,
+            rule_invalidator: None,
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_search_failure_returns_error_envelope() {
+        // 没有服务监听的端口，ES请求立即失败
+        let es_client = Arc::new(Elasticsearch::new(Transport::single_node("http://127.0.0.1:9").unwrap()));
+        let app = actix_web::test::init_service(
+            App::new()
+                .wrap(tracing_actix_web::TracingLogger::default())
+                .app_data(web::Data::new(es::ReadClient(es_client)))
+                .app_data(web::Data::new(test_app_state()))
+                .service(search_history),
+        ).await;
+
+        let request = actix_web::test::TestRequest::get().uri("/api/history?keyword=rust").to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // 错误响应不能带有 items/total，否则客户端会把失败当作空结果
+        let body: serde_json::Value = actix_web::test::read_body_json(response).await;
+        assert_eq!(body["error"]["code"], "internal_error");
+        assert!(body["error"]["request_id"].is_string());
+        assert!(body.get("items").is_none());
+        assert!(body.get("total").is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_search_rejected_when_es_bulkhead_saturated() {
+        let es_client = Arc::new(Elasticsearch::new(Transport::single_node("http://127.0.0.1:9").unwrap()));
+        let app_state = test_app_state();
+        let _held: Vec<_> = std::iter::from_fn(|| app_state.es_bulkhead.try_acquire()).collect();
+        let app = actix_web::test::init_service(
+            App::new()
+                .wrap(tracing_actix_web::TracingLogger::default())
+                .app_data(web::Data::new(es::ReadClient(es_client)))
+                .app_data(web::Data::new(app_state))
+                .service(search_history),
+        ).await;
+
+        let request = actix_web::test::TestRequest::get().uri("/api/history?keyword=rust").to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get("Retry-After").unwrap(), "1");
+
+        let body: serde_json::Value = actix_web::test::read_body_json(response).await;
+        assert_eq!(body["error"]["code"], "service_unavailable");
+    }
+
+    #[test]
+    fn test_export_query_requires_supported_format() {
+        let query: ExportQuery = serde_json::from_value(json!({ "format": "xlsx", "domain": "example.com" })).unwrap();
+        assert!(matches!(query.format, ExportFormat::Xlsx));
+        assert!(serde_json::from_value::<ExportQuery>(json!({ "format": "csv" })).is_err());
+        assert!(serde_json::from_value::<ExportQuery>(json!({})).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_bulk_tags_requires_filter() {
+        let es_client = Arc::new(Elasticsearch::new(Transport::single_node("http://127.0.0.1:9").unwrap()));
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(es_client))
+                .app_data(web::Data::new(test_app_state()))
+                .service(bulk_update_tags),
+        ).await;
+
+        let request = actix_web::test::TestRequest::post()
+            .uri("/api/history/tags/bulk")
+            .set_json(json!({ "add": ["research"] }))
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body: serde_json::Value = actix_web::test::read_body_json(response).await;
+        assert_eq!(body["error"]["message"], "At least one filter is required");
+    }
+
+    fn history_request(body: serde_json::Value) -> HistoryRequest {
+        serde_json::from_value(body).expect("Failed to deserialize HistoryRequest")
+    }
+
+    #[test]
+    fn test_resolve_url_accepts_either_field() {
+        let legacy = history_request(json!({
+            "url": "https://example.com/a",
+            "timestamp": "2024-03-19T10:30:00Z",
+            "domain": "example.com"
+        }));
+        assert_eq!(legacy.resolve_url(), Ok("https://example.com/a"));
+
+        let current = history_request(json!({
+            "original_url": "https://example.com/b",
+            "timestamp": "2024-03-19T10:30:00Z",
+            "domain": "example.com"
+        }));
+        assert_eq!(current.resolve_url(), Ok("https://example.com/b"));
+    }
+
+    #[test]
+    fn test_resolve_url_both_fields() {
+        let same = history_request(json!({
+            "url": "https://example.com/a",
+            "original_url": "https://example.com/a",
+            "timestamp": "2024-03-19T10:30:00Z",
+            "domain": "example.com"
+        }));
+        assert_eq!(same.resolve_url(), Ok("https://example.com/a"));
+
+        let conflicting = history_request(json!({
+            "url": "https://example.com/a",
+            "original_url": "https://example.com/b",
+            "timestamp": "2024-03-19T10:30:00Z",
+            "domain": "example.com"
+        }));
+        assert!(conflicting.resolve_url().is_err());
+
+        let missing = history_request(json!({
+            "timestamp": "2024-03-19T10:30:00Z",
+            "domain": "example.com"
+        }));
+        assert!(missing.resolve_url().is_err());
+    }
+
+    #[test]
+    fn test_dedup_import_records_keeps_latest() {
+        let records = vec![
+            prepared_record("https://example.com/video/1?a", "https://example.com/video/1", "2024-03-19T10:00:00Z"),
+            prepared_record("https://example.com/other", "https://example.com/other", "2024-03-19T09:00:00Z"),
+            prepared_record("https://example.com/video/1?b", "https://example.com/video/1", "2024-03-19T12:00:00+02:00"),
+            prepared_record("https://example.com/video/1?c", "https://example.com/video/1", "2024-03-19T11:00:00Z"),
+        ];
+
+        let deduped = dedup_import_records(records);
+
+        assert_eq!(deduped.len(), 2);
+        assert_eq!(deduped[0].normalized_url, "https://example.com/video/1");
+        assert_eq!(deduped[0].original_url, "https://example.com/video/1?c");
+        assert_eq!(deduped[0].duplicates, 3);
+        assert_eq!(deduped[1].original_url, "https://example.com/other");
+        assert_eq!(deduped[1].duplicates, 1);
+    }
+
+    #[test]
+    fn test_valid_canonical_url() {
+        let valid = history_request(json!({
+            "original_url": "https://example.com/a?utm_source=x",
+            "canonical_url": "https://example.com/a",
+            "timestamp": "2024-03-19T10:30:00Z",
+            "domain": "example.com"
+        }));
+        assert_eq!(valid.valid_canonical_url(), Some("https://example.com/a"));
+
+        for invalid in ["/a", "javascript:alert(1)", "not a url"] {
+            let request = history_request(json!({
+                "original_url": "https://example.com/a",
+                "canonical_url": invalid,
+                "timestamp": "2024-03-19T10:30:00Z",
+                "domain": "example.com"
+            }));
+            assert_eq!(request.valid_canonical_url(), None, "{} should be rejected", invalid);
+        }
+    }
+
+    #[test]
+    fn test_check_batch_size_boundary() {
+        assert!(check_batch_size(1, 1000).is_ok());
+        assert!(check_batch_size(1000, 1000).is_ok());
+        assert!(check_batch_size(1001, 1000).is_err());
+    }
+
+    #[test]
+    fn test_normalized_by_original() {
+        let url_mapping = std::collections::HashMap::from([(
+            "https://example.com/a".to_string(),
+            vec!["https://example.com/a?utm_source=x".to_string(), "https://EXAMPLE.com/a".to_string()],
+        )]);
+
+        let normalized = normalized_by_original(&url_mapping);
+        assert_eq!(normalized.len(), 2);
+        assert_eq!(normalized["https://example.com/a?utm_source=x"], "https://example.com/a");
+        assert_eq!(normalized["https://EXAMPLE.com/a"], "https://example.com/a");
+    }
+
+    #[test]
+    fn test_check_url_characters_rejects_control_characters() {
+        for url in ["https://example.com/a\nb", "https://example.com/\0", "https://exa\rmple.com/"] {
+            assert!(check_url_characters(url, MalformedUrlAction::Reject).is_err(), "{:?} should be rejected", url);
+        }
+        assert!(check_url_characters("not a url", MalformedUrlAction::Reject).is_err());
+        assert_eq!(
+            check_url_characters("https://example.com/page", MalformedUrlAction::Reject).unwrap(),
+            "https://example.com/page"
+        );
+    }
+
+    #[test]
+    fn test_check_url_characters_sanitizes_control_characters() {
+        assert_eq!(
+            check_url_characters("https://example.com/a\nb\0", MalformedUrlAction::Sanitize).unwrap(),
+            "https://example.com/ab"
+        );
+        assert!(check_url_characters("\0\n", MalformedUrlAction::Sanitize).is_err());
+    }
+
+    #[test]
+    fn test_resource_type_defaults_to_document() {
+        let request = |resource_type: serde_json::Value| history_request(serde_json::json!({
+            "original_url": "https://example.com/page",
+            "timestamp": "2024-03-19T10:30:00Z",
+            "domain": "example.com",
+            "resource_type": resource_type
+        }));
+
+        assert_eq!(request(json!("download")).resource_type(), "download");
+        assert_eq!(request(json!(" Image ")).resource_type(), "image");
+        assert_eq!(request(json!(null)).resource_type(), "document");
+        assert_eq!(request(json!("")).resource_type(), "document");
+        assert_eq!(request(json!("not a type")).resource_type(), "document");
+    }
+
+    #[test]
+    fn test_source_is_trimmed_and_validated() {
+        let request = |source: serde_json::Value| history_request(serde_json::json!({
+            "original_url": "https://example.com/page",
+            "timestamp": "2024-03-19T10:30:00Z",
+            "domain": "example.com",
+            "source": source
+        }));
+
+        assert_eq!(request(json!(" work-laptop ")).source(), Some("work-laptop"));
+        assert_eq!(request(json!(null)).source(), None);
+        assert_eq!(request(json!("  ")).source(), None);
+        assert_eq!(request(json!("a".repeat(MAX_SOURCE_LEN + 1))).source(), None);
+        assert_eq!(request(json!("phone\n")).source(), Some("phone"));
+        assert_eq!(request(json!("ph\u{0}one")).source(), None);
+    }
+
+    #[test]
+    fn test_valid_favicon_url() {
+        let request = |favicon_url: &str| history_request(serde_json::json!({
+            "original_url": "https://example.com/page",
+            "timestamp": "2024-03-19T10:30:00Z",
+            "domain": "example.com",
+            "favicon_url": favicon_url
+        }));
+
+        assert_eq!(request("https://example.com/favicon.ico").valid_favicon_url(), Some("https://example.com/favicon.ico"));
+        assert_eq!(request("data:image/png;base64,iVBORw0KGgo=").valid_favicon_url(), Some("data:image/png;base64,iVBORw0KGgo="));
+        assert_eq!(request("javascript:alert(1)").valid_favicon_url(), None);
+        assert_eq!(request("/favicon.ico").valid_favicon_url(), None);
+        assert_eq!(request("").valid_favicon_url(), None);
+    }
+
+    #[test]
+    fn test_check_purge_domain() {
+        assert!(check_purge_domain("example.com").is_ok());
+        assert!(check_purge_domain("").is_err());
+        assert!(check_purge_domain("  ").is_err());
+        assert!(check_purge_domain("*.com").is_err());
+        assert!(check_purge_domain("exam?le.com").is_err());
+    }
+
+    #[test]
+    fn test_check_url_scheme() {
+        let allowed = vec!["http".to_string(), "https".to_string()];
+
+        assert!(check_url_scheme("https://example.com/", &allowed).is_ok());
+        assert!(check_url_scheme("HTTP://example.com/", &allowed).is_ok());
+
+        for disallowed in ["chrome://settings", "about:blank", "file:///etc/passwd", "javascript:void(0)", "example.com"] {
+            assert!(check_url_scheme(disallowed, &allowed).is_err(), "{} should be rejected", disallowed);
+        }
+    }
 }
@@ -0,0 +1,105 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use tokio::sync::oneshot;
+use tracing::error;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// 线程池任务失败
+#[derive(Debug, thiserror::Error)]
+pub enum BlockingPoolError {
+    #[error("blocking pool job panicked or the pool was shut down")]
+    JobFailed,
+}
+
+/// 固定大小的专用线程池，用于CPU密集的同步任务（如批量正则归一化）
+/// 与 Actix 工作线程和 tokio 的 blocking 线程池隔离，重负载时不会挤占请求处理
+pub struct BlockingPool {
+    sender: mpsc::Sender<Job>,
+    threads: usize,
+}
+
+impl BlockingPool {
+    pub fn new(name: &str, threads: usize) -> Self {
+        let threads = threads.max(1);
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for index in 0..threads {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("{}-{}", name, index))
+                .spawn(move || loop {
+                    // 只在取任务时持有锁，任务本身并行执行
+                    let job = match receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_) => break,
+                    };
+                    match job {
+                        Ok(job) => {
+                            // 单个任务panic不应让线程退出，否则线程池会逐渐缩小
+                            if catch_unwind(AssertUnwindSafe(job)).is_err() {
+                                error!("Blocking pool job panicked");
+                            }
+                        }
+                        // 线程池已释放
+                        Err(_) => break,
+                    }
+                })
+                .expect("failed to spawn blocking pool thread");
+        }
+
+        Self { sender, threads }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// 在线程池中执行任务并等待结果
+    pub async fn run<F, T>(&self, job: F) -> Result<T, BlockingPoolError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (result_sender, result_receiver) = oneshot::channel();
+        self.sender
+            .send(Box::new(move || {
+                let _ = result_sender.send(job());
+            }))
+            .map_err(|_| BlockingPoolError::JobFailed)?;
+
+        result_receiver.await.map_err(|_| BlockingPoolError::JobFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_runs_jobs_on_pool_threads() {
+        let pool = BlockingPool::new("test-pool", 2);
+
+        let name = pool.run(|| thread::current().name().map(str::to_string)).await.unwrap();
+        assert!(name.unwrap().starts_with("test-pool-"));
+
+        let results = futures_util::future::join_all((0..8).map(|i| pool.run(move || i * 2))).await;
+        let results: Vec<i32> = results.into_iter().map(Result::unwrap).collect();
+        assert_eq!(results, vec![0, 2, 4, 6, 8, 10, 12, 14]);
+    }
+
+    #[tokio::test]
+    async fn test_panicking_job_does_not_shrink_pool() {
+        let pool = BlockingPool::new("test-pool", 1);
+
+        let failed = pool.run(|| -> i32 { panic!("boom") }).await;
+        assert!(failed.is_err());
+
+        // 唯一的线程仍然可用
+        assert_eq!(pool.run(|| 42).await.unwrap(), 42);
+    }
+}
@@ -0,0 +1,61 @@
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// 限制同时发往 ES 的搜索和聚合查询数量（隔板）
+///
+/// 达到上限时不排队等待，由调用方直接返回503，避免突发的昂贵查询压垮 ES。
+/// 与按IP限流相互独立。
+#[derive(Clone)]
+pub struct EsBulkhead {
+    permits: Arc<Semaphore>,
+    limit: usize,
+}
+
+impl EsBulkhead {
+    /// 上限至少为1
+    pub fn new(limit: usize) -> Self {
+        let limit = limit.max(1);
+        Self {
+            permits: Arc::new(Semaphore::new(limit)),
+            limit,
+        }
+    }
+
+    /// 获取一个查询名额，已满时返回 None；名额在返回值被丢弃时归还
+    pub fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        self.permits.clone().try_acquire_owned().ok()
+    }
+
+    /// 当前正在执行的查询数量
+    pub fn in_use(&self) -> usize {
+        self.limit - self.permits.available_permits()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejects_when_saturated() {
+        let bulkhead = EsBulkhead::new(2);
+
+        let first = bulkhead.try_acquire();
+        let second = bulkhead.try_acquire();
+        assert!(first.is_some() && second.is_some());
+        assert!(bulkhead.try_acquire().is_none());
+        assert_eq!(bulkhead.in_use(), 2);
+
+        drop(first);
+        assert_eq!(bulkhead.in_use(), 1);
+        assert!(bulkhead.try_acquire().is_some());
+    }
+
+    #[test]
+    fn test_zero_limit_allows_one_request() {
+        let bulkhead = EsBulkhead::new(0);
+        let permit = bulkhead.try_acquire();
+        assert!(permit.is_some());
+        assert!(bulkhead.try_acquire().is_none());
+    }
+}
@@ -77,6 +77,7 @@ pub struct CacheKeyGenerator;
 
 impl CacheKeyGenerator {
     /// 为历史搜索生成缓存键 - 基于查询的URL
+    /// `extra_params` 为影响结果的其他查询参数，按给定顺序拼接
     pub fn history_search_key(
         keyword: &Option<String>,
         domain: &Option<String>,
@@ -84,6 +85,7 @@ impl CacheKeyGenerator {
         end_date: &Option<String>,
         page: i32,
         page_size: i32,
+        extra_params: &[(&str, String)],
     ) -> String {
         let keyword = keyword.as_ref().map(|s| s.as_str()).unwrap_or("");
         let domain = domain.as_ref().map(|s| s.as_str()).unwrap_or("");
@@ -106,6 +108,9 @@ impl CacheKeyGenerator {
         }
         query_parts.push(format!("page={}", page));
         query_parts.push(format!("pageSize={}", page_size));
+        for (name, value) in extra_params {
+            query_parts.push(format!("{}={}", name, value));
+        }
         
         let query_url = if query_parts.is_empty() {
             "/api/history".to_string()
@@ -117,6 +122,12 @@ impl CacheKeyGenerator {
         format!("history:url:{:x}", Self::hash_string(&query_url))
     }
     
+    /// 为时间段对比生成缓存键
+    pub fn history_compare_key(period_a: &str, period_b: &str, limit: usize) -> String {
+        let query_url = format!("/api/history/compare?periodA={}&periodB={}&limit={}", period_a, period_b, limit);
+        format!("history:compare:{:x}", Self::hash_string(&query_url))
+    }
+
     /// 计算字符串的简单哈希值
     fn hash_string(s: &str) -> u64 {
         use std::collections::hash_map::DefaultHasher;
@@ -141,11 +152,20 @@ mod tests {
             &Some("2024-12-31".to_string()),
             1,
             30,
+            &[],
         );
         
         assert_eq!(key, "history:search:test:example.com:2024-01-01:2024-12-31:1:30");
     }
 
+    #[test]
+    fn test_compare_cache_key_depends_on_order() {
+        let key = CacheKeyGenerator::history_compare_key("now-60d..now-30d", "now-30d..now", 10);
+        assert!(key.starts_with("history:compare:"));
+        assert_eq!(key, CacheKeyGenerator::history_compare_key("now-60d..now-30d", "now-30d..now", 10));
+        assert_ne!(key, CacheKeyGenerator::history_compare_key("now-30d..now", "now-60d..now-30d", 10));
+    }
+
     #[test]
     fn test_cache_key_generation_with_none_values() {
         let key = CacheKeyGenerator::history_search_key(
@@ -155,6 +175,7 @@ mod tests {
             &None,
             1,
             30,
+            &[],
         );
         
         assert_eq!(key, "history:search:::::1:30");
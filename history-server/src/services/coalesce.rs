@@ -0,0 +1,104 @@
+use futures_util::future::{BoxFuture, FutureExt, Shared};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+/// 合并后的查询结果，错误以字符串形式共享给所有等待者
+pub type CoalescedResult = Result<Value, String>;
+
+type InFlight = Shared<BoxFuture<'static, CoalescedResult>>;
+
+/// 合并相同的并发查询（缓存击穿保护）
+///
+/// 同一个 key 的第一个请求负责执行查询，查询完成前到达的相同请求
+/// 直接等待它的结果，而不是各自访问 ES。
+#[derive(Clone, Default)]
+pub struct RequestCoalescer {
+    in_flight: Arc<Mutex<HashMap<String, InFlight>>>,
+}
+
+impl RequestCoalescer {
+    /// 执行或加入 key 对应的查询，返回结果以及当前请求是否加入了已有查询
+    pub async fn run<F, Fut>(&self, key: &str, query: F) -> (CoalescedResult, bool)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = CoalescedResult> + Send + 'static,
+    {
+        let (shared, joined) = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(key) {
+                Some(existing) => (existing.clone(), true),
+                None => {
+                    // 查询完成后由查询本身移除 key，之后的请求会重新查询（或命中缓存）
+                    let registry = self.in_flight.clone();
+                    let owned_key = key.to_string();
+                    let fut = query();
+                    let shared = async move {
+                        let result = fut.await;
+                        registry.lock().unwrap().remove(&owned_key);
+                        result
+                    }
+                    .boxed()
+                    .shared();
+                    in_flight.insert(key.to_string(), shared.clone());
+                    (shared, false)
+                }
+            }
+        };
+
+        (shared.await, joined)
+    }
+
+    /// 当前正在执行的查询数量
+    pub fn in_flight_count(&self) -> usize {
+        self.in_flight.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_concurrent_identical_queries_run_once() {
+        let coalescer = RequestCoalescer::default();
+        let executions = Arc::new(AtomicUsize::new(0));
+
+        let make_query = || {
+            let executions = executions.clone();
+            move || async move {
+                executions.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok(json!({ "items": [] }))
+            }
+        };
+
+        let (first, second) = tokio::join!(
+            coalescer.run("key", make_query()),
+            coalescer.run("key", make_query()),
+        );
+
+        assert_eq!(executions.load(Ordering::SeqCst), 1);
+        assert_eq!(first.0, second.0);
+        assert!(!first.1);
+        assert!(second.1);
+        assert_eq!(coalescer.in_flight_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_shared_and_cleared() {
+        let coalescer = RequestCoalescer::default();
+
+        let (result, _) = coalescer.run("key", || async { Err("boom".to_string()) }).await;
+        assert_eq!(result, Err("boom".to_string()));
+        assert_eq!(coalescer.in_flight_count(), 0);
+
+        let (result, joined) = coalescer.run("key", || async { Ok(json!(1)) }).await;
+        assert_eq!(result, Ok(json!(1)));
+        assert!(!joined);
+    }
+}
@@ -0,0 +1,201 @@
+use chrono::{DateTime, Utc};
+use elasticsearch::{BulkOperation, Elasticsearch};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::services::es;
+
+/// 每页处理的归一化URL数量
+const COMPOSITE_PAGE_SIZE: usize = 500;
+/// 单个归一化URL最多处理的文档数（ES默认的最大结果窗口）
+const MAX_DOCUMENTS_PER_URL: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompactionStatus {
+    Running,
+    Completed,
+    Failed,
+}
+
+/// 压缩任务进度
+#[derive(Debug, Clone, Serialize)]
+pub struct CompactionProgress {
+    pub job_id: String,
+    pub status: CompactionStatus,
+    pub sum_visit_counts: bool,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// 已处理的重复归一化URL数量
+    pub duplicate_urls: u64,
+    pub deleted_documents: u64,
+    pub failed_operations: u64,
+    pub error: Option<String>,
+}
+
+/// 同一时间只允许一个压缩任务，保存最近一次任务的进度
+#[derive(Clone, Default)]
+pub struct CompactionJobs {
+    current: Arc<Mutex<Option<CompactionProgress>>>,
+}
+
+impl CompactionJobs {
+    /// 启动后台压缩任务，已有任务在运行时返回 None
+    pub async fn start(
+        &self,
+        es_client: Arc<Elasticsearch>,
+        index: String,
+        sum_visit_counts: bool,
+    ) -> Option<CompactionProgress> {
+        let mut current = self.current.lock().await;
+
+        if matches!(current.as_ref(), Some(progress) if progress.status == CompactionStatus::Running) {
+            return None;
+        }
+
+        let progress = CompactionProgress {
+            job_id: uuid::Uuid::new_v4().to_string(),
+            status: CompactionStatus::Running,
+            sum_visit_counts,
+            started_at: Utc::now(),
+            finished_at: None,
+            duplicate_urls: 0,
+            deleted_documents: 0,
+            failed_operations: 0,
+            error: None,
+        };
+        *current = Some(progress.clone());
+
+        let jobs = self.clone();
+        tokio::spawn(async move {
+            let result = jobs.run(&es_client, &index, sum_visit_counts).await;
+
+            let mut current = jobs.current.lock().await;
+            if let Some(progress) = current.as_mut() {
+                progress.finished_at = Some(Utc::now());
+                match result {
+                    Ok(()) => {
+                        progress.status = CompactionStatus::Completed;
+                        tracing::info!(
+                            "Compaction {} completed: {} duplicate URLs, {} documents deleted",
+                            progress.job_id, progress.duplicate_urls, progress.deleted_documents
+                        );
+                    }
+                    Err(e) => {
+                        tracing::error!("Compaction {} failed: {}", progress.job_id, e);
+                        progress.status = CompactionStatus::Failed;
+                        progress.error = Some(e.to_string());
+                    }
+                }
+            }
+        });
+
+        Some(progress)
+    }
+
+    /// 获取当前（或最近一次）任务的进度
+    pub async fn progress(&self) -> Option<CompactionProgress> {
+        self.current.lock().await.clone()
+    }
+
+    /// 遍历所有重复的归一化URL：保留最新文档，删除其余文档，可选地把访问次数累加到保留的文档上
+    async fn run(
+        &self,
+        es_client: &Elasticsearch,
+        index: &str,
+        sum_visit_counts: bool,
+    ) -> Result<(), elasticsearch::Error> {
+        let mut after = None;
+
+        loop {
+            let (duplicates, after_key) = es::find_duplicate_normalized_urls(es_client, index, after, COMPOSITE_PAGE_SIZE).await?;
+
+            for (normalized_url, _) in duplicates {
+                let hits = es::get_documents_by_normalized_url(es_client, index, &normalized_url, MAX_DOCUMENTS_PER_URL).await?;
+                let (operations, deletes) = compaction_operations(&hits, sum_visit_counts);
+                let failed = es::bulk_operations(es_client, index, operations).await?;
+                // 删除操作在前，只统计实际成功的删除
+                let deleted = (deletes - failed.iter().filter(|&&position| position < deletes).count()) as u64;
+                let failed = failed.len() as u64;
+
+                let mut current = self.current.lock().await;
+                if let Some(progress) = current.as_mut() {
+                    progress.duplicate_urls += 1;
+                    progress.deleted_documents += deleted;
+                    progress.failed_operations += failed;
+                }
+            }
+
+            match after_key {
+                Some(key) => after = Some(key),
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// 为一组同归一化URL的文档（按时间倒序）生成 `_bulk` 操作：删除除第一个外的所有文档，
+/// 累加访问次数时更新保留文档的 visit_count（没有该字段的文档按1次计）
+/// 返回操作和其中删除操作的数量，删除操作排在前面
+fn compaction_operations(hits: &[Value], sum_visit_counts: bool) -> (Vec<BulkOperation<Value>>, usize) {
+    let Some((latest, rest)) = hits.split_first() else {
+        return (Vec::new(), 0);
+    };
+
+    let mut operations: Vec<BulkOperation<Value>> = rest
+        .iter()
+        .filter_map(|hit| {
+            let id = hit["_id"].as_str()?;
+            let index = hit["_index"].as_str()?;
+            Some(BulkOperation::delete(id).index(index).into())
+        })
+        .collect();
+    let deletes = operations.len();
+
+    if sum_visit_counts && !rest.is_empty() {
+        let visit_count: u64 = hits
+            .iter()
+            .map(|hit| hit["_source"]["visit_count"].as_u64().unwrap_or(1))
+            .sum();
+
+        if let (Some(id), Some(index)) = (latest["_id"].as_str(), latest["_index"].as_str()) {
+            operations.push(
+                BulkOperation::update(id, json!({ "doc": { "visit_count": visit_count } }))
+                    .index(index)
+                    .into(),
+            );
+        }
+    }
+
+    (operations, deletes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit(id: &str, visit_count: Option<u64>) -> Value {
+        let mut source = json!({ "normalized_url": "https://example.com/video/1" });
+        if let Some(visit_count) = visit_count {
+            source["visit_count"] = json!(visit_count);
+        }
+        json!({ "_id": id, "_index": "browser-history", "_source": source })
+    }
+
+    #[test]
+    fn test_compaction_operations() {
+        let hits = vec![hit("latest", None), hit("older", Some(3)), hit("oldest", None)];
+
+        let (operations, deletes) = compaction_operations(&hits, false);
+        assert_eq!((operations.len(), deletes), (2, 2));
+        let (operations, deletes) = compaction_operations(&hits, true);
+        assert_eq!((operations.len(), deletes), (3, 2));
+        assert_eq!(compaction_operations(&hits[..1], true).1, 0);
+        assert!(compaction_operations(&hits[..1], true).0.is_empty());
+        assert!(compaction_operations(&[], true).0.is_empty());
+    }
+}
@@ -0,0 +1,212 @@
+use elasticsearch::{Elasticsearch, Error as ElasticsearchError, ScrollParts, SearchParts};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures_util::stream::{self, Stream};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::services::scroll::ScrollGuard;
+
+/// 每次滚动读取的文档数
+const DUMP_SCROLL_SIZE: usize = 1000;
+const DUMP_SCROLL_KEEP_ALIVE: &str = "2m";
+
+/// 导出流的压缩方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DumpCompression {
+    Gzip,
+}
+
+/// 按页写出 NDJSON，启用压缩时经过 gzip 编码器（边读边压缩，不缓冲整个导出）
+enum Encoder {
+    Plain,
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(compression: Option<DumpCompression>) -> Self {
+        match compression {
+            Some(DumpCompression::Gzip) => Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+            None => Encoder::Plain,
+        }
+    }
+
+    /// 返回这一页可以发送的字节，压缩时可能为空（数据还在编码器的缓冲区中）
+    fn encode(&mut self, ndjson: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoder::Plain => Ok(ndjson),
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(&ndjson)?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+        }
+    }
+
+    /// 结束编码，返回剩余的压缩数据和 gzip 尾部
+    fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoder::Plain => Ok(Vec::new()),
+            Encoder::Gzip(encoder) => encoder.finish(),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DumpError {
+    #[error("Elasticsearch error: {0}")]
+    Elasticsearch(#[from] ElasticsearchError),
+    #[error("Compression error: {0}")]
+    Compression(#[from] std::io::Error),
+}
+
+struct DumpState {
+    client: Arc<Elasticsearch>,
+    index: String,
+    // 流被丢弃（读完、出错或客户端断开）时释放滚动上下文
+    scroll: ScrollGuard,
+    encoder: Option<Encoder>,
+    started: bool,
+}
+
+/// 用滚动查询读出整个索引，逐页转换为 NDJSON（每行 `{ "_id", "_source" }`）并按需压缩
+pub fn ndjson_stream(
+    client: Arc<Elasticsearch>,
+    index: String,
+    compression: Option<DumpCompression>,
+) -> impl Stream<Item = Result<Vec<u8>, DumpError>> {
+    let state = DumpState {
+        scroll: ScrollGuard::new(&client),
+        client,
+        index,
+        encoder: Some(Encoder::new(compression)),
+        started: false,
+    };
+
+    stream::try_unfold(state, next_chunk)
+}
+
+async fn next_chunk(mut state: DumpState) -> Result<Option<(Vec<u8>, DumpState)>, DumpError> {
+    loop {
+        // 编码器已经结束，导出完成
+        let Some(encoder) = state.encoder.as_mut() else {
+            return Ok(None);
+        };
+
+        let page = if !state.started {
+            state.started = true;
+            Some(first_page(&state.client, &state.index).await?)
+        } else {
+            match state.scroll.scroll_id() {
+                Some(scroll_id) => {
+                    let scroll_id = scroll_id.to_string();
+                    Some(next_page(&state.client, &scroll_id).await?)
+                }
+                None => None,
+            }
+        };
+        let hits = match &page {
+            Some(page) => {
+                state.scroll.track(page);
+                page["hits"]["hits"].as_array().cloned().unwrap_or_default()
+            }
+            None => Vec::new(),
+        };
+
+        if hits.is_empty() {
+            let tail = state.encoder.take().map(Encoder::finish).transpose()?.unwrap_or_default();
+            if tail.is_empty() {
+                return Ok(None);
+            }
+            return Ok(Some((tail, state)));
+        }
+
+        let chunk = encoder.encode(ndjson_lines(&hits))?;
+        if !chunk.is_empty() {
+            return Ok(Some((chunk, state)));
+        }
+    }
+}
+
+async fn first_page(client: &Elasticsearch, index: &str) -> Result<Value, ElasticsearchError> {
+    client
+        .search(SearchParts::Index(&[index]))
+        .scroll(DUMP_SCROLL_KEEP_ALIVE)
+        .body(json!({
+            "query": { "match_all": {} },
+            "size": DUMP_SCROLL_SIZE,
+            // 按索引顺序读取，滚动整个索引时最快
+            "sort": ["_doc"]
+        }))
+        .send()
+        .await?
+        .error_for_status_code()?
+        .json::<Value>()
+        .await
+}
+
+async fn next_page(client: &Elasticsearch, scroll_id: &str) -> Result<Value, ElasticsearchError> {
+    client
+        .scroll(ScrollParts::None)
+        .body(json!({ "scroll": DUMP_SCROLL_KEEP_ALIVE, "scroll_id": scroll_id }))
+        .send()
+        .await?
+        .error_for_status_code()?
+        .json::<Value>()
+        .await
+}
+
+/// 每个文档一行 `{ "_id", "_source" }`
+fn ndjson_lines(hits: &[Value]) -> Vec<u8> {
+    let mut lines = Vec::new();
+    for hit in hits {
+        let line = json!({ "_id": hit["_id"], "_source": hit["_source"] });
+        lines.extend_from_slice(line.to_string().as_bytes());
+        lines.push(b'\n');
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_ndjson_lines() {
+        let hits = vec![
+            json!({ "_id": "a", "_index": "history", "_source": { "domain": "example.com" } }),
+            json!({ "_id": "b", "_index": "history", "_source": { "domain": "example.org" } }),
+        ];
+
+        let lines = String::from_utf8(ndjson_lines(&hits)).unwrap();
+        assert_eq!(
+            lines,
+            "{\"_id\":\"a\",\"_source\":{\"domain\":\"example.com\"}}\n{\"_id\":\"b\",\"_source\":{\"domain\":\"example.org\"}}\n"
+        );
+    }
+
+    #[test]
+    fn test_gzip_encoder_streams_valid_gzip() {
+        let mut encoder = Encoder::new(Some(DumpCompression::Gzip));
+        let mut compressed = Vec::new();
+        for page in ["{\"_id\":\"a\"}\n", "{\"_id\":\"b\"}\n"] {
+            compressed.extend(encoder.encode(page.as_bytes().to_vec()).unwrap());
+        }
+        compressed.extend(encoder.finish().unwrap());
+
+        let mut decompressed = String::new();
+        GzDecoder::new(compressed.as_slice()).read_to_string(&mut decompressed).unwrap();
+        assert_eq!(decompressed, "{\"_id\":\"a\"}\n{\"_id\":\"b\"}\n");
+    }
+
+    #[test]
+    fn test_plain_encoder_passes_through() {
+        let mut encoder = Encoder::new(None);
+        assert_eq!(encoder.encode(b"line\n".to_vec()).unwrap(), b"line\n");
+        assert!(encoder.finish().unwrap().is_empty());
+    }
+}
@@ -1,12 +1,388 @@
 use elasticsearch::{
     Elasticsearch,
     SearchParts,
+    CountParts,
+    UpdateByQueryParts,
+    params::Conflicts,
     Error as ElasticsearchError,
     IndexParts,
+    indices::{IndicesGetMappingParts, IndicesPutMappingParts},
+    cat::CatIndicesParts,
+    BulkOperation,
+    BulkParts,
+    MsearchParts,
+    ScrollParts,
+    http::request::JsonBody,
 };
+use serde::{Deserialize, Serialize};
 use tracing::info;
 use serde_json::{json, Value};
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use chrono::{DateTime, Datelike, Duration, Months, NaiveDate, NaiveDateTime, SecondsFormat, Timelike, Utc};
+
+use crate::services::query_builder::{NormalizationStatus, QueryBuilder};
+use crate::services::scroll::ScrollGuard;
+
+/// 校验日期过滤值：以 `now` 开头的按ES日期数学表达式校验（如 `now-7d`、`now-1M/d`），
+/// 其他值视为绝对日期原样交给ES解析
+pub fn validate_date_filter(value: &str) -> Result<(), String> {
+    static DATE_MATH: OnceLock<Regex> = OnceLock::new();
+
+    if !is_relative_date(value) {
+        return Ok(());
+    }
+
+    let date_math = DATE_MATH.get_or_init(|| {
+        Regex::new(r"^now([+-]\d+[yMwdhHms])*(/[yMwdhHms])?$").unwrap()
+    });
+
+    if date_math.is_match(value) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid relative date '{}', expected forms like 'now', 'now-7d', 'now-1M' or 'now-1d/d'",
+            value
+        ))
+    }
+}
+
+/// 把日期过滤值解析为UTC时间，用于在发给ES之前检查范围
+/// 支持 RFC 3339、`yyyy-MM-ddTHH:mm:ss`、`yyyy-MM-dd` 和已通过 `validate_date_filter` 的 `now` 表达式（按UTC取整），
+/// 其他ES能识别的格式返回 None，不做检查
+pub fn resolve_date(value: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    static DATE_MATH_OP: OnceLock<Regex> = OnceLock::new();
+
+    let Some(math) = value.strip_prefix("now") else {
+        if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+            return Some(date.with_timezone(&Utc));
+        }
+        if let Ok(date) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S") {
+            return Some(date.and_utc());
+        }
+        return NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?.and_hms_opt(0, 0, 0).map(|date| date.and_utc());
+    };
+
+    let (offsets, rounding) = match math.split_once('/') {
+        Some((offsets, unit)) => (offsets, Some(unit)),
+        None => (math, None),
+    };
+    let op = DATE_MATH_OP.get_or_init(|| Regex::new(r"([+-])(\d+)([yMwdhHms])").unwrap());
+
+    let mut date = now;
+    for captures in op.captures_iter(offsets) {
+        let amount: u32 = captures[2].parse().ok()?;
+        let negative = &captures[1] == "-";
+        date = match &captures[3] {
+            "y" | "M" => {
+                let months = Months::new(if &captures[3] == "y" { amount.checked_mul(12)? } else { amount });
+                if negative { date.checked_sub_months(months)? } else { date.checked_add_months(months)? }
+            }
+            unit => {
+                let seconds = i64::from(amount) * match unit {
+                    "w" => 604_800,
+                    "d" => 86_400,
+                    "h" | "H" => 3_600,
+                    "m" => 60,
+                    _ => 1,
+                };
+                date + Duration::seconds(if negative { -seconds } else { seconds })
+            }
+        };
+    }
+
+    match rounding {
+        Some(unit) => round_down(date, unit),
+        None => Some(date),
+    }
+}
+
+/// 日期数学中的 `/unit`：取整到该单位的开始
+fn round_down(date: DateTime<Utc>, unit: &str) -> Option<DateTime<Utc>> {
+    let day = date.date_naive();
+    let start = match unit {
+        "y" => NaiveDate::from_ymd_opt(day.year(), 1, 1)?.and_hms_opt(0, 0, 0)?,
+        "M" => NaiveDate::from_ymd_opt(day.year(), day.month(), 1)?.and_hms_opt(0, 0, 0)?,
+        "w" => (day - Duration::days(i64::from(day.weekday().num_days_from_monday()))).and_hms_opt(0, 0, 0)?,
+        "d" => day.and_hms_opt(0, 0, 0)?,
+        "h" | "H" => day.and_hms_opt(date.hour(), 0, 0)?,
+        "m" => day.and_hms_opt(date.hour(), date.minute(), 0)?,
+        _ => day.and_hms_opt(date.hour(), date.minute(), date.second())?,
+    };
+    Some(start.and_utc())
+}
+
+/// 是否为相对于当前时间的日期（ES日期数学表达式），结果会随时间变化
+pub fn is_relative_date(value: &str) -> bool {
+    value.starts_with("now")
+}
+
+/// 解析 `start..end` 形式的时间段（两端均可为相对日期，如 `now-60d/d..now-30d/d`）
+pub fn parse_period(value: &str) -> Result<(String, String), String> {
+    let Some((start, end)) = value.split_once("..") else {
+        return Err(format!("Invalid period '{}', expected 'start..end'", value));
+    };
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() || end.is_empty() {
+        return Err(format!("Invalid period '{}', both start and end are required", value));
+    }
+    validate_date_filter(start)?;
+    validate_date_filter(end)?;
+    Ok((start.to_string(), end.to_string()))
+}
+
+/// 搜索单页最多返回的记录数
+pub const MAX_PAGE_SIZE: i32 = 1000;
+
+// 域名分面返回的域名数量
+const FACET_DOMAIN_COUNT: usize = 10;
+
+// 第一页结果少于该数量时才返回"你是不是要找"的建议
+const DID_YOU_MEAN_MAX_HITS: usize = 5;
+
+// 星期缩写，下标 + 1 为 ISO 星期编号（周一为1，周日为7），与 `day_of_week` 字段一致
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+fn weekday_number(name: &str) -> Option<u32> {
+    let name = name.trim().to_lowercase();
+    WEEKDAYS.iter().position(|day| *day == name).map(|position| position as u32 + 1)
+}
+
+/// 解析星期过滤，如 `sat,sun`、`mon-fri`、`fri-mon`（跨周末的区间），返回排序去重后的 ISO 星期编号
+pub fn parse_weekday_filter(spec: &str) -> Result<Vec<u32>, String> {
+    let invalid = || format!(
+        "Invalid weekday filter '{}', expected comma-separated days or ranges such as 'sat,sun' or 'mon-fri' (days: {})",
+        spec, WEEKDAYS.join(", ")
+    );
+
+    let mut days = Vec::new();
+    for part in spec.split(',') {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (weekday_number(start).ok_or_else(invalid)?, weekday_number(end).ok_or_else(invalid)?);
+                let mut day = start;
+                loop {
+                    days.push(day);
+                    if day == end {
+                        break;
+                    }
+                    day = day % 7 + 1;
+                }
+            }
+            None => days.push(weekday_number(part).ok_or_else(invalid)?),
+        }
+    }
+
+    days.sort_unstable();
+    days.dedup();
+    Ok(days)
+}
+
+/// 搜索结果排序方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortBy {
+    /// 按访问时间倒序（默认）
+    #[default]
+    Recent,
+    /// 按 normalized_url 合并，按访问次数倒序，每个URL返回最近一次访问
+    /// 没有 normalized_url 的记录（仅查询时归一化模式）不参与统计
+    Frequency,
+    /// 按相关度倒序，相关度相同时按访问时间倒序；配置了按索引新旧衰减时，较新索引中的记录排在前面
+    Relevance,
+}
+
+/// 按访问次数排序时可以翻到的最深位置（page * pageSize）
+/// 分页在 terms 聚合内完成，聚合需要取出前 page * pageSize 个URL，深翻页开销随页数线性增长
+pub const MAX_FREQUENCY_SORT_DEPTH: i32 = 10_000;
+
+/// ES 默认的 index.max_result_window，from + size 超过时查询会被拒绝
+pub const MAX_RESULT_WINDOW: i32 = 10_000;
+
+/// 检查分页是否在ES的结果窗口内（page * pageSize 即 from + size）
+pub fn validate_result_window(page: i32, page_size: i32) -> Result<(), String> {
+    if page.saturating_mul(page_size) > MAX_RESULT_WINDOW {
+        return Err(format!(
+            "Pagination supports at most the first {} results (page * pageSize)",
+            MAX_RESULT_WINDOW
+        ));
+    }
+    Ok(())
+}
+
+/// 检查分页深度，只限制按访问次数排序
+pub fn validate_page_depth(sort_by: SortBy, page: i32, page_size: i32) -> Result<(), String> {
+    if sort_by == SortBy::Frequency && page.saturating_mul(page_size) > MAX_FREQUENCY_SORT_DEPTH {
+        return Err(format!(
+            "sortBy=frequency supports at most the first {} results (page * pageSize)",
+            MAX_FREQUENCY_SORT_DEPTH
+        ));
+    }
+    Ok(())
+}
+
+// countMode=estimate 时统计总数的上限
+const ESTIMATE_TOTAL_HITS_CAP: i64 = 10000;
+
+/// 搜索结果总数的统计方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CountMode {
+    /// 精确统计（默认），配置了 track_total_hits 上限时统计到上限
+    #[default]
+    Exact,
+    /// 最多统计到 ESTIMATE_TOTAL_HITS_CAP，超出时返回 ">=N"，用于“约 N 条结果”
+    Estimate,
+    /// 不统计总数，`total` 为 null，只需要当前页时最快
+    None,
+}
+
+impl CountMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CountMode::Exact => "exact",
+            CountMode::Estimate => "estimate",
+            CountMode::None => "none",
+        }
+    }
+
+    /// 请求体中的 track_total_hits，`configured` 为配置的全局上限
+    fn track_total_hits(self, configured: Option<i64>) -> Value {
+        match (self, configured) {
+            (CountMode::Exact, Some(limit)) => json!(limit),
+            (CountMode::Exact, None) => json!(true),
+            (CountMode::Estimate, Some(limit)) => json!(limit.min(ESTIMATE_TOTAL_HITS_CAP)),
+            (CountMode::Estimate, None) => json!(ESTIMATE_TOTAL_HITS_CAP),
+            (CountMode::None, _) => json!(false),
+        }
+    }
+}
+
+/// 搜索和聚合使用的只读客户端，配置了 read_url 时指向只读副本，否则与写入客户端相同
+#[derive(Clone)]
+pub struct ReadClient(pub Arc<Elasticsearch>);
+
+impl std::ops::Deref for ReadClient {
+    type Target = Elasticsearch;
+
+    fn deref(&self) -> &Elasticsearch {
+        &self.0
+    }
+}
+
+/// 域名分面的分组方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupBy {
+    /// 按主机名（默认）
+    #[default]
+    Host,
+    /// 按站点（eTLD+1），只统计写入时记录了 registrable_domain 的记录
+    Site,
+}
+
+impl GroupBy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GroupBy::Host => "host",
+            GroupBy::Site => "site",
+        }
+    }
+
+    fn field(self) -> &'static str {
+        match self {
+            GroupBy::Host => "domain",
+            GroupBy::Site => "registrable_domain",
+        }
+    }
+}
+
+/// 自动补全的候选来源，只允许这些字段，避免对任意字段做聚合
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SuggestSource {
+    /// 按域名补全（默认）
+    #[default]
+    Domain,
+    /// 按完整的原始URL补全
+    Url,
+}
+
+impl SuggestSource {
+    /// 前缀查询和聚合使用的字段
+    fn field(self) -> &'static str {
+        match self {
+            SuggestSource::Domain => "domain",
+            SuggestSource::Url => "original_url",
+        }
+    }
+}
+
+/// 搜索的可选项
+#[derive(Debug, Clone, Default)]
+pub struct SearchOptions {
+    /// track_total_hits 的上限，None 时精确统计
+    pub track_total_hits: Option<i64>,
+    /// 是否在响应中附带域名分面（top N 域名与去重域名数）
+    pub include_facets: bool,
+    pub sort_by: SortBy,
+    /// 只返回指定归一化状态的记录
+    pub normalization_status: Option<NormalizationStatus>,
+    /// 按相关度排序且 index 为通配模式时，每个更早的索引的权重乘以该系数
+    pub recency_boost_decay: Option<f64>,
+    /// 最低得分，只在按相关度排序时生效（按时间或访问次数排序时得分没有意义）
+    pub min_score: Option<f64>,
+    /// 原始URL子串匹配，见 `QueryBuilder::url_contains`
+    pub url_contains: Option<String>,
+    /// 只返回这些星期（ISO 编号）的访问记录
+    pub weekdays: Option<Vec<u32>>,
+    /// 只返回这些资源类型的记录
+    pub resource_types: Option<Vec<String>>,
+    /// 只返回路径以此开头的记录，与域名过滤一起使用
+    pub path_prefix: Option<String>,
+    /// 域名分面按主机名还是按站点分组
+    pub group_by: GroupBy,
+    pub count_mode: CountMode,
+    /// 附带 term suggester，结果很少时返回拼写纠正后的关键词
+    pub did_you_mean: bool,
+    /// 域名分面中排除的噪音域名（及其子域名），不影响搜索结果本身
+    pub facet_excluded_domains: Vec<String>,
+    /// 关键词搜索是否包含旧版 `url` 字段，见 `QueryBuilder::keyword`
+    pub legacy_url_field: bool,
+}
+
+/// 根据关键词、域名和时间范围构建历史记录查询，没有任何条件时为 match_all
+fn build_history_query(
+    keyword: Option<String>,
+    domain: Option<String>,
+    start_date: Option<String>,
+    end_date: Option<String>,
+    legacy_url_field: bool,
+) -> QueryBuilder {
+    QueryBuilder::new()
+        .keyword(keyword.as_deref(), legacy_url_field)
+        .domain(domain.as_deref())
+        .timestamp_range(start_date.as_deref(), end_date.as_deref())
+}
+
+/// 搜索条件加上 SearchOptions 中的附加过滤（搜索、导出和批量打标签共用）
+pub fn filtered_history_query(
+    keyword: Option<String>,
+    domain: Option<String>,
+    start_date: Option<String>,
+    end_date: Option<String>,
+    options: &SearchOptions,
+) -> QueryBuilder {
+    build_history_query(keyword, domain, start_date, end_date, options.legacy_url_field)
+        .normalization_status(options.normalization_status)
+        .url_contains(options.url_contains.as_deref())
+        .weekdays(options.weekdays.as_deref())
+        .resource_types(options.resource_types.as_deref())
+        .path_prefix(options.path_prefix.as_deref())
+}
 
 pub async fn search_history(
     client: &Elasticsearch,
@@ -17,224 +393,2644 @@ pub async fn search_history(
     end_date: Option<String>,
     page: Option<i32>,
     page_size: Option<i32>,
+    options: &SearchOptions,
 ) -> Result<Value, ElasticsearchError> {
     let page = page.unwrap_or(1);
-    let page_size = page_size.unwrap_or(30).min(1000);
+    let page_size = page_size.unwrap_or(30).min(MAX_PAGE_SIZE);
     let from = (page - 1) * page_size;
 
-    // 构建查询
-    let mut query = json!({
-        "bool": {
-            "must": []
-        }
-    });
+    let query = filtered_history_query(keyword.clone(), domain, start_date, end_date, options).build();
 
-    let must_array = query["bool"]["must"].as_array_mut().unwrap();
+    // 构建完整的搜索请求，默认track_total_hits为true以获取准确的总数，配置了上限或按请求估算时只统计到上限
+    let track_total_hits = options.count_mode.track_total_hits(options.track_total_hits);
 
-    // 添加关键词搜索
-    if let Some(keyword) = keyword {
-        if !keyword.is_empty() {
-            must_array.push(json!({
-                "multi_match": {
-                    "query": keyword,
-                    "fields": ["url", "domain"],
-                    "type": "phrase_prefix"
+    let mut body = match options.sort_by {
+        SortBy::Relevance => json!({
+            "query": query,
+            "from": from,
+            "size": page_size,
+            "track_total_hits": track_total_hits,
+            "sort": [
+                "_score",
+                { "timestamp": { "order": "desc" } }
+            ]
+        }),
+        SortBy::Recent => json!({
+            "query": query,
+            "from": from,
+            "size": page_size,
+            "track_total_hits": track_total_hits,
+            "sort": [
+                { "timestamp": { "order": "desc" } }
+            ]
+        }),
+        // 按URL分组统计访问次数，分页在聚合内完成
+        SortBy::Frequency => json!({
+            "query": query,
+            "size": 0,
+            "aggs": {
+                "by_url": {
+                    "terms": {
+                        "field": "normalized_url",
+                        "size": (from + page_size).min(MAX_FREQUENCY_SORT_DEPTH),
+                        "order": { "_count": "desc" }
+                    },
+                    "aggs": {
+                        "latest": {
+                            "top_hits": {
+                                "size": 1,
+                                "sort": [{ "timestamp": { "order": "desc" } }]
+                            }
+                        },
+                        "page": {
+                            "bucket_sort": { "from": from, "size": page_size }
+                        }
+                    }
+                },
+                "unique_urls": {
+                    "cardinality": { "field": "normalized_url" }
                 }
-            }));
+            }
+        }),
+    };
+
+    if let (SortBy::Relevance, Some(min_score)) = (options.sort_by, options.min_score) {
+        body["min_score"] = json!(min_score);
+    }
+
+    // 只有按时间滚动的多个索引（通配模式）才需要按新旧加权
+    if let (SortBy::Relevance, Some(decay)) = (options.sort_by, options.recency_boost_decay) {
+        if index.contains('*') {
+            let boosts = recency_index_boosts(resolve_indices_cached(client, index).await?, decay);
+            if !boosts.is_empty() {
+                body["indices_boost"] = Value::Array(boosts);
+            }
         }
     }
 
-    // 添加域名过滤
-    if let Some(domain) = domain {
-        if !domain.is_empty() {
-            must_array.push(json!({
-                "term": {
-                    "domain": domain
-                }
-            }));
+    // 不统计总数时也不需要去重URL数
+    if let (SortBy::Frequency, CountMode::None) = (options.sort_by, options.count_mode) {
+        if let Some(aggs) = body["aggs"].as_object_mut() {
+            aggs.remove("unique_urls");
         }
     }
 
-    // 添加时间范围过滤
-    if start_date.is_some() || end_date.is_some() {
-        let mut range = json!({
-            "range": {
-                "timestamp": {}
+    if options.include_facets {
+        let field = options.group_by.field();
+        let facet_aggs = json!({
+            "domains": {
+                "terms": { "field": field, "size": FACET_DOMAIN_COUNT }
+            },
+            "unique_domains": {
+                "cardinality": { "field": field }
             }
         });
-
-        if let Some(start) = start_date {
-            range["range"]["timestamp"]["gte"] = json!(start);
-        }
-        if let Some(end) = end_date {
-            range["range"]["timestamp"]["lte"] = json!(end);
+        // 有排除列表时分面放在过滤聚合里，只影响分面统计
+        match excluded_domains_filter(&options.facet_excluded_domains) {
+            Some(filter) => {
+                body["aggs"]["facets"] = json!({ "filter": filter, "aggs": facet_aggs });
+            }
+            None => {
+                body["aggs"]["domains"] = facet_aggs["domains"].clone();
+                body["aggs"]["unique_domains"] = facet_aggs["unique_domains"].clone();
+            }
         }
-
-        must_array.push(range);
     }
 
-    // 如果没有任何查询条件，使用 match_all
-    if must_array.is_empty() {
-        query = json!({
-            "match_all": {}
-        });
+    let did_you_mean_keyword = keyword
+        .as_deref()
+        .map(str::trim)
+        .filter(|keyword| options.did_you_mean && from == 0 && !keyword.is_empty())
+        .map(str::to_string);
+    if let Some(keyword) = &did_you_mean_keyword {
+        body["suggest"] = did_you_mean_suggester(keyword);
     }
 
-    // 构建完整的搜索请求,添加track_total_hits确保获取准确的总数
-    let body = json!({
-        "query": query,
-        "from": from,
-        "size": page_size,
-        "track_total_hits": true,
-        "sort": [
-            { "timestamp": { "order": "desc" } }
-        ]
-    });
-
     tracing::info!("ES Query: {}", serde_json::to_string_pretty(&body).unwrap());
 
+    // ES返回错误状态（如索引不存在、查询无效）时响应体里没有 hits，不能当作空结果返回
     let response = client
         .search(SearchParts::Index(&[index]))
         .body(body)
         .send()
-        .await?;
+        .await?
+        .error_for_status_code()?;
 
     let response_body = response.json::<Value>().await?;
     
-    // 从ES响应中提取需要的数据
-    let hits = response_body["hits"]["hits"].as_array()
-        .unwrap_or(&Vec::new())
-        .iter()
-        .map(|hit| hit["_source"].clone())
-        .collect::<Vec<Value>>();
+    // 从ES响应中提取需要的数据，按访问次数排序时总数为去重URL数（cardinality 近似值，relation 为 approx）
+    let (hits, total_value, total_relation) = match options.sort_by {
+        SortBy::Recent | SortBy::Relevance => {
+            let hits = response_body["hits"]["hits"].as_array()
+                .unwrap_or(&Vec::new())
+                .iter()
+                .map(|hit| hit["_source"].clone())
+                .collect::<Vec<Value>>();
 
-    // 获取总记录数    
-    let total = response_body["hits"]["total"]["value"]
-        .as_i64()
-        .unwrap_or(0) as i32;
+            // 获取总记录数，统计被截断时（relation为gte）以 ">=N" 形式返回
+            let total_value = response_body["hits"]["total"]["value"]
+                .as_i64()
+                .unwrap_or(0);
+            (hits, total_value, total_relation(&response_body["hits"]["total"]))
+        }
+        SortBy::Frequency => {
+            let aggregations = &response_body["aggregations"];
+            (
+                parse_frequency_buckets(&aggregations["by_url"]),
+                aggregations["unique_urls"]["value"].as_i64().unwrap_or(0),
+                "approx",
+            )
+        }
+    };
+    let (total, total_relation) = match options.count_mode {
+        CountMode::None => (Value::Null, Value::Null),
+        _ if total_relation == "gte" => (json!(format!(">={}", total_value)), json!(total_relation)),
+        _ => (json!(total_value), json!(total_relation)),
+    };
 
     // 构建新的返回格式    
-    let result = json!({
+    let mut result = json!({
         "items": hits,
         "total": total,
+        "totalRelation": total_relation,
         "page": page,
         "pageSize": page_size
     });
 
+    if options.include_facets {
+        let aggregations = &response_body["aggregations"];
+        let facet_aggregations = if aggregations["facets"].is_object() { &aggregations["facets"] } else { aggregations };
+        result["facets"] = parse_domain_facets(facet_aggregations);
+    }
+
+    if let Some(keyword) = &did_you_mean_keyword {
+        let hit_count = result["items"].as_array().map_or(0, Vec::len);
+        if hit_count < DID_YOU_MEAN_MAX_HITS {
+            if let Some(suggestion) = corrected_keyword(keyword, &response_body["suggest"]["did_you_mean"]) {
+                result["didYouMean"] = json!(suggestion);
+            }
+        }
+    }
+
     Ok(result)
 }
 
-pub async fn insert_history(
-    client: &Elasticsearch,
-    index: &str,
-    original_url: &str,
-    normalized_url: &str,
-    timestamp: &str,
-    domain: &str,
-) -> Result<(), ElasticsearchError> {
-    let doc = json!({
-        "timestamp": timestamp,
-        "original_url": original_url,
-        "normalized_url": normalized_url,
-        "domain": domain
-    });
+/// 对关键词的每个词在 original_url 字段中查找更常见的相近词（只在词不存在或更少见时给出建议）
+/// 新文档不一定有旧版 url 字段，所以使用所有文档都有的 original_url
+fn did_you_mean_suggester(keyword: &str) -> Value {
+    json!({
+        "text": keyword,
+        "did_you_mean": {
+            "term": {
+                "field": "original_url",
+                "suggest_mode": "popular",
+                "min_word_length": 3,
+                "size": 1
+            }
+        }
+    })
+}
 
-    client
-        .index(IndexParts::Index(index))
-        .body(doc)
-        .send()
-        .await?;
+/// 用每个词得分最高的建议替换关键词中对应的部分，没有任何建议时返回 None
+/// ES 返回的 offset/length 以 UTF-16 码元计
+fn corrected_keyword(keyword: &str, entries: &Value) -> Option<String> {
+    let mut replacements: Vec<(usize, usize, &str)> = entries.as_array()?
+        .iter()
+        .filter_map(|entry| {
+            let offset = entry["offset"].as_u64()? as usize;
+            let length = entry["length"].as_u64()? as usize;
+            let option = entry["options"].as_array()?.first()?["text"].as_str()?;
+            Some((offset, length, option))
+        })
+        .collect();
+    if replacements.is_empty() {
+        return None;
+    }
+    replacements.sort_by_key(|(offset, _, _)| *offset);
 
-    Ok(())
+    let units: Vec<u16> = keyword.encode_utf16().collect();
+    let mut corrected = Vec::with_capacity(units.len());
+    let mut position = 0;
+    for (offset, length, option) in replacements {
+        if offset < position || offset + length > units.len() {
+            continue;
+        }
+        corrected.extend_from_slice(&units[position..offset]);
+        corrected.extend(option.encode_utf16());
+        position = offset + length;
+    }
+    corrected.extend_from_slice(&units[position..]);
+
+    let corrected = String::from_utf16(&corrected).ok()?;
+    (corrected != keyword).then_some(corrected)
 }
 
-/// 根据归一化URL查询历史记录（单个URL）
-pub async fn search_history_by_normalized_url(
-    client: &Elasticsearch,
-    index: &str,
-    normalized_url: &str,
-) -> Result<Option<Value>, ElasticsearchError> {
-    let query = json!({
-        "query": {
-            "term": {
-                "normalized_url": normalized_url
-            }
-        },
-        "size": 1,
-        "sort": [
-            { "timestamp": { "order": "desc" } }
-        ]
-    });
+/// 通配模式解析结果的缓存时间：滚动索引按天或按月新建，短时间内列表不变
+const RESOLVED_INDICES_TTL: std::time::Duration = std::time::Duration::from_secs(60);
 
-    tracing::info!("ES Query for normalized URL {}: {}", normalized_url, serde_json::to_string_pretty(&query).unwrap());
+/// 按通配模式缓存解析出的索引列表，过期后重新解析
+struct ResolvedIndices {
+    ttl: std::time::Duration,
+    entries: Mutex<HashMap<String, (Instant, Vec<String>)>>,
+}
+
+impl ResolvedIndices {
+    fn new(ttl: std::time::Duration) -> Self {
+        Self { ttl, entries: Mutex::new(HashMap::new()) }
+    }
+
+    fn get(&self, pattern: &str) -> Option<Vec<String>> {
+        let entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        entries.get(pattern)
+            .filter(|(resolved_at, _)| resolved_at.elapsed() < self.ttl)
+            .map(|(_, indices)| indices.clone())
+    }
+
+    fn insert(&self, pattern: &str, indices: Vec<String>) {
+        let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        entries.insert(pattern.to_string(), (Instant::now(), indices));
+    }
+}
+
+/// 列出匹配通配模式的具体索引名，结果缓存 `RESOLVED_INDICES_TTL`，按相关度排序的搜索不必每次都请求 _cat/indices
+async fn resolve_indices_cached(client: &Elasticsearch, pattern: &str) -> Result<Vec<String>, ElasticsearchError> {
+    static CACHE: OnceLock<ResolvedIndices> = OnceLock::new();
+    let cache = CACHE.get_or_init(|| ResolvedIndices::new(RESOLVED_INDICES_TTL));
+
+    if let Some(indices) = cache.get(pattern) {
+        return Ok(indices);
+    }
+    let indices = resolve_indices(client, pattern).await?;
+    cache.insert(pattern, indices.clone());
+    Ok(indices)
+}
 
+/// 列出匹配通配模式的具体索引名
+async fn resolve_indices(client: &Elasticsearch, pattern: &str) -> Result<Vec<String>, ElasticsearchError> {
     let response = client
-        .search(SearchParts::Index(&[index]))
-        .body(query)
+        .cat()
+        .indices(CatIndicesParts::Index(&[pattern]))
+        .format("json")
+        .h(&["index"])
         .send()
-        .await?;
+        .await?
+        .error_for_status_code()?;
 
-    let response_body = response.json::<Value>().await?;
-    
-    // 提取第一个匹配的文档
-    if let Some(hits) = response_body["hits"]["hits"].as_array() {
-        if let Some(first_hit) = hits.first() {
-            return Ok(Some(first_hit["_source"].clone()));
-        }
-    }
+    let rows = response.json::<Value>().await?;
+    Ok(rows.as_array()
+        .unwrap_or(&Vec::new())
+        .iter()
+        .filter_map(|row| row["index"].as_str().map(str::to_string))
+        .collect())
+}
 
-    Ok(None)
+/// 生成 `indices_boost`：滚动索引名以日期结尾，按名称倒序即从新到旧，
+/// 最新的索引权重为1，之后每个索引乘以 decay（限制在 (0, 1] 内）
+fn recency_index_boosts(mut indices: Vec<String>, decay: f64) -> Vec<Value> {
+    let decay = if decay > 0.0 { decay.min(1.0) } else { 1.0 };
+    indices.sort_unstable_by(|a, b| b.cmp(a));
+
+    let mut boost = 1.0;
+    indices.into_iter()
+        .map(|index| {
+            let entry = json!({ index: boost });
+            boost *= decay;
+            entry
+        })
+        .collect()
 }
 
-/// 批量查询归一化URL的历史记录
-pub async fn search_history_by_normalized_urls(
+/// 将按URL分组的聚合转换为记录列表：每个URL最近一次访问的文档，附带 `visits` 访问次数
+fn parse_frequency_buckets(by_url: &Value) -> Vec<Value> {
+    by_url["buckets"].as_array()
+        .unwrap_or(&Vec::new())
+        .iter()
+        .map(|bucket| {
+            let mut item = bucket["latest"]["hits"]["hits"][0]["_source"].clone();
+            item["visits"] = bucket["doc_count"].clone();
+            item
+        })
+        .collect()
+}
+
+/// 读取 `hits.total.relation`，只有明确为 "gte" 时才视为下限，其余情况为精确值
+fn total_relation(total: &Value) -> &'static str {
+    if total["relation"] == "gte" {
+        "gte"
+    } else {
+        "eq"
+    }
+}
+
+/// 按天统计历史记录数量，可选地附带每天访问最多的域名
+pub async fn history_timeline(
     client: &Elasticsearch,
     index: &str,
-    normalized_urls: Vec<String>,
-) -> Result<HashMap<String, Value>, ElasticsearchError> {
-    if normalized_urls.is_empty() {
-        tracing::info!("No normalized URLs provided");
-        return Ok(HashMap::new());
-    }
+    domain: Option<String>,
+    start_date: Option<String>,
+    end_date: Option<String>,
+    top_domain_per_bucket: bool,
+) -> Result<Value, ElasticsearchError> {
+    let query = build_history_query(None, domain, start_date, end_date, false).build();
 
-    let query = json!({
-        "query": {
-            "terms": {
-                "normalized_url": normalized_urls
-            }
-        },
-        "size": normalized_urls.len(),
-        "sort": [
-            { "timestamp": { "order": "desc" } }
-        ]
+    let mut per_day = json!({
+        "date_histogram": {
+            "field": "timestamp",
+            "calendar_interval": "day",
+            "format": "yyyy-MM-dd",
+            "min_doc_count": 0
+        }
     });
 
-    tracing::info!("ES Query for {} normalized URLs: {}", normalized_urls.len(), serde_json::to_string_pretty(&query).unwrap());
+    if top_domain_per_bucket {
+        per_day["aggs"] = json!({
+            "top_domain": {
+                "terms": { "field": "domain", "size": 1 }
+            }
+        });
+    }
+
+    let body = json!({
+        "query": query,
+        "size": 0,
+        "aggs": { "per_day": per_day }
+    });
 
     let response = client
         .search(SearchParts::Index(&[index]))
-        .body(query)
+        .body(body)
         .send()
-        .await?;
+        .await?
+        .error_for_status_code()?;
 
     let response_body = response.json::<Value>().await?;
-    tracing::info!("ES Response: {}", serde_json::to_string_pretty(&response_body).unwrap());
-    
-    let mut results = HashMap::new();
-    
-    // 提取匹配的文档，按normalized_url分组
-    if let Some(hits) = response_body["hits"]["hits"].as_array() {
-        for hit in hits {
-            if let Some(source) = hit.get("_source") {
-                if let Some(normalized_url) = source["normalized_url"].as_str() {
-                    // 只保留每个normalized_url的最新记录
-                    if !results.contains_key(normalized_url) {
-                        results.insert(normalized_url.to_string(), source.clone());
-                    }
+
+    Ok(json!({
+        "buckets": parse_timeline_buckets(&response_body["aggregations"]["per_day"], top_domain_per_bucket)
+    }))
+}
+
+/// 空档检测的时间粒度
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GapInterval {
+    #[default]
+    Hour,
+    Day,
+}
+
+impl GapInterval {
+    fn calendar_interval(self) -> &'static str {
+        match self {
+            GapInterval::Hour => "hour",
+            GapInterval::Day => "day",
+        }
+    }
+
+    /// 桶长度（毫秒），按UTC分桶时小时和天都是定长的
+    fn millis(self) -> i64 {
+        match self {
+            GapInterval::Hour => 3_600_000,
+            GapInterval::Day => 86_400_000,
+        }
+    }
+}
+
+/// 空档检测最多的桶数，远低于ES的 search.max_buckets（默认65536），范围过大时直接返回400
+pub const MAX_GAP_BUCKETS: i64 = 10_000;
+
+/// 检查空档检测的时间范围：开始不能晚于结束，范围内的桶数不能超过 `MAX_GAP_BUCKETS`
+/// 无法在本地解析的日期格式不检查，交给ES处理
+pub fn validate_gap_range(start_date: &str, end_date: &str, interval: GapInterval, now: DateTime<Utc>) -> Result<(), String> {
+    let (Some(start), Some(end)) = (resolve_date(start_date, now), resolve_date(end_date, now)) else {
+        return Ok(());
+    };
+    if start > end {
+        return Err(format!("startDate ({}) must not be after endDate ({})", start_date, end_date));
+    }
+
+    let buckets = (end - start).num_milliseconds() / interval.millis() + 1;
+    if buckets > MAX_GAP_BUCKETS {
+        return Err(format!(
+            "Range from {} to {} has {} {} buckets, at most {} are allowed; narrow the range or use a larger interval",
+            start_date, end_date, buckets, interval.calendar_interval(), MAX_GAP_BUCKETS
+        ));
+    }
+    Ok(())
+}
+
+/// 找出时间范围内没有任何访问记录的时段，按持续时间从长到短返回最多 `limit` 个
+pub async fn browsing_gaps(
+    client: &Elasticsearch,
+    index: &str,
+    start_date: &str,
+    end_date: &str,
+    interval: GapInterval,
+    limit: usize,
+) -> Result<Value, ElasticsearchError> {
+    let response = client
+        .search(SearchParts::Index(&[index]))
+        .body(gaps_query(start_date, end_date, interval))
+        .send()
+        .await?
+        .error_for_status_code()?;
+
+    let response_body = response.json::<Value>().await?;
+
+    let mut gaps = find_gaps(&response_body["aggregations"]["per_interval"], interval.millis());
+    gaps.truncate(limit);
+
+    Ok(json!({
+        "interval": interval.calendar_interval(),
+        "gaps": gaps
+    }))
+}
+
+/// 在时间范围内按固定粒度分桶，extended_bounds 保证范围两端没有记录的时段也有空桶
+fn gaps_query(start_date: &str, end_date: &str, interval: GapInterval) -> Value {
+    json!({
+        "query": build_history_query(None, None, Some(start_date.to_string()), Some(end_date.to_string()), false).build(),
+        "size": 0,
+        "aggs": {
+            "per_interval": {
+                "date_histogram": {
+                    "field": "timestamp",
+                    "calendar_interval": interval.calendar_interval(),
+                    "min_doc_count": 0,
+                    "extended_bounds": { "min": start_date, "max": end_date }
+                }
+            }
+        }
+    })
+}
+
+/// 把连续的空桶合并为空档 `{ start, end, duration_seconds }`，按持续时间倒序（相同时较早的在前）
+fn find_gaps(per_interval: &Value, interval_millis: i64) -> Vec<Value> {
+    let mut gaps: Vec<(i64, i64)> = Vec::new();
+    // 当前连续空桶的 (起点, 终点)，终点为最后一个空桶的结束时间
+    let mut open: Option<(i64, i64)> = None;
+
+    for bucket in per_interval["buckets"].as_array().unwrap_or(&Vec::new()) {
+        let Some(key) = bucket["key"].as_i64() else { continue };
+        if bucket["doc_count"].as_u64().unwrap_or(0) == 0 {
+            let start = open.map_or(key, |(start, _)| start);
+            open = Some((start, key + interval_millis));
+        } else if let Some(gap) = open.take() {
+            gaps.push(gap);
+        }
+    }
+    gaps.extend(open);
+
+    gaps.sort_by(|a, b| (b.1 - b.0).cmp(&(a.1 - a.0)).then(a.0.cmp(&b.0)));
+    gaps.into_iter()
+        .map(|(start, end)| json!({
+            "start": millis_to_rfc3339(start),
+            "end": millis_to_rfc3339(end),
+            "duration_seconds": (end - start) / 1000
+        }))
+        .collect()
+}
+
+fn millis_to_rfc3339(millis: i64) -> Option<String> {
+    DateTime::from_timestamp_millis(millis).map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+}
+
+// 对比两个时间段时每个时间段参与比较的域名数量（按访问次数取前N个）
+const COMPARE_DOMAIN_CANDIDATES: usize = 500;
+
+/// 对比两个时间段内各域名的访问次数，返回变化最大的 `limit` 个域名
+/// `change` 为时间段B相对A的增减（count_b - count_a）
+///
+/// 先分别取两个时间段的 top 域名作为候选，再对候选的并集在两个时间段内重新精确计数，
+/// 避免只在一侧 top 列表中的域名在另一侧被当作0
+pub async fn compare_periods(
+    client: &Elasticsearch,
+    index: &str,
+    period_a: (&str, &str),
+    period_b: (&str, &str),
+    limit: usize,
+) -> Result<Value, ElasticsearchError> {
+    let candidates = compare_periods_search(client, index, compare_periods_query(period_a, period_b, None)).await?;
+    let candidates = &candidates["aggregations"];
+
+    let domains = candidate_domains(&candidates["period_a"]["domains"], &candidates["period_b"]["domains"]);
+    let counted = if domains.is_empty() {
+        candidates.clone()
+    } else {
+        compare_periods_search(client, index, compare_periods_query(period_a, period_b, Some(&domains))).await?["aggregations"].take()
+    };
+
+    Ok(json!({
+        "period_a": { "start": period_a.0, "end": period_a.1, "total": candidates["period_a"]["doc_count"] },
+        "period_b": { "start": period_b.0, "end": period_b.1, "total": candidates["period_b"]["doc_count"] },
+        "domains": domain_deltas(&counted["period_a"]["domains"], &counted["period_b"]["domains"], limit)
+    }))
+}
+
+async fn compare_periods_search(client: &Elasticsearch, index: &str, body: Value) -> Result<Value, ElasticsearchError> {
+    client
+        .search(SearchParts::Index(&[index]))
+        .body(body)
+        .send()
+        .await?
+        .error_for_status_code()?
+        .json::<Value>()
+        .await
+}
+
+/// 两个时间段各自过滤后按域名分组，一次请求完成
+/// `domains` 为 None 时每个时间段取 top 候选域名，否则只统计这些域名（每个都有计数）
+fn compare_periods_query(period_a: (&str, &str), period_b: (&str, &str), domains: Option<&[String]>) -> Value {
+    let terms = match domains {
+        Some(domains) => json!({ "field": "domain", "size": domains.len(), "include": domains }),
+        None => json!({ "field": "domain", "size": COMPARE_DOMAIN_CANDIDATES }),
+    };
+    let per_period = |(start, end): (&str, &str)| json!({
+        "filter": { "range": { "timestamp": { "gte": start, "lte": end } } },
+        "aggs": {
+            "domains": { "terms": terms }
+        }
+    });
+
+    json!({
+        "size": 0,
+        "aggs": {
+            "period_a": per_period(period_a),
+            "period_b": per_period(period_b)
+        }
+    })
+}
+
+/// 两个时间段候选域名的并集（排序去重）
+fn candidate_domains(domains_a: &Value, domains_b: &Value) -> Vec<String> {
+    let mut domains: Vec<String> = [domains_a, domains_b]
+        .into_iter()
+        .flat_map(|domains| domains["buckets"].as_array().cloned().unwrap_or_default())
+        .filter_map(|bucket| bucket["key"].as_str().map(str::to_string))
+        .collect();
+    domains.sort_unstable();
+    domains.dedup();
+    domains
+}
+
+/// 合并两个时间段的域名桶为 `[{ domain, count_a, count_b, change }]`，
+/// 按变化的绝对值倒序（相同时按域名），在某个时间段没有访问的域名该侧计为0
+fn domain_deltas(domains_a: &Value, domains_b: &Value, limit: usize) -> Vec<Value> {
+    let counts = |domains: &Value| -> HashMap<String, i64> {
+        domains["buckets"].as_array()
+            .unwrap_or(&Vec::new())
+            .iter()
+            .filter_map(|bucket| Some((bucket["key"].as_str()?.to_string(), bucket["doc_count"].as_i64()?)))
+            .collect()
+    };
+    let (counts_a, counts_b) = (counts(domains_a), counts(domains_b));
+
+    let mut domains: Vec<&String> = counts_a.keys().chain(counts_b.keys()).collect();
+    domains.sort_unstable();
+    domains.dedup();
+
+    let mut deltas: Vec<(&String, i64, i64)> = domains.into_iter()
+        .map(|domain| {
+            let count_a = counts_a.get(domain).copied().unwrap_or(0);
+            let count_b = counts_b.get(domain).copied().unwrap_or(0);
+            (domain, count_a, count_b)
+        })
+        .collect();
+    deltas.sort_by(|a, b| (b.2 - b.1).abs().cmp(&(a.2 - a.1).abs()).then(a.0.cmp(b.0)));
+
+    deltas.into_iter()
+        .take(limit)
+        .map(|(domain, count_a, count_b)| json!({
+            "domain": domain,
+            "count_a": count_a,
+            "count_b": count_b,
+            "change": count_b - count_a
+        }))
+        .collect()
+}
+
+/// 将按天聚合转换为 `[{ date, count, top_domain? }]`
+fn parse_timeline_buckets(per_day: &Value, top_domain_per_bucket: bool) -> Vec<Value> {
+    per_day["buckets"].as_array()
+        .unwrap_or(&Vec::new())
+        .iter()
+        .map(|bucket| {
+            let mut entry = json!({
+                "date": bucket["key_as_string"],
+                "count": bucket["doc_count"]
+            });
+            if top_domain_per_bucket {
+                // 没有记录的日期 top_domain 为 null
+                entry["top_domain"] = bucket["top_domain"]["buckets"][0]["key"].clone();
+            }
+            entry
+        })
+        .collect()
+}
+
+/// 排除噪音域名及其子域名的过滤条件，列表为空时返回 None
+fn excluded_domains_filter(domains: &[String]) -> Option<Value> {
+    let domains: Vec<String> = domains
+        .iter()
+        .map(|domain| domain.trim().trim_start_matches("*.").to_lowercase())
+        .filter(|domain| !domain.is_empty())
+        .collect();
+    if domains.is_empty() {
+        return None;
+    }
+
+    let mut must_not = vec![json!({ "terms": { "domain": domains } })];
+    must_not.extend(domains.iter().map(|domain| json!({ "wildcard": { "domain": format!("*.{}", domain) } })));
+    Some(json!({ "bool": { "must_not": must_not } }))
+}
+
+/// 将域名分面聚合转换为 `{ domains: [{domain, count}], unique_domains }`
+fn parse_domain_facets(aggregations: &Value) -> Value {
+    let domains: Vec<Value> = aggregations["domains"]["buckets"].as_array()
+        .unwrap_or(&Vec::new())
+        .iter()
+        .map(|bucket| json!({
+            "domain": bucket["key"],
+            "count": bucket["doc_count"]
+        }))
+        .collect();
+
+    json!({
+        "domains": domains,
+        "unique_domains": aggregations["unique_domains"]["value"].as_u64().unwrap_or(0)
+    })
+}
+
+// pathPrefix 的最大长度
+const MAX_PATH_PREFIX_LEN: usize = 1024;
+
+/// 校验路径前缀过滤：必须配合 domain 使用，以 `/` 开头，不包含查询串或片段
+pub fn validate_path_prefix(path_prefix: &str, domain: Option<&str>) -> Result<(), String> {
+    if !domain.is_some_and(|domain| !domain.trim().is_empty()) {
+        return Err("pathPrefix requires domain".to_string());
+    }
+    if !path_prefix.starts_with('/') {
+        return Err("pathPrefix must start with '/'".to_string());
+    }
+    if path_prefix.len() > MAX_PATH_PREFIX_LEN {
+        return Err(format!("pathPrefix must be at most {} bytes", MAX_PATH_PREFIX_LEN));
+    }
+    if path_prefix.contains(['?', '#']) || path_prefix.chars().any(char::is_control) {
+        return Err("pathPrefix must be a URL path without query or fragment".to_string());
+    }
+    Ok(())
+}
+
+/// 未上报 resource_type 的记录（包括加入该字段之前写入的文档）视为页面访问
+pub const DEFAULT_RESOURCE_TYPE: &str = "document";
+
+/// 资源类型只允许小写字母、数字、`_` 和 `-`，最长32个字符（如 document、image、download、main_frame）
+pub fn is_valid_resource_type(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= 32
+        && value.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_' || b == b'-')
+}
+
+/// 解析资源类型过滤，如 `document` 或 `document,download`，返回去重后的小写类型
+pub fn parse_resource_type_filter(spec: &str) -> Result<Vec<String>, String> {
+    let mut types: Vec<String> = Vec::new();
+    for part in spec.split(',') {
+        let resource_type = part.trim().to_lowercase();
+        if !is_valid_resource_type(&resource_type) {
+            return Err(format!("Invalid resource type '{}'", part.trim()));
+        }
+        if !types.contains(&resource_type) {
+            types.push(resource_type);
+        }
+    }
+    Ok(types)
+}
+
+/// 构建写入ES的历史记录文档，`normalized_url` 为 None 时（仅查询时归一化模式）不写入该字段
+/// 文档只保存 `original_url`/`normalized_url`，旧版的 `url` 字段由调用方按 write_legacy_url_field 配置决定是否写入
+pub fn history_document(
+    original_url: &str,
+    normalized_url: Option<&str>,
+    timestamp: &str,
+    domain: &str,
+    favicon_url: Option<&str>,
+    resource_type: &str,
+    registrable_domain: Option<&str>,
+) -> Value {
+    // 域名统一存为小写，使term过滤不区分大小写
+    let mut doc = json!({
+        "timestamp": timestamp,
+        "original_url": original_url,
+        "domain": domain.to_lowercase(),
+        "resource_type": resource_type
+    });
+
+    if let Some(normalized_url) = normalized_url {
+        doc["normalized_url"] = json!(normalized_url);
+    }
+
+    // 站点（eTLD+1），用于按站点而不是主机名聚合；未配置公共后缀列表时不写入
+    if let Some(registrable_domain) = registrable_domain {
+        doc["registrable_domain"] = json!(registrable_domain);
+    }
+
+    // 路径单独存储，用于按站点子目录（pathPrefix）过滤
+    if let Ok(parsed) = url::Url::parse(original_url) {
+        doc["url_path"] = json!(parsed.path());
+    }
+
+    // 站点图标只随结果返回，不参与搜索
+    if let Some(favicon_url) = favicon_url {
+        doc["favicon_url"] = json!(favicon_url);
+    }
+
+    // 预先计算访问时的星期（按时间戳自带的时区，周一为1），按星期过滤时不需要脚本查询
+    if let Ok(visited_at) = DateTime::parse_from_rfc3339(timestamp) {
+        doc["day_of_week"] = json!(visited_at.weekday().number_from_monday());
+    }
+
+    doc
+}
+
+/// 由文档内容计算确定的文档ID：sha256(归一化URL（没有时为原始URL） + 访问时间 [+ 上报来源])
+/// 同一次访问重复上报时ID相同，写入会覆盖而不是新增；不同设备同时访问同一URL是不同的记录，
+/// 所以有上报来源时也参与计算（没有来源的文档ID与之前保持一致）
+pub fn history_document_id(doc: &Value) -> String {
+    let url = doc["normalized_url"].as_str()
+        .or_else(|| doc["original_url"].as_str())
+        .unwrap_or_default();
+    let timestamp = canonical_timestamp(doc["timestamp"].as_str().unwrap_or_default());
+
+    let mut hasher = Sha256::new();
+    hasher.update(url.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(timestamp.as_bytes());
+    if let Some(source) = doc["source"].as_str() {
+        hasher.update(b"\n");
+        hasher.update(source.as_bytes());
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// 同一时刻的不同写法（时区偏移、小数秒位数）统一为UTC的RFC 3339格式，无法解析时按原文
+fn canonical_timestamp(timestamp: &str) -> String {
+    match DateTime::parse_from_rfc3339(timestamp) {
+        Ok(visited_at) => visited_at.with_timezone(&Utc).to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        Err(_) => timestamp.to_string(),
+    }
+}
+
+/// 写入单条历史记录文档（由 `history_document` 构建），`dedup_by_id` 为 true 时使用 `history_document_id` 作为文档ID
+pub async fn insert_history(
+    client: &Elasticsearch,
+    index: &str,
+    doc: Value,
+    dedup_by_id: bool,
+) -> Result<(), ElasticsearchError> {
+    let id = dedup_by_id.then(|| history_document_id(&doc));
+    let parts = match &id {
+        Some(id) => IndexParts::IndexId(index, id),
+        None => IndexParts::Index(index),
+    };
+
+    // ES拒绝写入（429队列已满、503、映射错误等）时返回错误，不能当作已写入
+    client
+        .index(parts)
+        .body(doc)
+        .send()
+        .await?
+        .error_for_status_code()?;
+
+    Ok(())
+}
+
+/// 批量写入中单条文档的失败信息
+#[derive(Debug, Clone, Serialize)]
+pub struct BulkItemFailure {
+    pub index: usize,
+    pub status: u16,
+    pub reason: String,
+}
+
+/// 批量写入结果
+#[derive(Debug)]
+pub enum BulkInsertOutcome {
+    /// 整个请求被ES拒绝（429队列已满或413请求过大），没有任何文档被写入
+    Rejected { status: u16 },
+    /// 请求已处理：backpressure 为因ES繁忙（429）未写入、可稍后重试的条目下标，failed 为其他失败
+    Completed {
+        backpressure: Vec<usize>,
+        failed: Vec<BulkItemFailure>,
+    },
+}
+
+/// 批量写入失败：整个请求失败，或响应中的条目与发送的文档对不上（不能当作全部写入成功）
+#[derive(Debug, thiserror::Error)]
+pub enum BulkInsertError {
+    #[error("Elasticsearch error: {0}")]
+    Elasticsearch(#[from] ElasticsearchError),
+    #[error("Bulk response has {received} items for {expected} documents")]
+    ItemCountMismatch { expected: usize, received: usize },
+}
+
+/// 使用 `_bulk` 批量写入历史记录文档，下标与 `docs` 的顺序一致
+/// `dedup_by_id` 为 true 时使用 `history_document_id` 作为文档ID
+pub async fn bulk_insert_history(
+    client: &Elasticsearch,
+    index: &str,
+    docs: Vec<Value>,
+    dedup_by_id: bool,
+) -> Result<BulkInsertOutcome, BulkInsertError> {
+    if docs.is_empty() {
+        return Ok(BulkInsertOutcome::Completed { backpressure: Vec::new(), failed: Vec::new() });
+    }
+
+    let body: Vec<BulkOperation<Value>> = docs
+        .into_iter()
+        .map(|doc| {
+            if dedup_by_id {
+                let id = history_document_id(&doc);
+                BulkOperation::index(doc).id(id).into()
+            } else {
+                BulkOperation::index(doc).into()
+            }
+        })
+        .collect();
+
+    let expected = body.len();
+    let response = client
+        .bulk(BulkParts::Index(index))
+        .body(body)
+        .send()
+        .await?;
+
+    let status = response.status_code().as_u16();
+    if status == 429 || status == 413 {
+        tracing::warn!("Bulk insert rejected by Elasticsearch with status {}", status);
+        return Ok(BulkInsertOutcome::Rejected { status });
+    }
+
+    // 其他非 2xx（如 400、401、404、503）整个请求都失败了，响应中没有 items
+    let response_body = response.error_for_status_code()?.json::<Value>().await?;
+    parse_bulk_response(&response_body, expected)
+}
+
+/// 解析 `_bulk` 响应中每个条目的状态，条目缺失或数量与发送的 `expected` 个文档不一致时返回错误
+fn parse_bulk_response(response_body: &Value, expected: usize) -> Result<BulkInsertOutcome, BulkInsertError> {
+    let items = response_body["items"].as_array();
+    let received = items.map_or(0, Vec::len);
+    let Some(items) = items.filter(|_| received == expected) else {
+        return Err(BulkInsertError::ItemCountMismatch { expected, received });
+    };
+
+    let mut backpressure = Vec::new();
+    let mut failed = Vec::new();
+    for (index, item) in items.iter().enumerate() {
+        let result = &item["index"];
+        let status = result["status"].as_u64().unwrap_or(0) as u16;
+
+        if status == 429 {
+            backpressure.push(index);
+        } else if result.get("error").is_some() || !(200..300).contains(&status) {
+            failed.push(BulkItemFailure {
+                index,
+                status,
+                reason: result["error"]["reason"].as_str().unwrap_or("unknown error").to_string(),
+            });
+        }
+    }
+
+    Ok(BulkInsertOutcome::Completed { backpressure, failed })
+}
+
+/// 根据归一化URL查询历史记录（单个URL）
+pub async fn search_history_by_normalized_url(
+    client: &Elasticsearch,
+    index: &str,
+    normalized_url: &str,
+) -> Result<Option<Value>, ElasticsearchError> {
+    let query = json!({
+        "query": {
+            "term": {
+                "normalized_url": normalized_url
+            }
+        },
+        "size": 1,
+        "sort": [
+            { "timestamp": { "order": "desc" } }
+        ]
+    });
+
+    tracing::info!("ES Query for normalized URL {}: {}", normalized_url, serde_json::to_string_pretty(&query).unwrap());
+
+    let response = client
+        .search(SearchParts::Index(&[index]))
+        .body(query)
+        .send()
+        .await?;
+
+    let response_body = response.json::<Value>().await?;
+    
+    // 提取第一个匹配的文档
+    if let Some(hits) = response_body["hits"]["hits"].as_array() {
+        if let Some(first_hit) = hits.first() {
+            return Ok(Some(first_hit["_source"].clone()));
+        }
+    }
+
+    Ok(None)
+}
+
+/// 返回在索引中有记录的归一化URL，只做聚合不返回文档
+/// `legacy_urls` 与 `search_history_by_normalized_urls` 相同：没有 normalized_url 的旧文档按原始URL匹配
+pub async fn existing_normalized_urls(
+    client: &Elasticsearch,
+    index: &str,
+    normalized_urls: &[String],
+    legacy_urls: &HashMap<String, String>,
+) -> Result<std::collections::HashSet<String>, ElasticsearchError> {
+    if normalized_urls.is_empty() {
+        return Ok(std::collections::HashSet::new());
+    }
+
+    let response = client
+        .search(SearchParts::Index(&[index]))
+        .body(existing_urls_query(normalized_urls, legacy_urls))
+        .send()
+        .await?
+        .error_for_status_code()?;
+
+    let response_body = response.json::<Value>().await?;
+    Ok(parse_existing_urls(&response_body["aggregations"], legacy_urls))
+}
+
+/// 归一化URL按 normalized_url 分组；旧文档的原始URL字段类型不确定（不能做terms聚合），每个原始URL用一个命名过滤器计数
+fn existing_urls_query(normalized_urls: &[String], legacy_urls: &HashMap<String, String>) -> Value {
+    let mut body = json!({
+        "query": normalized_url_lookup_query(normalized_urls, legacy_urls),
+        "size": 0,
+        "aggs": {
+            "present": {
+                "terms": { "field": "normalized_url", "size": normalized_urls.len() }
+            }
+        }
+    });
+
+    if !legacy_urls.is_empty() {
+        let filters: serde_json::Map<String, Value> = legacy_urls
+            .keys()
+            .map(|original_url| (original_url.clone(), legacy_url_match(&[original_url])))
+            .collect();
+        body["aggs"]["legacy"] = json!({ "filters": { "filters": filters } });
+    }
+
+    body
+}
+
+fn parse_existing_urls(aggregations: &Value, legacy_urls: &HashMap<String, String>) -> std::collections::HashSet<String> {
+    let mut present: std::collections::HashSet<String> = aggregations["present"]["buckets"].as_array()
+        .unwrap_or(&Vec::new())
+        .iter()
+        .filter_map(|bucket| bucket["key"].as_str().map(str::to_string))
+        .collect();
+
+    if let Some(buckets) = aggregations["legacy"]["buckets"].as_object() {
+        for (original_url, bucket) in buckets {
+            if bucket["doc_count"].as_u64().unwrap_or(0) > 0 {
+                if let Some(normalized_url) = legacy_urls.get(original_url) {
+                    present.insert(normalized_url.clone());
+                }
+            }
+        }
+    }
+
+    present
+}
+
+// 旧文档可能没有 normalized_url，按原始URL回退匹配时使用的字段（旧版文档的URL存于 url）
+const LEGACY_URL_FIELDS: [&str; 2] = ["original_url", "url"];
+
+/// 批量查询归一化URL的历史记录
+/// `source_fields` 为空时返回完整的 `_source`，否则只返回指定字段（始终包含 normalized_url 用于分组）
+/// `legacy_urls` 为原始URL到归一化URL的映射：没有 normalized_url 的旧文档按原始URL精确匹配后归入对应的归一化URL
+pub async fn search_history_by_normalized_urls(
+    client: &Elasticsearch,
+    index: &str,
+    normalized_urls: Vec<String>,
+    legacy_urls: &HashMap<String, String>,
+    source_fields: Option<&[String]>,
+) -> Result<HashMap<String, Value>, ElasticsearchError> {
+    if normalized_urls.is_empty() {
+        tracing::info!("No normalized URLs provided");
+        return Ok(HashMap::new());
+    }
+
+    let mut query = json!({
+        "query": normalized_url_lookup_query(&normalized_urls, legacy_urls),
+        "size": normalized_urls.len() + legacy_urls.len(),
+        "sort": [
+            { "timestamp": { "order": "desc" } }
+        ]
+    });
+
+    if let Some(fields) = source_fields.filter(|fields| !fields.is_empty()) {
+        let mut includes: Vec<&str> = fields.iter().map(String::as_str).collect();
+        for required in std::iter::once("normalized_url").chain(LEGACY_URL_FIELDS) {
+            if !includes.contains(&required) {
+                includes.push(required);
+            }
+        }
+        query["_source"] = json!(includes);
+    }
+
+    tracing::info!("ES Query for {} normalized URLs: {}", normalized_urls.len(), serde_json::to_string_pretty(&query).unwrap());
+
+    let response = client
+        .search(SearchParts::Index(&[index]))
+        .body(query)
+        .send()
+        .await?;
+
+    let response_body = response.json::<Value>().await?;
+    tracing::info!("ES Response: {}", serde_json::to_string_pretty(&response_body).unwrap());
+    
+    let mut results = HashMap::new();
+    
+    // 提取匹配的文档，按normalized_url分组
+    if let Some(hits) = response_body["hits"]["hits"].as_array() {
+        for hit in hits {
+            if let Some(source) = hit.get("_source") {
+                if let Some(normalized_url) = lookup_key(source, legacy_urls) {
+                    // 只保留每个normalized_url的最新记录
+                    if !results.contains_key(normalized_url) {
+                        results.insert(normalized_url.to_string(), source.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(results)
+}
+
+/// 匹配 normalized_url，或对没有 normalized_url 的旧文档匹配原始URL
+fn normalized_url_lookup_query(normalized_urls: &[String], legacy_urls: &HashMap<String, String>) -> Value {
+    let terms = json!({ "terms": { "normalized_url": normalized_urls } });
+    if legacy_urls.is_empty() {
+        return terms;
+    }
+
+    let original_urls: Vec<&String> = legacy_urls.keys().collect();
+    json!({
+        "bool": {
+            "should": [terms, legacy_url_match(&original_urls)],
+            "minimum_should_match": 1
+        }
+    })
+}
+
+/// 没有 normalized_url、原始URL为其中之一的旧文档
+fn legacy_url_match(original_urls: &[&String]) -> Value {
+    // 原始URL字段可能是keyword，也可能是带keyword子字段的text
+    let legacy_matches: Vec<Value> = LEGACY_URL_FIELDS
+        .iter()
+        .flat_map(|field| [field.to_string(), format!("{}.keyword", field)])
+        .map(|field| json!({ "terms": { field: original_urls } }))
+        .collect();
+
+    json!({
+        "bool": {
+            "must_not": [{ "exists": { "field": "normalized_url" } }],
+            "should": legacy_matches,
+            "minimum_should_match": 1
+        }
+    })
+}
+
+/// 文档所属的归一化URL：优先使用 normalized_url，旧文档按原始URL精确查找（排除text字段分词带来的误匹配）
+fn lookup_key<'a>(source: &'a Value, legacy_urls: &'a HashMap<String, String>) -> Option<&'a str> {
+    if let Some(normalized_url) = source["normalized_url"].as_str() {
+        return Some(normalized_url);
+    }
+
+    LEGACY_URL_FIELDS
+        .iter()
+        .filter_map(|field| source[*field].as_str())
+        .find_map(|original_url| legacy_urls.get(original_url).map(String::as_str))
+}
+
+/// _msearch 查询失败：整个请求失败，或其中某个查询返回了错误（不能当作没有结果）
+#[derive(Debug, thiserror::Error)]
+pub enum MsearchError {
+    #[error("Elasticsearch error: {0}")]
+    Elasticsearch(#[from] ElasticsearchError),
+    #[error("Query {position} in multi-search failed: {reason}")]
+    Item { position: usize, reason: String },
+}
+
+/// 取出 _msearch 响应中与请求一一对应的 `expected` 个结果，任何一个查询出错或缺失时返回错误
+fn msearch_responses(response_body: &Value, expected: usize) -> Result<Vec<&Value>, MsearchError> {
+    let empty = Vec::new();
+    let responses = response_body["responses"].as_array().unwrap_or(&empty);
+
+    (0..expected)
+        .map(|position| match responses.get(position) {
+            Some(response) if response["error"].is_null() => Ok(response),
+            Some(response) => Err(MsearchError::Item { position, reason: response["error"].to_string() }),
+            None => Err(MsearchError::Item { position, reason: "missing response".to_string() }),
+        })
+        .collect()
+}
+
+/// 某个归一化URL的一页访问记录
+#[derive(Debug, Clone, Serialize)]
+pub struct VisitPage {
+    pub total: u64,
+    pub items: Vec<Value>,
+}
+
+/// 分页查询每个归一化URL的全部访问记录（按时间倒序），所有URL在一次 _msearch 中查询
+/// `lookups` 为 (归一化URL, 归一化为它的原始URL列表)，原始URL用于匹配没有 normalized_url 的旧文档
+pub async fn search_visits_by_normalized_urls(
+    client: &Elasticsearch,
+    index: &str,
+    lookups: &[(String, Vec<String>)],
+    from: usize,
+    size: usize,
+) -> Result<Vec<VisitPage>, MsearchError> {
+    if lookups.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut body: Vec<JsonBody<Value>> = Vec::with_capacity(lookups.len() * 2);
+    for (normalized_url, original_urls) in lookups {
+        let legacy_urls: HashMap<String, String> = original_urls
+            .iter()
+            .map(|original_url| (original_url.clone(), normalized_url.clone()))
+            .collect();
+
+        body.push(json!({}).into());
+        body.push(json!({
+            "query": normalized_url_lookup_query(std::slice::from_ref(normalized_url), &legacy_urls),
+            "from": from,
+            "size": size,
+            "track_total_hits": true,
+            "sort": [
+                { "timestamp": { "order": "desc" } }
+            ]
+        }).into());
+    }
+
+    let response = client
+        .msearch(MsearchParts::Index(&[index]))
+        .body(body)
+        .send()
+        .await?
+        .error_for_status_code()?;
+
+    let response_body = response.json::<Value>().await?;
+    parse_visit_pages(&response_body, lookups.len())
+}
+
+/// 解析 _msearch 响应，结果与请求一一对应；单个查询失败时整体返回错误，而不是当作没有访问记录
+fn parse_visit_pages(response_body: &Value, expected: usize) -> Result<Vec<VisitPage>, MsearchError> {
+    let empty = Vec::new();
+
+    Ok(msearch_responses(response_body, expected)?
+        .into_iter()
+        .map(|response| VisitPage {
+            total: response["hits"]["total"]["value"].as_u64().unwrap_or(0),
+            items: response["hits"]["hits"].as_array()
+                .unwrap_or(&empty)
+                .iter()
+                .map(|hit| hit["_source"].clone())
+                .collect(),
+        })
+        .collect())
+}
+
+/// 分页列出存在多个文档的归一化URL（composite聚合），返回 (归一化URL, 文档数) 与下一页的 after_key
+pub async fn find_duplicate_normalized_urls(
+    client: &Elasticsearch,
+    index: &str,
+    after: Option<Value>,
+    page_size: usize,
+) -> Result<(Vec<(String, u64)>, Option<Value>), ElasticsearchError> {
+    let mut composite = json!({
+        "size": page_size,
+        "sources": [
+            { "normalized_url": { "terms": { "field": "normalized_url" } } }
+        ]
+    });
+
+    if let Some(after) = after {
+        composite["after"] = after;
+    }
+
+    let body = json!({
+        "size": 0,
+        "aggs": {
+            "urls": { "composite": composite }
+        }
+    });
+
+    // 查询失败时返回错误，不能当作没有重复的URL
+    let response = client
+        .search(SearchParts::Index(&[index]))
+        .body(body)
+        .send()
+        .await?
+        .error_for_status_code()?;
+
+    let response_body = response.json::<Value>().await?;
+    let aggregation = &response_body["aggregations"]["urls"];
+
+    let duplicates = aggregation["buckets"].as_array()
+        .unwrap_or(&Vec::new())
+        .iter()
+        .filter_map(|bucket| {
+            let doc_count = bucket["doc_count"].as_u64().unwrap_or(0);
+            let normalized_url = bucket["key"]["normalized_url"].as_str()?;
+            (doc_count > 1).then(|| (normalized_url.to_string(), doc_count))
+        })
+        .collect();
+
+    // 没有after_key或本页为空时表示已遍历完
+    let has_buckets = aggregation["buckets"].as_array().map(|b| !b.is_empty()).unwrap_or(false);
+    let after_key = aggregation.get("after_key").filter(|_| has_buckets).cloned();
+
+    Ok((duplicates, after_key))
+}
+
+/// 获取某个归一化URL的全部文档（按时间倒序），返回原始命中（包含 _id、_index、_source）
+pub async fn get_documents_by_normalized_url(
+    client: &Elasticsearch,
+    index: &str,
+    normalized_url: &str,
+    size: usize,
+) -> Result<Vec<Value>, ElasticsearchError> {
+    let body = json!({
+        "query": {
+            "term": {
+                "normalized_url": normalized_url
+            }
+        },
+        "size": size,
+        "sort": [
+            { "timestamp": { "order": "desc" } }
+        ]
+    });
+
+    let response = client
+        .search(SearchParts::Index(&[index]))
+        .body(body)
+        .send()
+        .await?
+        .error_for_status_code()?;
+
+    let response_body = response.json::<Value>().await?;
+
+    Ok(response_body["hits"]["hits"].as_array().cloned().unwrap_or_default())
+}
+
+/// 执行任意 `_bulk` 操作，返回失败条目的下标（与 `operations` 的顺序一致）
+pub async fn bulk_operations(
+    client: &Elasticsearch,
+    index: &str,
+    operations: Vec<BulkOperation<Value>>,
+) -> Result<Vec<usize>, ElasticsearchError> {
+    if operations.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let response = client
+        .bulk(BulkParts::Index(index))
+        .body(operations)
+        .send()
+        .await?
+        .error_for_status_code()?;
+
+    let response_body = response.json::<Value>().await?;
+
+    let failures = response_body["items"].as_array()
+        .unwrap_or(&Vec::new())
+        .iter()
+        .enumerate()
+        .filter(|(_, item)| {
+            item.as_object()
+                .and_then(|operation| operation.values().next())
+                .map(|result| result.get("error").is_some())
+                .unwrap_or(false)
+        })
+        .map(|(position, _)| position)
+        .collect();
+
+    Ok(failures)
+}
+
+/// 仅查询时归一化模式下查找候选记录的域名：各URL的主机名，以及加上或去掉 `www.` 的形式
+/// 归一化规则改写协议、去掉 `www.` 或重排路径时，存储的原始URL不以归一化URL开头，但域名不变
+pub fn url_lookup_domains<'a>(urls: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut domains = Vec::new();
+    for url in urls {
+        let Some(host) = url::Url::parse(url).ok().and_then(|parsed| parsed.host_str().map(str::to_lowercase)) else {
+            continue;
+        };
+        let alternate = match host.strip_prefix("www.") {
+            Some(bare) => bare.to_string(),
+            None => format!("www.{}", host),
+        };
+        for domain in [host, alternate] {
+            if !domains.contains(&domain) {
+                domains.push(domain);
+            }
+        }
+    }
+    domains
+}
+
+/// 查询候选记录，每个URL单独查询（一次 _msearch），按时间倒序返回各自的 `_source`
+/// 用于仅查询时归一化模式：索引中没有 normalized_url，由调用方对候选记录即时归一化后再匹配
+/// `lookups` 为（归一化URL, 候选域名），原始URL以归一化URL开头或域名匹配的记录都是候选
+/// 每个URL最多 `size_per_url` 条，某个URL的候选很多时不会挤掉其他URL的候选
+pub async fn search_history_candidates(
+    client: &Elasticsearch,
+    index: &str,
+    lookups: &[(String, Vec<String>)],
+    size_per_url: usize,
+) -> Result<Vec<Vec<Value>>, MsearchError> {
+    if lookups.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut body: Vec<JsonBody<Value>> = Vec::with_capacity(lookups.len() * 2);
+    for (normalized_url, domains) in lookups {
+        body.push(json!({}).into());
+        body.push(json!({
+            "query": {
+                "bool": {
+                    "should": [
+                        { "prefix": { "original_url": normalized_url } },
+                        { "terms": { "domain": domains } }
+                    ],
+                    "minimum_should_match": 1
+                }
+            },
+            "size": size_per_url,
+            "sort": [
+                { "timestamp": { "order": "desc" } }
+            ]
+        }).into());
+    }
+
+    tracing::info!("ES candidate query for {} URLs, at most {} candidates each", lookups.len(), size_per_url);
+
+    let response = client
+        .msearch(MsearchParts::Index(&[index]))
+        .body(body)
+        .send()
+        .await?
+        .error_for_status_code()?;
+
+    let response_body = response.json::<Value>().await?;
+    let candidates = msearch_responses(&response_body, lookups.len())?
+        .into_iter()
+        .map(|response| {
+            response["hits"]["hits"].as_array()
+                .map(|hits| hits.iter().map(|hit| hit["_source"].clone()).collect())
+                .unwrap_or_default()
+        })
+        .collect();
+
+    Ok(candidates)
+}
+
+/// 返回以 `prefix` 开头的补全候选，按记录数倒序，最多 `size` 条：`[{ value, count }]`
+pub async fn suggest(
+    client: &Elasticsearch,
+    index: &str,
+    source: SuggestSource,
+    prefix: &str,
+    size: usize,
+) -> Result<Vec<Value>, ElasticsearchError> {
+    let body = suggest_query(source, prefix, size, chrono::Utc::now().timestamp_millis());
+
+    let response = client
+        .search(SearchParts::Index(&[index]))
+        .body(body)
+        .send()
+        .await?
+        .error_for_status_code()?;
+
+    let response_body = response.json::<Value>().await?;
+
+    Ok(parse_suggestions(&response_body["aggregations"]))
+}
+
+/// 自动补全先取最近访问的若干倍候选，再按访问次数和最近访问时间综合排序
+const SUGGEST_CANDIDATE_FACTOR: usize = 5;
+/// 最近访问权重的半衰期（天）：最后一次访问每早这么多天，得分减半
+const SUGGEST_RECENCY_HALF_LIFE_DAYS: f64 = 14.0;
+
+/// 候选得分 = ln(1 + 访问次数) × 0.5^(距最后一次访问的天数 / 半衰期)
+const SUGGEST_SCORE_SCRIPT: &str =
+    "double ageDays = Math.max(0.0, params.now - params.last_visit) / 86400000.0; \
+     return Math.log(1 + params.count) * Math.pow(0.5, ageDays / params.half_life_days);";
+
+/// `now_millis` 为计算最近访问权重的当前时间（毫秒）
+fn suggest_query(source: SuggestSource, prefix: &str, size: usize, now_millis: i64) -> Value {
+    let field = source.field();
+    // 域名写入时已转为小写，keyword 字段的前缀匹配区分大小写
+    let prefix = match source {
+        SuggestSource::Domain => prefix.to_lowercase(),
+        SuggestSource::Url => prefix.to_string(),
+    };
+    json!({
+        "query": { "prefix": { field: prefix } },
+        "size": 0,
+        "aggs": {
+            "suggestions": {
+                "terms": {
+                    "field": field,
+                    "size": size * SUGGEST_CANDIDATE_FACTOR,
+                    "order": { "last_visit": "desc" }
+                },
+                "aggs": {
+                    "last_visit": { "max": { "field": "timestamp" } },
+                    "score": {
+                        "bucket_script": {
+                            "buckets_path": { "count": "_count", "last_visit": "last_visit" },
+                            "script": {
+                                "source": SUGGEST_SCORE_SCRIPT,
+                                "params": {
+                                    "now": now_millis,
+                                    "half_life_days": SUGGEST_RECENCY_HALF_LIFE_DAYS
+                                }
+                            }
+                        }
+                    },
+                    "rank": {
+                        "bucket_sort": {
+                            "sort": [{ "score": { "order": "desc" } }],
+                            "size": size
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// 最近访问过的不重复域名，按最后一次访问时间倒序，最多 `limit` 个：`[{ domain, last_visit, count }]`
+pub async fn recent_domains(
+    client: &Elasticsearch,
+    index: &str,
+    limit: usize,
+) -> Result<Vec<Value>, ElasticsearchError> {
+    let response = client
+        .search(SearchParts::Index(&[index]))
+        .body(recent_domains_query(limit))
+        .send()
+        .await?
+        .error_for_status_code()?;
+
+    let response_body = response.json::<Value>().await?;
+
+    Ok(parse_recent_domains(&response_body["aggregations"]))
+}
+
+/// 按域名分组，并按组内最大时间戳排序
+fn recent_domains_query(limit: usize) -> Value {
+    json!({
+        "size": 0,
+        "aggs": {
+            "domains": {
+                "terms": {
+                    "field": "domain",
+                    "size": limit,
+                    "order": { "last_visit": "desc" }
+                },
+                "aggs": {
+                    "last_visit": { "max": { "field": "timestamp" } }
+                }
+            }
+        }
+    })
+}
+
+fn parse_recent_domains(aggregations: &Value) -> Vec<Value> {
+    aggregations["domains"]["buckets"].as_array()
+        .unwrap_or(&Vec::new())
+        .iter()
+        .map(|bucket| json!({
+            "domain": bucket["key"],
+            "last_visit": bucket["last_visit"]["value_as_string"],
+            "count": bucket["doc_count"]
+        }))
+        .collect()
+}
+
+/// 各上报来源（设备）的记录数和最后一次上报的访问时间，按记录数倒序：
+/// `{ sources: [{ source, count, last_seen }], unattributed }`，unattributed 为没有 source 的记录数
+pub async fn source_stats(
+    client: &Elasticsearch,
+    index: &str,
+    limit: usize,
+) -> Result<Value, ElasticsearchError> {
+    let response = client
+        .search(SearchParts::Index(&[index]))
+        .body(source_stats_query(limit))
+        .send()
+        .await?
+        .error_for_status_code()?;
+
+    let response_body = response.json::<Value>().await?;
+
+    Ok(parse_source_stats(&response_body["aggregations"]))
+}
+
+fn source_stats_query(limit: usize) -> Value {
+    json!({
+        "size": 0,
+        "aggs": {
+            "sources": {
+                "terms": { "field": "source", "size": limit },
+                "aggs": {
+                    "last_seen": { "max": { "field": "timestamp" } }
+                }
+            },
+            "unattributed": {
+                "missing": { "field": "source" }
+            }
+        }
+    })
+}
+
+fn parse_source_stats(aggregations: &Value) -> Value {
+    let sources: Vec<Value> = aggregations["sources"]["buckets"].as_array()
+        .unwrap_or(&Vec::new())
+        .iter()
+        .map(|bucket| json!({
+            "source": bucket["key"],
+            "count": bucket["doc_count"],
+            "last_seen": bucket["last_seen"]["value_as_string"]
+        }))
+        .collect();
+
+    json!({
+        "sources": sources,
+        "unattributed": aggregations["unattributed"]["doc_count"].as_u64().unwrap_or(0)
+    })
+}
+
+/// 将补全聚合转换为 `[{ value, count, last_visit }]`，顺序即排名
+fn parse_suggestions(aggregations: &Value) -> Vec<Value> {
+    aggregations["suggestions"]["buckets"].as_array()
+        .unwrap_or(&Vec::new())
+        .iter()
+        .map(|bucket| json!({
+            "value": bucket["key"],
+            "count": bucket["doc_count"],
+            "last_visit": bucket["last_visit"]["value_as_string"]
+        }))
+        .collect()
+}
+
+/// 导出时每次滚动读取的记录数
+const EXPORT_SCROLL_SIZE: usize = 1000;
+const EXPORT_SCROLL_KEEP_ALIVE: &str = "1m";
+
+/// 导出结果：记录按访问时间倒序，`truncated` 表示匹配的记录超过了导出上限
+pub struct ExportedHistory {
+    pub records: Vec<Value>,
+    pub truncated: bool,
+}
+
+/// 按搜索条件滚动读取记录用于导出，最多 `max_rows` 条
+/// 只使用 `options` 中的过滤条件，排序固定为按时间倒序
+pub async fn export_history(
+    client: &Elasticsearch,
+    index: &str,
+    keyword: Option<String>,
+    domain: Option<String>,
+    start_date: Option<String>,
+    end_date: Option<String>,
+    options: &SearchOptions,
+    max_rows: usize,
+) -> Result<ExportedHistory, ElasticsearchError> {
+    let query = filtered_history_query(keyword, domain, start_date, end_date, options).build();
+
+    let mut page = client
+        .search(SearchParts::Index(&[index]))
+        .scroll(EXPORT_SCROLL_KEEP_ALIVE)
+        .body(json!({
+            "query": query,
+            "size": EXPORT_SCROLL_SIZE.min(max_rows.max(1)),
+            "track_total_hits": max_rows as i64 + 1,
+            "sort": [
+                { "timestamp": { "order": "desc" } }
+            ]
+        }))
+        .send()
+        .await?
+        .error_for_status_code()?
+        .json::<Value>()
+        .await?;
+
+    let truncated = page["hits"]["total"]["value"].as_u64().unwrap_or(0) > max_rows as u64;
+
+    // 读完、出错或客户端断开时都会释放滚动上下文
+    let mut scroll = ScrollGuard::new(client);
+    scroll.track(&page);
+
+    let mut records = Vec::new();
+    loop {
+        let hits = page["hits"]["hits"].as_array().cloned().unwrap_or_default();
+        if hits.is_empty() {
+            break;
+        }
+        let remaining = max_rows - records.len();
+        records.extend(hits.into_iter().take(remaining).map(|mut hit| hit["_source"].take()));
+        if records.len() >= max_rows {
+            break;
+        }
+
+        let scroll_id = match scroll.scroll_id() {
+            Some(scroll_id) => scroll_id.to_string(),
+            None => break,
+        };
+        page = client
+            .scroll(ScrollParts::None)
+            .body(json!({ "scroll": EXPORT_SCROLL_KEEP_ALIVE, "scroll_id": scroll_id }))
+            .send()
+            .await?
+            .error_for_status_code()?
+            .json::<Value>()
+            .await?;
+        scroll.track(&page);
+    }
+
+    Ok(ExportedHistory { records, truncated })
+}
+
+/// 取最近访问的不重复原始URL作为样本（用于规则分析），最多 `size` 条
+pub async fn sample_original_urls(
+    client: &Elasticsearch,
+    index: &str,
+    size: usize,
+) -> Result<Vec<String>, ElasticsearchError> {
+    // 按 original_url 折叠，每个URL只返回最近的一条，保证返回 size 个不重复的URL
+    let body = json!({
+        "query": { "match_all": {} },
+        "size": size,
+        "collapse": { "field": "original_url" },
+        "_source": ["original_url"],
+        "sort": [
+            { "timestamp": { "order": "desc" } }
+        ]
+    });
+
+    let response = client
+        .search(SearchParts::Index(&[index]))
+        .body(body)
+        .send()
+        .await?
+        .error_for_status_code()?;
+
+    let response_body = response.json::<Value>().await?;
+
+    let mut urls: Vec<String> = Vec::new();
+    for hit in response_body["hits"]["hits"].as_array().unwrap_or(&Vec::new()) {
+        if let Some(url) = hit["_source"]["original_url"].as_str() {
+            if !urls.iter().any(|existing| existing == url) {
+                urls.push(url.to_string());
+            }
+        }
+    }
+
+    Ok(urls)
+}
+
+/// 单次请求最多添加或移除的标签数
+pub const MAX_TAGS_PER_REQUEST: usize = 20;
+/// 单个标签的最大长度（字符）
+pub const MAX_TAG_LENGTH: usize = 64;
+
+/// 校验并规范化要添加和移除的标签：去掉首尾空白、去重，同一个标签不能既添加又移除
+pub fn normalize_tag_changes(add: &[String], remove: &[String]) -> Result<(Vec<String>, Vec<String>), String> {
+    fn normalize(tags: &[String]) -> Result<Vec<String>, String> {
+        let mut normalized: Vec<String> = Vec::new();
+        for tag in tags {
+            let tag = tag.trim();
+            if tag.is_empty() {
+                return Err("Tags must not be empty".to_string());
+            }
+            if tag.chars().count() > MAX_TAG_LENGTH {
+                return Err(format!("Tag '{}' is longer than {} characters", tag, MAX_TAG_LENGTH));
+            }
+            if tag.chars().any(char::is_control) {
+                return Err(format!("Tag '{}' contains control characters", tag.escape_default()));
+            }
+            if !normalized.iter().any(|existing| existing == tag) {
+                normalized.push(tag.to_string());
+            }
+        }
+        Ok(normalized)
+    }
+
+    let add = normalize(add)?;
+    let remove = normalize(remove)?;
+    if add.is_empty() && remove.is_empty() {
+        return Err("At least one tag to add or remove is required".to_string());
+    }
+    if add.len() + remove.len() > MAX_TAGS_PER_REQUEST {
+        return Err(format!("At most {} tags can be changed at once", MAX_TAGS_PER_REQUEST));
+    }
+    if let Some(tag) = add.iter().find(|tag| remove.contains(tag)) {
+        return Err(format!("Tag '{}' cannot be both added and removed", tag));
+    }
+    Ok((add, remove))
+}
+
+/// 添加和移除标签的脚本，标签没有变化的文档不重新写入（计入 noops）
+const UPDATE_TAGS_SCRIPT: &str = r#"
+if (ctx._source.tags == null) {
+    ctx._source.tags = new ArrayList();
+}
+boolean changed = false;
+for (def tag : params.add) {
+    if (!ctx._source.tags.contains(tag)) {
+        ctx._source.tags.add(tag);
+        changed = true;
+    }
+}
+if (ctx._source.tags.removeAll(params.remove)) {
+    changed = true;
+}
+if (!changed) {
+    ctx.op = 'noop';
+}
+"#;
+
+/// 批量打标签的结果
+#[derive(Debug, Default, Serialize)]
+pub struct TagUpdateOutcome {
+    pub updated: u64,
+    pub noops: u64,
+    pub failed: u64,
+}
+
+/// 统计匹配查询的记录数
+pub async fn count_matching(client: &Elasticsearch, index: &str, query: &Value) -> Result<u64, ElasticsearchError> {
+    let response_body = client
+        .count(CountParts::Index(&[index]))
+        .body(json!({ "query": query }))
+        .send()
+        .await?
+        .error_for_status_code()?
+        .json::<Value>()
+        .await?;
+
+    Ok(response_body["count"].as_u64().unwrap_or(0))
+}
+
+/// 用 update_by_query 为匹配查询的记录添加和移除标签，版本冲突的文档跳过（计入 failed）
+/// 最多处理 `max_docs` 条，计数之后新写入的匹配记录不会让更新超出上限
+pub async fn update_tags_by_query(
+    client: &Elasticsearch,
+    index: &str,
+    query: Value,
+    add: &[String],
+    remove: &[String],
+    max_docs: u64,
+) -> Result<TagUpdateOutcome, ElasticsearchError> {
+    let response_body = client
+        .update_by_query(UpdateByQueryParts::Index(&[index]))
+        .max_docs(max_docs as i64)
+        .conflicts(Conflicts::Proceed)
+        .refresh(true)
+        .body(json!({
+            "query": query,
+            "script": {
+                "source": UPDATE_TAGS_SCRIPT,
+                "lang": "painless",
+                "params": { "add": add, "remove": remove }
+            }
+        }))
+        .send()
+        .await?
+        .error_for_status_code()?
+        .json::<Value>()
+        .await?;
+
+    let failures = response_body["failures"].as_array().map(|failures| failures.len() as u64).unwrap_or(0);
+    Ok(TagUpdateOutcome {
+        updated: response_body["updated"].as_u64().unwrap_or(0),
+        noops: response_body["noops"].as_u64().unwrap_or(0),
+        failed: failures + response_body["version_conflicts"].as_u64().unwrap_or(0),
+    })
+}
+
+/// 为后来加入的 resource_type、url_path、registrable_domain、tags、source 显式添加 keyword 映射，避免动态映射成 text；
+/// 字段已存在且类型不同时ES会返回错误（不影响已有映射）
+pub async fn ensure_keyword_mappings(client: &Elasticsearch, index: &str) -> Result<(), ElasticsearchError> {
+    client
+        .indices()
+        .put_mapping(IndicesPutMappingParts::Index(&[index]))
+        .body(json!({
+            "properties": {
+                "resource_type": { "type": "keyword" },
+                "url_path": { "type": "keyword" },
+                "registrable_domain": { "type": "keyword" },
+                "tags": { "type": "keyword" },
+                "source": { "type": "keyword" },
+                "normalization_failed": { "type": "boolean" }
+            }
+        }))
+        .send()
+        .await?
+        .error_for_status_code()?;
+
+    Ok(())
+}
+
+/// 需要为keyword类型（或带keyword子字段）的字段，term查询依赖它们
+const KEYWORD_FIELDS: [&str; 2] = ["domain", "normalized_url"];
+
+/// 获取索引映射并校验关键字段类型，返回发现的问题列表（为空表示映射正确）
+pub async fn check_index_mapping(
+    client: &Elasticsearch,
+    index: &str,
+) -> Result<Vec<String>, ElasticsearchError> {
+    let response = client
+        .indices()
+        .get_mapping(IndicesGetMappingParts::Index(&[index]))
+        .send()
+        .await?;
+
+    let status = response.status_code();
+    if !status.is_success() {
+        return Ok(vec![format!("Failed to fetch mapping for index '{}' (status {})", index, status)]);
+    }
+
+    let response_body = response.json::<Value>().await?;
+
+    // 索引名可能是别名，响应的键为实际索引名
+    let mut problems = Vec::new();
+    match response_body.as_object() {
+        Some(indices) if !indices.is_empty() => {
+            for (index_name, index_mapping) in indices {
+                for problem in verify_mapping(&index_mapping["mappings"]) {
+                    problems.push(format!("{}: {}", index_name, problem));
                 }
             }
         }
+        _ => problems.push(format!("No mapping returned for index '{}'", index)),
     }
 
-    Ok(results)
-} 
\ No newline at end of file
+    Ok(problems)
+}
+
+/// 校验单个索引的映射：timestamp必须是date类型，关键字段必须可做term查询
+fn verify_mapping(mappings: &Value) -> Vec<String> {
+    let properties = &mappings["properties"];
+    let mut problems = Vec::new();
+
+    match properties["timestamp"]["type"].as_str() {
+        Some("date") | Some("date_nanos") => {}
+        Some(other) => problems.push(format!("field 'timestamp' is '{}', expected 'date'", other)),
+        None => problems.push("field 'timestamp' is missing".to_string()),
+    }
+
+    for field in KEYWORD_FIELDS {
+        let mapping = &properties[field];
+        let is_keyword = mapping["type"] == "keyword" || mapping["fields"]["keyword"]["type"] == "keyword";
+
+        if mapping.is_null() {
+            problems.push(format!("field '{}' is missing", field));
+        } else if !is_keyword {
+            problems.push(format!("field '{}' has no keyword mapping", field));
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_weekday_filter() {
+        assert_eq!(parse_weekday_filter("sat,sun"), Ok(vec![6, 7]));
+        assert_eq!(parse_weekday_filter("Mon-Fri"), Ok(vec![1, 2, 3, 4, 5]));
+        assert_eq!(parse_weekday_filter("fri-mon"), Ok(vec![1, 5, 6, 7]));
+        assert_eq!(parse_weekday_filter("sun, sun ,wed-wed"), Ok(vec![3, 7]));
+        assert!(parse_weekday_filter("weekend").is_err());
+        assert!(parse_weekday_filter("mon-").is_err());
+        assert!(parse_weekday_filter("").is_err());
+    }
+
+    #[test]
+    fn test_history_document_day_of_week() {
+        // 2024-03-23 是周六
+        let doc = history_document("https://example.com", None, "2024-03-23T10:30:00Z", "example.com", None, DEFAULT_RESOURCE_TYPE, None);
+        assert_eq!(doc["day_of_week"], 6);
+
+        // 按时间戳自带的时区计算：UTC周六晚上在 +09:00 已是周日
+        let doc = history_document("https://example.com", None, "2024-03-24T07:30:00+09:00", "example.com", None, DEFAULT_RESOURCE_TYPE, None);
+        assert_eq!(doc["day_of_week"], 7);
+
+        let doc = history_document("https://example.com", None, "yesterday", "example.com", None, DEFAULT_RESOURCE_TYPE, None);
+        assert!(doc.get("day_of_week").is_none());
+    }
+
+    #[test]
+    fn test_validate_path_prefix() {
+        assert!(validate_path_prefix("/api/v2/", Some("docs.example.com")).is_ok());
+        assert!(validate_path_prefix("/api/v2/", None).is_err());
+        assert!(validate_path_prefix("/api/v2/", Some(" ")).is_err());
+        assert!(validate_path_prefix("api/v2", Some("docs.example.com")).is_err());
+        assert!(validate_path_prefix("/api?x=1", Some("docs.example.com")).is_err());
+    }
+
+    #[test]
+    fn test_history_document_url_path() {
+        let doc = history_document("https://docs.example.com/api/v2/users?page=2#top", None, "2024-03-19T10:30:00Z", "docs.example.com", None, DEFAULT_RESOURCE_TYPE, None);
+        assert_eq!(doc["url_path"], "/api/v2/users");
+
+        let doc = history_document("not a url", None, "2024-03-19T10:30:00Z", "example.com", None, DEFAULT_RESOURCE_TYPE, None);
+        assert!(doc.get("url_path").is_none());
+    }
+
+    #[test]
+    fn test_parse_resource_type_filter() {
+        assert_eq!(parse_resource_type_filter("document"), Ok(vec!["document".to_string()]));
+        assert_eq!(parse_resource_type_filter("Document, download,document"), Ok(vec!["document".to_string(), "download".to_string()]));
+        assert!(parse_resource_type_filter("").is_err());
+        assert!(parse_resource_type_filter("image,").is_err());
+        assert!(parse_resource_type_filter("a b").is_err());
+        assert!(!is_valid_resource_type(&"x".repeat(33)));
+    }
+
+    #[test]
+    fn test_history_document_id() {
+        let doc = history_document("https://example.com/a?utm=x", Some("https://example.com/a"), "2024-03-19T10:30:00Z", "example.com", None, DEFAULT_RESOURCE_TYPE, None);
+        let retried = history_document("https://example.com/a?utm=y", Some("https://example.com/a"), "2024-03-19T10:30:00Z", "Example.com", None, DEFAULT_RESOURCE_TYPE, None);
+        let later = history_document("https://example.com/a?utm=x", Some("https://example.com/a"), "2024-03-19T10:31:00Z", "example.com", None, DEFAULT_RESOURCE_TYPE, None);
+
+        assert_eq!(history_document_id(&doc), history_document_id(&retried));
+        assert_ne!(history_document_id(&doc), history_document_id(&later));
+        assert_eq!(history_document_id(&doc).len(), 64);
+
+        // 仅查询时归一化模式下按原始URL计算
+        let query_only = history_document("https://example.com/a?utm=x", None, "2024-03-19T10:30:00Z", "example.com", None, DEFAULT_RESOURCE_TYPE, None);
+        assert_ne!(history_document_id(&doc), history_document_id(&query_only));
+    }
+
+    #[test]
+    fn test_history_document_id_uses_canonical_timestamp() {
+        let utc = history_document("https://example.com/a", None, "2024-01-01T00:00:00Z", "example.com", None, DEFAULT_RESOURCE_TYPE, None);
+        let offset = history_document("https://example.com/a", None, "2024-01-01T08:00:00+08:00", "example.com", None, DEFAULT_RESOURCE_TYPE, None);
+        let fractional = history_document("https://example.com/a", None, "2024-01-01T00:00:00.000Z", "example.com", None, DEFAULT_RESOURCE_TYPE, None);
+
+        assert_eq!(history_document_id(&utc), history_document_id(&offset));
+        assert_eq!(history_document_id(&utc), history_document_id(&fractional));
+        assert_eq!(canonical_timestamp("2024-01-01T08:00:00.250+08:00"), "2024-01-01T00:00:00.250Z");
+        assert_eq!(canonical_timestamp("yesterday"), "yesterday");
+    }
+
+    #[test]
+    fn test_history_document_id_includes_source() {
+        let doc = history_document("https://example.com/a", Some("https://example.com/a"), "2024-03-19T10:30:00Z", "example.com", None, DEFAULT_RESOURCE_TYPE, None);
+        let mut laptop = doc.clone();
+        laptop["source"] = json!("work-laptop");
+        let mut phone = doc.clone();
+        phone["source"] = json!("phone");
+
+        assert_ne!(history_document_id(&laptop), history_document_id(&phone));
+        assert_ne!(history_document_id(&laptop), history_document_id(&doc));
+    }
+
+    #[test]
+    fn test_validate_page_depth() {
+        assert!(validate_page_depth(SortBy::Frequency, 1, 30).is_ok());
+        assert!(validate_page_depth(SortBy::Frequency, 10, 1000).is_ok());
+        assert!(validate_page_depth(SortBy::Frequency, 11, 1000).is_err());
+        assert!(validate_page_depth(SortBy::Frequency, i32::MAX, 1000).is_err());
+        // 其他排序方式不受限制
+        assert!(validate_page_depth(SortBy::Recent, 11, 1000).is_ok());
+    }
+
+    #[test]
+    fn test_url_lookup_domains() {
+        assert_eq!(
+            url_lookup_domains(["https://example.com/a", "http://www.example.com/a?utm_source=x"]),
+            vec!["example.com", "www.example.com"]
+        );
+        assert_eq!(url_lookup_domains(["https://Docs.Example.com/"]), vec!["docs.example.com", "www.docs.example.com"]);
+        assert!(url_lookup_domains(["not a url"]).is_empty());
+    }
+
+    #[test]
+    fn test_validate_result_window() {
+        assert!(validate_result_window(1, 100).is_ok());
+        assert!(validate_result_window(100, 100).is_ok());
+        assert!(validate_result_window(101, 100).is_err());
+        assert!(validate_result_window(i32::MAX, 100).is_err());
+    }
+
+    #[test]
+    fn test_count_mode_track_total_hits() {
+        assert_eq!(CountMode::Exact.track_total_hits(None), json!(true));
+        assert_eq!(CountMode::Exact.track_total_hits(Some(50000)), json!(50000));
+        assert_eq!(CountMode::Estimate.track_total_hits(None), json!(ESTIMATE_TOTAL_HITS_CAP));
+        assert_eq!(CountMode::Estimate.track_total_hits(Some(1000)), json!(1000));
+        assert_eq!(CountMode::None.track_total_hits(Some(1000)), json!(false));
+    }
+
+    #[test]
+    fn test_suggest_query_uses_source_field() {
+        let query = suggest_query(SuggestSource::Url, "https://exa", 5, 1_710_844_200_000);
+        assert_eq!(query["query"]["prefix"]["original_url"], "https://exa");
+        assert_eq!(query["aggs"]["suggestions"]["terms"]["field"], "original_url");
+        assert_eq!(query["aggs"]["suggestions"]["terms"]["size"], 5 * SUGGEST_CANDIDATE_FACTOR);
+
+        let query = suggest_query(SuggestSource::Domain, "exa", 10, 1_710_844_200_000);
+        assert_eq!(query["query"]["prefix"]["domain"], "exa");
+
+        assert!(serde_json::from_value::<SuggestSource>(json!("title")).is_err());
+    }
+
+    #[test]
+    fn test_suggest_query_lowercases_domain_prefix() {
+        let query = suggest_query(SuggestSource::Domain, "GitH", 10, 1_710_844_200_000);
+        assert_eq!(query["query"]["prefix"]["domain"], "gith");
+
+        // URL 保持原样，路径和查询参数区分大小写
+        let query = suggest_query(SuggestSource::Url, "https://example.com/ReadMe", 10, 1_710_844_200_000);
+        assert_eq!(query["query"]["prefix"]["original_url"], "https://example.com/ReadMe");
+    }
+
+    #[test]
+    fn test_suggest_query_ranks_by_frequency_and_recency() {
+        let query = suggest_query(SuggestSource::Domain, "exa", 10, 1_710_844_200_000);
+        let suggestions = &query["aggs"]["suggestions"];
+
+        assert_eq!(suggestions["terms"]["order"], json!({ "last_visit": "desc" }));
+        assert_eq!(suggestions["aggs"]["last_visit"]["max"]["field"], "timestamp");
+        assert_eq!(
+            suggestions["aggs"]["score"]["bucket_script"]["buckets_path"],
+            json!({ "count": "_count", "last_visit": "last_visit" })
+        );
+        assert_eq!(suggestions["aggs"]["score"]["bucket_script"]["script"]["params"]["now"], 1_710_844_200_000i64);
+        assert_eq!(
+            suggestions["aggs"]["rank"]["bucket_sort"],
+            json!({ "sort": [{ "score": { "order": "desc" } }], "size": 10 })
+        );
+    }
+
+    #[test]
+    fn test_recent_domains() {
+        let query = recent_domains_query(10);
+        assert_eq!(query["aggs"]["domains"]["terms"]["order"], json!({ "last_visit": "desc" }));
+        assert_eq!(query["aggs"]["domains"]["aggs"]["last_visit"]["max"]["field"], "timestamp");
+
+        let aggregations = json!({
+            "domains": {
+                "buckets": [
+                    { "key": "news.example.com", "doc_count": 2, "last_visit": { "value": 1.71e12, "value_as_string": "2024-03-19T10:30:00.000Z" } },
+                    { "key": "example.com", "doc_count": 40, "last_visit": { "value": 1.70e12, "value_as_string": "2023-11-14T22:13:20.000Z" } }
+                ]
+            }
+        });
+        assert_eq!(parse_recent_domains(&aggregations), vec![
+            json!({ "domain": "news.example.com", "last_visit": "2024-03-19T10:30:00.000Z", "count": 2 }),
+            json!({ "domain": "example.com", "last_visit": "2023-11-14T22:13:20.000Z", "count": 40 }),
+        ]);
+    }
+
+    #[test]
+    fn test_source_stats() {
+        let query = source_stats_query(50);
+        assert_eq!(query["aggs"]["sources"]["terms"], json!({ "field": "source", "size": 50 }));
+        assert_eq!(query["aggs"]["unattributed"]["missing"]["field"], "source");
+
+        let aggregations = json!({
+            "sources": {
+                "buckets": [
+                    { "key": "work-laptop", "doc_count": 120, "last_seen": { "value": 1.71e12, "value_as_string": "2024-03-19T10:30:00.000Z" } },
+                    { "key": "phone", "doc_count": 8, "last_seen": { "value": 1.70e12, "value_as_string": "2023-11-14T22:13:20.000Z" } }
+                ]
+            },
+            "unattributed": { "doc_count": 3000 }
+        });
+        assert_eq!(parse_source_stats(&aggregations), json!({
+            "sources": [
+                { "source": "work-laptop", "count": 120, "last_seen": "2024-03-19T10:30:00.000Z" },
+                { "source": "phone", "count": 8, "last_seen": "2023-11-14T22:13:20.000Z" }
+            ],
+            "unattributed": 3000
+        }));
+    }
+
+    #[test]
+    fn test_parse_suggestions() {
+        let aggregations = json!({
+            "suggestions": {
+                "buckets": [
+                    { "key": "example.org", "doc_count": 2, "last_visit": { "value": 1.71e12, "value_as_string": "2024-03-19T10:30:00.000Z" } },
+                    { "key": "example.com", "doc_count": 7, "last_visit": { "value": 1.70e12, "value_as_string": "2023-11-14T22:13:20.000Z" } }
+                ]
+            }
+        });
+        assert_eq!(
+            parse_suggestions(&aggregations),
+            vec![
+                json!({ "value": "example.org", "count": 2, "last_visit": "2024-03-19T10:30:00.000Z" }),
+                json!({ "value": "example.com", "count": 7, "last_visit": "2023-11-14T22:13:20.000Z" }),
+            ]
+        );
+        assert!(parse_suggestions(&json!({})).is_empty());
+    }
+
+    #[test]
+    fn test_parse_visit_pages() {
+        let page = json!({
+            "hits": {
+                "total": { "value": 12, "relation": "eq" },
+                "hits": [
+                    { "_source": { "original_url": "https://example.com/a?x=1" } },
+                    { "_source": { "original_url": "https://example.com/a?x=2" } }
+                ]
+            }
+        });
+        let no_visits = json!({ "hits": { "total": { "value": 0, "relation": "eq" }, "hits": [] } });
+
+        let pages = parse_visit_pages(&json!({ "responses": [page, no_visits] }), 2).unwrap();
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].total, 12);
+        assert_eq!(pages[0].items.len(), 2);
+        assert_eq!(pages[1].total, 0);
+        assert!(pages[1].items.is_empty());
+    }
+
+    #[test]
+    fn test_parse_visit_pages_propagates_item_errors() {
+        let page = json!({ "hits": { "total": { "value": 1, "relation": "eq" }, "hits": [{ "_source": {} }] } });
+        let mixed = json!({
+            "responses": [
+                page,
+                { "error": { "type": "search_phase_execution_exception" }, "status": 400 }
+            ]
+        });
+
+        match parse_visit_pages(&mixed, 2) {
+            Err(MsearchError::Item { position, reason }) => {
+                assert_eq!(position, 1);
+                assert!(reason.contains("search_phase_execution_exception"));
+            }
+            other => panic!("expected an item error, got {:?}", other),
+        }
+
+        // 响应数量少于请求时也是错误
+        assert!(matches!(
+            parse_visit_pages(&json!({ "responses": [page] }), 2),
+            Err(MsearchError::Item { position: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_resolved_indices_expire() {
+        let cache = ResolvedIndices::new(std::time::Duration::from_secs(60));
+        assert_eq!(cache.get("browser-history-*"), None);
+        cache.insert("browser-history-*", vec!["browser-history-2024.03".to_string()]);
+        assert_eq!(cache.get("browser-history-*"), Some(vec!["browser-history-2024.03".to_string()]));
+        assert_eq!(cache.get("other-*"), None);
+
+        let expired = ResolvedIndices::new(std::time::Duration::ZERO);
+        expired.insert("browser-history-*", vec!["browser-history-2024.03".to_string()]);
+        assert_eq!(expired.get("browser-history-*"), None);
+    }
+
+    #[test]
+    fn test_recency_index_boosts() {
+        let indices = vec![
+            "browser-history-2024.01".to_string(),
+            "browser-history-2024.03".to_string(),
+            "browser-history-2024.02".to_string(),
+        ];
+
+        assert_eq!(recency_index_boosts(indices.clone(), 0.5), vec![
+            json!({ "browser-history-2024.03": 1.0 }),
+            json!({ "browser-history-2024.02": 0.5 }),
+            json!({ "browser-history-2024.01": 0.25 }),
+        ]);
+        // 超出范围的系数不加权衰减
+        assert_eq!(recency_index_boosts(indices, 0.0)[2], json!({ "browser-history-2024.01": 1.0 }));
+        assert!(recency_index_boosts(Vec::new(), 0.8).is_empty());
+    }
+
+    #[test]
+    fn test_parse_frequency_buckets() {
+        let by_url = json!({
+            "buckets": [
+                {
+                    "key": "https://example.com/a",
+                    "doc_count": 9,
+                    "latest": { "hits": { "hits": [
+                        { "_source": { "url": "https://example.com/a?x=1", "normalized_url": "https://example.com/a" } }
+                    ] } }
+                }
+            ]
+        });
+
+        assert_eq!(parse_frequency_buckets(&by_url), vec![json!({
+            "url": "https://example.com/a?x=1",
+            "normalized_url": "https://example.com/a",
+            "visits": 9
+        })]);
+    }
+
+    #[test]
+    fn test_lookup_key_falls_back_to_original_url() {
+        let legacy_urls = HashMap::from([
+            ("https://example.com/a?utm=1".to_string(), "https://example.com/a".to_string()),
+        ]);
+
+        let current = json!({ "normalized_url": "https://example.com/b", "original_url": "https://example.com/b?x" });
+        assert_eq!(lookup_key(&current, &legacy_urls), Some("https://example.com/b"));
+
+        let legacy = json!({ "original_url": "https://example.com/a?utm=1" });
+        assert_eq!(lookup_key(&legacy, &legacy_urls), Some("https://example.com/a"));
+
+        let oldest = json!({ "url": "https://example.com/a?utm=1" });
+        assert_eq!(lookup_key(&oldest, &legacy_urls), Some("https://example.com/a"));
+
+        let unrelated = json!({ "original_url": "https://example.com/other" });
+        assert_eq!(lookup_key(&unrelated, &legacy_urls), None);
+    }
+
+    #[test]
+    fn test_lookup_query_without_legacy_urls_is_plain_terms() {
+        let query = normalized_url_lookup_query(&["https://example.com/a".to_string()], &HashMap::new());
+        assert_eq!(query, json!({ "terms": { "normalized_url": ["https://example.com/a"] } }));
+    }
+
+    #[test]
+    fn test_existing_urls_include_legacy_documents() {
+        let normalized_urls = vec!["https://example.com/a".to_string(), "https://example.com/b".to_string()];
+        let legacy_urls = HashMap::from([
+            ("https://example.com/a?utm=1".to_string(), "https://example.com/a".to_string()),
+            ("https://example.com/b?utm=1".to_string(), "https://example.com/b".to_string()),
+        ]);
+
+        let query = existing_urls_query(&normalized_urls, &legacy_urls);
+        assert_eq!(query["query"], normalized_url_lookup_query(&normalized_urls, &legacy_urls));
+        let filter = &query["aggs"]["legacy"]["filters"]["filters"]["https://example.com/b?utm=1"];
+        assert_eq!(filter["bool"]["must_not"][0]["exists"]["field"], "normalized_url");
+
+        // a 只有新文档，b 只有旧文档
+        let aggregations = json!({
+            "present": { "buckets": [{ "key": "https://example.com/a", "doc_count": 2 }] },
+            "legacy": { "buckets": {
+                "https://example.com/a?utm=1": { "doc_count": 0 },
+                "https://example.com/b?utm=1": { "doc_count": 3 }
+            } }
+        });
+        let present = parse_existing_urls(&aggregations, &legacy_urls);
+        assert_eq!(present.len(), 2);
+        assert!(present.contains("https://example.com/b"));
+    }
+
+    #[test]
+    fn test_total_relation() {
+        assert_eq!(total_relation(&json!({ "value": 10000, "relation": "gte" })), "gte");
+        assert_eq!(total_relation(&json!({ "value": 42, "relation": "eq" })), "eq");
+        assert_eq!(total_relation(&json!({ "value": 42 })), "eq");
+    }
+
+    #[test]
+    fn test_parse_timeline_buckets() {
+        let per_day = json!({
+            "buckets": [
+                {
+                    "key_as_string": "2024-03-01",
+                    "doc_count": 5,
+                    "top_domain": { "buckets": [{ "key": "example.com", "doc_count": 3 }] }
+                },
+                {
+                    "key_as_string": "2024-03-02",
+                    "doc_count": 0,
+                    "top_domain": { "buckets": [] }
+                }
+            ]
+        });
+
+        assert_eq!(parse_timeline_buckets(&per_day, true), vec![
+            json!({ "date": "2024-03-01", "count": 5, "top_domain": "example.com" }),
+            json!({ "date": "2024-03-02", "count": 0, "top_domain": null }),
+        ]);
+        assert_eq!(parse_timeline_buckets(&per_day, false)[0], json!({ "date": "2024-03-01", "count": 5 }));
+    }
+
+    #[test]
+    fn test_find_gaps() {
+        let hour = GapInterval::Hour.millis();
+        let per_interval = json!({
+            "buckets": [
+                { "key": 0, "doc_count": 0 },
+                { "key": hour, "doc_count": 4 },
+                { "key": 2 * hour, "doc_count": 0 },
+                { "key": 3 * hour, "doc_count": 0 },
+                { "key": 4 * hour, "doc_count": 0 },
+                { "key": 5 * hour, "doc_count": 1 },
+                { "key": 6 * hour, "doc_count": 0 }
+            ]
+        });
+
+        assert_eq!(find_gaps(&per_interval, hour), vec![
+            json!({ "start": "1970-01-01T02:00:00Z", "end": "1970-01-01T05:00:00Z", "duration_seconds": 10800 }),
+            json!({ "start": "1970-01-01T00:00:00Z", "end": "1970-01-01T01:00:00Z", "duration_seconds": 3600 }),
+            json!({ "start": "1970-01-01T06:00:00Z", "end": "1970-01-01T07:00:00Z", "duration_seconds": 3600 }),
+        ]);
+        assert!(find_gaps(&json!({ "buckets": [{ "key": 0, "doc_count": 2 }] }), hour).is_empty());
+    }
+
+    #[test]
+    fn test_gaps_query_covers_whole_range() {
+        let query = gaps_query("now-7d", "now", GapInterval::Day);
+        let histogram = &query["aggs"]["per_interval"]["date_histogram"];
+        assert_eq!(histogram["calendar_interval"], "day");
+        assert_eq!(histogram["min_doc_count"], 0);
+        assert_eq!(histogram["extended_bounds"], json!({ "min": "now-7d", "max": "now" }));
+    }
+
+    #[test]
+    fn test_resolve_date() {
+        let now = DateTime::parse_from_rfc3339("2024-03-20T15:45:30Z").unwrap().with_timezone(&Utc);
+        let at = |value: &str| resolve_date(value, now).map(|date| date.to_rfc3339_opts(SecondsFormat::Secs, true));
+
+        assert_eq!(at("now").as_deref(), Some("2024-03-20T15:45:30Z"));
+        assert_eq!(at("now-7d").as_deref(), Some("2024-03-13T15:45:30Z"));
+        assert_eq!(at("now-1M/d").as_deref(), Some("2024-02-20T00:00:00Z"));
+        assert_eq!(at("now+1h/h").as_deref(), Some("2024-03-20T16:00:00Z"));
+        assert_eq!(at("now/w").as_deref(), Some("2024-03-18T00:00:00Z"));
+        assert_eq!(at("now-1y/y").as_deref(), Some("2023-01-01T00:00:00Z"));
+        assert_eq!(at("2024-03-01").as_deref(), Some("2024-03-01T00:00:00Z"));
+        assert_eq!(at("2024-03-01T08:00:00+08:00").as_deref(), Some("2024-03-01T00:00:00Z"));
+        assert_eq!(at("2024-03-01T08:00:00").as_deref(), Some("2024-03-01T08:00:00Z"));
+        assert_eq!(at("03/01/2024"), None);
+    }
+
+    #[test]
+    fn test_validate_gap_range() {
+        let now = Utc::now();
+        assert!(validate_gap_range("now-7d", "now", GapInterval::Hour, now).is_ok());
+        assert!(validate_gap_range("now", "now-7d", GapInterval::Hour, now).unwrap_err().contains("must not be after"));
+        assert!(validate_gap_range("2024-03-31", "2024-03-01", GapInterval::Day, now).is_err());
+
+        // 五年按小时约4.4万个桶，超过上限；按天则没问题
+        assert!(validate_gap_range("now-5y", "now", GapInterval::Hour, now).unwrap_err().contains("buckets"));
+        assert!(validate_gap_range("now-5y", "now", GapInterval::Day, now).is_ok());
+
+        // 本地无法解析的格式交给ES
+        assert!(validate_gap_range("03/01/2024", "now", GapInterval::Hour, now).is_ok());
+    }
+
+    #[test]
+    fn test_parse_period() {
+        assert_eq!(parse_period("now-60d/d..now-30d/d"), Ok(("now-60d/d".to_string(), "now-30d/d".to_string())));
+        assert_eq!(parse_period("2024-03-01 .. 2024-03-31"), Ok(("2024-03-01".to_string(), "2024-03-31".to_string())));
+        assert!(parse_period("2024-03-01").is_err());
+        assert!(parse_period("..now").is_err());
+        assert!(parse_period("now-7x..now").is_err());
+
+        assert!(is_relative_date("now-30d/d"));
+        assert!(!is_relative_date("2024-03-01"));
+    }
+
+    #[test]
+    fn test_domain_deltas() {
+        let domains_a = json!({ "buckets": [
+            { "key": "example.com", "doc_count": 10 },
+            { "key": "news.example.com", "doc_count": 4 },
+            { "key": "old.example.com", "doc_count": 3 }
+        ]});
+        let domains_b = json!({ "buckets": [
+            { "key": "example.com", "doc_count": 12 },
+            { "key": "news.example.com", "doc_count": 20 },
+            { "key": "new.example.com", "doc_count": 2 }
+        ]});
+
+        assert_eq!(domain_deltas(&domains_a, &domains_b, 3), vec![
+            json!({ "domain": "news.example.com", "count_a": 4, "count_b": 20, "change": 16 }),
+            json!({ "domain": "old.example.com", "count_a": 3, "count_b": 0, "change": -3 }),
+            json!({ "domain": "example.com", "count_a": 10, "count_b": 12, "change": 2 }),
+        ]);
+        assert_eq!(domain_deltas(&domains_a, &domains_b, 10).len(), 4);
+    }
+
+    #[test]
+    fn test_compare_periods_query() {
+        let query = compare_periods_query(("now-60d", "now-30d"), ("now-30d", "now"), None);
+        assert_eq!(query["aggs"]["period_a"]["filter"]["range"]["timestamp"], json!({ "gte": "now-60d", "lte": "now-30d" }));
+        assert_eq!(query["aggs"]["period_b"]["aggs"]["domains"]["terms"]["field"], "domain");
+        assert!(query["aggs"]["period_b"]["aggs"]["domains"]["terms"]["include"].is_null());
+
+        // 第二次只统计候选域名的并集，两个时间段都对每个候选计数
+        let domains = vec!["a.com".to_string(), "b.com".to_string()];
+        let query = compare_periods_query(("now-60d", "now-30d"), ("now-30d", "now"), Some(&domains));
+        for period in ["period_a", "period_b"] {
+            assert_eq!(
+                query["aggs"][period]["aggs"]["domains"]["terms"],
+                json!({ "field": "domain", "size": 2, "include": ["a.com", "b.com"] })
+            );
+        }
+    }
+
+    #[test]
+    fn test_candidate_domains_is_union() {
+        let domains_a = json!({ "buckets": [{ "key": "b.com", "doc_count": 9 }, { "key": "a.com", "doc_count": 5 }] });
+        let domains_b = json!({ "buckets": [{ "key": "c.com", "doc_count": 7 }, { "key": "a.com", "doc_count": 1 }] });
+        assert_eq!(candidate_domains(&domains_a, &domains_b), vec!["a.com", "b.com", "c.com"]);
+        assert!(candidate_domains(&Value::Null, &Value::Null).is_empty());
+    }
+
+    #[test]
+    fn test_did_you_mean_suggester_uses_original_url() {
+        let suggester = did_you_mean_suggester("rsut");
+        assert_eq!(suggester["text"], "rsut");
+        assert_eq!(suggester["did_you_mean"]["term"]["field"], "original_url");
+    }
+
+    #[test]
+    fn test_corrected_keyword() {
+        let entries = json!([
+            { "text": "rsut", "offset": 0, "length": 4, "options": [{ "text": "rust", "score": 0.75, "freq": 42 }] },
+            { "text": "tutorial", "offset": 5, "length": 8, "options": [] },
+            { "text": "asnyc", "offset": 14, "length": 5, "options": [{ "text": "async", "score": 0.8, "freq": 7 }] }
+        ]);
+        assert_eq!(corrected_keyword("Rsut tutorial asnyc", &entries).as_deref(), Some("rust tutorial async"));
+
+        // 没有建议或建议与原词相同时不返回
+        let no_options = json!([{ "text": "rust", "offset": 0, "length": 4, "options": [] }]);
+        assert_eq!(corrected_keyword("rust", &no_options), None);
+        assert_eq!(corrected_keyword("rust", &Value::Null), None);
+
+        // 偏移量按 UTF-16 计算
+        let entries = json!([{ "text": "rsut", "offset": 3, "length": 4, "options": [{ "text": "rust" }] }]);
+        assert_eq!(corrected_keyword("中文 rsut", &entries).as_deref(), Some("中文 rust"));
+    }
+
+    #[test]
+    fn test_excluded_domains_filter() {
+        assert_eq!(excluded_domains_filter(&[]), None);
+        assert_eq!(excluded_domains_filter(&[" ".to_string()]), None);
+        assert_eq!(
+            excluded_domains_filter(&["CloudFront.net".to_string(), "*.akamaihd.net".to_string()]),
+            Some(json!({
+                "bool": {
+                    "must_not": [
+                        { "terms": { "domain": ["cloudfront.net", "akamaihd.net"] } },
+                        { "wildcard": { "domain": "*.cloudfront.net" } },
+                        { "wildcard": { "domain": "*.akamaihd.net" } }
+                    ]
+                }
+            }))
+        );
+    }
+
+    #[test]
+    fn test_parse_domain_facets() {
+        let aggregations = json!({
+            "domains": {
+                "buckets": [
+                    { "key": "example.com", "doc_count": 12 },
+                    { "key": "blog.example.com", "doc_count": 3 }
+                ]
+            },
+            "unique_domains": { "value": 7 }
+        });
+
+        assert_eq!(parse_domain_facets(&aggregations), json!({
+            "domains": [
+                { "domain": "example.com", "count": 12 },
+                { "domain": "blog.example.com", "count": 3 }
+            ],
+            "unique_domains": 7
+        }));
+    }
+
+    #[test]
+    fn test_history_document_lowercases_domain() {
+        let doc = history_document("https://Example.com/Path", Some("https://Example.com/Path"), "2024-03-19T10:30:00Z", "Example.COM", None, DEFAULT_RESOURCE_TYPE, None);
+
+        assert_eq!(doc["domain"], "example.com");
+        assert_eq!(doc["original_url"], "https://Example.com/Path");
+    }
+
+    #[test]
+    fn test_validate_date_filter() {
+        for valid in ["now", "now-7d", "now-1M", "now+1h", "now-1d/d", "now-1y-2M", "2024-01-01", "2024-01-01T00:00:00Z"] {
+            assert!(validate_date_filter(valid).is_ok(), "{} should be valid", valid);
+        }
+
+        for invalid in ["now-7", "now-7x", "now7d", "now-d", "now/dd", "nowish"] {
+            assert!(validate_date_filter(invalid).is_err(), "{} should be invalid", invalid);
+        }
+    }
+
+    #[test]
+    fn test_parse_bulk_response() {
+        let response = json!({
+            "errors": true,
+            "items": [
+                { "index": { "status": 201 } },
+                { "index": { "status": 429, "error": { "type": "es_rejected_execution_exception", "reason": "queue full" } } },
+                { "index": { "status": 400, "error": { "type": "mapper_parsing_exception", "reason": "failed to parse field [timestamp]" } } },
+                { "index": { "status": 429, "error": { "type": "es_rejected_execution_exception", "reason": "queue full" } } }
+            ]
+        });
+
+        match parse_bulk_response(&response, 4) {
+            Ok(BulkInsertOutcome::Completed { backpressure, failed }) => {
+                assert_eq!(backpressure, vec![1, 3]);
+                assert_eq!(failed.len(), 1);
+                assert_eq!(failed[0].index, 2);
+                assert_eq!(failed[0].status, 400);
+            }
+            outcome => panic!("Unexpected outcome: {:?}", outcome),
+        }
+
+        // 条目数与发送的文档数不一致时不能当作已处理
+        assert!(matches!(
+            parse_bulk_response(&response, 5),
+            Err(BulkInsertError::ItemCountMismatch { expected: 5, received: 4 })
+        ));
+    }
+
+    #[test]
+    fn test_parse_bulk_response_without_items() {
+        let response = json!({
+            "error": {
+                "type": "illegal_argument_exception",
+                "reason": "The bulk request must be terminated by a newline [\\n]"
+            },
+            "status": 400
+        });
+
+        assert!(matches!(
+            parse_bulk_response(&response, 2),
+            Err(BulkInsertError::ItemCountMismatch { expected: 2, received: 0 })
+        ));
+    }
+
+    #[test]
+    fn test_verify_mapping_ok() {
+        let mappings = json!({
+            "properties": {
+                "timestamp": { "type": "date" },
+                "domain": { "type": "keyword" },
+                "normalized_url": { "type": "text", "fields": { "keyword": { "type": "keyword" } } }
+            }
+        });
+
+        assert!(verify_mapping(&mappings).is_empty());
+    }
+
+    #[test]
+    fn test_verify_mapping_dynamic_timestamp() {
+        let mappings = json!({
+            "properties": {
+                "timestamp": { "type": "text", "fields": { "keyword": { "type": "keyword" } } },
+                "domain": { "type": "text" }
+            }
+        });
+
+        let problems = verify_mapping(&mappings);
+        assert_eq!(problems, vec![
+            "field 'timestamp' is 'text', expected 'date'".to_string(),
+            "field 'domain' has no keyword mapping".to_string(),
+            "field 'normalized_url' is missing".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_normalize_tag_changes() {
+        let (add, remove) = normalize_tag_changes(
+            &[" research ".to_string(), "research".to_string(), "rust".to_string()],
+            &["todo".to_string()],
+        ).unwrap();
+        assert_eq!(add, vec!["research", "rust"]);
+        assert_eq!(remove, vec!["todo"]);
+
+        assert!(normalize_tag_changes(&[], &[]).is_err());
+        assert!(normalize_tag_changes(&["  ".to_string()], &[]).is_err());
+        assert!(normalize_tag_changes(&["a".repeat(MAX_TAG_LENGTH + 1)], &[]).is_err());
+        assert!(normalize_tag_changes(&["rust".to_string()], &[" rust".to_string()]).is_err());
+
+        let too_many: Vec<String> = (0..=MAX_TAGS_PER_REQUEST).map(|i| format!("tag{}", i)).collect();
+        assert!(normalize_tag_changes(&too_many, &[]).is_err());
+    }
+}
@@ -0,0 +1,130 @@
+use futures_util::future::BoxFuture;
+use redis::streams::{StreamId, StreamReadOptions, StreamReadReply};
+use redis::{AsyncCommands, RedisResult};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::IngestQueueConfig;
+
+/// 消息中存放历史记录JSON的字段名
+pub const PAYLOAD_FIELD: &str = "payload";
+
+/// 单条消息处理失败的原因
+#[derive(Debug)]
+pub enum HandleError {
+    /// 记录无效，重试也不会成功：确认后丢弃
+    Rejected(String),
+    /// 暂时无法写入（如ES不可用）：不确认，稍后重新投递
+    Retry(String),
+}
+
+/// 处理一条消息的 payload，与HTTP上报使用相同的校验、归一化和写入流程
+pub type MessageHandler = Arc<dyn Fn(Value) -> BoxFuture<'static, Result<(), HandleError>> + Send + Sync>;
+
+/// 启动后台消费者：从 Redis Stream 的消费组读取历史记录并写入，连接断开时自动重连
+pub fn spawn_consumer(config: IngestQueueConfig, handler: MessageHandler) {
+    tokio::spawn(async move {
+        let retry_delay = Duration::from_millis(config.retry_delay_ms);
+        loop {
+            if let Err(e) = consume(&config, &handler).await {
+                tracing::error!("Ingest queue consumer on {} failed, reconnecting: {}", config.stream, e);
+            }
+            tokio::time::sleep(retry_delay).await;
+        }
+    });
+}
+
+async fn consume(config: &IngestQueueConfig, handler: &MessageHandler) -> RedisResult<()> {
+    let client = redis::Client::open(config.url.as_str())?;
+    // 阻塞读取会占住连接，消费者使用独立连接，不与缓存共用
+    let mut connection = client.get_multiplexed_async_connection().await?;
+    ensure_group(&mut connection, config).await?;
+
+    let options = StreamReadOptions::default()
+        .group(&config.group, &config.consumer)
+        .count(config.batch_size)
+        .block(config.block_ms as usize);
+
+    // 先处理本消费者已读取但未确认的消息（上次退出或写入失败时留下的），处理完后再读取新消息
+    let mut read_id = "0";
+    loop {
+        let reply: StreamReadReply = connection
+            .xread_options(&[&config.stream], &[read_id], &options)
+            .await?;
+        let entries: Vec<StreamId> = reply.keys.into_iter().flat_map(|key| key.ids).collect();
+
+        if entries.is_empty() {
+            read_id = ">";
+            continue;
+        }
+
+        for entry in &entries {
+            let result = match parse_payload(entry) {
+                Ok(payload) => handler(payload).await,
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok(()) => {}
+                Err(HandleError::Rejected(reason)) => {
+                    tracing::warn!("Dropping queued history record {}: {}", entry.id, reason);
+                }
+                Err(HandleError::Retry(reason)) => {
+                    tracing::warn!("Failed to store queued history record {}, will retry: {}", entry.id, reason);
+                    read_id = "0";
+                    tokio::time::sleep(Duration::from_millis(config.retry_delay_ms)).await;
+                    break;
+                }
+            }
+
+            let _: i64 = connection.xack(&config.stream, &config.group, &[&entry.id]).await?;
+        }
+    }
+}
+
+/// 创建消费组（流不存在时一并创建），消费组已存在时忽略
+async fn ensure_group(connection: &mut redis::aio::MultiplexedConnection, config: &IngestQueueConfig) -> RedisResult<()> {
+    let created: RedisResult<()> = connection
+        .xgroup_create_mkstream(&config.stream, &config.group, "$")
+        .await;
+
+    match created {
+        Err(e) if e.code() == Some("BUSYGROUP") => Ok(()),
+        other => other,
+    }
+}
+
+/// 读取消息中的JSON记录
+fn parse_payload(entry: &StreamId) -> Result<Value, HandleError> {
+    let payload: String = entry
+        .get(PAYLOAD_FIELD)
+        .ok_or_else(|| HandleError::Rejected(format!("missing '{}' field", PAYLOAD_FIELD)))?;
+
+    serde_json::from_str(&payload).map_err(|e| HandleError::Rejected(format!("invalid JSON payload: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn entry(fields: &[(&str, &str)]) -> StreamId {
+        StreamId {
+            id: "1700000000000-0".to_string(),
+            map: fields
+                .iter()
+                .map(|(name, value)| (name.to_string(), redis::Value::Data(value.as_bytes().to_vec())))
+                .collect::<HashMap<_, _>>(),
+        }
+    }
+
+    #[test]
+    fn test_parse_payload() {
+        let payload = parse_payload(&entry(&[(PAYLOAD_FIELD, r#"{"original_url":"https://example.com","domain":"example.com"}"#)]));
+        assert_eq!(payload.unwrap()["domain"], "example.com");
+
+        assert!(matches!(parse_payload(&entry(&[])), Err(HandleError::Rejected(_))));
+        assert!(matches!(parse_payload(&entry(&[(PAYLOAD_FIELD, "not json")])), Err(HandleError::Rejected(_))));
+    }
+}
@@ -2,4 +2,18 @@ pub mod es;
 pub mod cache;
 pub mod redis_cache;
 pub mod database;
-pub mod url_normalizer; 
\ No newline at end of file
+pub mod url_normalizer;
+pub mod compaction;
+pub mod coalesce;
+pub mod query_builder;
+pub mod retention;
+pub mod optimizer;
+pub mod scroll;
+pub mod blocking_pool;
+pub mod ingest_queue;
+pub mod bulkhead;
+pub mod xlsx_export;
+pub mod rule_expiry;
+pub mod site;
+pub mod rule_sync;
+pub mod dump;
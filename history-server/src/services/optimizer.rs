@@ -0,0 +1,137 @@
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use elasticsearch::{
+    indices::{IndicesForcemergeParts, IndicesStatsParts},
+    Elasticsearch,
+    Error as ElasticsearchError,
+};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::OptimizerConfig;
+
+/// 索引的段与合并状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SegmentStats {
+    segments: u64,
+    deleted_docs: u64,
+    merges_in_progress: u64,
+}
+
+/// 启动定时任务：每天在配置的UTC小时对索引执行 only_expunge_deletes 的 force merge
+pub fn spawn_scheduler(client: Arc<Elasticsearch>, index: String, config: &OptimizerConfig) {
+    let hour_utc = config.hour_utc.min(23);
+
+    tokio::spawn(async move {
+        loop {
+            let wait = duration_until_hour(Utc::now(), hour_utc);
+            tracing::info!("Next index optimization of {} in {} minutes", index, wait.as_secs() / 60);
+            tokio::time::sleep(wait).await;
+
+            if let Err(e) = expunge_deletes(&client, &index).await {
+                tracing::error!("Index optimization of {} failed: {}", index, e);
+            }
+        }
+    });
+}
+
+/// 距离下一个整点 `hour_utc` 的时间，当前正好在该时刻时等到第二天
+fn duration_until_hour(now: DateTime<Utc>, hour_utc: u32) -> Duration {
+    let today = now
+        .date_naive()
+        .and_hms_opt(hour_utc, 0, 0)
+        .expect("hour_utc is clamped to 0-23")
+        .and_utc();
+    let next = if today > now { today } else { today + ChronoDuration::days(1) };
+
+    (next - now).to_std().unwrap_or_default()
+}
+
+/// 回收已删除文档占用的空间，已有合并在进行时跳过
+async fn expunge_deletes(client: &Elasticsearch, index: &str) -> Result<(), ElasticsearchError> {
+    let before = segment_stats(client, index).await?;
+    if before.merges_in_progress > 0 {
+        tracing::info!(
+            "Skipping optimization of {}: {} merges already in progress",
+            index, before.merges_in_progress
+        );
+        return Ok(());
+    }
+
+    tracing::info!(
+        "Optimizing {}: {} segments, {} deleted documents before merge",
+        index, before.segments, before.deleted_docs
+    );
+
+    client
+        .indices()
+        .forcemerge(IndicesForcemergeParts::Index(&[index]))
+        .only_expunge_deletes(true)
+        .send()
+        .await?
+        .error_for_status_code()?;
+
+    let after = segment_stats(client, index).await?;
+    tracing::info!(
+        "Optimized {}: {} -> {} segments, {} -> {} deleted documents",
+        index, before.segments, after.segments, before.deleted_docs, after.deleted_docs
+    );
+
+    Ok(())
+}
+
+async fn segment_stats(client: &Elasticsearch, index: &str) -> Result<SegmentStats, ElasticsearchError> {
+    let response = client
+        .indices()
+        .stats(IndicesStatsParts::IndexMetric(&[index], &["segments", "docs", "merge"]))
+        .send()
+        .await?
+        .error_for_status_code()?;
+
+    Ok(parse_segment_stats(&response.json::<Value>().await?))
+}
+
+fn parse_segment_stats(stats: &Value) -> SegmentStats {
+    let total = &stats["_all"]["total"];
+    SegmentStats {
+        segments: total["segments"]["count"].as_u64().unwrap_or(0),
+        deleted_docs: total["docs"]["deleted"].as_u64().unwrap_or(0),
+        merges_in_progress: total["merges"]["current"].as_u64().unwrap_or(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    #[test]
+    fn test_duration_until_hour() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 1, 30, 0).unwrap();
+        assert_eq!(duration_until_hour(now, 3), Duration::from_secs(90 * 60));
+
+        // 已过当天的执行时间，等到第二天
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 3, 0, 0).unwrap();
+        assert_eq!(duration_until_hour(now, 3), Duration::from_secs(24 * 3600));
+    }
+
+    #[test]
+    fn test_parse_segment_stats() {
+        let stats = json!({
+            "_all": {
+                "total": {
+                    "docs": { "count": 100, "deleted": 40 },
+                    "segments": { "count": 12 },
+                    "merges": { "current": 1 }
+                }
+            }
+        });
+
+        assert_eq!(parse_segment_stats(&stats), SegmentStats {
+            segments: 12,
+            deleted_docs: 40,
+            merges_in_progress: 1,
+        });
+    }
+}
@@ -0,0 +1,357 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::services::es::DEFAULT_RESOURCE_TYPE;
+
+/// 记录的归一化状态，用于查找需要重新处理的记录
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NormalizationStatus {
+    /// 带有 `normalization_failed: true` 标记
+    Failed,
+    /// 没有 `normalized_url` 字段（旧数据或仅查询时归一化模式写入的记录）
+    Missing,
+    /// 有 `normalized_url` 且没有失败标记
+    Ok,
+}
+
+impl NormalizationStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NormalizationStatus::Failed => "failed",
+            NormalizationStatus::Missing => "missing",
+            NormalizationStatus::Ok => "ok",
+        }
+    }
+}
+
+/// ES bool 查询构建器
+///
+/// 分别累积 `must`/`must_not`/`filter` 子句，序列化时只输出非空的部分，
+/// 没有任何子句时生成 `match_all`。
+#[derive(Debug, Clone, Default)]
+pub struct QueryBuilder {
+    must: Vec<Value>,
+    must_not: Vec<Value>,
+    filter: Vec<Value>,
+}
+
+impl QueryBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 添加参与评分的必须匹配子句
+    pub fn must(mut self, clause: Value) -> Self {
+        self.must.push(clause);
+        self
+    }
+
+    /// 添加必须不匹配的子句
+    pub fn must_not(mut self, clause: Value) -> Self {
+        self.must_not.push(clause);
+        self
+    }
+
+    /// 添加不参与评分的过滤子句
+    pub fn filter(mut self, clause: Value) -> Self {
+        self.filter.push(clause);
+        self
+    }
+
+    /// 关键词前缀匹配原始URL、归一化URL和域名，空关键词忽略
+    /// `legacy_url_field` 为 true 时同时匹配旧版文档的 `url` 字段（新文档只在 write_legacy_url_field 开启时写入该字段）
+    pub fn keyword(self, keyword: Option<&str>, legacy_url_field: bool) -> Self {
+        let mut fields = vec!["original_url", "normalized_url", "domain"];
+        if legacy_url_field {
+            fields.push("url");
+        }
+
+        match keyword.filter(|keyword| !keyword.is_empty()) {
+            Some(keyword) => self.must(json!({
+                "multi_match": {
+                    "query": keyword,
+                    "fields": fields,
+                    "type": "phrase_prefix"
+                }
+            })),
+            None => self,
+        }
+    }
+
+    /// 原始URL中任意位置包含该片段（不区分大小写），空片段忽略
+    /// 使用 keyword 字段上的 wildcard 查询，不需要额外的映射，但以 `*` 开头的通配需要扫描全部词项，
+    /// 比关键词搜索慢得多；数据量大时应为 original_url 增加 n-gram 子字段并改用 match_phrase
+    pub fn url_contains(self, fragment: Option<&str>) -> Self {
+        match fragment.filter(|fragment| !fragment.is_empty()) {
+            Some(fragment) => self.filter(json!({
+                "wildcard": {
+                    "original_url": {
+                        "value": format!("*{}*", escape_wildcard(fragment)),
+                        "case_insensitive": true
+                    }
+                }
+            })),
+            None => self,
+        }
+    }
+
+    /// 访问时的星期（ISO 编号，写入时预先计算的 `day_of_week` 字段），为 None 时忽略
+    pub fn weekdays(self, weekdays: Option<&[u32]>) -> Self {
+        match weekdays {
+            Some(weekdays) => self.filter(json!({ "terms": { "day_of_week": weekdays } })),
+            None => self,
+        }
+    }
+
+    /// 资源类型过滤，为 None 时忽略；包含默认的 document 时也匹配没有 resource_type 字段的旧记录
+    pub fn resource_types(self, resource_types: Option<&[String]>) -> Self {
+        let Some(resource_types) = resource_types else {
+            return self;
+        };
+        let terms = json!({ "terms": { "resource_type": resource_types } });
+
+        if resource_types.iter().any(|resource_type| resource_type == DEFAULT_RESOURCE_TYPE) {
+            self.filter(json!({
+                "bool": {
+                    "should": [
+                        terms,
+                        { "bool": { "must_not": [{ "exists": { "field": "resource_type" } }] } }
+                    ],
+                    "minimum_should_match": 1
+                }
+            }))
+        } else {
+            self.filter(terms)
+        }
+    }
+
+    /// 路径前缀匹配（写入时从URL解析的 `url_path` 字段），空前缀忽略
+    pub fn path_prefix(self, path_prefix: Option<&str>) -> Self {
+        match path_prefix.filter(|path_prefix| !path_prefix.is_empty()) {
+            Some(path_prefix) => self.filter(json!({ "prefix": { "url_path": path_prefix } })),
+            None => self,
+        }
+    }
+
+    /// 域名精确匹配（域名统一小写存储），空域名忽略
+    pub fn domain(self, domain: Option<&str>) -> Self {
+        match domain.filter(|domain| !domain.is_empty()) {
+            Some(domain) => self.must(json!({
+                "term": {
+                    "domain": domain.to_lowercase()
+                }
+            })),
+            None => self,
+        }
+    }
+
+    /// 时间范围（包含两端），两端都为空时忽略
+    pub fn timestamp_range(self, start: Option<&str>, end: Option<&str>) -> Self {
+        if start.is_none() && end.is_none() {
+            return self;
+        }
+
+        let mut bounds = Map::new();
+        if let Some(start) = start {
+            bounds.insert("gte".to_string(), json!(start));
+        }
+        if let Some(end) = end {
+            bounds.insert("lte".to_string(), json!(end));
+        }
+
+        self.must(json!({
+            "range": {
+                "timestamp": bounds
+            }
+        }))
+    }
+
+    /// 按归一化状态过滤，None 时忽略
+    pub fn normalization_status(self, status: Option<NormalizationStatus>) -> Self {
+        let failed = json!({ "term": { "normalization_failed": true } });
+        let has_normalized_url = json!({ "exists": { "field": "normalized_url" } });
+
+        match status {
+            Some(NormalizationStatus::Failed) => self.filter(failed),
+            Some(NormalizationStatus::Missing) => self.must_not(has_normalized_url),
+            Some(NormalizationStatus::Ok) => self.filter(has_normalized_url).must_not(failed),
+            None => self,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.must.is_empty() && self.must_not.is_empty() && self.filter.is_empty()
+    }
+
+    /// 生成查询（用于请求体的 `query` 字段）
+    pub fn build(self) -> Value {
+        if self.is_empty() {
+            return json!({ "match_all": {} });
+        }
+
+        let mut bool_query = Map::new();
+        for (name, clauses) in [("must", self.must), ("must_not", self.must_not), ("filter", self.filter)] {
+            if !clauses.is_empty() {
+                bool_query.insert(name.to_string(), Value::Array(clauses));
+            }
+        }
+
+        json!({ "bool": bool_query })
+    }
+}
+
+/// 转义 wildcard 查询中的特殊字符，使片段按字面匹配
+fn escape_wildcard(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '*' | '?' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_builder_is_match_all() {
+        assert_eq!(QueryBuilder::new().build(), json!({ "match_all": {} }));
+        assert_eq!(
+            QueryBuilder::new().keyword(Some(""), true).domain(None).timestamp_range(None, None).build(),
+            json!({ "match_all": {} })
+        );
+    }
+
+    #[test]
+    fn test_history_filters() {
+        let query = QueryBuilder::new()
+            .keyword(Some("rust"), false)
+            .domain(Some("Example.COM"))
+            .timestamp_range(Some("now-7d"), None)
+            .build();
+
+        assert_eq!(query, json!({
+            "bool": {
+                "must": [
+                    {
+                        "multi_match": {
+                            "query": "rust",
+                            "fields": ["original_url", "normalized_url", "domain"],
+                            "type": "phrase_prefix"
+                        }
+                    },
+                    { "term": { "domain": "example.com" } },
+                    { "range": { "timestamp": { "gte": "now-7d" } } }
+                ]
+            }
+        }));
+    }
+
+    #[test]
+    fn test_keyword_includes_legacy_url_field_only_when_enabled() {
+        let fields = |legacy_url_field| {
+            QueryBuilder::new().keyword(Some("rust"), legacy_url_field).build()["bool"]["must"][0]["multi_match"]["fields"].clone()
+        };
+
+        assert_eq!(fields(true), json!(["original_url", "normalized_url", "domain", "url"]));
+        assert_eq!(fields(false), json!(["original_url", "normalized_url", "domain"]));
+    }
+
+    #[test]
+    fn test_url_contains() {
+        assert_eq!(
+            QueryBuilder::new().url_contains(Some("my-article*")).build(),
+            json!({
+                "bool": {
+                    "filter": [{
+                        "wildcard": {
+                            "original_url": { "value": "*my-article\\**", "case_insensitive": true }
+                        }
+                    }]
+                }
+            })
+        );
+        assert_eq!(QueryBuilder::new().url_contains(Some("")).build(), QueryBuilder::new().build());
+    }
+
+    #[test]
+    fn test_resource_types() {
+        assert_eq!(
+            QueryBuilder::new().resource_types(Some(&["image".to_string()])).build(),
+            json!({ "bool": { "filter": [{ "terms": { "resource_type": ["image"] } }] } })
+        );
+        // 旧记录没有 resource_type，视为 document
+        assert_eq!(
+            QueryBuilder::new().resource_types(Some(&["document".to_string()])).build(),
+            json!({
+                "bool": {
+                    "filter": [{
+                        "bool": {
+                            "should": [
+                                { "terms": { "resource_type": ["document"] } },
+                                { "bool": { "must_not": [{ "exists": { "field": "resource_type" } }] } }
+                            ],
+                            "minimum_should_match": 1
+                        }
+                    }]
+                }
+            })
+        );
+        assert_eq!(QueryBuilder::new().resource_types(None).build(), json!({ "match_all": {} }));
+    }
+
+    #[test]
+    fn test_path_prefix() {
+        assert_eq!(
+            QueryBuilder::new().domain(Some("docs.example.com")).path_prefix(Some("/api/v2/")).build(),
+            json!({
+                "bool": {
+                    "must": [{ "term": { "domain": "docs.example.com" } }],
+                    "filter": [{ "prefix": { "url_path": "/api/v2/" } }]
+                }
+            })
+        );
+        assert_eq!(QueryBuilder::new().path_prefix(Some("")).build(), json!({ "match_all": {} }));
+    }
+
+    #[test]
+    fn test_normalization_status_filters() {
+        assert_eq!(
+            QueryBuilder::new().normalization_status(Some(NormalizationStatus::Missing)).build(),
+            json!({ "bool": { "must_not": [{ "exists": { "field": "normalized_url" } }] } })
+        );
+        assert_eq!(
+            QueryBuilder::new().normalization_status(Some(NormalizationStatus::Failed)).build(),
+            json!({ "bool": { "filter": [{ "term": { "normalization_failed": true } }] } })
+        );
+        assert_eq!(
+            QueryBuilder::new().normalization_status(Some(NormalizationStatus::Ok)).build(),
+            json!({
+                "bool": {
+                    "must_not": [{ "term": { "normalization_failed": true } }],
+                    "filter": [{ "exists": { "field": "normalized_url" } }]
+                }
+            })
+        );
+        assert_eq!(QueryBuilder::new().normalization_status(None).build(), json!({ "match_all": {} }));
+    }
+
+    #[test]
+    fn test_only_non_empty_sections_are_emitted() {
+        let query = QueryBuilder::new()
+            .filter(json!({ "exists": { "field": "normalized_url" } }))
+            .must_not(json!({ "term": { "domain": "ads.example.com" } }))
+            .build();
+
+        assert_eq!(query, json!({
+            "bool": {
+                "must_not": [{ "term": { "domain": "ads.example.com" } }],
+                "filter": [{ "exists": { "field": "normalized_url" } }]
+            }
+        }));
+    }
+}
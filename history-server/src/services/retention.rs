@@ -0,0 +1,238 @@
+use elasticsearch::{
+    params::Conflicts,
+    BulkOperation,
+    DeleteByQueryParts,
+    Elasticsearch,
+    Error as ElasticsearchError,
+    ScrollParts,
+    SearchParts,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+use crate::config::{PurgeStrategy, RetentionConfig};
+use crate::services::es;
+use crate::services::query_builder::QueryBuilder;
+use crate::services::scroll::ScrollGuard;
+
+/// 滚动查询上下文的保持时间
+const SCROLL_KEEP_ALIVE: &str = "1m";
+
+/// 清理结果
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PurgeOutcome {
+    pub deleted: u64,
+    pub failed: u64,
+}
+
+/// 早于保留期的记录
+fn expired_query(max_age_days: u32) -> Value {
+    QueryBuilder::new()
+        .filter(json!({
+            "range": {
+                "timestamp": { "lt": format!("now-{}d", max_age_days) }
+            }
+        }))
+        .build()
+}
+
+/// 指定域名的记录，可选地包含其所有子域名（域名统一小写存储）
+fn domain_query(domain: &str, include_subdomains: bool) -> Value {
+    let domain = domain.to_lowercase();
+    let clause = if include_subdomains {
+        json!({
+            "bool": {
+                "should": [
+                    { "term": { "domain": domain } },
+                    { "wildcard": { "domain": format!("*.{}", domain) } }
+                ],
+                "minimum_should_match": 1
+            }
+        })
+    } else {
+        json!({ "term": { "domain": domain } })
+    };
+
+    QueryBuilder::new().filter(clause).build()
+}
+
+/// 按配置的策略删除早于 `max_age_days` 的历史记录
+pub async fn purge_expired(
+    client: &Elasticsearch,
+    index: &str,
+    max_age_days: u32,
+    config: &RetentionConfig,
+) -> Result<PurgeOutcome, ElasticsearchError> {
+    purge(client, index, expired_query(max_age_days), config).await
+}
+
+/// 按配置的策略删除某个域名（可选包含子域名）的全部历史记录
+pub async fn purge_domain(
+    client: &Elasticsearch,
+    index: &str,
+    domain: &str,
+    include_subdomains: bool,
+    config: &RetentionConfig,
+) -> Result<PurgeOutcome, ElasticsearchError> {
+    purge(client, index, domain_query(domain, include_subdomains), config).await
+}
+
+async fn purge(
+    client: &Elasticsearch,
+    index: &str,
+    query: Value,
+    config: &RetentionConfig,
+) -> Result<PurgeOutcome, ElasticsearchError> {
+    match config.strategy {
+        PurgeStrategy::DeleteByQuery => purge_by_query(client, index, query).await,
+        PurgeStrategy::Bulk => {
+            purge_in_batches(
+                client,
+                index,
+                query,
+                config.batch_size.max(1),
+                Duration::from_millis(config.batch_pause_ms),
+            ).await
+        }
+    }
+}
+
+async fn purge_by_query(
+    client: &Elasticsearch,
+    index: &str,
+    query: Value,
+) -> Result<PurgeOutcome, ElasticsearchError> {
+    let response = client
+        .delete_by_query(DeleteByQueryParts::Index(&[index]))
+        .conflicts(Conflicts::Proceed)
+        .body(json!({ "query": query }))
+        .send()
+        .await?
+        .error_for_status_code()?;
+
+    let response_body = response.json::<Value>().await?;
+
+    Ok(PurgeOutcome {
+        deleted: response_body["deleted"].as_u64().unwrap_or(0),
+        failed: response_body["failures"].as_array().map(|failures| failures.len() as u64).unwrap_or(0),
+    })
+}
+
+/// 滚动读取过期记录ID并分批删除，批次之间暂停
+async fn purge_in_batches(
+    client: &Elasticsearch,
+    index: &str,
+    query: Value,
+    batch_size: usize,
+    pause: Duration,
+) -> Result<PurgeOutcome, ElasticsearchError> {
+    let mut outcome = PurgeOutcome::default();
+
+    let response = client
+        .search(SearchParts::Index(&[index]))
+        .scroll(SCROLL_KEEP_ALIVE)
+        .body(json!({
+            "query": query,
+            "size": batch_size,
+            "_source": false,
+            "sort": ["_doc"]
+        }))
+        .send()
+        .await?
+        .error_for_status_code()?;
+    let mut page = response.json::<Value>().await?;
+
+    // 结束、出错或请求被取消时都会释放滚动上下文
+    let mut scroll = ScrollGuard::new(client);
+    scroll.track(&page);
+
+    loop {
+        let ids = hit_ids(&page);
+        if ids.is_empty() {
+            return Ok(outcome);
+        }
+
+        let batch_len = ids.len() as u64;
+        let operations: Vec<BulkOperation<Value>> = ids
+            .into_iter()
+            .map(|id| BulkOperation::delete(id).into())
+            .collect();
+
+        let failed = es::bulk_operations(client, index, operations).await?.len() as u64;
+        outcome.failed += failed;
+        outcome.deleted += batch_len - failed;
+
+        tracing::info!("Retention purge progress: {} deleted, {} failed", outcome.deleted, outcome.failed);
+        tokio::time::sleep(pause).await;
+
+        let scroll_id = match scroll.scroll_id() {
+            Some(scroll_id) => scroll_id.to_string(),
+            None => return Ok(outcome),
+        };
+
+        page = client
+            .scroll(ScrollParts::None)
+            .body(json!({ "scroll": SCROLL_KEEP_ALIVE, "scroll_id": scroll_id }))
+            .send()
+            .await?
+            .error_for_status_code()?
+            .json::<Value>()
+            .await?;
+        scroll.track(&page);
+    }
+}
+
+fn hit_ids(page: &Value) -> Vec<String> {
+    page["hits"]["hits"].as_array()
+        .unwrap_or(&Vec::new())
+        .iter()
+        .filter_map(|hit| hit["_id"].as_str().map(str::to_string))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expired_query() {
+        assert_eq!(expired_query(30), json!({
+            "bool": {
+                "filter": [{ "range": { "timestamp": { "lt": "now-30d" } } }]
+            }
+        }));
+    }
+
+    #[test]
+    fn test_domain_query() {
+        assert_eq!(domain_query("Example.com", false), json!({
+            "bool": {
+                "filter": [{ "term": { "domain": "example.com" } }]
+            }
+        }));
+        assert_eq!(domain_query("example.com", true), json!({
+            "bool": {
+                "filter": [{
+                    "bool": {
+                        "should": [
+                            { "term": { "domain": "example.com" } },
+                            { "wildcard": { "domain": "*.example.com" } }
+                        ],
+                        "minimum_should_match": 1
+                    }
+                }]
+            }
+        }));
+    }
+
+    #[test]
+    fn test_hit_ids() {
+        let page = json!({
+            "_scroll_id": "abc",
+            "hits": { "hits": [{ "_id": "1" }, { "_id": "2" }] }
+        });
+        assert_eq!(hit_ids(&page), vec!["1".to_string(), "2".to_string()]);
+        assert!(hit_ids(&json!({ "hits": { "hits": [] } })).is_empty());
+    }
+}
@@ -0,0 +1,106 @@
+use futures_util::StreamExt;
+use redis::{AsyncCommands, RedisResult};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::services::url_normalizer::UrlNormalizer;
+
+/// 订阅断开后重新订阅前的等待时间
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// 多实例部署时通过 Redis 发布/订阅同步规则变更
+///
+/// 规则变更的实例刷新自己的缓存后发布一条通知（内容为本实例ID），其他实例收到后刷新各自的规则缓存。
+/// 通知丢失（如 Redis 暂时不可用）时其他实例仍会在规则缓存过期后加载新规则。
+#[derive(Clone)]
+pub struct RuleInvalidator {
+    client: redis::Client,
+    channel: String,
+    instance_id: String,
+}
+
+impl RuleInvalidator {
+    pub fn new(redis_url: &str, channel: &str) -> RedisResult<Self> {
+        Ok(Self {
+            client: redis::Client::open(redis_url)?,
+            channel: channel.to_string(),
+            instance_id: uuid::Uuid::new_v4().to_string(),
+        })
+    }
+
+    /// 通知其他实例刷新规则缓存，失败时只记录错误
+    pub async fn publish(&self) {
+        let result: RedisResult<i64> = async {
+            let mut connection = self.client.get_multiplexed_async_connection().await?;
+            connection.publish(&self.channel, &self.instance_id).await
+        }
+        .await;
+
+        match result {
+            Ok(receivers) => tracing::info!("Published rule invalidation to {} subscribers on {}", receivers, self.channel),
+            Err(e) => tracing::error!("Failed to publish rule invalidation on {}: {}", self.channel, e),
+        }
+    }
+
+    /// 启动后台订阅：收到其他实例的通知时刷新本实例的规则缓存，连接断开时自动重新订阅
+    pub fn spawn_subscriber(&self, url_normalizer: Arc<UrlNormalizer>) {
+        let invalidator = self.clone();
+        tokio::spawn(async move {
+            let mut resubscribing = false;
+            loop {
+                match invalidator.subscribe(&url_normalizer, resubscribing).await {
+                    Ok(()) => tracing::warn!("Rule invalidation subscription on {} closed, resubscribing", invalidator.channel),
+                    Err(e) => tracing::error!("Rule invalidation subscription on {} failed, resubscribing: {}", invalidator.channel, e),
+                }
+                resubscribing = true;
+                tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+            }
+        });
+    }
+
+    async fn subscribe(&self, url_normalizer: &UrlNormalizer, resubscribing: bool) -> RedisResult<()> {
+        let mut pubsub = self.client.get_async_pubsub().await?;
+        pubsub.subscribe(&self.channel).await?;
+        tracing::info!("✓ Subscribed to rule invalidations on {}", self.channel);
+
+        // 断开期间可能错过了通知，重新订阅后主动刷新一次
+        if resubscribing {
+            refresh(url_normalizer).await;
+        }
+
+        let mut messages = pubsub.on_message();
+        while let Some(message) = messages.next().await {
+            let sender: String = message.get_payload()?;
+            if self.is_from_other_instance(&sender) {
+                tracing::info!("Rule invalidation received from instance {}", sender);
+                refresh(url_normalizer).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// 本实例发布的通知不需要处理（发布前已经刷新过）
+    fn is_from_other_instance(&self, sender: &str) -> bool {
+        sender != self.instance_id
+    }
+}
+
+async fn refresh(url_normalizer: &UrlNormalizer) {
+    if let Err(e) = url_normalizer.refresh_rules_cache().await {
+        tracing::error!("Failed to refresh normalizer cache after rule invalidation: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ignores_own_invalidations() {
+        let invalidator = RuleInvalidator::new("redis://localhost:6379", "rules").unwrap();
+        let other = RuleInvalidator::new("redis://localhost:6379", "rules").unwrap();
+
+        assert!(!invalidator.is_from_other_instance(&invalidator.instance_id));
+        assert!(invalidator.is_from_other_instance(&other.instance_id));
+    }
+}
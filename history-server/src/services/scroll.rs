@@ -0,0 +1,109 @@
+use elasticsearch::{ClearScrollParts, Elasticsearch};
+use serde_json::{json, Value};
+
+/// 滚动查询上下文守卫
+///
+/// 被丢弃时释放ES上的滚动上下文，无论是正常结束、出错返回，还是客户端断开导致
+/// 请求的 future 被取消，都不会让上下文一直占用到超时。
+pub struct ScrollGuard {
+    scroll_id: Option<String>,
+    release: Option<Box<dyn FnOnce(String) + Send>>,
+}
+
+impl ScrollGuard {
+    pub fn new(client: &Elasticsearch) -> Self {
+        let client = client.clone();
+        Self::with_release(move |scroll_id| {
+            // Drop 中不能 await，交给运行时在后台释放
+            match tokio::runtime::Handle::try_current() {
+                Ok(handle) => {
+                    handle.spawn(async move {
+                        let result = client
+                            .clear_scroll(ClearScrollParts::None)
+                            .body(json!({ "scroll_id": [scroll_id] }))
+                            .send()
+                            .await;
+                        if let Err(e) = result {
+                            tracing::warn!("Failed to clear scroll context: {}", e);
+                        }
+                    });
+                }
+                Err(_) => tracing::warn!("No runtime available to clear scroll context, it will expire on its own"),
+            }
+        })
+    }
+
+    fn with_release(release: impl FnOnce(String) + Send + 'static) -> Self {
+        Self {
+            scroll_id: None,
+            release: Some(Box::new(release)),
+        }
+    }
+
+    /// 记录搜索/滚动响应中最新的 `_scroll_id`，ES 可能在滚动过程中返回新的ID
+    pub fn track(&mut self, page: &Value) {
+        if let Some(scroll_id) = page["_scroll_id"].as_str() {
+            self.scroll_id = Some(scroll_id.to_string());
+        }
+    }
+
+    pub fn scroll_id(&self) -> Option<&str> {
+        self.scroll_id.as_deref()
+    }
+}
+
+impl Drop for ScrollGuard {
+    fn drop(&mut self) {
+        if let (Some(scroll_id), Some(release)) = (self.scroll_id.take(), self.release.take()) {
+            release(scroll_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    fn recording_guard() -> (ScrollGuard, Arc<Mutex<Vec<String>>>) {
+        let released = Arc::new(Mutex::new(Vec::new()));
+        let sink = released.clone();
+        let guard = ScrollGuard::with_release(move |scroll_id| sink.lock().unwrap().push(scroll_id));
+        (guard, released)
+    }
+
+    #[test]
+    fn test_releases_latest_scroll_id_on_drop() {
+        let (mut guard, released) = recording_guard();
+        guard.track(&json!({ "_scroll_id": "first" }));
+        guard.track(&json!({ "hits": {} }));
+        guard.track(&json!({ "_scroll_id": "second" }));
+        drop(guard);
+
+        assert_eq!(*released.lock().unwrap(), vec!["second".to_string()]);
+    }
+
+    #[test]
+    fn test_nothing_to_release_without_scroll_id() {
+        let (guard, released) = recording_guard();
+        drop(guard);
+
+        assert!(released.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_releases_when_consumer_is_cancelled() {
+        let (guard, released) = recording_guard();
+
+        // 模拟客户端断开：滚动读取还在进行时 future 被丢弃
+        let export = async move {
+            let mut guard = guard;
+            guard.track(&json!({ "_scroll_id": "in-progress" }));
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+        };
+        let _ = tokio::time::timeout(Duration::from_millis(10), export).await;
+
+        assert_eq!(*released.lock().unwrap(), vec!["in-progress".to_string()]);
+    }
+}
@@ -0,0 +1,46 @@
+use publicsuffix::{List, Psl};
+
+/// 按公共后缀列表（Public Suffix List）计算可注册域名（eTLD+1），
+/// 如 `shop.example.com` 和 `www.example.com` 都属于站点 `example.com`，`a.example.co.uk` 属于 `example.co.uk`
+pub struct SiteResolver {
+    list: List,
+}
+
+impl SiteResolver {
+    /// 从 public_suffix_list.dat 的内容构建
+    pub fn parse(contents: &str) -> Result<Self, publicsuffix::Error> {
+        Ok(Self { list: contents.parse()? })
+    }
+
+    /// 从文件加载公共后缀列表
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(Self::parse(&contents)?)
+    }
+
+    /// 主机名对应的可注册域名，主机名本身就是公共后缀或无法识别时返回 None
+    pub fn registrable_domain(&self, host: &str) -> Option<String> {
+        let host = host.trim().trim_end_matches('.').to_lowercase();
+        let domain = self.list.domain(host.as_bytes())?;
+        std::str::from_utf8(domain.as_bytes()).ok().map(str::to_string)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolver() -> SiteResolver {
+        SiteResolver::parse("// ===BEGIN ICANN DOMAINS===\ncom\nuk\nco.uk\n// ===END ICANN DOMAINS===\n").unwrap()
+    }
+
+    #[test]
+    fn test_registrable_domain() {
+        let resolver = resolver();
+        assert_eq!(resolver.registrable_domain("shop.example.com").as_deref(), Some("example.com"));
+        assert_eq!(resolver.registrable_domain("WWW.Example.com").as_deref(), Some("example.com"));
+        assert_eq!(resolver.registrable_domain("example.com").as_deref(), Some("example.com"));
+        assert_eq!(resolver.registrable_domain("a.b.example.co.uk").as_deref(), Some("example.co.uk"));
+        assert_eq!(resolver.registrable_domain("co.uk"), None);
+    }
+}
@@ -1,72 +1,485 @@
 use regex::Regex;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::sync::Mutex;
 use std::collections::HashMap;
 use chrono::{DateTime, Utc};
-use tracing::{info, warn, error};
+use tracing::{debug, info, warn, error};
+use serde::Serialize;
 
-use crate::services::database::{DatabaseService, NormalizationRule};
+use crate::config::RuleMatchMode;
+use crate::services::blocking_pool::{BlockingPool, BlockingPoolError};
+use crate::services::database::{DatabaseService, NormalizationRule, TestRuleResponse, RULE_KIND_STRIP_PATH_PARAMS};
+
+/// 未配置时单条规则替换结果的最大长度（字节）
+pub const DEFAULT_MAX_OUTPUT_LENGTH: usize = 8192;
+
+/// 对单条规则替换结果的检查，不通过时视为该规则不匹配
+#[derive(Debug, Clone, Copy)]
+pub struct OutputCheck {
+    /// 最大长度（字节）
+    pub max_length: usize,
+    /// 结果必须仍是带主机名的绝对URL
+    pub require_valid_url: bool,
+}
+
+impl Default for OutputCheck {
+    fn default() -> Self {
+        Self { max_length: DEFAULT_MAX_OUTPUT_LENGTH, require_valid_url: false }
+    }
+}
+
+/// 替换结果是否为带主机名的绝对URL（丢掉协议或主机的结果会破坏按域名过滤）
+fn is_valid_absolute_url(url: &str) -> bool {
+    url::Url::parse(url).is_ok_and(|parsed| parsed.has_host())
+}
+
+/// URL归一化错误
+#[derive(Debug, thiserror::Error)]
+pub enum NormalizationError {
+    #[error("Invalid regex pattern '{pattern}': {source}")]
+    RegexCompile {
+        pattern: String,
+        #[source]
+        source: regex::Error,
+    },
+    #[error("Failed to load normalization rules: {0}")]
+    DatabaseUnavailable(#[from] sqlx::Error),
+    #[error("Invalid replacement '{replacement}': {reason}")]
+    InvalidReplacement {
+        replacement: String,
+        reason: String,
+    },
+    #[error("Invalid rule order: {0}")]
+    InvalidRuleOrder(String),
+    #[error("Batch normalization failed: {0}")]
+    BlockingPool(#[from] BlockingPoolError),
+}
+
+/// 编译规则的正则表达式，并校验替换串引用的捕获组都存在
+pub fn compile_rule(pattern: &str, replacement: &str) -> Result<Regex, NormalizationError> {
+    let regex = compile_pattern(pattern)?;
+    validate_replacement(&regex, replacement)?;
+    Ok(regex)
+}
+
+fn compile_pattern(pattern: &str) -> Result<Regex, NormalizationError> {
+    Regex::new(pattern).map_err(|source| NormalizationError::RegexCompile {
+        pattern: pattern.to_string(),
+        source,
+    })
+}
+
+/// 校验替换串中的 `$N`、`$name`、`${name}` 引用，regex在引用不存在的组时会静默替换为空串
+fn validate_replacement(regex: &Regex, replacement: &str) -> Result<(), NormalizationError> {
+    let invalid = |reason: String| NormalizationError::InvalidReplacement {
+        replacement: replacement.to_string(),
+        reason,
+    };
+
+    let mut rest = replacement;
+    while let Some(position) = rest.find('$') {
+        rest = &rest[position + 1..];
+
+        // `$$` 是字面量 `$`
+        if let Some(after) = rest.strip_prefix('$') {
+            rest = after;
+            continue;
+        }
+
+        let name = if let Some(braced) = rest.strip_prefix('{') {
+            let end = braced.find('}').ok_or_else(|| invalid("unterminated '${'".to_string()))?;
+            rest = &braced[end + 1..];
+            &braced[..end]
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let name = &rest[..end];
+            rest = &rest[end..];
+            name
+        };
+
+        if name.is_empty() {
+            continue;
+        }
+
+        let exists = match name.parse::<usize>() {
+            Ok(group) => group < regex.captures_len(),
+            Err(_) => regex.capture_names().flatten().any(|group| group == name),
+        };
+
+        if !exists {
+            return Err(invalid(format!("references capture group '{}' which does not exist in the pattern", name)));
+        }
+    }
+
+    Ok(())
+}
 
 /// URL归一化服务
 /// 负责根据数据库中的规则对URL进行归一化处理
 pub struct UrlNormalizer {
     db: Arc<DatabaseService>,
-    /// 缓存编译后的正则表达式，避免重复编译
-    regex_cache: Arc<Mutex<HashMap<i32, (Regex, String, DateTime<Utc>)>>>,
+    /// 缓存编译后的正则表达式，按模式字符串索引，相同模式的规则共享同一个 Regex
+    regex_cache: Arc<Mutex<HashMap<String, (Regex, DateTime<Utc>)>>>,
     /// 缓存规则列表，减少数据库查询
     rules_cache: Arc<Mutex<Option<(Vec<NormalizationRule>, DateTime<Utc>)>>>,
     /// 缓存过期时间（秒）
     cache_ttl_seconds: u64,
+    /// 多条规则匹配时的处理方式
+    match_mode: RuleMatchMode,
+    /// 是否把归一化失败写入 normalization_failures 表
+    record_failures: bool,
+    /// 大批量归一化使用的专用线程池
+    blocking_pool: Option<Arc<BlockingPool>>,
+    /// 批量达到该数量时才交给线程池，小批量直接在当前任务中处理
+    parallel_threshold: usize,
+    /// 单条规则替换结果的检查（最大长度、是否仍为有效URL）
+    output_check: OutputCheck,
+    /// 正则缓存和规则缓存的命中/未命中次数，用于评估缓存TTL是否合适
+    regex_hits: AtomicU64,
+    regex_misses: AtomicU64,
+    rules_hits: AtomicU64,
+    rules_misses: AtomicU64,
+}
+
+/// 归一化缓存的状态和命中统计（自进程启动以来）
+#[derive(Debug, Clone, Serialize)]
+pub struct NormalizerCacheStats {
+    pub regex_cache_size: usize,
+    pub rules_cached: bool,
+    pub regex_hits: u64,
+    pub regex_misses: u64,
+    /// 还没有任何查找时为 None
+    pub regex_hit_ratio: Option<f64>,
+    pub rules_hits: u64,
+    pub rules_misses: u64,
+    pub rules_hit_ratio: Option<f64>,
+}
+
+fn hit_ratio(hits: u64, misses: u64) -> Option<f64> {
+    let total = hits + misses;
+    if total == 0 {
+        None
+    } else {
+        Some(hits as f64 / total as f64)
+    }
 }
 
 #[derive(Debug)]
 pub struct NormalizationResult {
     pub original_url: String,
     pub normalized_url: String,
+    /// 第一个生效的规则
     pub applied_rule: Option<NormalizationRule>,
+    /// 按应用顺序排列的所有生效规则ID（first_match 模式下最多一个）
+    pub applied_rule_ids: Vec<i32>,
     pub matched: bool,
+    /// 应用失败而被跳过的规则及错误信息
+    pub rule_errors: Vec<(i32, String)>,
+}
+
+/// 规则编译失败信息，用于缓存刷新时报告损坏的规则
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleCompileError {
+    pub rule_id: i32,
+    pub error: String,
+}
+
+/// 去掉路径各段中 `;` 之后的矩阵参数（如 `/app;jsessionid=ABC/page`），路径中没有参数时返回 None
+pub fn strip_path_params(url: &str) -> Option<String> {
+    let mut parsed = url::Url::parse(url).ok()?;
+    if !parsed.path().contains(';') {
+        return None;
+    }
+
+    let stripped: Vec<&str> = parsed
+        .path()
+        .split('/')
+        .map(|segment| segment.split(';').next().unwrap_or(segment))
+        .collect();
+    let stripped = stripped.join("/");
+    parsed.set_path(&stripped);
+
+    Some(parsed.to_string())
+}
+
+/// 将URL中的国际化域名转换为 punycode（如 `münchen.de` -> `xn--mnchen-3ya.de`），
+/// 使同一域名无论浏览器上报的是 Unicode 还是 punycode 都得到相同的结果；在规则匹配之前调用
+/// 只改写主机部分，主机为纯ASCII或无法转换时原样返回
+pub fn canonicalize_host(url: &str) -> String {
+    let Some(scheme_end) = url.find("://") else {
+        return url.to_string();
+    };
+    let authority_start = scheme_end + 3;
+    let authority_end = url[authority_start..]
+        .find(['/', '?', '#'])
+        .map_or(url.len(), |offset| authority_start + offset);
+    let authority = &url[authority_start..authority_end];
+
+    // 去掉用户信息和端口（IDN主机不会是 IPv6 字面量）
+    let host_start = authority.rfind('@').map_or(0, |at| at + 1);
+    let host_end = authority[host_start..].find(':').map_or(authority.len(), |colon| host_start + colon);
+    let host = &authority[host_start..host_end];
+    if host.is_ascii() {
+        return url.to_string();
+    }
+
+    match idna::domain_to_ascii(host) {
+        Ok(ascii_host) => format!(
+            "{}{}{}",
+            &url[..authority_start + host_start],
+            ascii_host,
+            &url[authority_start + host_end..]
+        ),
+        Err(_) => {
+            warn!("Failed to convert host '{}' to punycode", host);
+            url.to_string()
+        }
+    }
+}
+
+/// 用已编译的正则应用单个规则，不匹配时返回 None
+/// 替换结果超过最大长度（如重复的分组引用把短URL放大），或启用了URL校验而结果不再是绝对URL时，视为不匹配
+fn apply_compiled_rule(url: &str, rule: &NormalizationRule, regex: &Regex, check: OutputCheck) -> Option<String> {
+    // 内置规则：pattern 只用于限定适用的URL
+    if rule.kind == RULE_KIND_STRIP_PATH_PARAMS {
+        return if regex.is_match(url) { strip_path_params(url) } else { None };
+    }
+
+    let result = regex.replace(url, &rule.replacement);
+
+    // 如果结果与原URL相同，说明没有匹配
+    if result == url {
+        None
+    } else if result.len() > check.max_length {
+        warn!(
+            "Rule {} output is {} bytes, exceeding the limit of {}; treating as no match",
+            rule.id, result.len(), check.max_length
+        );
+        None
+    } else if check.require_valid_url && !is_valid_absolute_url(&result) {
+        warn!("Rule {} produced an invalid URL '{}' from '{}'; treating as no match", rule.id, result, url);
+        None
+    } else {
+        Some(result.to_string())
+    }
+}
+
+/// 同步归一化，规则须已按顺序过滤为启用的规则，供线程池使用
+fn normalize_compiled(
+    original_url: &str,
+    compiled: &[(NormalizationRule, Regex)],
+    match_mode: RuleMatchMode,
+    check: OutputCheck,
+) -> String {
+    let mut current_url = canonicalize_host(original_url);
+
+    for (rule, regex) in compiled {
+        if let Some(normalized_url) = apply_compiled_rule(&current_url, rule, regex, check) {
+            current_url = normalized_url;
+            if match_mode == RuleMatchMode::FirstMatch {
+                break;
+            }
+        }
+    }
+
+    current_url
+}
+
+/// 重排序预览中单个URL的差异
+#[derive(Debug, Clone, Serialize)]
+pub struct ReorderDiff {
+    pub url: String,
+    pub current_normalized_url: String,
+    pub proposed_normalized_url: String,
+    pub current_rule_ids: Vec<i32>,
+    pub proposed_rule_ids: Vec<i32>,
+}
+
+/// 同一URL被多条启用的规则匹配时，每条规则单独应用的结果
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleOutput {
+    pub rule_id: i32,
+    pub normalized_url: String,
+}
+
+/// 规则冲突：按当前顺序第一条规则生效，其余规则被遮蔽
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleConflict {
+    pub url: String,
+    /// 按规则顺序排列，第一个为 first_match 模式下生效的规则
+    pub matches: Vec<RuleOutput>,
+    /// 各规则的结果是否不同（相同时只是冗余，不同时顺序决定了结果）
+    pub differing: bool,
+}
+
+/// 按建议的ID顺序排列规则，建议顺序必须恰好包含每条现有规则一次
+fn reorder_rules(rules: &[NormalizationRule], proposed_order: &[i32]) -> Result<Vec<NormalizationRule>, NormalizationError> {
+    let by_id: HashMap<i32, &NormalizationRule> = rules.iter().map(|rule| (rule.id, rule)).collect();
+    let mut reordered = Vec::with_capacity(rules.len());
+    let mut seen = std::collections::HashSet::new();
+
+    for id in proposed_order {
+        let rule = by_id.get(id)
+            .ok_or_else(|| NormalizationError::InvalidRuleOrder(format!("unknown rule id {}", id)))?;
+        if !seen.insert(*id) {
+            return Err(NormalizationError::InvalidRuleOrder(format!("rule id {} listed more than once", id)));
+        }
+        reordered.push((*rule).clone());
+    }
+
+    if reordered.len() != rules.len() {
+        let missing: Vec<String> = rules.iter()
+            .filter(|rule| !seen.contains(&rule.id))
+            .map(|rule| rule.id.to_string())
+            .collect();
+        return Err(NormalizationError::InvalidRuleOrder(format!("missing rule ids {}", missing.join(", "))));
+    }
+
+    Ok(reordered)
+}
+
+/// 用候选规则测试一个URL，附带第一个匹配的捕获组和展开后的替换串
+fn apply_test_rule(regex: &Regex, replacement: &str, url: &str) -> TestRuleResponse {
+    let normalized_url = regex.replace(url, replacement).into_owned();
+    let (captures, expanded_replacement) = match regex.captures(url) {
+        Some(groups) => {
+            let mut expanded = String::new();
+            groups.expand(replacement, &mut expanded);
+            let captures = groups.iter().map(|group| group.map(|group| group.as_str().to_string())).collect();
+            (captures, Some(expanded))
+        }
+        None => (Vec::new(), None),
+    };
+
+    TestRuleResponse {
+        matched: normalized_url != url,
+        original_url: url.to_string(),
+        normalized_url,
+        captures,
+        expanded_replacement,
+    }
 }
 
 impl UrlNormalizer {
-    pub fn new(db: Arc<DatabaseService>) -> Self {
+    pub fn new(db: Arc<DatabaseService>, match_mode: RuleMatchMode) -> Self {
         Self {
             db,
             regex_cache: Arc::new(Mutex::new(HashMap::new())),
             rules_cache: Arc::new(Mutex::new(None)),
             cache_ttl_seconds: 300, // 5分钟缓存
+            match_mode,
+            record_failures: false,
+            blocking_pool: None,
+            parallel_threshold: usize::MAX,
+            output_check: OutputCheck::default(),
+            regex_hits: AtomicU64::new(0),
+            regex_misses: AtomicU64::new(0),
+            rules_hits: AtomicU64::new(0),
+            rules_misses: AtomicU64::new(0),
         }
     }
 
+    /// 设置单条规则替换结果的最大长度
+    pub fn with_max_output_length(mut self, max_output_length: usize) -> Self {
+        self.output_check.max_length = max_output_length;
+        self
+    }
+
+    /// 要求规则替换结果仍是带主机名的绝对URL，否则视为不匹配
+    pub fn with_output_url_validation(mut self, enabled: bool) -> Self {
+        self.output_check.require_valid_url = enabled;
+        self
+    }
+
+    /// 使用专用线程池处理不少于 threshold 个URL的批量归一化
+    pub fn with_blocking_pool(mut self, pool: Arc<BlockingPool>, threshold: usize) -> Self {
+        self.blocking_pool = Some(pool);
+        self.parallel_threshold = threshold.max(1);
+        self
+    }
+
+    /// 把归一化失败持久化到数据库，便于之后排查和重新处理
+    pub fn with_failure_log(mut self, enabled: bool) -> Self {
+        self.record_failures = enabled;
+        self
+    }
+
     /// 归一化单个URL
-    /// 按照规则顺序依次尝试，第一个匹配的规则生效
+    /// 按照规则顺序依次尝试，first_match 模式下第一个匹配的规则生效，chain 模式下依次应用所有匹配的规则
     pub async fn normalize_url(&self, original_url: &str) -> String {
+        self.try_normalize_url(original_url)
+            .await
+            .unwrap_or_else(|| original_url.to_string())
+    }
+
+    /// 与 `normalize_url` 相同，但整体归一化出错（如规则无法加载）时返回 None，失败已记录
+    pub async fn try_normalize_url(&self, original_url: &str) -> Option<String> {
         match self.normalize_url_detailed(original_url).await {
-            Ok(result) => result.normalized_url,
+            Ok(result) => {
+                for (rule_id, error) in &result.rule_errors {
+                    self.record_failure(original_url, Some(*rule_id), error);
+                }
+                Some(result.normalized_url)
+            }
             Err(e) => {
                 error!("Failed to normalize URL {}: {}", original_url, e);
-                original_url.to_string()
+                self.record_failure(original_url, None, &e.to_string());
+                None
             }
         }
     }
 
+    /// 异步写入失败记录，不阻塞归一化；数据库不可用时只记录日志
+    fn record_failure(&self, url: &str, rule_id: Option<i32>, error: &str) {
+        if !self.record_failures {
+            return;
+        }
+
+        let db = self.db.clone();
+        let (url, error) = (url.to_string(), error.to_string());
+        tokio::spawn(async move {
+            if let Err(e) = db.record_normalization_failure(&url, rule_id, &error).await {
+                warn!("Failed to record normalization failure for {}: {}", url, e);
+            }
+        });
+    }
+
     /// 详细的归一化处理，返回完整结果
-    pub async fn normalize_url_detailed(&self, original_url: &str) -> Result<NormalizationResult, Box<dyn std::error::Error + Send + Sync>> {
+    pub async fn normalize_url_detailed(&self, original_url: &str) -> Result<NormalizationResult, NormalizationError> {
         let rules = self.get_cached_rules().await?;
-        
+        self.normalize_with_rules(original_url, &rules).await
+    }
+
+    /// 按给定的规则顺序归一化URL
+    async fn normalize_with_rules(&self, original_url: &str, rules: &[NormalizationRule]) -> Result<NormalizationResult, NormalizationError> {
+        let mut current_url = canonicalize_host(original_url);
+        let mut applied_rule: Option<NormalizationRule> = None;
+        let mut applied_rule_ids = Vec::new();
+        let mut rule_errors = Vec::new();
+
         for rule in rules.iter() {
             if !rule.enabled {
                 continue;
             }
 
-            match self.apply_rule(original_url, rule).await {
+            match self.apply_rule(&current_url, rule).await {
                 Ok(Some(normalized_url)) => {
-                    info!("URL normalized: {} -> {} (rule: {})", original_url, normalized_url, rule.id);
-                    return Ok(NormalizationResult {
-                        original_url: original_url.to_string(),
-                        normalized_url,
-                        applied_rule: Some(rule.clone()),
-                        matched: true,
-                    });
+                    info!("URL normalized: {} -> {} (rule: {})", current_url, normalized_url, rule.id);
+                    if tracing::enabled!(tracing::Level::DEBUG) {
+                        self.log_normalization_diff(&current_url, &normalized_url, rule).await;
+                    }
+                    current_url = normalized_url;
+                    applied_rule_ids.push(rule.id);
+                    if applied_rule.is_none() {
+                        applied_rule = Some(rule.clone());
+                    }
+
+                    if self.match_mode == RuleMatchMode::FirstMatch {
+                        break;
+                    }
                 }
                 Ok(None) => {
                     // 规则不匹配，继续下一个
@@ -74,22 +487,57 @@ impl UrlNormalizer {
                 }
                 Err(e) => {
                     warn!("Rule {} failed to apply: {}", rule.id, e);
+                    rule_errors.push((rule.id, e.to_string()));
                     continue;
                 }
             }
         }
 
-        // 没有规则匹配，返回原URL
+        // 没有规则匹配时返回原URL
         Ok(NormalizationResult {
             original_url: original_url.to_string(),
-            normalized_url: original_url.to_string(),
-            applied_rule: None,
-            matched: false,
+            normalized_url: current_url,
+            matched: applied_rule.is_some(),
+            applied_rule,
+            applied_rule_ids,
+            rule_errors,
         })
     }
 
+    /// 调试日志：规则匹配的位置、被替换的子串和替换结果，只在 debug 级别启用时计算
+    async fn log_normalization_diff(&self, before: &str, after: &str, rule: &NormalizationRule) {
+        // 规则刚刚应用过，正则已在缓存中；直接读取缓存，不计入命中统计
+        let Some(regex) = self.regex_cache.lock().await.get(&rule.pattern).map(|(regex, _)| regex.clone()) else {
+            return;
+        };
+        let Some(found) = regex.find(before) else {
+            return;
+        };
+
+        debug!(
+            rule_id = rule.id,
+            rule_kind = %rule.kind,
+            original = before,
+            normalized = after,
+            match_start = found.start(),
+            match_end = found.end(),
+            replaced = found.as_str(),
+            "Normalization diff"
+        );
+    }
+
     /// 批量归一化URL
+    /// 配置了线程池且批量较大时在线程池中并行处理，线程池不可用时退回逐个处理
     pub async fn normalize_urls(&self, original_urls: Vec<String>) -> Vec<String> {
+        if let Some(pool) = &self.blocking_pool {
+            if original_urls.len() >= self.parallel_threshold {
+                match self.normalize_urls_on_pool(pool, &original_urls).await {
+                    Ok(results) => return results,
+                    Err(e) => warn!("Falling back to sequential normalization: {}", e),
+                }
+            }
+        }
+
         let mut results = Vec::with_capacity(original_urls.len());
         
         for url in original_urls {
@@ -100,57 +548,93 @@ impl UrlNormalizer {
         results
     }
 
+    /// 预先取出规则和编译好的正则，按块分给线程池同步处理，结果保持输入顺序
+    async fn normalize_urls_on_pool(&self, pool: &Arc<BlockingPool>, original_urls: &[String]) -> Result<Vec<String>, NormalizationError> {
+        let rules = self.get_cached_rules().await?;
+        let mut compiled = Vec::with_capacity(rules.len());
+        for rule in rules.into_iter().filter(|rule| rule.enabled) {
+            match self.get_cached_regex(&rule).await {
+                Ok(regex) => compiled.push((rule, regex)),
+                Err(e) => warn!("Rule {} failed to apply: {}", rule.id, e),
+            }
+        }
+        let compiled = Arc::new(compiled);
+        let (match_mode, output_check) = (self.match_mode, self.output_check);
+
+        let chunk_size = original_urls.len().div_ceil(pool.threads()).max(1);
+        let jobs = original_urls.chunks(chunk_size).map(|chunk| {
+            let chunk = chunk.to_vec();
+            let compiled = compiled.clone();
+            pool.run(move || {
+                chunk.iter()
+                    .map(|url| normalize_compiled(url, &compiled, match_mode, output_check))
+                    .collect::<Vec<String>>()
+            })
+        });
+
+        let mut results = Vec::with_capacity(original_urls.len());
+        for chunk_results in futures_util::future::join_all(jobs).await {
+            results.extend(chunk_results?);
+        }
+
+        Ok(results)
+    }
+
     /// 应用单个规则
-    async fn apply_rule(&self, url: &str, rule: &NormalizationRule) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+    async fn apply_rule(&self, url: &str, rule: &NormalizationRule) -> Result<Option<String>, NormalizationError> {
         let regex = self.get_cached_regex(rule).await?;
-        
-        let result = regex.replace(url, &rule.replacement);
-        
-        // 如果结果与原URL相同，说明没有匹配
-        if result == url {
-            Ok(None)
-        } else {
-            Ok(Some(result.to_string()))
-        }
+        Ok(apply_compiled_rule(url, rule, &regex, self.output_check))
     }
 
     /// 获取缓存的正则表达式
-    async fn get_cached_regex(&self, rule: &NormalizationRule) -> Result<Regex, Box<dyn std::error::Error + Send + Sync>> {
-        let mut cache = self.regex_cache.lock().await;
-        
-        // 检查缓存中是否有该规则的正则表达式
-        if let Some((regex, cached_pattern, cached_time)) = cache.get(&rule.id) {
-            // 检查模式是否变化或缓存是否过期
-            if cached_pattern == &rule.pattern && 
-               (Utc::now() - *cached_time).num_seconds() < self.cache_ttl_seconds as i64 {
-                return Ok(regex.clone());
+    async fn get_cached_regex(&self, rule: &NormalizationRule) -> Result<Regex, NormalizationError> {
+        let regex = {
+            let mut cache = self.regex_cache.lock().await;
+
+            // 检查缓存中是否有该模式的正则表达式，且缓存未过期；
+            // 规则在缓存之后被修改过（updated_at 更新）时也重新编译，保证数据库中的任何变更都会生效
+            match cache.get(&rule.pattern) {
+                Some((regex, cached_time))
+                    if (Utc::now() - *cached_time).num_seconds() < self.cache_ttl_seconds as i64
+                        && rule.updated_at <= *cached_time => {
+                    self.regex_hits.fetch_add(1, Ordering::Relaxed);
+                    regex.clone()
+                }
+                _ => {
+                    self.regex_misses.fetch_add(1, Ordering::Relaxed);
+                    // 编译新的正则表达式并更新缓存
+                    let regex = compile_pattern(&rule.pattern)?;
+                    cache.insert(rule.pattern.clone(), (regex.clone(), Utc::now()));
+                    regex
+                }
             }
+        };
+
+        // 替换串因规则而异，共享模式时也要逐条校验；内置规则不使用替换串
+        if rule.kind != RULE_KIND_STRIP_PATH_PARAMS {
+            validate_replacement(&regex, &rule.replacement)?;
         }
 
-        // 编译新的正则表达式
-        let regex = Regex::new(&rule.pattern)
-            .map_err(|e| format!("Invalid regex pattern '{}': {}", rule.pattern, e))?;
-        
-        // 更新缓存
-        cache.insert(rule.id, (regex.clone(), rule.pattern.clone(), Utc::now()));
-        
         Ok(regex)
     }
 
     /// 获取缓存的规则列表
-    async fn get_cached_rules(&self) -> Result<Vec<NormalizationRule>, Box<dyn std::error::Error + Send + Sync>> {
+    async fn get_cached_rules(&self) -> Result<Vec<NormalizationRule>, NormalizationError> {
         let mut cache = self.rules_cache.lock().await;
         
         // 检查缓存是否有效
         if let Some((rules, cached_time)) = cache.as_ref() {
-            if (Utc::now() - *cached_time).num_seconds() < self.cache_ttl_seconds as i64 {
-                return Ok(rules.clone());
+            let now = Utc::now();
+            if (now - *cached_time).num_seconds() < self.cache_ttl_seconds as i64 {
+                self.rules_hits.fetch_add(1, Ordering::Relaxed);
+                // 缓存期间过期的规则立即失效，不等缓存刷新
+                return Ok(rules.iter().filter(|rule| !rule.is_expired(now)).cloned().collect());
             }
         }
+        self.rules_misses.fetch_add(1, Ordering::Relaxed);
 
         // 从数据库获取最新规则
-        let rules = self.db.get_normalization_rules().await
-            .map_err(|e| format!("Failed to load normalization rules: {}", e))?;
+        let rules = self.db.get_normalization_rules().await?;
         
         info!("Loaded {} normalization rules from database", rules.len());
         
@@ -161,41 +645,116 @@ impl UrlNormalizer {
     }
 
     /// 刷新规则缓存
-    /// 在规则变更时调用，强制重新加载
-    pub async fn refresh_rules_cache(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let mut rules_cache = self.rules_cache.lock().await;
-        let mut regex_cache = self.regex_cache.lock().await;
-        
-        // 清除缓存
-        *rules_cache = None;
-        regex_cache.clear();
-        
-        info!("Normalization rules cache refreshed");
-        Ok(())
+    /// 在规则变更时调用，强制重新加载并预编译所有启用的规则
+    /// 返回编译失败的规则列表，便于立即发现损坏的规则
+    pub async fn refresh_rules_cache(&self) -> Result<Vec<RuleCompileError>, NormalizationError> {
+        {
+            let mut rules_cache = self.rules_cache.lock().await;
+            let mut regex_cache = self.regex_cache.lock().await;
+
+            // 清除缓存
+            *rules_cache = None;
+            regex_cache.clear();
+        }
+
+        // 重新加载规则并预编译正则表达式
+        let rules = self.get_cached_rules().await?;
+        let mut broken_rules = Vec::new();
+
+        for rule in rules.iter().filter(|rule| rule.enabled) {
+            if let Err(e) = self.get_cached_regex(rule).await {
+                warn!("Rule {} failed to compile: {}", rule.id, e);
+                broken_rules.push(RuleCompileError {
+                    rule_id: rule.id,
+                    error: e.to_string(),
+                });
+            }
+        }
+
+        info!("Normalization rules cache refreshed ({} rules, {} broken)", rules.len(), broken_rules.len());
+        Ok(broken_rules)
+    }
+
+    /// 用候选规则批量测试URL，按输入顺序返回每个URL的前后对比
+    pub fn test_rule_on_urls(pattern: &str, replacement: &str, urls: &[String]) -> Result<Vec<TestRuleResponse>, NormalizationError> {
+        let regex = compile_rule(pattern, replacement)?;
+
+        Ok(urls.iter().map(|url| apply_test_rule(&regex, replacement, url)).collect())
     }
 
     /// 测试规则
-    pub async fn test_rule(&self, pattern: &str, replacement: &str, test_url: &str) -> Result<NormalizationResult, Box<dyn std::error::Error + Send + Sync>> {
-        let regex = Regex::new(pattern)
-            .map_err(|e| format!("Invalid regex pattern '{}': {}", pattern, e))?;
-        
-        let result = regex.replace(test_url, replacement);
-        let matched = result != test_url;
-        
-        Ok(NormalizationResult {
-            original_url: test_url.to_string(),
-            normalized_url: result.to_string(),
-            applied_rule: None, // 测试时不返回具体规则
-            matched,
-        })
+    /// 用候选规则测试单个URL，并返回第一个匹配的捕获组和展开后的替换串，便于调试多捕获组的规则
+    pub async fn test_rule(&self, pattern: &str, replacement: &str, test_url: &str) -> Result<TestRuleResponse, NormalizationError> {
+        let regex = compile_rule(pattern, replacement)?;
+        Ok(apply_test_rule(&regex, replacement, test_url))
+    }
+
+    /// 预览规则重排序的影响
+    /// 分别按当前顺序和建议顺序归一化样例URL，只返回结果或生效规则发生变化的URL
+    pub async fn simulate_reorder(&self, proposed_order: &[i32], urls: &[String]) -> Result<Vec<ReorderDiff>, NormalizationError> {
+        let current_rules = self.get_cached_rules().await?;
+        let proposed_rules = reorder_rules(&current_rules, proposed_order)?;
+
+        let mut diffs = Vec::new();
+        for url in urls {
+            let current = self.normalize_with_rules(url, &current_rules).await?;
+            let proposed = self.normalize_with_rules(url, &proposed_rules).await?;
+
+            if current.normalized_url != proposed.normalized_url || current.applied_rule_ids != proposed.applied_rule_ids {
+                diffs.push(ReorderDiff {
+                    url: url.clone(),
+                    current_normalized_url: current.normalized_url,
+                    proposed_normalized_url: proposed.normalized_url,
+                    current_rule_ids: current.applied_rule_ids,
+                    proposed_rule_ids: proposed.applied_rule_ids,
+                });
+            }
+        }
+
+        Ok(diffs)
+    }
+
+    /// 找出被多条启用规则同时匹配的URL，每条规则都作用于原始URL以便比较各自的结果
+    pub async fn find_conflicts(&self, urls: &[String]) -> Result<Vec<RuleConflict>, NormalizationError> {
+        let rules = self.get_cached_rules().await?;
+
+        let mut conflicts = Vec::new();
+        for url in urls {
+            let mut matches = Vec::new();
+            for rule in rules.iter().filter(|rule| rule.enabled) {
+                match self.apply_rule(url, rule).await {
+                    Ok(Some(normalized_url)) => matches.push(RuleOutput { rule_id: rule.id, normalized_url }),
+                    Ok(None) => {}
+                    Err(e) => warn!("Rule {} failed to apply: {}", rule.id, e),
+                }
+            }
+
+            if matches.len() > 1 {
+                let differing = matches.iter().any(|output| output.normalized_url != matches[0].normalized_url);
+                conflicts.push(RuleConflict { url: url.clone(), matches, differing });
+            }
+        }
+
+        Ok(conflicts)
     }
 
     /// 获取缓存统计信息
-    pub async fn get_cache_stats(&self) -> (usize, bool) {
-        let regex_cache = self.regex_cache.lock().await;
-        let rules_cache = self.rules_cache.lock().await;
-        
-        (regex_cache.len(), rules_cache.is_some())
+    pub async fn get_cache_stats(&self) -> NormalizerCacheStats {
+        let regex_cache_size = self.regex_cache.lock().await.len();
+        let rules_cached = self.rules_cache.lock().await.is_some();
+        let (regex_hits, regex_misses) = (self.regex_hits.load(Ordering::Relaxed), self.regex_misses.load(Ordering::Relaxed));
+        let (rules_hits, rules_misses) = (self.rules_hits.load(Ordering::Relaxed), self.rules_misses.load(Ordering::Relaxed));
+
+        NormalizerCacheStats {
+            regex_cache_size,
+            rules_cached,
+            regex_hits,
+            regex_misses,
+            regex_hit_ratio: hit_ratio(regex_hits, regex_misses),
+            rules_hits,
+            rules_misses,
+            rules_hit_ratio: hit_ratio(rules_hits, rules_misses),
+        }
     }
 }
 
@@ -208,6 +767,279 @@ mod tests {
         // 这里可以添加URL归一化的单元测试
     }
 
+    #[test]
+    fn test_compile_rule_errors() {
+        assert!(matches!(
+            compile_rule(r"https://example\.com/(\d+", "$1"),
+            Err(NormalizationError::RegexCompile { .. })
+        ));
+        assert!(matches!(
+            compile_rule(r"https://example\.com/(\d+)", "https://example.com/$2"),
+            Err(NormalizationError::InvalidReplacement { .. })
+        ));
+        assert!(matches!(
+            compile_rule(r"https://example\.com/(?P<id>\d+)", "https://example.com/${name}"),
+            Err(NormalizationError::InvalidReplacement { .. })
+        ));
+    }
+
+    fn rule(id: i32) -> NormalizationRule {
+        NormalizationRule {
+            id,
+            kind: crate::services::database::RULE_KIND_REGEX.to_string(),
+            category: None,
+            pattern: String::new(),
+            replacement: String::new(),
+            enabled: true,
+            order_index: id,
+            expires_at: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    fn normalizer() -> UrlNormalizer {
+        let db = DatabaseService::lazy("postgres://localhost/history_manager_test");
+        UrlNormalizer::new(Arc::new(db), RuleMatchMode::FirstMatch)
+    }
+
+    async fn cached_time(normalizer: &UrlNormalizer, pattern: &str) -> Option<DateTime<Utc>> {
+        normalizer.regex_cache.lock().await.get(pattern).map(|(_, cached_time)| *cached_time)
+    }
+
+    #[tokio::test]
+    async fn test_cached_regex_follows_pattern_changes() {
+        let normalizer = normalizer();
+        let mut edited = rule(1);
+        edited.pattern = r"^https://a\.example\.com/(.*)$".to_string();
+        edited.replacement = "https://example.com/$1".to_string();
+
+        let regex = normalizer.get_cached_regex(&edited).await.unwrap();
+        assert_eq!(regex.as_str(), edited.pattern);
+
+        // 修改模式后使用新模式，改回原模式时不会拿到修改期间的正则
+        let original_pattern = edited.pattern.clone();
+        edited.pattern = r"^https://b\.example\.com/(.*)$".to_string();
+        assert_eq!(normalizer.get_cached_regex(&edited).await.unwrap().as_str(), edited.pattern);
+
+        edited.pattern = original_pattern;
+        assert_eq!(normalizer.get_cached_regex(&edited).await.unwrap().as_str(), edited.pattern);
+    }
+
+    #[tokio::test]
+    async fn test_cached_regex_recompiled_after_rule_update() {
+        let normalizer = normalizer();
+        let mut cached = rule(1);
+        cached.pattern = r"^http://(.*)$".to_string();
+        cached.replacement = "https://$1".to_string();
+        cached.updated_at = Utc::now() - chrono::Duration::minutes(1);
+
+        normalizer.get_cached_regex(&cached).await.unwrap();
+        let first = cached_time(&normalizer, &cached.pattern).await.unwrap();
+
+        // 规则未变更时复用缓存
+        normalizer.get_cached_regex(&cached).await.unwrap();
+        assert_eq!(cached_time(&normalizer, &cached.pattern).await, Some(first));
+
+        // 规则在缓存之后被修改（包括删除后重建为相同模式），重新编译
+        cached.updated_at = Utc::now() + chrono::Duration::seconds(1);
+        normalizer.get_cached_regex(&cached).await.unwrap();
+        assert!(cached_time(&normalizer, &cached.pattern).await.unwrap() > first);
+    }
+
+    #[tokio::test]
+    async fn test_find_conflicts() {
+        let normalizer = normalizer();
+        let mut strip_query = rule(1);
+        strip_query.pattern = r"^([^?]+)\?.*$".to_string();
+        strip_query.replacement = "$1".to_string();
+        let mut strip_tracking = rule(2);
+        strip_tracking.pattern = r"^(.+)\?utm_[^&]*$".to_string();
+        strip_tracking.replacement = "$1".to_string();
+        let mut force_https = rule(3);
+        force_https.pattern = r"^http://(.+)$".to_string();
+        force_https.replacement = "https://$1".to_string();
+        *normalizer.rules_cache.lock().await = Some((vec![strip_query, strip_tracking, force_https], Utc::now()));
+
+        let urls = vec![
+            "https://example.com/page?utm_source=x".to_string(),
+            "http://example.com/page?id=1".to_string(),
+            "https://example.com/page".to_string(),
+        ];
+        let conflicts = normalizer.find_conflicts(&urls).await.unwrap();
+
+        assert_eq!(conflicts.len(), 2);
+        // 两条规则结果相同，只是冗余
+        assert_eq!(conflicts[0].matches.iter().map(|output| output.rule_id).collect::<Vec<_>>(), vec![1, 2]);
+        assert!(!conflicts[0].differing);
+        // 结果不同，规则顺序决定了结果
+        assert_eq!(conflicts[1].matches.iter().map(|output| output.rule_id).collect::<Vec<_>>(), vec![1, 3]);
+        assert!(conflicts[1].differing);
+    }
+
+    #[test]
+    fn test_normalize_compiled_match_modes() {
+        let mut strip_query = rule(1);
+        strip_query.replacement = "$1".to_string();
+        let mut force_https = rule(2);
+        force_https.replacement = "https://$1".to_string();
+        let compiled = vec![
+            (strip_query, Regex::new(r"^([^?]+)\?.*$").unwrap()),
+            (force_https, Regex::new(r"^http://(.+)$").unwrap()),
+        ];
+
+        let url = "http://example.com/page?utm_source=x";
+        assert_eq!(normalize_compiled(url, &compiled, RuleMatchMode::FirstMatch, OutputCheck::default()), "http://example.com/page");
+        assert_eq!(normalize_compiled(url, &compiled, RuleMatchMode::Chain, OutputCheck::default()), "https://example.com/page");
+        assert_eq!(normalize_compiled("ftp://example.com", &compiled, RuleMatchMode::Chain, OutputCheck::default()), "ftp://example.com");
+    }
+
+    #[test]
+    fn test_canonicalize_host() {
+        let punycode = "https://xn--mnchen-3ya.de/stadt?q=1";
+        assert_eq!(canonicalize_host("https://münchen.de/stadt?q=1"), punycode);
+        assert_eq!(canonicalize_host("https://MÜNCHEN.de/stadt?q=1"), punycode);
+        assert_eq!(canonicalize_host(punycode), punycode);
+        assert_eq!(
+            canonicalize_host("https://user@münchen.de:8443/stadt"),
+            "https://user@xn--mnchen-3ya.de:8443/stadt"
+        );
+        // 路径中的非ASCII字符不受影响
+        assert_eq!(canonicalize_host("https://example.com/münchen"), "https://example.com/münchen");
+        assert_eq!(canonicalize_host("not a url"), "not a url");
+    }
+
+    #[test]
+    fn test_unicode_and_punycode_hosts_normalize_alike() {
+        let rule = NormalizationRule { pattern: r"\?.*$".to_string(), replacement: String::new(), ..rule(1) };
+        let compiled = vec![(rule.clone(), Regex::new(&rule.pattern).unwrap())];
+
+        assert_eq!(
+            normalize_compiled("https://münchen.de/stadt?ref=a", &compiled, RuleMatchMode::FirstMatch, OutputCheck::default()),
+            normalize_compiled("https://xn--mnchen-3ya.de/stadt?ref=b", &compiled, RuleMatchMode::FirstMatch, OutputCheck::default())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cache_stats_count_hits_and_misses() {
+        let normalizer = normalizer();
+        assert_eq!(normalizer.get_cache_stats().await.regex_hit_ratio, None);
+
+        let rule = NormalizationRule { pattern: "^http://".to_string(), replacement: "https://".to_string(), ..rule(1) };
+        for _ in 0..4 {
+            normalizer.get_cached_regex(&rule).await.unwrap();
+        }
+
+        let stats = normalizer.get_cache_stats().await;
+        assert_eq!((stats.regex_hits, stats.regex_misses), (3, 1));
+        assert_eq!(stats.regex_hit_ratio, Some(0.75));
+        assert_eq!(stats.regex_cache_size, 1);
+    }
+
+    #[test]
+    fn test_rule_on_urls() {
+        let urls = vec!["http://example.com/a?utm_source=x".to_string(), "https://example.com/b".to_string()];
+        let results = UrlNormalizer::test_rule_on_urls(r"\?utm_[^&]*$", "", &urls).unwrap();
+
+        assert_eq!(results.len(), 2);
+        assert!(results[0].matched);
+        assert_eq!(results[0].normalized_url, "http://example.com/a");
+        assert_eq!(results[0].captures, vec![Some("?utm_source=x".to_string())]);
+        assert_eq!(results[0].expanded_replacement.as_deref(), Some(""));
+        assert!(!results[1].matched);
+        assert_eq!(results[1].normalized_url, "https://example.com/b");
+        assert!(results[1].captures.is_empty());
+        assert_eq!(results[1].expanded_replacement, None);
+
+        assert!(UrlNormalizer::test_rule_on_urls("(", "", &urls).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rule_reports_capture_groups() {
+        let normalizer = normalizer();
+
+        let result = normalizer
+            .test_rule(r"^https://(www\.)?(?P<host>[^/]+)/(\w+)", "https://${host}/v2/$3", "https://example.com/docs/intro")
+            .await
+            .unwrap();
+        assert!(result.matched);
+        assert_eq!(result.normalized_url, "https://example.com/v2/docs/intro");
+        assert_eq!(result.captures, vec![
+            Some("https://example.com/docs".to_string()),
+            None,
+            Some("example.com".to_string()),
+            Some("docs".to_string()),
+        ]);
+        assert_eq!(result.expanded_replacement.as_deref(), Some("https://example.com/v2/docs"));
+
+        let result = normalizer.test_rule(r"utm_source=\w+", "", "https://example.com/").await.unwrap();
+        assert!(!result.matched);
+        assert!(result.captures.is_empty());
+        assert_eq!(result.expanded_replacement, None);
+    }
+
+    #[test]
+    fn test_oversized_rule_output_is_treated_as_no_match() {
+        let rule = NormalizationRule { pattern: "^(.*)$".to_string(), replacement: "$1".repeat(100), ..rule(1) };
+        let regex = Regex::new(&rule.pattern).unwrap();
+        let url = "https://example.com/a-fairly-short-path";
+
+        let small = OutputCheck { max_length: 1024, ..OutputCheck::default() };
+
+        assert_eq!(apply_compiled_rule(url, &rule, &regex, small), None);
+        assert_eq!(apply_compiled_rule(url, &rule, &regex, OutputCheck::default()).map(|output| output.len()), Some(url.len() * 100));
+        assert_eq!(normalize_compiled(url, &[(rule, regex)], RuleMatchMode::FirstMatch, small), url);
+    }
+
+    #[test]
+    fn test_invalid_url_output_is_rejected_when_validation_enabled() {
+        // 去掉协议的替换结果不再是绝对URL
+        let rule = NormalizationRule { pattern: "^https?://".to_string(), replacement: String::new(), ..rule(1) };
+        let regex = Regex::new(&rule.pattern).unwrap();
+        let url = "https://example.com/page";
+        let validating = OutputCheck { require_valid_url: true, ..OutputCheck::default() };
+
+        assert_eq!(apply_compiled_rule(url, &rule, &regex, OutputCheck::default()), Some("example.com/page".to_string()));
+        assert_eq!(apply_compiled_rule(url, &rule, &regex, validating), None);
+
+        let rule = NormalizationRule { pattern: "^http://".to_string(), replacement: "https://".to_string(), ..rule };
+        let regex = Regex::new(&rule.pattern).unwrap();
+        assert_eq!(apply_compiled_rule("http://example.com/page", &rule, &regex, validating), Some(url.to_string()));
+    }
+
+    #[test]
+    fn test_strip_path_params() {
+        assert_eq!(
+            strip_path_params("https://legacy.example.com/app;jsessionid=ABC123/page.do?id=1").as_deref(),
+            Some("https://legacy.example.com/app/page.do?id=1")
+        );
+        assert_eq!(
+            strip_path_params("https://legacy.example.com/a;x=1;y=2/b;z=3").as_deref(),
+            Some("https://legacy.example.com/a/b")
+        );
+        assert_eq!(strip_path_params("https://example.com/page?a=1;b=2"), None);
+        assert_eq!(strip_path_params("https://example.com/page"), None);
+    }
+
+    #[test]
+    fn test_reorder_rules() {
+        let rules = vec![rule(1), rule(2), rule(3)];
+
+        let reordered = reorder_rules(&rules, &[3, 1, 2]).unwrap();
+        assert_eq!(reordered.iter().map(|rule| rule.id).collect::<Vec<_>>(), vec![3, 1, 2]);
+
+        assert!(matches!(reorder_rules(&rules, &[3, 1]), Err(NormalizationError::InvalidRuleOrder(_))));
+        assert!(matches!(reorder_rules(&rules, &[3, 1, 1]), Err(NormalizationError::InvalidRuleOrder(_))));
+        assert!(matches!(reorder_rules(&rules, &[3, 1, 2, 4]), Err(NormalizationError::InvalidRuleOrder(_))));
+    }
+
+    #[test]
+    fn test_compile_rule_valid_replacements() {
+        assert!(compile_rule(r"https://example\.com/(\d+).*", "https://example.com/$1").is_ok());
+        assert!(compile_rule(r"https://example\.com/(?P<id>\d+).*", "https://example.com/${id}?x=$$1").is_ok());
+        assert!(compile_rule(r"https://example\.com/.*", "https://example.com/$0").is_ok());
+    }
+
     #[test]
     fn test_regex_pattern() {
         let pattern = r"https://example\.com/video/(\d+).*";
@@ -0,0 +1,118 @@
+use chrono::{DateTime, NaiveDateTime};
+use rust_xlsxwriter::{Format, Workbook, XlsxError};
+use serde_json::Value;
+
+/// Excel 单元格超链接的最大长度，更长的URL写成普通文本
+const MAX_HYPERLINK_LENGTH: usize = 2079;
+/// Excel 单个工作表最多的超链接数量，超过后的URL写成普通文本
+const MAX_HYPERLINKS_PER_SHEET: usize = 65530;
+
+const HEADERS: [(&str, f64); 4] = [
+    ("Visited at (UTC)", 20.0),
+    ("Domain", 28.0),
+    ("URL", 80.0),
+    ("Normalized URL", 80.0),
+];
+
+/// 导出文件的 Content-Type
+pub const XLSX_CONTENT_TYPE: &str = "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+
+/// 把历史记录（ES文档 `_source`）写成XLSX：表头加粗并冻结，访问时间为日期类型，URL为可点击的超链接
+pub fn history_workbook(records: &[Value]) -> Result<Vec<u8>, XlsxError> {
+    let mut workbook = Workbook::new();
+    let worksheet = workbook.add_worksheet();
+    worksheet.set_name("History")?;
+
+    let header_format = Format::new().set_bold();
+    let date_format = Format::new().set_num_format("yyyy-mm-dd hh:mm:ss");
+
+    for (col, (title, width)) in HEADERS.iter().enumerate() {
+        worksheet.write_string_with_format(0, col as u16, *title, &header_format)?;
+        worksheet.set_column_width(col as u16, *width)?;
+    }
+    worksheet.set_freeze_panes(1, 0)?;
+
+    let mut hyperlinks = 0;
+    for (index, record) in records.iter().enumerate() {
+        let row = index as u32 + 1;
+
+        let timestamp = record["timestamp"].as_str().unwrap_or_default();
+        match visited_at_utc(timestamp) {
+            Some(visited_at) => worksheet.write_datetime_with_format(row, 0, &visited_at, &date_format)?,
+            None => worksheet.write_string(row, 0, timestamp)?,
+        };
+
+        worksheet.write_string(row, 1, record["domain"].as_str().unwrap_or_default())?;
+
+        // 旧文档只有 url 字段
+        let url = record["original_url"].as_str()
+            .or_else(|| record["url"].as_str())
+            .unwrap_or_default();
+        if is_linkable(url) && hyperlinks < MAX_HYPERLINKS_PER_SHEET {
+            worksheet.write_url(row, 2, url)?;
+            hyperlinks += 1;
+        } else {
+            worksheet.write_string(row, 2, url)?;
+        }
+
+        if let Some(normalized_url) = record["normalized_url"].as_str() {
+            worksheet.write_string(row, 3, normalized_url)?;
+        }
+    }
+
+    if !records.is_empty() {
+        worksheet.autofilter(0, 0, records.len() as u32, HEADERS.len() as u16 - 1)?;
+    }
+
+    workbook.save_to_buffer()
+}
+
+/// 解析RFC 3339访问时间并转换为UTC，无法解析时返回 None（按原文写入）
+fn visited_at_utc(timestamp: &str) -> Option<NaiveDateTime> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .ok()
+        .map(|visited_at| visited_at.naive_utc())
+}
+
+/// 只有 http(s) URL 且长度在Excel限制内时才写成超链接
+fn is_linkable(url: &str) -> bool {
+    (url.starts_with("http://") || url.starts_with("https://")) && url.len() <= MAX_HYPERLINK_LENGTH
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_visited_at_converts_to_utc() {
+        let visited_at = visited_at_utc("2024-03-19T18:30:00+08:00").unwrap();
+        assert_eq!(visited_at.to_string(), "2024-03-19 10:30:00");
+        assert!(visited_at_utc("yesterday").is_none());
+    }
+
+    #[test]
+    fn test_is_linkable() {
+        assert!(is_linkable("https://example.com/a"));
+        assert!(!is_linkable("javascript:alert(1)"));
+        assert!(!is_linkable(&format!("https://example.com/{}", "a".repeat(MAX_HYPERLINK_LENGTH))));
+    }
+
+    #[test]
+    fn test_history_workbook_is_xlsx() {
+        let records = vec![
+            json!({
+                "timestamp": "2024-03-19T10:30:00Z",
+                "domain": "example.com",
+                "original_url": "https://example.com/a?utm_source=x",
+                "normalized_url": "https://example.com/a"
+            }),
+            json!({ "timestamp": "not a date", "domain": "legacy.example.com", "url": "https://legacy.example.com/" }),
+        ];
+
+        let buffer = history_workbook(&records).unwrap();
+        // XLSX 是 zip 包
+        assert!(buffer.starts_with(b"PK"));
+        assert!(history_workbook(&[]).unwrap().starts_with(b"PK"));
+    }
+}